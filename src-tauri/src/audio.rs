@@ -1,7 +1,24 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{Device, Host, Stream, StreamConfig};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use crate::error::SipError;
+use crate::events;
+use crate::resample::SimpleResampler;
+use crate::ringer::Ringing;
+use crate::settings::Ringer;
+
+/// Sample rate of call audio between the backend and the media tasks
+pub const CALL_AUDIO_RATE: u32 = 48000;
+
+/// Converter from a device's own rate to `to`, or None when they match
+///
+/// cpal opens devices at their default rate (often 44.1kHz). Built so the
+/// conversion is always `upsample`, which keeps the phase across callbacks.
+fn rate_converter(from: u32, to: u32) -> Option<SimpleResampler> {
+    (from != to).then(|| SimpleResampler::new(to, from))
+}
 
 /// Audio manager for handling microphone input and speaker output
 pub struct AudioManager {
@@ -12,7 +29,7 @@ pub struct AudioManager {
 
 impl AudioManager {
     /// Create a new audio manager
-    pub fn new() -> Result<Self, String> {
+    pub fn new() -> Result<Self, SipError> {
         let host = cpal::default_host();
         
         println!("[Audio] Available audio host: {}", host.id().name());
@@ -25,10 +42,10 @@ impl AudioManager {
     }
 
     /// List available input devices
-    pub fn list_input_devices(&self) -> Result<Vec<String>, String> {
+    pub fn list_input_devices(&self) -> Result<Vec<String>, SipError> {
         let devices = self.host
             .input_devices()
-            .map_err(|e| format!("Failed to enumerate input devices: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to enumerate input devices: {}", e)))?;
 
         let mut device_names = Vec::new();
         for device in devices {
@@ -41,10 +58,10 @@ impl AudioManager {
     }
 
     /// List available output devices
-    pub fn list_output_devices(&self) -> Result<Vec<String>, String> {
+    pub fn list_output_devices(&self) -> Result<Vec<String>, SipError> {
         let devices = self.host
             .output_devices()
-            .map_err(|e| format!("Failed to enumerate output devices: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to enumerate output devices: {}", e)))?;
 
         let mut device_names = Vec::new();
         for device in devices {
@@ -57,7 +74,7 @@ impl AudioManager {
     }
 
     /// Initialize default input device
-    pub fn init_input(&mut self) -> Result<(), String> {
+    pub fn init_input(&mut self) -> Result<(), SipError> {
         // Try to get default device
         let device = self.host
             .default_input_device()
@@ -71,7 +88,7 @@ impl AudioManager {
                         }
                     }
                 }
-                "No default input device available. Please check your audio configuration.".to_string()
+                SipError::Audio("No default input device available. Please check your audio configuration.".to_string())
             })?;
 
         let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
@@ -82,7 +99,7 @@ impl AudioManager {
     }
 
     /// Initialize default output device
-    pub fn init_output(&mut self) -> Result<(), String> {
+    pub fn init_output(&mut self) -> Result<(), SipError> {
         // Try to get default device
         let device = self.host
             .default_output_device()
@@ -96,7 +113,7 @@ impl AudioManager {
                         }
                     }
                 }
-                "No default output device available. Please check your audio configuration.".to_string()
+                SipError::Audio("No default output device available. Please check your audio configuration.".to_string())
             })?;
 
         let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
@@ -107,10 +124,10 @@ impl AudioManager {
     }
     
     /// Initialize specific input device by name
-    pub fn init_input_by_name(&mut self, device_name: &str) -> Result<(), String> {
+    pub fn init_input_by_name(&mut self, device_name: &str) -> Result<(), SipError> {
         let devices = self.host
             .input_devices()
-            .map_err(|e| format!("Failed to enumerate input devices: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to enumerate input devices: {}", e)))?;
 
         for device in devices {
             if let Ok(name) = device.name() {
@@ -122,14 +139,14 @@ impl AudioManager {
             }
         }
 
-        Err(format!("Input device '{}' not found", device_name))
+        Err(SipError::Audio(format!("Input device '{}' not found", device_name)))
     }
 
     /// Initialize specific output device by name
-    pub fn init_output_by_name(&mut self, device_name: &str) -> Result<(), String> {
+    pub fn init_output_by_name(&mut self, device_name: &str) -> Result<(), SipError> {
         let devices = self.host
             .output_devices()
-            .map_err(|e| format!("Failed to enumerate output devices: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to enumerate output devices: {}", e)))?;
 
         for device in devices {
             if let Ok(name) = device.name() {
@@ -141,20 +158,98 @@ impl AudioManager {
             }
         }
 
-        Err(format!("Output device '{}' not found", device_name))
+        Err(SipError::Audio(format!("Output device '{}' not found", device_name)))
+    }
+
+    /// Stream configurations the named device supports as a microphone or
+    /// speaker. A device that reports none gets an empty list, not an error.
+    pub fn device_capabilities(&self, device_name: &str, kind: DeviceKind) -> Result<DeviceCapabilities, SipError> {
+        let devices = match kind {
+            DeviceKind::Input => self.host.input_devices(),
+            DeviceKind::Output => self.host.output_devices(),
+        }
+        .map_err(|e| SipError::Audio(format!("Failed to enumerate {} devices: {}", kind.label(), e)))?;
+
+        let device = devices
+            .into_iter()
+            .find(|device| device.name().is_ok_and(|name| name == device_name))
+            .ok_or_else(|| SipError::Audio(format!("{} device '{}' not found", kind.title(), device_name)))?;
+
+        let configs: Vec<DeviceConfigRange> = match kind {
+            DeviceKind::Input => device.supported_input_configs().map(|configs| configs.map(DeviceConfigRange::from).collect()),
+            DeviceKind::Output => device.supported_output_configs().map(|configs| configs.map(DeviceConfigRange::from).collect()),
+        }
+        .map_err(|e| SipError::Audio(format!("Failed to query {} device '{}': {}", kind.label(), device_name, e)))?;
+
+        if configs.is_empty() {
+            println!("[Audio] {} device '{}' reports no supported configurations", kind.title(), device_name);
+        }
+
+        Ok(DeviceCapabilities {
+            name: device_name.to_string(),
+            kind,
+            configs,
+        })
+    }
+
+    /// Open the named input device, falling back to the default device
+    /// Returns the name of the device actually opened
+    fn open_input(&mut self, preferred: Option<&str>) -> Result<String, SipError> {
+        if let Some(name) = preferred {
+            match self.init_input_by_name(name) {
+                Ok(()) => return Ok(name.to_string()),
+                Err(e) => println!("[Audio] {}, using default input", e),
+            }
+        }
+        self.init_input()?;
+        Ok(self.input_device
+            .as_ref()
+            .and_then(|d| d.name().ok())
+            .unwrap_or_else(|| "Unknown".to_string()))
+    }
+
+    /// Open the named output device, falling back to the default device
+    /// Returns the name of the device actually opened
+    pub fn open_output(&mut self, preferred: Option<&str>) -> Result<String, SipError> {
+        if let Some(name) = preferred {
+            match self.init_output_by_name(name) {
+                Ok(()) => return Ok(name.to_string()),
+                Err(e) => println!("[Audio] {}, using default output", e),
+            }
+        }
+        self.init_output()?;
+        Ok(self.output_device
+            .as_ref()
+            .and_then(|d| d.name().ok())
+            .unwrap_or_else(|| "Unknown".to_string()))
     }
 
     /// Start capturing audio from microphone
     /// Returns a channel receiver that will receive audio samples
-    pub fn start_capture(&self) -> Result<(Stream, mpsc::Receiver<Vec<i16>>), String> {
+    pub fn start_capture(&self) -> Result<(Stream, mpsc::Receiver<Vec<i16>>), SipError> {
+        let (tx, rx) = mpsc::channel(100);
+        let stream = self.start_capture_into(tx, Arc::new(AudioDrops::default()), None)?;
+        Ok((stream, rx))
+    }
+
+    /// Start capturing audio into an existing channel
+    /// If `faults` is given, a fatal stream error (device unplugged) is reported on it.
+    /// Frames that don't fit in the channel are dropped and counted in `drops`
+    /// rather than blocking the real-time callback.
+    fn start_capture_into(
+        &self,
+        tx: mpsc::Sender<Vec<i16>>,
+        drops: Arc<AudioDrops>,
+        faults: Option<std::sync::mpsc::Sender<MediaControl>>,
+    ) -> Result<Stream, SipError> {
         let device = self.input_device
             .as_ref()
-            .ok_or("Input device not initialized")?;
+            .ok_or_else(|| SipError::Audio("Input device not initialized".to_string()))?;
 
         // Get supported config
         let supported_config = device
             .default_input_config()
-            .map_err(|e| format!("Failed to get input config: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to get input config: {}", e)))?;
 
         tracing::info!("[Audio] Default input config: {:?}", supported_config);
         println!("[Audio] Default input config: {:?}", supported_config);
@@ -169,10 +264,13 @@ impl AudioManager {
         tracing::info!("[Audio] Using input config: channels={}, sample_rate={}", config.channels, config.sample_rate.0);
         println!("[Audio] Using input config: {:?}", config);
 
-        let (tx, rx) = mpsc::channel(100);
         let channels = config.channels;
+        let converter = rate_converter(config.sample_rate.0, CALL_AUDIO_RATE);
 
-        let err_fn = |err| eprintln!("[Audio] Input stream error: {}", err);
+        let err_fn = move |err| {
+            eprintln!("[Audio] Input stream error: {}", err);
+            report_stream_error(&faults, StreamDirection::Input, err);
+        };
 
         // Build input stream
         let stream = device
@@ -191,34 +289,53 @@ impl AudioManager {
                     } else {
                         data.to_vec()
                     };
+                    let samples = match &converter {
+                        Some(converter) => converter.upsample(&samples).unwrap_or(samples),
+                        None => samples,
+                    };
                     
-                    if let Err(e) = tx.blocking_send(samples) {
-                        eprintln!("[Audio] Failed to send audio data: {}", e);
+                    match tx.try_send(samples) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(samples)) => {
+                            drops.capture.fetch_add(samples.len() as u64, Ordering::Relaxed);
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => {}
                     }
                 },
                 err_fn,
                 None,
             )
-            .map_err(|e| format!("Failed to build input stream: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to build input stream: {}", e)))?;
 
-        stream.play().map_err(|e| format!("Failed to start input stream: {}", e))?;
+        stream.play().map_err(|e| SipError::Audio(format!("Failed to start input stream: {}", e)))?;
 
         println!("[Audio] ✓ Microphone capture started");
 
-        Ok((stream, rx))
+        Ok(stream)
     }
 
-    /// Start playing audio to speaker
-    /// Returns a channel sender to send audio samples for playback
-    pub fn start_playback(&self) -> Result<(Stream, mpsc::Sender<Vec<i16>>), String> {
+    /// Start playing audio to speaker from an existing channel
+    /// The receiver is shared so a replacement stream can pick it up if this one fails
+    /// Local tones (key feedback) in `tone` are mixed on top of the call audio
+    /// Playout latency is held near `target_latency_ms` (see `PlayoutBuffer`);
+    /// how much is queued is published in `depth_ms` after every callback
+    fn start_playback_from(
+        &self,
+        rx: Arc<std::sync::Mutex<mpsc::Receiver<Vec<i16>>>>,
+        tone: Arc<std::sync::Mutex<ToneBuffer>>,
+        drops: Arc<AudioDrops>,
+        depth_ms: Arc<AtomicU32>,
+        target_latency_ms: u32,
+        faults: Option<std::sync::mpsc::Sender<MediaControl>>,
+    ) -> Result<Stream, SipError> {
         let device = self.output_device
             .as_ref()
-            .ok_or("Output device not initialized")?;
+            .ok_or_else(|| SipError::Audio("Output device not initialized".to_string()))?;
 
         // Get supported config
         let supported_config = device
             .default_output_config()
-            .map_err(|e| format!("Failed to get output config: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to get output config: {}", e)))?;
 
         println!("[Audio] Default output config: {:?}", supported_config);
 
@@ -231,12 +348,15 @@ impl AudioManager {
 
         println!("[Audio] Using output config: {:?}", config);
 
-        let (tx, mut rx) = mpsc::channel::<Vec<i16>>(100);
-        let buffer = Arc::new(std::sync::Mutex::new(Vec::<i16>::new()));
-        let buffer_clone = buffer.clone();
         let channels = config.channels;
+        tone.lock().unwrap().sample_rate = config.sample_rate.0;
+        let mut playout = PlayoutBuffer::new(config.sample_rate.0, channels, target_latency_ms);
+        let converter = rate_converter(CALL_AUDIO_RATE, config.sample_rate.0);
 
-        let err_fn = |err| eprintln!("[Audio] Output stream error: {}", err);
+        let err_fn = move |err| {
+            eprintln!("[Audio] Output stream error: {}", err);
+            report_stream_error(&faults, StreamDirection::Output, err);
+        };
 
         // Build output stream
         let stream = device
@@ -244,58 +364,61 @@ impl AudioManager {
                 &config,
                 move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
                     // Try to receive new audio data
+                    let mut rx = match rx.try_lock() {
+                        Ok(rx) => rx,
+                        Err(_) => {
+                            data.fill(0);
+                            return;
+                        }
+                    };
                     while let Ok(samples) = rx.try_recv() {
-                        let mut buf = buffer_clone.lock().unwrap();
-                        // Duplicate mono to stereo if needed
-                        if channels == 2 {
-                            for sample in samples {
-                                buf.push(sample);
-                                buf.push(sample); // Duplicate for right channel
-                            }
-                        } else {
-                            buf.extend_from_slice(&samples);
+                        let samples = match &converter {
+                            Some(converter) => converter.upsample(&samples).unwrap_or(samples),
+                            None => samples,
+                        };
+                        // Arriving faster than we play: drop audio instead of
+                        // letting latency creep up
+                        let dropped = playout.push(&samples);
+                        if dropped > 0 {
+                            drops.playback.fetch_add(dropped as u64, Ordering::Relaxed);
                         }
                     }
 
-                    // Fill output buffer
-                    let mut buf = buffer_clone.lock().unwrap();
-                    let available = buf.len().min(data.len());
-                    
-                    if available > 0 {
-                        data[..available].copy_from_slice(&buf[..available]);
-                        buf.drain(..available);
-                        
-                        // Fill remaining with silence
-                        if available < data.len() {
-                            data[available..].fill(0);
+                    playout.fill(data);
+                    depth_ms.store(playout.depth_ms(), Ordering::Relaxed);
+
+                    // Mix any local tone into every channel
+                    if let Ok(mut tone) = tone.try_lock() {
+                        for frame in data.chunks_mut(channels as usize) {
+                            let Some(sample) = tone.samples.pop_front() else { break };
+                            for out in frame {
+                                *out = out.saturating_add(sample);
+                            }
                         }
-                    } else {
-                        // No data available, output silence
-                        data.fill(0);
                     }
                 },
                 err_fn,
                 None,
             )
-            .map_err(|e| format!("Failed to build output stream: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to build output stream: {}", e)))?;
 
-        stream.play().map_err(|e| format!("Failed to start output stream: {}", e))?;
+        stream.play().map_err(|e| SipError::Audio(format!("Failed to start output stream: {}", e)))?;
 
         println!("[Audio] ✓ Speaker playback started");
 
-        Ok((stream, tx))
+        Ok(stream)
     }
 
     /// Test speaker by playing a tone
-    pub fn test_speaker(&self, frequency: f32, duration_ms: u64) -> Result<String, String> {
+    pub fn test_speaker(&self, frequency: f32, duration_ms: u64) -> Result<String, SipError> {
         let device = self.output_device
             .as_ref()
-            .ok_or("Output device not initialized")?;
+            .ok_or_else(|| SipError::Audio("Output device not initialized".to_string()))?;
 
         // Get supported config
         let supported_config = device
             .default_output_config()
-            .map_err(|e| format!("Failed to get output config: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to get output config: {}", e)))?;
 
         let config = StreamConfig {
             channels: supported_config.channels().min(2),
@@ -328,9 +451,9 @@ impl AudioManager {
                 err_fn,
                 None,
             )
-            .map_err(|e| format!("Failed to build output stream: {}", e))?;
+            .map_err(|e| SipError::Audio(format!("Failed to build output stream: {}", e)))?;
 
-        stream.play().map_err(|e| format!("Failed to start output stream: {}", e))?;
+        stream.play().map_err(|e| SipError::Audio(format!("Failed to start output stream: {}", e)))?;
 
         // Play for specified duration
         std::thread::sleep(std::time::Duration::from_millis(duration_ms));
@@ -339,6 +462,789 @@ impl AudioManager {
 
         Ok(format!("Speaker test complete! Played {}Hz tone for {}ms", frequency, duration_ms))
     }
+
+    /// Sample rate of the output device's default configuration
+    pub fn output_sample_rate(&self) -> Result<u32, SipError> {
+        let device = self.output_device
+            .as_ref()
+            .ok_or_else(|| SipError::Audio("Output device not initialized".to_string()))?;
+        device
+            .default_output_config()
+            .map(|config| config.sample_rate().0)
+            .map_err(|e| SipError::Audio(format!("Failed to get output config: {}", e)))
+    }
+
+    /// Play mono `sound` (at the output sample rate) over and over at
+    /// `volume` until the returned stream is dropped
+    pub fn start_loop(&self, sound: Vec<i16>, volume: f32) -> Result<Stream, SipError> {
+        let device = self.output_device
+            .as_ref()
+            .ok_or_else(|| SipError::Audio("Output device not initialized".to_string()))?;
+        if sound.is_empty() {
+            return Err(SipError::Audio("Nothing to play".to_string()));
+        }
+
+        let supported_config = device
+            .default_output_config()
+            .map_err(|e| SipError::Audio(format!("Failed to get output config: {}", e)))?;
+        let config = StreamConfig {
+            channels: supported_config.channels().min(2),
+            sample_rate: supported_config.sample_rate(),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let channels = config.channels as usize;
+        let volume = volume.clamp(0.0, 1.0);
+
+        let mut position = 0;
+        let err_fn = |err| eprintln!("[Audio] Output stream error: {}", err);
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                    for frame in data.chunks_mut(channels) {
+                        let sample = (sound[position] as f32 * volume) as i16;
+                        frame.fill(sample);
+                        position = (position + 1) % sound.len();
+                    }
+                },
+                err_fn,
+                None,
+            )
+            .map_err(|e| SipError::Audio(format!("Failed to build output stream: {}", e)))?;
+
+        stream.play().map_err(|e| SipError::Audio(format!("Failed to start output stream: {}", e)))?;
+        Ok(stream)
+    }
+}
+
+/// Which half of the call audio path a stream belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamDirection {
+    Input,
+    Output,
+}
+
+impl StreamDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StreamDirection::Input => "input",
+            StreamDirection::Output => "output",
+        }
+    }
+}
+
+/// Messages handled by the call audio thread
+#[derive(Debug)]
+enum MediaControl {
+    /// A stream reported that its device went away
+    StreamFailed(StreamDirection),
+    /// Move playback to the named device (default if it is gone); replies
+    /// with the device actually opened
+    SwitchOutput(String, std::sync::mpsc::Sender<Result<String, SipError>>),
+    /// Tear down the streams and exit the thread
+    Stop,
+}
+
+/// Forward fatal stream errors to the call audio thread so it can rebuild the stream
+fn report_stream_error(
+    faults: &Option<std::sync::mpsc::Sender<MediaControl>>,
+    direction: StreamDirection,
+    err: cpal::StreamError,
+) {
+    if let (Some(faults), cpal::StreamError::DeviceNotAvailable) = (faults, err) {
+        let _ = faults.send(MediaControl::StreamFailed(direction));
+    }
+}
+
+/// How many times to try reopening a device after it disappears
+const RECOVERY_ATTEMPTS: u32 = 5;
+/// Delay between recovery attempts (gives the OS time to settle after a hot-plug)
+const RECOVERY_DELAY_MS: u64 = 500;
+
+/// Channel ends the RTP tasks use to exchange samples with the call audio streams
+pub struct AudioChannels {
+    /// Microphone samples
+    pub capture: mpsc::Receiver<Vec<i16>>,
+    /// Samples to play on the speaker
+    pub playback: mpsc::Sender<Vec<i16>>,
+}
+
+/// Capture and playback streams for an active call
+///
+/// cpal streams are not Send, so they are created and owned by a dedicated
+/// thread. When a device disappears mid-call (e.g. a USB headset is unplugged)
+/// that thread rebuilds the stream on the preferred device or the system
+/// default, reusing the same channels so the RTP tasks keep running.
+#[derive(Debug)]
+pub struct CallAudio {
+    control: std::sync::mpsc::Sender<MediaControl>,
+    tone: Arc<std::sync::Mutex<ToneBuffer>>,
+    drops: Arc<AudioDrops>,
+    // Milliseconds queued for the speaker; None when nothing is played
+    playout_depth: Option<Arc<AtomicU32>>,
+}
+
+/// Playout latency used when none is configured
+pub const DEFAULT_PLAYOUT_TARGET_MS: u32 = 60;
+
+/// Smallest playout target; below one packet every frame would underrun
+pub const MIN_PLAYOUT_TARGET_MS: u32 = 20;
+
+/// Largest playout target; beyond this the delay makes conversation awkward
+pub const MAX_PLAYOUT_TARGET_MS: u32 = 1000;
+
+/// Playback queue that keeps mouth-to-ear latency near a target
+///
+/// If RX delivers slightly faster than the device plays, the queue would grow
+/// for the whole call. Once it passes the high-water mark (twice the target)
+/// the oldest audio is dropped back down to the target. When it runs dry the
+/// output is held silent until the target has been rebuilt, so one late packet
+/// doesn't turn into a stream of tiny underruns.
+#[derive(Debug)]
+pub struct PlayoutBuffer {
+    /// Interleaved samples waiting to be played
+    samples: std::collections::VecDeque<i16>,
+    channels: usize,
+    sample_rate: u32,
+    /// Target depth in interleaved samples
+    target: usize,
+    /// Depth at which audio is dropped back to `target`
+    high_water: usize,
+    /// Holding silence until `target` samples are queued again
+    refilling: bool,
+}
+
+impl PlayoutBuffer {
+    pub fn new(sample_rate: u32, channels: u16, target_ms: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let target_ms = target_ms.max(MIN_PLAYOUT_TARGET_MS);
+        let target = (sample_rate as u64 * target_ms as u64 / 1000) as usize * channels;
+        Self {
+            samples: std::collections::VecDeque::with_capacity(target * 2),
+            channels,
+            sample_rate: sample_rate.max(1),
+            target,
+            high_water: target * 2,
+            refilling: true,
+        }
+    }
+
+    /// Queue a mono frame, duplicating it across channels
+    ///
+    /// Returns how many samples (per channel) were dropped to pull latency back down.
+    pub fn push(&mut self, mono: &[i16]) -> usize {
+        for &sample in mono {
+            for _ in 0..self.channels {
+                self.samples.push_back(sample);
+            }
+        }
+
+        if self.samples.len() <= self.high_water {
+            return 0;
+        }
+        let excess = self.samples.len() - self.target;
+        self.samples.drain(..excess);
+        excess / self.channels
+    }
+
+    /// Milliseconds of audio queued
+    pub fn depth_ms(&self) -> u32 {
+        (self.samples.len() / self.channels) as u32 * 1000 / self.sample_rate
+    }
+
+    /// Fill an interleaved output buffer, padding with silence on underrun
+    pub fn fill(&mut self, out: &mut [i16]) {
+        if self.refilling && self.samples.len() < self.target {
+            out.fill(0);
+            return;
+        }
+        self.refilling = false;
+
+        let available = self.samples.len().min(out.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..available)) {
+            *slot = sample;
+        }
+        if available < out.len() {
+            out[available..].fill(0);
+            self.refilling = true;
+        }
+    }
+}
+
+/// Largest correction of the playback rate for clock drift (0.1%)
+pub const MAX_DRIFT_ADJUST: f64 = 0.001;
+
+/// Weight of each depth reading in the long-term average (roughly the last
+/// ten seconds at one reading per 20ms packet)
+const DRIFT_SMOOTHING: f64 = 0.002;
+
+/// Estimates clock drift between the sender and the output device from the
+/// playout depth
+///
+/// Jitter moves the depth around from packet to packet; drift shows up as a
+/// slow trend away from the target. The long-term average is turned into a
+/// rate factor for the RX resampler: above 1.0 stretches the audio while the
+/// queue runs low, below 1.0 shrinks it while audio piles up.
+#[derive(Debug)]
+pub struct DriftEstimator {
+    target_ms: f64,
+    average_ms: f64,
+}
+
+impl DriftEstimator {
+    pub fn new(target_ms: u32) -> Self {
+        let target_ms = target_ms.max(MIN_PLAYOUT_TARGET_MS) as f64;
+        Self { target_ms, average_ms: target_ms }
+    }
+
+    /// Take a depth reading and return the rate factor to resample with
+    pub fn update(&mut self, depth_ms: u32) -> f64 {
+        self.average_ms += (depth_ms as f64 - self.average_ms) * DRIFT_SMOOTHING;
+        let error = (self.average_ms - self.target_ms) / self.target_ms;
+        1.0 - (error * MAX_DRIFT_ADJUST).clamp(-MAX_DRIFT_ADJUST, MAX_DRIFT_ADJUST)
+    }
+}
+
+/// Samples thrown away because a side of the call audio couldn't keep up
+#[derive(Debug, Default)]
+pub struct AudioDrops {
+    /// Microphone samples dropped because the TX task wasn't draining the channel
+    capture: AtomicU64,
+    /// Received samples (per channel) dropped to keep the playback buffer bounded
+    playback: AtomicU64,
+}
+
+/// Snapshot of the drop counters, for diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct AudioDropCounts {
+    pub capture_dropped: u64,
+    pub playback_dropped: u64,
+}
+
+impl AudioDrops {
+    pub fn counts(&self) -> AudioDropCounts {
+        AudioDropCounts {
+            capture_dropped: self.capture.load(Ordering::Relaxed),
+            playback_dropped: self.playback.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Microphone or speaker side of an audio device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Input,
+    Output,
+}
+
+impl DeviceKind {
+    fn label(self) -> &'static str {
+        match self {
+            DeviceKind::Input => "input",
+            DeviceKind::Output => "output",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            DeviceKind::Input => "Input",
+            DeviceKind::Output => "Output",
+        }
+    }
+}
+
+/// One range of stream configurations a device accepts
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct DeviceConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// Sample format as cpal names it ("i16", "f32", ...)
+    pub sample_format: String,
+}
+
+impl From<cpal::SupportedStreamConfigRange> for DeviceConfigRange {
+    fn from(range: cpal::SupportedStreamConfigRange) -> Self {
+        Self {
+            channels: range.channels(),
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            sample_format: format!("{:?}", range.sample_format()).to_ascii_lowercase(),
+        }
+    }
+}
+
+/// Supported configurations of one device, for the settings screen
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct DeviceCapabilities {
+    pub name: String,
+    pub kind: DeviceKind,
+    pub configs: Vec<DeviceConfigRange>,
+}
+
+/// Highest input gain or output volume (2x, about +6 dB)
+pub const MAX_LEVEL: f32 = 2.0;
+
+/// Microphone gain and speaker volume of the call audio, as linear factors
+/// (1.0 = unchanged). Kept as f32 bits so the TX and RX tasks read them on
+/// every frame without locking while the UI changes them mid-call.
+#[derive(Debug)]
+pub struct AudioLevels {
+    input_gain: AtomicU32,
+    output_volume: AtomicU32,
+}
+
+impl Default for AudioLevels {
+    fn default() -> Self {
+        Self {
+            input_gain: AtomicU32::new(1.0f32.to_bits()),
+            output_volume: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+}
+
+impl AudioLevels {
+    pub fn input_gain(&self) -> f32 {
+        f32::from_bits(self.input_gain.load(Ordering::Relaxed))
+    }
+
+    /// Set the microphone gain (clamped to 0..=MAX_LEVEL)
+    pub fn set_input_gain(&self, gain: f32) {
+        self.input_gain.store(clamp_level(gain).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn output_volume(&self) -> f32 {
+        f32::from_bits(self.output_volume.load(Ordering::Relaxed))
+    }
+
+    /// Set the speaker volume (clamped to 0..=MAX_LEVEL)
+    pub fn set_output_volume(&self, volume: f32) {
+        self.output_volume.store(clamp_level(volume).to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Check a gain or volume from the UI before it is applied or saved
+pub fn check_level(level: f32) -> Result<f32, SipError> {
+    if (0.0..=MAX_LEVEL).contains(&level) {
+        Ok(level)
+    } else {
+        Err(SipError::InvalidLevel(format!("Level {} is outside 0 to {}", level, MAX_LEVEL)))
+    }
+}
+
+/// Limit a gain or volume to 0..=MAX_LEVEL (NaN counts as unchanged)
+pub fn clamp_level(level: f32) -> f32 {
+    if level.is_nan() {
+        1.0
+    } else {
+        level.clamp(0.0, MAX_LEVEL)
+    }
+}
+
+/// Scale samples by a gain or volume, saturating at full scale
+pub fn apply_level(samples: &mut [i16], level: f32) {
+    if (level - 1.0).abs() < f32::EPSILON {
+        return;
+    }
+    for sample in samples {
+        *sample = (*sample as f32 * level).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    }
+}
+
+/// Length of the local feedback tone for a dialpad key
+pub const DTMF_TONE_MS: u64 = 150;
+
+/// Mono samples still to be mixed into playback, at the output device's rate
+#[derive(Debug)]
+struct ToneBuffer {
+    samples: std::collections::VecDeque<i16>,
+    sample_rate: u32,
+}
+
+impl Default for ToneBuffer {
+    fn default() -> Self {
+        Self {
+            samples: std::collections::VecDeque::new(),
+            sample_rate: 48000,
+        }
+    }
+}
+
+/// Low and high frequency (Hz) of a DTMF key
+pub fn dtmf_frequencies(digit: char) -> Option<(f32, f32)> {
+    const LOW: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+    const HIGH: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+    const KEYS: [[char; 4]; 4] = [
+        ['1', '2', '3', 'A'],
+        ['4', '5', '6', 'B'],
+        ['7', '8', '9', 'C'],
+        ['*', '0', '#', 'D'],
+    ];
+
+    let digit = digit.to_ascii_uppercase();
+    KEYS.iter().enumerate().find_map(|(row, keys)| {
+        keys.iter().position(|&key| key == digit).map(|col| (LOW[row], HIGH[col]))
+    })
+}
+
+/// Generate the dual tone for a DTMF key (mono, ~-10 dBFS per tone)
+pub fn generate_dtmf_tone(digit: char, duration_ms: u64, sample_rate: u32) -> Option<Vec<i16>> {
+    let (low, high) = dtmf_frequencies(digit)?;
+    let count = (sample_rate as u64 * duration_ms / 1000) as usize;
+    let amplitude = i16::MAX as f32 * 0.3;
+
+    Some(
+        (0..count)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let value = (2.0 * std::f32::consts::PI * low * t).sin()
+                    + (2.0 * std::f32::consts::PI * high * t).sin();
+                (value * amplitude / 2.0) as i16
+            })
+            .collect(),
+    )
+}
+
+impl CallAudio {
+    /// Open the call audio streams
+    ///
+    /// Empty or missing device names select the system default.
+    pub fn start(
+        input_device: Option<String>,
+        output_device: Option<String>,
+    ) -> Result<(Self, AudioChannels), SipError> {
+        let (capture_tx, capture_rx) = mpsc::channel::<Vec<i16>>(100);
+        let (playback_tx, playback_rx) = mpsc::channel::<Vec<i16>>(100);
+        let playback_rx = Arc::new(std::sync::Mutex::new(playback_rx));
+
+        let (control_tx, control_rx) = std::sync::mpsc::channel::<MediaControl>();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel::<Result<(), SipError>>();
+        let faults = control_tx.clone();
+        let tone = Arc::new(std::sync::Mutex::new(ToneBuffer::default()));
+        let call_tone = tone.clone();
+        let drops = Arc::new(AudioDrops::default());
+        let call_drops = drops.clone();
+        let depth_ms = Arc::new(AtomicU32::new(0));
+        let call_depth = depth_ms.clone();
+        let target_latency_ms = crate::settings::load_playout_target_ms()
+            .unwrap_or(DEFAULT_PLAYOUT_TARGET_MS);
+
+        let input_device = input_device.filter(|name| !name.is_empty());
+        let mut output_device = output_device.filter(|name| !name.is_empty());
+
+        std::thread::Builder::new()
+            .name("call-audio".to_string())
+            .spawn(move || {
+                let mut manager = match AudioManager::new() {
+                    Ok(manager) => manager,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                let streams = manager
+                    .open_input(input_device.as_deref())
+                    .and_then(|_| manager.start_capture_into(capture_tx.clone(), drops.clone(), Some(faults.clone())))
+                    .and_then(|input| {
+                        manager
+                            .open_output(output_device.as_deref())
+                            .and_then(|_| manager.start_playback_from(playback_rx.clone(), tone.clone(), drops.clone(), depth_ms.clone(), target_latency_ms, Some(faults.clone())))
+                            .map(|output| (input, output))
+                    });
+
+                let (mut input_stream, mut output_stream) = match streams {
+                    Ok(streams) => {
+                        let _ = ready_tx.send(Ok(()));
+                        streams
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                while let Ok(message) = control_rx.recv() {
+                    match message {
+                        MediaControl::StreamFailed(direction) => {
+                            tracing::warn!("[Audio] {} device lost, attempting recovery", direction.as_str());
+                            println!("[Audio] ⚠ {} device lost, attempting recovery", direction.as_str());
+
+                            let preferred = match direction {
+                                StreamDirection::Input => input_device.as_deref(),
+                                StreamDirection::Output => output_device.as_deref(),
+                            };
+
+                            let mut recovered = None;
+                            for attempt in 1..=RECOVERY_ATTEMPTS {
+                                std::thread::sleep(std::time::Duration::from_millis(RECOVERY_DELAY_MS));
+
+                                // Refresh the host so newly attached devices are visible
+                                if let Ok(fresh) = AudioManager::new() {
+                                    manager = fresh;
+                                }
+
+                                let result = match direction {
+                                    StreamDirection::Input => manager
+                                        .open_input(preferred)
+                                        .and_then(|name| {
+                                            manager
+                                                .start_capture_into(capture_tx.clone(), drops.clone(), Some(faults.clone()))
+                                                .map(|stream| (name, stream))
+                                        }),
+                                    StreamDirection::Output => manager
+                                        .open_output(preferred)
+                                        .and_then(|name| {
+                                            manager
+                                                .start_playback_from(playback_rx.clone(), tone.clone(), drops.clone(), depth_ms.clone(), target_latency_ms, Some(faults.clone()))
+                                                .map(|stream| (name, stream))
+                                        }),
+                                };
+
+                                match result {
+                                    Ok(result) => {
+                                        recovered = Some(result);
+                                        break;
+                                    }
+                                    Err(e) => {
+                                        tracing::warn!("[Audio] Recovery attempt {} failed: {}", attempt, e);
+                                    }
+                                }
+                            }
+
+                            match recovered {
+                                Some((name, stream)) => {
+                                    match direction {
+                                        StreamDirection::Input => input_stream = stream,
+                                        StreamDirection::Output => output_stream = stream,
+                                    }
+                                    tracing::info!("[Audio] ✓ {} recovered on '{}'", direction.as_str(), name);
+                                    println!("[Audio] ✓ {} recovered on '{}'", direction.as_str(), name);
+                                    events::emit("audio_device_changed", serde_json::json!({
+                                        "direction": direction.as_str(),
+                                        "device": name,
+                                        "message": format!("Audio {} switched to {}", direction.as_str(), name),
+                                    }));
+                                }
+                                None => {
+                                    tracing::error!("[Audio] ✗ No {} device available after recovery attempts", direction.as_str());
+                                    events::emit("audio_device_changed", serde_json::json!({
+                                        "direction": direction.as_str(),
+                                        "device": null,
+                                        "message": format!("No audio {} device available", direction.as_str()),
+                                    }));
+                                }
+                            }
+                        }
+                        MediaControl::SwitchOutput(name, reply) => {
+                            // The new stream drains the same playback channel,
+                            // so the RX task keeps running untouched
+                            let result = manager
+                                .open_output(Some(&name))
+                                .and_then(|opened| {
+                                    manager
+                                        .start_playback_from(playback_rx.clone(), tone.clone(), drops.clone(), depth_ms.clone(), target_latency_ms, Some(faults.clone()))
+                                        .map(|stream| (opened, stream))
+                                });
+
+                            match result {
+                                Ok((opened, stream)) => {
+                                    output_stream = stream;
+                                    // Recovery reopens what is actually playing,
+                                    // not a name that may have fallen back
+                                    output_device = Some(opened.clone());
+                                    tracing::info!("[Audio] ✓ Output switched to '{}'", opened);
+                                    println!("[Audio] ✓ Output switched to '{}'", opened);
+                                    events::emit("audio_device_changed", serde_json::json!({
+                                        "direction": StreamDirection::Output.as_str(),
+                                        "device": opened,
+                                        "message": format!("Audio output switched to {}", opened),
+                                    }));
+                                    let _ = reply.send(Ok(opened));
+                                }
+                                Err(e) => {
+                                    tracing::error!("[Audio] ✗ Failed to switch output: {}", e);
+                                    let _ = reply.send(Err(e));
+                                }
+                            }
+                        }
+                        MediaControl::Stop => break,
+                    }
+                }
+
+                drop(input_stream);
+                drop(output_stream);
+                println!("[Audio] Call audio streams closed");
+            })
+            .map_err(|e| SipError::Audio(format!("Failed to spawn audio thread: {}", e)))?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| SipError::Audio("Audio thread exited during setup".to_string()))??;
+
+        Ok((
+            Self { control: control_tx, tone: call_tone, drops: call_drops, playout_depth: Some(call_depth) },
+            AudioChannels {
+                capture: capture_rx,
+                playback: playback_tx,
+            },
+        ))
+    }
+
+    /// Play a DTMF key's tone on the local speaker only (key feedback),
+    /// replacing any tone still playing
+    pub fn play_dtmf_tone(&self, digit: char) -> Result<(), SipError> {
+        let mut tone = self.tone.lock().unwrap();
+        let samples = generate_dtmf_tone(digit, DTMF_TONE_MS, tone.sample_rate)
+            .ok_or_else(|| SipError::Audio(format!("No DTMF tone for '{}'", digit)))?;
+        tone.samples = samples.into();
+        Ok(())
+    }
+
+    /// Move playback to another output device mid-call without touching the
+    /// RTP session. Falls back to the default device if `name` is not present.
+    /// Returns the name of the device now playing.
+    pub fn switch_output(&self, name: &str) -> Result<String, SipError> {
+        let (reply_tx, reply_rx) = std::sync::mpsc::channel();
+        self.control
+            .send(MediaControl::SwitchOutput(name.to_string(), reply_tx))
+            .map_err(|_| SipError::Audio("Call audio is not running".to_string()))?;
+        reply_rx
+            .recv()
+            .map_err(|_| SipError::Audio("Audio thread exited during output switch".to_string()))?
+    }
+
+    /// How many samples were dropped on the capture and playback side so far
+    pub fn drop_counts(&self) -> AudioDropCounts {
+        self.drops.counts()
+    }
+
+    /// Milliseconds of audio queued for the speaker, kept up to date by the
+    /// output stream (None when the backend plays nothing)
+    pub fn playout_depth(&self) -> Option<Arc<AtomicU32>> {
+        self.playout_depth.clone()
+    }
+
+    /// Stop the streams (also happens when the last handle is dropped)
+    pub fn stop(&self) {
+        let _ = self.control.send(MediaControl::Stop);
+    }
+}
+
+impl Drop for CallAudio {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Source and sink of call audio
+///
+/// `start_rtp_media` opens the call's capture and playback through the
+/// engine's backend. cpal is the default; `NullBackend` needs no hardware so
+/// the media path can run headless (CI, tests).
+pub trait AudioBackend: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Open capture and playback for a call
+    ///
+    /// Capture delivers mono samples at `CALL_AUDIO_RATE` whatever rate the
+    /// device runs at; playback takes the same.
+    fn start(
+        &self,
+        input_device: Option<String>,
+        output_device: Option<String>,
+    ) -> Result<(CallAudio, AudioChannels), SipError>;
+
+    /// Ring for an incoming call until the handle is dropped
+    fn ring(&self, ringer: &Ringer) -> Result<Ringing, SipError>;
+}
+
+/// Audio devices through cpal's default host
+pub struct CpalBackend;
+
+impl AudioBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+
+    fn start(
+        &self,
+        input_device: Option<String>,
+        output_device: Option<String>,
+    ) -> Result<(CallAudio, AudioChannels), SipError> {
+        CallAudio::start(input_device, output_device)
+    }
+
+    fn ring(&self, ringer: &Ringer) -> Result<Ringing, SipError> {
+        crate::ringer::play(ringer)
+    }
+}
+
+/// Capture frame produced by the null backend: 20ms at `CALL_AUDIO_RATE`
+const NULL_FRAME_SAMPLES: usize = 960;
+const NULL_FRAME_MS: u64 = 20;
+
+/// No devices: capture produces silence in real time and playback is discarded
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    fn start(
+        &self,
+        _input_device: Option<String>,
+        _output_device: Option<String>,
+    ) -> Result<(CallAudio, AudioChannels), SipError> {
+        let (capture_tx, capture_rx) = mpsc::channel::<Vec<i16>>(100);
+        let (playback_tx, mut playback_rx) = mpsc::channel::<Vec<i16>>(100);
+        let (control_tx, control_rx) = std::sync::mpsc::channel::<MediaControl>();
+        let tone = Arc::new(std::sync::Mutex::new(ToneBuffer::default()));
+        let thread_tone = tone.clone();
+        let drops = Arc::new(AudioDrops::default());
+        let thread_drops = drops.clone();
+
+        std::thread::Builder::new()
+            .name("call-audio-null".to_string())
+            .spawn(move || {
+                let frame = std::time::Duration::from_millis(NULL_FRAME_MS);
+                loop {
+                    match control_rx.recv_timeout(frame) {
+                        Ok(MediaControl::Stop) | Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                        Ok(MediaControl::StreamFailed(_)) => {}
+                        Ok(MediaControl::SwitchOutput(name, reply)) => {
+                            let _ = reply.send(Ok(name));
+                        }
+                        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                            if let Err(mpsc::error::TrySendError::Full(frame)) =
+                                capture_tx.try_send(vec![0; NULL_FRAME_SAMPLES])
+                            {
+                                thread_drops.capture.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                            }
+                            while playback_rx.try_recv().is_ok() {}
+                            thread_tone.lock().unwrap().samples.clear();
+                        }
+                    }
+                }
+                println!("[Audio] Null call audio stopped");
+            })
+            .map_err(|e| SipError::Audio(format!("Failed to spawn audio thread: {}", e)))?;
+
+        Ok((
+            CallAudio { control: control_tx, tone, drops, playout_depth: None },
+            AudioChannels {
+                capture: capture_rx,
+                playback: playback_tx,
+            },
+        ))
+    }
+
+    fn ring(&self, _ringer: &Ringer) -> Result<Ringing, SipError> {
+        Ok(Ringing::silent())
+    }
 }
 
 impl Default for AudioManager {
@@ -357,6 +1263,187 @@ mod tests {
         assert!(manager.is_ok());
     }
 
+    #[test]
+    fn test_device_capabilities_unknown_device() {
+        let manager = AudioManager::new().unwrap();
+        let result = manager.device_capabilities("No Such Device", DeviceKind::Output);
+        assert_eq!(result, Err(SipError::Audio("Output device 'No Such Device' not found".to_string())));
+        assert_eq!(serde_json::to_string(&DeviceKind::Input).unwrap(), "\"input\"");
+    }
+
+    #[test]
+    fn test_null_backend_produces_silence() {
+        let (call_audio, mut channels) = NullBackend.start(None, None).unwrap();
+
+        let frame = channels.capture.blocking_recv().unwrap();
+        assert_eq!(frame.len(), NULL_FRAME_SAMPLES);
+        assert!(frame.iter().all(|&s| s == 0));
+
+        // Playback is accepted and thrown away
+        channels.playback.blocking_send(vec![1000; 160]).unwrap();
+
+        call_audio.stop();
+        // The thread exits and drops its sender, ending the capture stream
+        while channels.capture.blocking_recv().is_some() {}
+    }
+
+    #[test]
+    fn test_check_level() {
+        assert_eq!(check_level(0.0), Ok(0.0));
+        assert_eq!(check_level(MAX_LEVEL), Ok(MAX_LEVEL));
+        assert_eq!(check_level(2.5), Err(SipError::InvalidLevel("Level 2.5 is outside 0 to 2".to_string())));
+        assert_eq!(check_level(f32::NAN).unwrap_err().kind(), "invalid_level");
+        assert!(check_level(-0.1).is_err());
+    }
+
+    #[test]
+    fn test_rate_converter() {
+        assert!(rate_converter(48000, CALL_AUDIO_RATE).is_none());
+
+        // 10ms callbacks from a 44.1kHz mic come out as 10ms at 48kHz
+        let capture = rate_converter(44100, CALL_AUDIO_RATE).unwrap();
+        let total: usize = (0..100).map(|_| capture.upsample(&[1000; 441]).unwrap().len()).sum();
+        assert!((47990..=48010).contains(&total), "got {}", total);
+
+        // And back down for a 44.1kHz speaker
+        let playback = rate_converter(CALL_AUDIO_RATE, 44100).unwrap();
+        let total: usize = (0..100).map(|_| playback.upsample(&[1000; 480]).unwrap().len()).sum();
+        assert!((44090..=44110).contains(&total), "got {}", total);
+    }
+
+    #[test]
+    fn test_dtmf_frequencies() {
+        assert_eq!(dtmf_frequencies('1'), Some((697.0, 1209.0)));
+        assert_eq!(dtmf_frequencies('5'), Some((770.0, 1336.0)));
+        assert_eq!(dtmf_frequencies('0'), Some((941.0, 1336.0)));
+        assert_eq!(dtmf_frequencies('#'), Some((941.0, 1477.0)));
+        assert_eq!(dtmf_frequencies('d'), Some((941.0, 1633.0)));
+        assert_eq!(dtmf_frequencies('x'), None);
+    }
+
+    // Signal power at one frequency (Goertzel)
+    fn tone_power(samples: &[i16], frequency: f32, sample_rate: u32) -> f32 {
+        let coeff = 2.0 * (2.0 * std::f32::consts::PI * frequency / sample_rate as f32).cos();
+        let (mut s1, mut s2) = (0.0f32, 0.0f32);
+        for &sample in samples {
+            let s0 = sample as f32 / i16::MAX as f32 + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        s1 * s1 + s2 * s2 - coeff * s1 * s2
+    }
+
+    #[test]
+    fn test_generate_dtmf_tone() {
+        let samples = generate_dtmf_tone('1', DTMF_TONE_MS, 48000).unwrap();
+        assert_eq!(samples.len(), 7200);
+
+        // Energy at 697 and 1209 Hz, not at the other row/column tones
+        let on = tone_power(&samples, 697.0, 48000).min(tone_power(&samples, 1209.0, 48000));
+        let off = tone_power(&samples, 941.0, 48000).max(tone_power(&samples, 1477.0, 48000));
+        assert!(on > off * 100.0, "on {} off {}", on, off);
+
+        assert!(generate_dtmf_tone('x', DTMF_TONE_MS, 48000).is_none());
+    }
+
+    #[test]
+    fn test_playout_buffer_trims_to_target() {
+        // 60ms target at 8kHz stereo: 480 frames, high water at 960
+        let mut playout = PlayoutBuffer::new(8000, 2, 60);
+        assert_eq!(playout.push(&[1; 480]), 0);
+        assert_eq!(playout.push(&[2; 480]), 0);
+        assert_eq!(playout.samples.len(), 1920);
+
+        // Crossing the high-water mark drops the oldest audio down to the target
+        assert_eq!(playout.push(&[3; 160]), 640);
+        assert_eq!(playout.samples.len(), 960);
+
+        let mut out = vec![0i16; 4];
+        playout.fill(&mut out);
+        assert_eq!(out, vec![2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_playout_buffer_refills_after_underrun() {
+        let mut playout = PlayoutBuffer::new(8000, 1, 20);
+        let mut out = vec![7i16; 160];
+
+        // Nothing plays until the 20ms target is queued
+        playout.push(&[5; 100]);
+        playout.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0));
+
+        playout.push(&[5; 100]);
+        playout.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 5));
+
+        // Underrun: the tail is padded and playback waits for the target again
+        playout.fill(&mut out);
+        assert!(out[..40].iter().all(|&s| s == 5));
+        assert!(out[40..].iter().all(|&s| s == 0));
+
+        playout.push(&[6; 80]);
+        out.fill(7);
+        playout.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0));
+        assert_eq!(playout.samples.len(), 80);
+    }
+
+    #[test]
+    fn test_playout_depth() {
+        let mut playout = PlayoutBuffer::new(8000, 2, 20);
+        assert_eq!(playout.depth_ms(), 0);
+        playout.push(&[1; 240]);
+        assert_eq!(playout.depth_ms(), 30);
+    }
+
+    #[test]
+    fn test_drift_estimator() {
+        // On target: no correction
+        let mut drift = DriftEstimator::new(60);
+        assert_eq!(drift.update(60), 1.0);
+
+        // A single spike barely moves the long-term average
+        let factor = drift.update(200);
+        assert!(factor < 1.0 && factor > 1.0 - MAX_DRIFT_ADJUST / 10.0);
+
+        // Audio piling up for a long time: shrink, by at most 0.1%
+        let factor = (0..5000).map(|_| drift.update(200)).last().unwrap();
+        assert_eq!(factor, 1.0 - MAX_DRIFT_ADJUST);
+
+        // Running dry: stretch
+        let mut drift = DriftEstimator::new(60);
+        let factor = (0..5000).map(|_| drift.update(20)).last().unwrap();
+        assert!(factor > 1.0 && factor <= 1.0 + MAX_DRIFT_ADJUST);
+    }
+
+    #[test]
+    fn test_playout_target_has_a_floor() {
+        let mut playout = PlayoutBuffer::new(8000, 1, 0);
+        playout.push(&[1; 159]);
+        let mut out = vec![9i16; 10];
+        playout.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn test_audio_levels() {
+        let levels = AudioLevels::default();
+        assert_eq!(levels.input_gain(), 1.0);
+        levels.set_input_gain(5.0);
+        assert_eq!(levels.input_gain(), MAX_LEVEL);
+        levels.set_output_volume(-1.0);
+        assert_eq!(levels.output_volume(), 0.0);
+        levels.set_output_volume(f32::NAN);
+        assert_eq!(levels.output_volume(), 1.0);
+
+        let mut samples = [1000, -1000, 30000, i16::MIN];
+        apply_level(&mut samples, 1.5);
+        assert_eq!(samples, [1500, -1500, i16::MAX, i16::MIN]);
+        apply_level(&mut samples, 0.0);
+        assert_eq!(samples, [0; 4]);
+    }
+
     #[test]
     fn test_list_devices() {
         let manager = AudioManager::new().unwrap();
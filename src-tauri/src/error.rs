@@ -0,0 +1,137 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+/// Errors returned by the SIP, RTP and audio layers
+///
+/// Serialized to the frontend as `{ kind, message }` so the UI can react to
+/// the kind of failure instead of matching on message text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SipError {
+    /// The SIP stack has not been initialized (no socket)
+    NotInitialized,
+    /// The operation needs a registered account
+    NotRegistered,
+    /// There is no call to act on
+    NoActiveCall,
+    /// Another call is already being set up or is up
+    CallInProgress,
+    /// The dialed number or URI is not something we can call
+    InvalidTarget(String),
+    /// The server rejected our credentials or the challenge could not be answered
+    AuthFailed(String),
+    /// No response arrived in time
+    Timeout(String),
+    /// Socket, DNS or address failure
+    Network(String),
+    /// Malformed or unexpected SIP/SDP/RTP data
+    Protocol(String),
+    /// The remote side answered with a final failure response (4xx/5xx/6xx)
+    Rejected(String),
+    /// The server is temporarily unavailable (503/480) and asked us to retry
+    /// after `retry_after` seconds
+    Unavailable { message: String, retry_after: u64 },
+    /// Audio device, stream or resampler failure
+    Audio(String),
+    /// A gain or volume outside 0..=MAX_LEVEL (or not a number)
+    InvalidLevel(String),
+    /// Failure inside the app itself (task join, event delivery, ...)
+    Internal(String),
+    /// The user gave up on the registration or call attempt
+    Cancelled,
+}
+
+impl SipError {
+    /// Machine-readable kind sent to the frontend
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SipError::NotInitialized => "not_initialized",
+            SipError::NotRegistered => "not_registered",
+            SipError::NoActiveCall => "no_active_call",
+            SipError::CallInProgress => "call_in_progress",
+            SipError::InvalidTarget(_) => "invalid_target",
+            SipError::AuthFailed(_) => "auth_failed",
+            SipError::Timeout(_) => "timeout",
+            SipError::Network(_) => "network",
+            SipError::Protocol(_) => "protocol",
+            SipError::Rejected(_) => "rejected",
+            SipError::Unavailable { .. } => "unavailable",
+            SipError::Audio(_) => "audio",
+            SipError::InvalidLevel(_) => "invalid_level",
+            SipError::Internal(_) => "internal",
+            SipError::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl fmt::Display for SipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SipError::NotInitialized => write!(f, "SIP not initialized"),
+            SipError::NotRegistered => write!(f, "Not registered"),
+            SipError::NoActiveCall => write!(f, "No active call"),
+            SipError::CallInProgress => write!(f, "A call is already in progress"),
+            SipError::Cancelled => write!(f, "Cancelled"),
+            SipError::InvalidTarget(msg)
+            | SipError::AuthFailed(msg)
+            | SipError::Timeout(msg)
+            | SipError::Network(msg)
+            | SipError::Protocol(msg)
+            | SipError::Rejected(msg)
+            | SipError::Audio(msg)
+            | SipError::InvalidLevel(msg)
+            | SipError::Internal(msg) => write!(f, "{}", msg),
+            SipError::Unavailable { message, retry_after } => {
+                write!(f, "{} (retry after {}s)", message, retry_after)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SipError {}
+
+impl Serialize for SipError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let retry_after = match self {
+            SipError::Unavailable { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        };
+
+        let mut state = serializer.serialize_struct("SipError", 2 + retry_after.is_some() as usize)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        if let Some(retry_after) = retry_after {
+            state.serialize_field("retry_after", &retry_after)?;
+        }
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serializes_kind_and_message() {
+        let json = serde_json::to_value(SipError::Timeout("Timeout waiting for INVITE response".to_string())).unwrap();
+        assert_eq!(json["kind"], "timeout");
+        assert_eq!(json["message"], "Timeout waiting for INVITE response");
+
+        let json = serde_json::to_value(SipError::NotRegistered).unwrap();
+        assert_eq!(json["kind"], "not_registered");
+        assert_eq!(json["message"], "Not registered");
+        assert!(json.get("retry_after").is_none());
+    }
+
+    #[test]
+    fn test_serializes_retry_after() {
+        let json = serde_json::to_value(SipError::Unavailable {
+            message: "Call failed: SIP/2.0 503 Service Unavailable".to_string(),
+            retry_after: 120,
+        })
+        .unwrap();
+        assert_eq!(json["kind"], "unavailable");
+        assert_eq!(json["retry_after"], 120);
+        assert_eq!(json["message"], "Call failed: SIP/2.0 503 Service Unavailable (retry after 120s)");
+    }
+}
@@ -0,0 +1,36 @@
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+use std::sync::Mutex;
+
+/// Receives every event emitted by the engine (installed by main.rs to
+/// forward them to the frontend as "sip-event")
+type EventSink = Box<dyn Fn(Value) + Send + Sync>;
+
+static EVENT_SINK: Lazy<Mutex<Option<EventSink>>> = Lazy::new(|| Mutex::new(None));
+
+/// Install the function that delivers engine events to the frontend
+pub fn set_sink<F>(sink: F)
+where
+    F: Fn(Value) + Send + Sync + 'static,
+{
+    *EVENT_SINK.lock().unwrap() = Some(Box::new(sink));
+}
+
+/// Emit an event of the given type from the SIP/audio tasks
+///
+/// `fields` should be a JSON object; its entries are merged next to the
+/// `type` field so the payload has the same shape as the command events.
+pub fn emit(event_type: &str, fields: Value) {
+    let mut payload = match fields {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    payload.insert("type".to_string(), Value::String(event_type.to_string()));
+    let payload = Value::Object(payload);
+
+    tracing::debug!("[Event] {}", payload);
+
+    if let Some(sink) = EVENT_SINK.lock().unwrap().as_ref() {
+        sink(payload);
+    }
+}
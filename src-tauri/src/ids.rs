@@ -0,0 +1,134 @@
+//! Branches, tags and Call-IDs for outgoing SIP messages
+//!
+//! Production code uses random UUIDs. Tests can install a generator that
+//! yields a fixed sequence, so built messages are reproducible byte for byte.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Source of the unique identifiers put into SIP messages
+pub trait IdGenerator {
+    /// Call-ID of a new call, registration or subscription
+    fn call_id(&self) -> String;
+    /// From/To tag
+    fn tag(&self) -> String;
+    /// Via branch, including the RFC 3261 magic cookie
+    fn branch(&self) -> String;
+}
+
+/// Random identifiers (UUID v4)
+pub struct UuidIds;
+
+impl IdGenerator for UuidIds {
+    fn call_id(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    fn tag(&self) -> String {
+        uuid::Uuid::new_v4().simple().to_string()
+    }
+
+    fn branch(&self) -> String {
+        format!("z9hG4bK{}", uuid::Uuid::new_v4().simple())
+    }
+}
+
+/// Numbered identifiers for tests: "call-1", "tag-2", "z9hG4bKbranch-3", ...
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialIds {
+    next: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+impl SequentialIds {
+    fn next(&self, prefix: &str) -> String {
+        let n = self.next.get() + 1;
+        self.next.set(n);
+        format!("{}-{}", prefix, n)
+    }
+}
+
+#[cfg(test)]
+impl IdGenerator for SequentialIds {
+    fn call_id(&self) -> String {
+        self.next("call")
+    }
+
+    fn tag(&self) -> String {
+        self.next("tag")
+    }
+
+    fn branch(&self) -> String {
+        format!("z9hG4bK{}", self.next("branch"))
+    }
+}
+
+// Per thread, so a test's generator can't leak into tests running alongside it
+thread_local! {
+    static GENERATOR: RefCell<Rc<dyn IdGenerator>> = RefCell::new(Rc::new(UuidIds));
+}
+
+fn with_generator<T>(f: impl FnOnce(&dyn IdGenerator) -> T) -> T {
+    let generator = GENERATOR.with(|generator| generator.borrow().clone());
+    f(generator.as_ref())
+}
+
+/// New Call-ID
+pub fn call_id() -> String {
+    with_generator(|generator| generator.call_id())
+}
+
+/// New From/To tag
+pub fn tag() -> String {
+    with_generator(|generator| generator.tag())
+}
+
+/// New Via branch
+pub fn branch() -> String {
+    with_generator(|generator| generator.branch())
+}
+
+/// Use `generator` on this thread until the returned guard is dropped
+#[cfg(test)]
+pub fn set_generator(generator: impl IdGenerator + 'static) -> GeneratorGuard {
+    GENERATOR.with(|current| *current.borrow_mut() = Rc::new(generator));
+    GeneratorGuard
+}
+
+/// Restores random identifiers when dropped
+#[cfg(test)]
+pub struct GeneratorGuard;
+
+#[cfg(test)]
+impl Drop for GeneratorGuard {
+    fn drop(&mut self) {
+        GENERATOR.with(|current| *current.borrow_mut() = Rc::new(UuidIds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_ids() {
+        assert_eq!(call_id().len(), 36);
+        assert_eq!(tag().len(), 32);
+        assert!(branch().starts_with("z9hG4bK"));
+        assert_ne!(tag(), tag());
+    }
+
+    #[test]
+    fn test_sequential_ids() {
+        {
+            let _ids = set_generator(SequentialIds::default());
+            assert_eq!(call_id(), "call-1");
+            assert_eq!(tag(), "tag-2");
+            assert_eq!(branch(), "z9hG4bKbranch-3");
+        }
+
+        // Dropping the guard goes back to random identifiers
+        assert_eq!(call_id().len(), 36);
+    }
+}
@@ -3,18 +3,31 @@
 
 mod sip;
 mod rtp;
+mod srtp;
+mod stun;
+mod preflight;
+mod codec_probe;
+mod ringer;
 mod audio;
 mod resample;
 mod settings;
+mod events;
+mod ids;
+mod message;
+mod error;
+#[cfg(test)]
+mod mock_sip;
 
+use error::SipError;
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 use tauri::Manager;
 
 // SIP State Management
+// Registration state lives in the engine only (sip::is_registered); a
+// copy here would miss background retries, refreshes and unregisters
 struct SipState {
     initialized: bool,
-    registered: bool,
     current_call: Option<String>,
 }
 
@@ -22,7 +35,6 @@ impl Default for SipState {
     fn default() -> Self {
         Self {
             initialized: false,
-            registered: false,
             current_call: None,
         }
     }
@@ -45,7 +57,7 @@ struct SipEvent {
 async fn init_sip(
     state: tauri::State<'_, Mutex<SipState>>,
     app_handle: tauri::AppHandle,
-) -> Result<String, String> {
+) -> Result<String, SipError> {
     println!("Initializing SIP stack...");
     
     // Initialize SIP with rsipstack
@@ -59,7 +71,7 @@ async fn init_sip(
         registered: None,
         state: Some("INITIALIZED".to_string()),
         message: Some("SIP stack initialized".to_string()),
-    }).map_err(|e| e.to_string())?;
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
     
     Ok("SIP stack initialized".to_string())
 }
@@ -70,51 +82,33 @@ async fn register_account(
     server: String,
     user: String,
     password: String,
-    state: tauri::State<'_, Mutex<SipState>>,
-    app_handle: tauri::AppHandle,
-) -> Result<String, String> {
+) -> Result<String, SipError> {
     println!("Registering account: {}@{}", user, server);
     
-    // Register with rsipstack
+    // Register with rsipstack; it reports REGISTERING and then REGISTERED/FAILED
     sip::register_account(&server, &user, &password).await?;
     
-    // Wait a bit for registration to complete
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-    
-    let mut sip_state = state.lock().unwrap();
-    sip_state.registered = true;
-    
-    app_handle.emit_all("sip-event", SipEvent {
-        event_type: "registration_state".to_string(),
-        registered: Some(true),
-        state: Some("REGISTERED".to_string()),
-        message: Some(format!("Registered as {}@{}", user, server)),
-    }).map_err(|e| e.to_string())?;
-    
     Ok("Registration successful".to_string())
 }
 
-// Make outbound call
+// Make outbound call; `direction` (sendrecv by default) can make it
+// listen-only (recvonly)
 #[tauri::command]
 async fn make_call(
     number: String,
+    direction: Option<rtp::MediaDirection>,
     state: tauri::State<'_, Mutex<SipState>>,
     app_handle: tauri::AppHandle,
-) -> Result<String, String> {
+) -> Result<String, SipError> {
     println!("Making call to: {}", number);
     
-    // Check registration status
-    let is_registered = {
-        let sip_state = state.lock().unwrap();
-        sip_state.registered
-    };
-    
-    if !is_registered {
-        return Err("Not registered".to_string());
+    // The engine knows about registrations that finished in the background
+    if !sip::is_registered().await {
+        return Err(SipError::NotRegistered);
     }
     
     // Make call with rsipstack
-    sip::make_call(&number).await?;
+    sip::make_call(&number, direction.unwrap_or_default()).await?;
     
     // Update state
     {
@@ -127,28 +121,36 @@ async fn make_call(
         registered: None,
         state: Some("OUTGOING".to_string()),
         message: Some(format!("Calling {}", number)),
-    }).map_err(|e| e.to_string())?;
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
     
     Ok("Call initiated".to_string())
 }
 
+// Give up on the registration or outgoing call still in progress (e.g. a
+// mistyped server); the pending command returns a "cancelled" error
+#[tauri::command]
+async fn cancel_pending() -> Result<bool, SipError> {
+    Ok(sip::cancel_pending().await)
+}
+
 // Answer incoming call
 #[tauri::command]
 async fn answer_call(
+    codec: Option<String>,
     _state: tauri::State<'_, Mutex<SipState>>,
     app_handle: tauri::AppHandle,
-) -> Result<String, String> {
+) -> Result<String, SipError> {
     println!("Answering call");
     
     // Answer with rsipstack
-    sip::answer_call().await?;
+    sip::answer_call(codec.as_deref()).await?;
     
     app_handle.emit_all("sip-event", SipEvent {
         event_type: "call_state".to_string(),
         registered: None,
         state: Some("ACTIVE".to_string()),
         message: Some("Call answered".to_string()),
-    }).map_err(|e| e.to_string())?;
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
     
     Ok("Call answered".to_string())
 }
@@ -158,11 +160,15 @@ async fn answer_call(
 async fn hangup_call(
     state: tauri::State<'_, Mutex<SipState>>,
     app_handle: tauri::AppHandle,
-) -> Result<String, String> {
+) -> Result<String, SipError> {
     println!("Hanging up call");
     
-    // Hangup with rsipstack
-    sip::hangup_call().await?;
+    // Hangup with rsipstack. When the BYE fails the call is still cleared
+    // locally, so the state below is updated before the error is returned.
+    let result = match sip::hangup_call().await {
+        Err(e @ (SipError::NotInitialized | SipError::NotRegistered | SipError::NoActiveCall)) => return Err(e),
+        result => result,
+    };
     
     // Update state
     {
@@ -175,14 +181,116 @@ async fn hangup_call(
         registered: None,
         state: Some("REGISTERED".to_string()),
         message: Some("Call ended".to_string()),
-    }).map_err(|e| e.to_string())?;
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
     
+    result?;
     Ok("Call ended".to_string())
 }
 
+// Send a dialpad key during the call (RFC 4733 event plus local tone)
+#[tauri::command]
+async fn send_dtmf(digit: String) -> Result<String, SipError> {
+    let mut chars = digit.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(key), None) => key,
+        _ => return Err(SipError::Protocol(format!("Expected a single DTMF key, got \"{}\"", digit))),
+    };
+
+    sip::send_dtmf(key).await?;
+
+    Ok(format!("Sent DTMF {}", key))
+}
+
+// Audio samples dropped in the current call because capture or playback
+// couldn't keep up
+#[tauri::command]
+async fn get_audio_drop_counts() -> Result<audio::AudioDropCounts, SipError> {
+    Ok(sip::audio_drop_counts().await)
+}
+
+// Codec, packet time, RTP addresses and encryption of the current call
+#[tauri::command]
+async fn get_media_info() -> Result<sip::MediaInfo, SipError> {
+    sip::media_info().await
+}
+
+// Local, advertised, public and server addresses, for NAT troubleshooting
+#[tauri::command]
+async fn get_network_info() -> Result<sip::NetworkInfo, SipError> {
+    Ok(sip::network_info().await)
+}
+
+// Switch the speaker/headset used by the current call
+#[tauri::command]
+async fn switch_output_device(name: String) -> Result<String, SipError> {
+    let device = sip::switch_output_device(&name).await?;
+    Ok(format!("Audio output: {}", device))
+}
+
+// Set the microphone gain (1.0 = unchanged, up to 2.0), applied at once and
+// remembered for the input device in use
+#[tauri::command]
+async fn set_input_gain(gain: f32) -> Result<(), SipError> {
+    let gain = audio::check_level(gain)?;
+    let device = sip::set_input_gain(gain).await;
+    settings::save_input_gain(&device, gain).map_err(SipError::Internal)
+}
+
+// Set the speaker volume (1.0 = unchanged, up to 2.0), applied at once and
+// remembered for the output device in use
+#[tauri::command]
+async fn set_output_volume(volume: f32) -> Result<(), SipError> {
+    let volume = audio::check_level(volume)?;
+    let device = sip::set_output_volume(volume).await;
+    settings::save_output_volume(&device, volume).map_err(SipError::Internal)
+}
+
+// Gain and volume remembered for an audio device (empty name = default device)
+#[tauri::command]
+async fn load_device_levels(device: String) -> Result<settings::DeviceLevels, String> {
+    settings::load_device_levels(&device)
+}
+
+// Send an instant message (SIP MESSAGE) to a number or SIP URI
+#[tauri::command]
+async fn send_message(target: String, text: String) -> Result<String, SipError> {
+    sip::send_message(&target, &text).await?;
+    Ok(format!("Message sent to {}", target))
+}
+
+// Put the active call on hold in a local park slot; returns the slot
+#[tauri::command]
+async fn park_call() -> Result<u32, SipError> {
+    sip::park_call().await
+}
+
+// Calls waiting in park slots
+#[tauri::command]
+async fn list_parked() -> Result<Vec<sip::ParkedCall>, SipError> {
+    Ok(sip::list_parked().await)
+}
+
+// Take a parked call off hold
+#[tauri::command]
+async fn retrieve_parked(slot: u32) -> Result<(), SipError> {
+    sip::retrieve_parked(slot).await
+}
+
+// Call waiting: hold the current call and answer or resume the other one
+#[tauri::command]
+async fn swap_calls() -> Result<(), SipError> {
+    sip::swap_calls().await
+}
+
+// Seconds since the current call was answered (0 when no call is active)
+#[tauri::command]
+async fn call_duration() -> Result<u64, SipError> {
+    Ok(sip::call_duration().await)
+}
+
 // Unregister (de-register) from SIP server
 #[tauri::command]
-async fn unregister() -> Result<String, String> {
+async fn unregister() -> Result<String, SipError> {
     println!("Unregistering from SIP server...");
     
     // Unregister from server
@@ -191,23 +299,112 @@ async fn unregister() -> Result<String, String> {
     Ok("Unregistered successfully".to_string())
 }
 
+// Go offline: unregister but keep the socket and credentials
+#[tauri::command]
+async fn go_offline(app_handle: tauri::AppHandle) -> Result<String, SipError> {
+    println!("Going offline...");
+
+    sip::go_offline().await?;
+
+    app_handle.emit_all("sip-event", SipEvent {
+        event_type: "registration_state".to_string(),
+        registered: Some(sip::is_registered().await),
+        state: Some("OFFLINE".to_string()),
+        message: Some("Offline".to_string()),
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
+
+    Ok("Offline".to_string())
+}
+
+// Go online: re-register with the stored credentials
+#[tauri::command]
+async fn go_online(
+    state: tauri::State<'_, Mutex<SipState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, SipError> {
+    println!("Going online...");
+
+    sip::go_online().await?;
+
+    state.lock().unwrap().initialized = true;
+
+    app_handle.emit_all("sip-event", SipEvent {
+        event_type: "registration_state".to_string(),
+        registered: Some(sip::is_registered().await),
+        state: Some("REGISTERED".to_string()),
+        message: Some("Online".to_string()),
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
+
+    Ok("Online".to_string())
+}
+
+// Drop all calls and state and re-initialize the SIP stack (recovery when
+// it is wedged); the account has to be registered again
+#[tauri::command]
+async fn reset_sip(
+    state: tauri::State<'_, Mutex<SipState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, SipError> {
+    println!("Resetting SIP stack...");
+
+    sip::reset().await?;
+
+    *state.lock().unwrap() = SipState {
+        initialized: true,
+        ..SipState::default()
+    };
+
+    app_handle.emit_all("sip-event", SipEvent {
+        event_type: "initialized".to_string(),
+        registered: Some(false),
+        state: Some("INITIALIZED".to_string()),
+        message: Some("SIP stack reset".to_string()),
+    }).map_err(|e| SipError::Internal(e.to_string()))?;
+
+    Ok("SIP stack reset".to_string())
+}
+
+// Check audio devices, UDP and server resolution before registering;
+// `server` defaults to the saved one
+#[tauri::command]
+async fn preflight_check(server: Option<String>) -> Result<preflight::PreflightReport, String> {
+    let server = match server {
+        Some(server) => server,
+        None => settings::load_credentials()?.0,
+    };
+    Ok(preflight::run(&server).await)
+}
+
+// Run a test tone through each codec and report how well it survives
+#[tauri::command]
+async fn test_codecs() -> Result<Vec<codec_probe::CodecProbe>, String> {
+    Ok(codec_probe::run())
+}
+
 // List available audio input devices
 #[tauri::command]
-async fn list_audio_input_devices() -> Result<Vec<String>, String> {
+async fn list_audio_input_devices() -> Result<Vec<String>, SipError> {
     let audio_manager = audio::AudioManager::new()?;
     audio_manager.list_input_devices()
 }
 
 // List available audio output devices
 #[tauri::command]
-async fn list_audio_output_devices() -> Result<Vec<String>, String> {
+async fn list_audio_output_devices() -> Result<Vec<String>, SipError> {
     let audio_manager = audio::AudioManager::new()?;
     audio_manager.list_output_devices()
 }
 
+// Sample rates, channel counts and sample formats an audio device supports
+#[tauri::command]
+async fn get_device_capabilities(name: String, kind: audio::DeviceKind) -> Result<audio::DeviceCapabilities, SipError> {
+    let audio_manager = audio::AudioManager::new()?;
+    audio_manager.device_capabilities(&name, kind)
+}
+
 // Test microphone (returns true if mic is working)
 #[tauri::command]
-async fn test_microphone(device_name: Option<String>) -> Result<String, String> {
+async fn test_microphone(device_name: Option<String>) -> Result<String, SipError> {
     // Run in blocking task since Stream is not Send
     tokio::task::spawn_blocking(move || {
         let mut audio_manager = audio::AudioManager::new()?;
@@ -234,16 +431,16 @@ async fn test_microphone(device_name: Option<String>) -> Result<String, String>
         if sample_count > 0 {
             Ok(format!("✓ Microphone working! Received {} audio buffers", sample_count))
         } else {
-            Err("No audio data received from microphone".to_string())
+            Err(SipError::Audio("No audio data received from microphone".to_string()))
         }
     })
     .await
-    .map_err(|e| format!("Task join error: {}", e))?
+    .map_err(|e| SipError::Internal(format!("Task join error: {}", e)))?
 }
 
 // Test speaker by playing a tone
 #[tauri::command]
-async fn test_speaker(device_name: Option<String>) -> Result<String, String> {
+async fn test_speaker(device_name: Option<String>) -> Result<String, SipError> {
     // Run in blocking task since Stream is not Send
     tokio::task::spawn_blocking(move || {
         let mut audio_manager = audio::AudioManager::new()?;
@@ -258,7 +455,7 @@ async fn test_speaker(device_name: Option<String>) -> Result<String, String> {
         audio_manager.test_speaker(440.0, 1000)
     })
     .await
-    .map_err(|e| format!("Task join error: {}", e))?
+    .map_err(|e| SipError::Internal(format!("Task join error: {}", e)))?
 }
 
 // Save SIP credentials
@@ -298,6 +495,292 @@ async fn load_audio_devices() -> Result<(String, String), String> {
     settings::load_audio_devices()
 }
 
+// Save the secure media (SRTP) preference
+#[tauri::command]
+async fn save_secure_media(enabled: bool) -> Result<(), String> {
+    settings::save_secure_media(enabled)
+}
+
+// Load the secure media (SRTP) preference
+#[tauri::command]
+async fn load_secure_media() -> Result<bool, String> {
+    settings::load_secure_media()
+}
+
+// Save how in-call DTMF is sent (rfc2833, info or auto)
+#[tauri::command]
+async fn save_dtmf_mode(mode: settings::DtmfMode) -> Result<(), String> {
+    settings::save_dtmf_mode(mode)
+}
+
+// Load how in-call DTMF is sent
+#[tauri::command]
+async fn load_dtmf_mode() -> Result<settings::DtmfMode, String> {
+    settings::load_dtmf_mode()
+}
+
+// Save the STUN server and TURN relay used to reach call media
+#[tauri::command]
+async fn save_media_relay(relay: settings::MediaRelay) -> Result<(), String> {
+    settings::save_media_relay(&relay)
+}
+
+// Load the STUN/TURN configuration
+#[tauri::command]
+async fn load_media_relay() -> Result<settings::MediaRelay, String> {
+    settings::load_media_relay()
+}
+
+// Save which traffic (RTP, SIP) gets DSCP QoS marking
+#[tauri::command]
+async fn save_qos(qos: settings::QosMarking) -> Result<(), String> {
+    settings::save_qos(&qos)
+}
+
+// Load the DSCP QoS marking settings
+#[tauri::command]
+async fn load_qos() -> Result<settings::QosMarking, String> {
+    settings::load_qos()
+}
+
+// Save the display name sent with outgoing calls
+#[tauri::command]
+async fn save_display_name(name: String) -> Result<(), String> {
+    settings::save_display_name(&name)
+}
+
+// Load the display name
+#[tauri::command]
+async fn load_display_name() -> Result<String, String> {
+    settings::load_display_name()
+}
+
+// Save the audio bandwidth limit advertised in SDP (None for no limit)
+#[tauri::command]
+async fn save_bandwidth_limit(kbps: Option<u32>) -> Result<(), String> {
+    settings::save_bandwidth_limit(kbps)
+}
+
+// Load the audio bandwidth limit
+#[tauri::command]
+async fn load_bandwidth_limit() -> Result<Option<u32>, String> {
+    settings::load_bandwidth_limit()
+}
+
+// Turn do not disturb on or off; the state is kept across restarts
+#[tauri::command]
+async fn set_dnd(enabled: bool) -> Result<(), String> {
+    sip::set_dnd(enabled).await;
+    settings::save_dnd(enabled)
+}
+
+// Whether do not disturb is on
+#[tauri::command]
+async fn load_dnd() -> Result<bool, String> {
+    settings::load_dnd()
+}
+
+// Set the callers whose calls are answered automatically, and the delay
+#[tauri::command]
+async fn set_auto_answer(auto_answer: settings::AutoAnswer) -> Result<(), String> {
+    settings::save_auto_answer(&auto_answer)?;
+    sip::set_auto_answer(auto_answer).await;
+    Ok(())
+}
+
+// Load the auto-answer callers and delay
+#[tauri::command]
+async fn load_auto_answer() -> Result<settings::AutoAnswer, String> {
+    settings::load_auto_answer()
+}
+
+// Let a call that is about to be auto-answered ring normally instead
+#[tauri::command]
+async fn cancel_auto_answer() -> Result<bool, String> {
+    Ok(sip::cancel_auto_answer().await)
+}
+
+// Save whether requests use compact header names (f:, t:, i:, v:, ...)
+#[tauri::command]
+async fn save_compact_headers(enabled: bool) -> Result<(), String> {
+    settings::save_compact_headers(enabled)
+}
+
+// Load whether requests use compact header names
+#[tauri::command]
+async fn load_compact_headers() -> Result<bool, String> {
+    settings::load_compact_headers()
+}
+
+// Save the maximum call duration in seconds (0 = unlimited)
+#[tauri::command]
+async fn save_max_call_duration(secs: u64) -> Result<(), String> {
+    settings::save_max_call_duration(secs)
+}
+
+// Load the maximum call duration in seconds
+#[tauri::command]
+async fn load_max_call_duration() -> Result<u64, String> {
+    settings::load_max_call_duration()
+}
+
+// Save the codec order used for offers and for answering (e.g. ["PCMA", "PCMU"])
+#[tauri::command]
+async fn save_codec_preferences(codecs: Vec<String>) -> Result<(), String> {
+    settings::save_codec_preferences(codecs)
+}
+
+// Load the codec preference order
+#[tauri::command]
+async fn load_codec_preferences() -> Result<Vec<String>, String> {
+    settings::load_codec_preferences()
+}
+
+// Save how E.164 numbers are dialed ("sip", "user_phone" or "tel")
+#[tauri::command]
+async fn save_dial_as(dial_as: settings::DialAs) -> Result<(), String> {
+    settings::save_dial_as(dial_as)
+}
+
+// Load how E.164 numbers are dialed
+#[tauri::command]
+async fn load_dial_as() -> Result<settings::DialAs, String> {
+    settings::load_dial_as()
+}
+
+// Save which address family to reach the server over ("auto", "ipv4" or "ipv6");
+// takes effect when the stack is next initialized
+#[tauri::command]
+async fn save_ip_version_preference(preference: settings::IpVersionPreference) -> Result<(), String> {
+    settings::save_ip_version_preference(preference)
+}
+
+// Load the address family preference
+#[tauri::command]
+async fn load_ip_version_preference() -> Result<settings::IpVersionPreference, String> {
+    settings::load_ip_version_preference()
+}
+
+// Save SIP response timeouts
+#[tauri::command]
+async fn save_timeouts(timeouts: settings::SipTimeouts) -> Result<(), String> {
+    settings::save_timeouts(&timeouts)
+}
+
+// Load SIP response timeouts
+#[tauri::command]
+async fn load_timeouts() -> Result<settings::SipTimeouts, String> {
+    settings::load_timeouts()
+}
+
+// Export settings (without the password) to a file
+#[tauri::command]
+async fn export_settings(path: String) -> Result<(), String> {
+    settings::export_settings(std::path::Path::new(&path))
+}
+
+// Import settings from an exported file
+#[tauri::command]
+async fn import_settings(path: String) -> Result<(), String> {
+    settings::import_settings(std::path::Path::new(&path))
+}
+
+// Save the CRLF keepalive settings (applied at the next registration)
+#[tauri::command]
+async fn save_keepalive(keepalive: settings::KeepaliveSettings) -> Result<(), String> {
+    settings::save_keepalive(&keepalive)
+}
+
+// Load the CRLF keepalive settings
+#[tauri::command]
+async fn load_keepalive() -> Result<settings::KeepaliveSettings, String> {
+    settings::load_keepalive()
+}
+
+// Save the outbound proxy all requests are sent through
+#[tauri::command]
+async fn save_outbound_proxy(proxy: String) -> Result<(), String> {
+    settings::save_outbound_proxy(&proxy)
+}
+
+// Load the outbound proxy
+#[tauri::command]
+async fn load_outbound_proxy() -> Result<String, String> {
+    settings::load_outbound_proxy()
+}
+
+// Save the P-Preferred-Identity sent on outgoing calls
+#[tauri::command]
+async fn save_preferred_identity(identity: String) -> Result<(), String> {
+    settings::save_preferred_identity(&identity)
+}
+
+// Load the P-Preferred-Identity sent on outgoing calls
+#[tauri::command]
+async fn load_preferred_identity() -> Result<String, String> {
+    settings::load_preferred_identity()
+}
+
+// Save the playback latency target
+#[tauri::command]
+async fn save_playout_target(target_ms: u32) -> Result<(), String> {
+    settings::save_playout_target_ms(target_ms)
+}
+
+// Load the playback latency target
+#[tauri::command]
+async fn load_playout_target() -> Result<u32, String> {
+    settings::load_playout_target_ms()
+}
+
+// Save whether calls without working audio are hung up
+#[tauri::command]
+async fn save_require_media(enabled: bool) -> Result<(), String> {
+    settings::save_require_media(enabled)
+}
+
+// Load whether calls without working audio are hung up
+#[tauri::command]
+async fn load_require_media() -> Result<bool, String> {
+    settings::load_require_media()
+}
+
+// Turn clock drift compensation on or off
+#[tauri::command]
+async fn save_drift_compensation(enabled: bool) -> Result<(), String> {
+    settings::save_drift_compensation(enabled)
+}
+
+// Load whether clock drift compensation is on
+#[tauri::command]
+async fn load_drift_compensation() -> Result<bool, String> {
+    settings::load_drift_compensation()
+}
+
+// Save whether telephone-events are offered for DTMF
+#[tauri::command]
+async fn save_offer_telephone_event(enabled: bool) -> Result<(), String> {
+    settings::save_offer_telephone_event(enabled)
+}
+
+// Load whether telephone-events are offered for DTMF
+#[tauri::command]
+async fn load_offer_telephone_event() -> Result<bool, String> {
+    settings::load_offer_telephone_event()
+}
+
+// Save where, how loud and with what sound incoming calls ring
+#[tauri::command]
+async fn save_ringer(ringer: settings::Ringer) -> Result<(), String> {
+    settings::save_ringer(&ringer)
+}
+
+// Load the ring device, volume and ringtone
+#[tauri::command]
+async fn load_ringer() -> Result<settings::Ringer, String> {
+    settings::load_ringer()
+}
+
 fn main() {
     // Initialize file logging
     let log_dir = std::env::current_exe()
@@ -319,13 +802,35 @@ fn main() {
     
     tauri::Builder::default()
         .manage(Mutex::new(SipState::default()))
+        .setup(|app| {
+            // Headless runs (CI, machines without sound hardware) use silent audio
+            if std::env::var("PLATYPUS_AUDIO_BACKEND").as_deref() == Ok("null") {
+                tracing::info!("Using the null audio backend");
+                tauri::async_runtime::block_on(sip::set_audio_backend(std::sync::Arc::new(audio::NullBackend)));
+            }
+
+            // Forward events raised by the SIP/audio tasks to the frontend
+            let app_handle = app.handle();
+            events::set_sink(move |payload| {
+                if let Err(e) = app_handle.emit_all("sip-event", payload) {
+                    eprintln!("Failed to emit event: {}", e);
+                }
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             init_sip,
             register_account,
             make_call,
             answer_call,
             hangup_call,
+            call_duration,
+            send_dtmf,
+            get_audio_drop_counts,
+            switch_output_device,
             unregister,
+            go_offline,
+            go_online,
             list_audio_input_devices,
             list_audio_output_devices,
             test_microphone,
@@ -334,7 +839,69 @@ fn main() {
             load_sip_credentials,
             clear_sip_credentials,
             save_audio_devices,
-            load_audio_devices
+            load_audio_devices,
+            save_secure_media,
+            load_secure_media,
+            save_timeouts,
+            load_timeouts,
+            save_dial_as,
+            load_dial_as,
+            save_playout_target,
+            load_playout_target,
+            save_preferred_identity,
+            load_preferred_identity,
+            save_outbound_proxy,
+            load_outbound_proxy,
+            save_keepalive,
+            load_keepalive,
+            export_settings,
+            import_settings,
+            save_dtmf_mode,
+            load_dtmf_mode,
+            save_codec_preferences,
+            load_codec_preferences,
+            send_message,
+            save_max_call_duration,
+            load_max_call_duration,
+            save_compact_headers,
+            load_compact_headers,
+            set_dnd,
+            load_dnd,
+            save_media_relay,
+            load_media_relay,
+            save_qos,
+            load_qos,
+            save_display_name,
+            load_display_name,
+            save_bandwidth_limit,
+            load_bandwidth_limit,
+            preflight_check,
+            reset_sip,
+            save_ip_version_preference,
+            load_ip_version_preference,
+            set_input_gain,
+            set_output_volume,
+            load_device_levels,
+            cancel_pending,
+            park_call,
+            list_parked,
+            retrieve_parked,
+            swap_calls,
+            save_drift_compensation,
+            load_drift_compensation,
+            get_media_info,
+            save_offer_telephone_event,
+            load_offer_telephone_event,
+            set_auto_answer,
+            load_auto_answer,
+            cancel_auto_answer,
+            save_require_media,
+            load_require_media,
+            get_device_capabilities,
+            test_codecs,
+            save_ringer,
+            load_ringer,
+            get_network_info
         ])
         .on_window_event(|event| {
             if let tauri::WindowEvent::CloseRequested { api, .. } = event.event() {
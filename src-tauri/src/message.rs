@@ -0,0 +1,612 @@
+//! SIP request construction
+//!
+//! Every outgoing request shares the same header block (Via, Route, From,
+//! To, Call-ID, CSeq) and trailer (User-Agent, Content-Length, body). The
+//! builders here only add what differs per method, so Content-Length and
+//! the CRLF line endings are always right.
+
+pub const USER_AGENT: &str = "Platypus-Phone/0.1.0";
+const MAX_FORWARDS: &str = "70";
+
+/// Methods we answer when they are sent to us. Keep in step with
+/// `sip::handle_request`; advertising a method we ignore breaks peers.
+pub const ALLOW: &[&str] = &["INVITE", "ACK", "BYE", "CANCEL", "OPTIONS", "NOTIFY", "MESSAGE"];
+
+/// Option tags (RFC 3261 19.2) for the extensions we implement
+pub const SUPPORTED: &[&str] = &["replaces"];
+
+/// Compact forms of header names (RFC 3261 7.3.3 and the extensions
+/// that define one)
+pub const COMPACT_FORMS: &[(&str, &str)] = &[
+    ("Call-ID", "i"),
+    ("Contact", "m"),
+    ("Content-Encoding", "e"),
+    ("Content-Length", "l"),
+    ("Content-Type", "c"),
+    ("From", "f"),
+    ("Subject", "s"),
+    ("Supported", "k"),
+    ("To", "t"),
+    ("Via", "v"),
+    ("Event", "o"),
+    ("Refer-To", "r"),
+    ("Referred-By", "b"),
+    ("Allow-Events", "u"),
+    ("Session-Expires", "x"),
+];
+
+/// Whether a header name as it appears in a message is `name`, in either
+/// its full or compact form (case-insensitive)
+pub fn header_name_is(found: &str, name: &str) -> bool {
+    let found = found.trim();
+    found.eq_ignore_ascii_case(name)
+        || COMPACT_FORMS.iter().any(|(full, compact)| {
+            (name.eq_ignore_ascii_case(full) || name.eq_ignore_ascii_case(compact))
+                && (found.eq_ignore_ascii_case(full) || found.eq_ignore_ascii_case(compact))
+        })
+}
+
+/// CSeq header value: sequence number and method (RFC 3261 8.1.1.5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CSeq {
+    pub number: u32,
+    pub method: String,
+}
+
+impl CSeq {
+    /// Parse a CSeq header value such as `2 INVITE`
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split_whitespace();
+        let number = parts.next()?.parse().ok()?;
+        let method = parts.next()?.to_ascii_uppercase();
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { number, method })
+    }
+
+    /// CSeq header of a message (full header block, any name form)
+    pub fn of(message: &str) -> Option<Self> {
+        message
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("CSeq").then(|| Self::parse(value))?
+            })
+    }
+}
+
+impl std::fmt::Display for CSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.number, self.method)
+    }
+}
+
+/// Fields common to every request we send
+pub struct RequestParams<'a> {
+    /// Request-URI
+    pub uri: &'a str,
+    /// Our address for the Via header
+    pub local_addr: &'a str,
+    pub branch: &'a str,
+    /// Ready-made Route header lines (each ending in CRLF), may be empty
+    pub route: &'a str,
+    pub from_uri: &'a str,
+    /// Display name for the From header, empty for none
+    pub from_name: &'a str,
+    pub from_tag: &'a str,
+    pub to_uri: &'a str,
+    pub to_tag: Option<&'a str>,
+    pub call_id: &'a str,
+    pub cseq: u32,
+    /// Use compact header names, for servers that expect them
+    pub compact: bool,
+}
+
+/// REGISTER (or unregister, with `expires` 0)
+pub fn build_register(params: &RequestParams, contact: &str, expires: u32, authorization: Option<&str>) -> String {
+    let contact = format!("<{}>", contact);
+    let expires = expires.to_string();
+    let mut headers = vec![
+        ("Contact", contact.as_str()),
+        ("Max-Forwards", MAX_FORWARDS),
+        ("Expires", expires.as_str()),
+    ];
+    let (allow, supported) = capabilities();
+    headers.push(("Allow", &allow));
+    headers.push(("Supported", &supported));
+    if let Some(authorization) = authorization {
+        headers.push(("Authorization", authorization));
+    }
+    build_request("REGISTER", params, &headers, "", None, "")
+}
+
+/// INVITE carrying an SDP offer. `extra_headers` are ready-made header
+/// lines (e.g. P-Preferred-Identity) placed after Contact.
+pub fn build_invite(params: &RequestParams, contact: &str, extra_headers: &str, sdp: &str) -> String {
+    let contact = format!("<{}>", contact);
+    let (allow, supported) = capabilities();
+    build_request(
+        "INVITE",
+        params,
+        &[("Contact", contact.as_str()), ("Allow", &allow), ("Supported", &supported)],
+        extra_headers,
+        Some("application/sdp"),
+        sdp,
+    )
+}
+
+/// ACK for a 2xx to our INVITE
+pub fn build_ack(params: &RequestParams) -> String {
+    build_request("ACK", params, &[], "", None, "")
+}
+
+/// BYE ending a dialog
+pub fn build_bye(params: &RequestParams) -> String {
+    build_request("BYE", params, &[], "", None, "")
+}
+
+/// INFO inside a dialog
+pub fn build_info(params: &RequestParams, content_type: &str, body: &str) -> String {
+    build_request("INFO", params, &[], "", Some(content_type), body)
+}
+
+/// SUBSCRIBE to an event package
+pub fn build_subscribe(params: &RequestParams, contact: &str, event: &str, accept: &str, expires: u32) -> String {
+    let contact = format!("<{}>", contact);
+    let expires = expires.to_string();
+    build_request(
+        "SUBSCRIBE",
+        params,
+        &[("Contact", contact.as_str()), ("Max-Forwards", MAX_FORWARDS), ("Event", event), ("Accept", accept), ("Expires", expires.as_str())],
+        "",
+        None,
+        "",
+    )
+}
+
+/// MESSAGE carrying an instant message (RFC 3428); it has no Contact
+pub fn build_message(params: &RequestParams, content_type: &str, body: &str) -> String {
+    build_request("MESSAGE", params, &[], "", Some(content_type), body)
+}
+
+/// CANCEL for an INVITE we sent. Request-URI, Via, Route, From, To, Call-ID
+/// and the CSeq number are copied from that INVITE (RFC 3261 9.1).
+pub fn build_cancel(invite: &str) -> String {
+    invite_transaction_request("CANCEL", invite, None)
+}
+
+/// ACK for a non-2xx final response to an INVITE we sent. It belongs to the
+/// INVITE transaction (RFC 3261 17.1.1.3), so it is copied from the INVITE
+/// like a CANCEL, except that To is the response's (with its tag).
+pub fn build_non2xx_ack(invite: &str, response: &str) -> String {
+    let to = response
+        .split("\r\n")
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            header_name_is(name, "To").then(|| value.trim())
+        });
+    invite_transaction_request("ACK", invite, to)
+}
+
+// A request in the transaction of `invite`: same Request-URI, Via (branch
+// included), Route, From, To (unless `to` replaces it), Call-ID and CSeq number
+fn invite_transaction_request(method: &str, invite: &str, to: Option<&str>) -> String {
+    let mut lines = invite.split("\r\n");
+    let uri = lines.next().and_then(|line| line.split_whitespace().nth(1)).unwrap_or("");
+    let mut message = format!("{} {} SIP/2.0\r\n", method, uri);
+    let mut compact = false;
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let name = name.trim();
+        if let Some(to) = to.filter(|_| header_name_is(name, "To")) {
+            message.push_str(&format!("{}: {}\r\n", name, to));
+        } else if ["Via", "Route", "From", "To", "Call-ID"].iter().any(|copied| header_name_is(name, copied)) {
+            // Answer in kind when the INVITE went out with compact names
+            compact |= name == "v";
+            message.push_str(line);
+            message.push_str("\r\n");
+        } else if name.eq_ignore_ascii_case("CSeq") {
+            let number = CSeq::parse(value).map_or(1, |cseq| cseq.number);
+            message.push_str(&format!("CSeq: {} {}\r\n", number, method));
+        }
+    }
+
+    message.push_str(&format!(
+        "Max-Forwards: {}\r\nUser-Agent: {}\r\nContent-Length: 0\r\n\r\n",
+        MAX_FORWARDS, USER_AGENT
+    ));
+    if compact { compact_headers(&message) } else { message }
+}
+
+// Allow and Supported header values
+fn capabilities() -> (String, String) {
+    (ALLOW.join(", "), SUPPORTED.join(", "))
+}
+
+/// Insert a header line into a serialized message, just before the body
+/// headers (Content-Type or Content-Length) so it lands in the header block.
+pub fn insert_header(message: &str, name: &str, value: &str) -> String {
+    let pos = ["\r\nContent-Type:", "\r\nc:", "\r\nContent-Length:", "\r\nl:", "\r\n\r\n"]
+        .iter()
+        .find_map(|marker| message.find(marker))
+        .map(|pos| pos + 2)
+        .unwrap_or(message.len());
+    format!("{}{}: {}\r\n{}", &message[..pos], name, value, &message[pos..])
+}
+
+/// Copy of a request to send again as a new transaction after a challenge:
+/// a new branch on the top Via, the CSeq number one higher, and the
+/// credentials header (`Authorization` or `Proxy-Authorization`) added in
+/// place of any the request already had.
+pub fn build_retry(request: &str, branch: &str, auth_name: &str, auth_value: &str) -> String {
+    let (head, body) = request.split_once("\r\n\r\n").unwrap_or((request, ""));
+    let mut lines = head.split("\r\n");
+    let mut retry = lines.next().unwrap_or("").to_string();
+    let mut via_done = false;
+
+    for line in lines {
+        let credentials = line.split_once(':').is_some_and(|(name, _)| {
+            header_name_is(name, "Authorization") || header_name_is(name, "Proxy-Authorization")
+        });
+        if credentials {
+            continue;
+        }
+        retry.push_str("\r\n");
+        let Some((name, value)) = line.split_once(':') else {
+            retry.push_str(line);
+            continue;
+        };
+        if !via_done && header_name_is(name, "Via") {
+            via_done = true;
+            let value = match value.find(";branch=") {
+                Some(start) => {
+                    let start = start + ";branch=".len();
+                    let end = value[start..].find(';').map_or(value.len(), |end| start + end);
+                    format!("{}{}{}", &value[..start], branch, &value[end..])
+                }
+                None => format!("{};branch={}", value, branch),
+            };
+            retry.push_str(&format!("{}:{}", name, value));
+        } else if let Some(cseq) = CSeq::parse(value).filter(|_| name.trim().eq_ignore_ascii_case("CSeq")) {
+            let next = CSeq { number: cseq.number + 1, ..cseq };
+            retry.push_str(&format!("{}: {}", name, next));
+        } else {
+            retry.push_str(line);
+        }
+    }
+
+    insert_header(&format!("{}\r\n\r\n{}", retry, body), auth_name, auth_value)
+}
+
+// A display name as a quoted-string (RFC 3261 25.1): backslashes and
+// quotes are escaped, and line breaks and other control characters, which
+// a quoted-string cannot carry, are dropped
+fn quoted_display_name(name: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in name.trim().chars().filter(|c| !c.is_control()) {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// `headers` go right after CSeq; Max-Forwards is added there unless the
+// caller already placed it. `extra_headers` are raw lines that follow.
+fn build_request(
+    method: &str,
+    params: &RequestParams,
+    headers: &[(&str, &str)],
+    extra_headers: &str,
+    content_type: Option<&str>,
+    body: &str,
+) -> String {
+    let from = if params.from_name.trim().is_empty() {
+        format!("<{}>", params.from_uri)
+    } else {
+        format!("{} <{}>", quoted_display_name(params.from_name), params.from_uri)
+    };
+    let to = match params.to_tag {
+        Some(tag) => format!("<{}>;tag={}", params.to_uri, tag),
+        None => format!("<{}>", params.to_uri),
+    };
+
+    let mut message = format!(
+        "{} {} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {};branch={}\r\n\
+         {}\
+         From: {};tag={}\r\n\
+         To: {}\r\n\
+         Call-ID: {}\r\n\
+         CSeq: {} {}\r\n",
+        method,
+        params.uri,
+        params.local_addr,
+        params.branch,
+        params.route,
+        from,
+        params.from_tag,
+        to,
+        params.call_id,
+        params.cseq,
+        method
+    );
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str(extra_headers);
+    if !headers.iter().any(|(name, _)| *name == "Max-Forwards") {
+        message.push_str(&format!("Max-Forwards: {}\r\n", MAX_FORWARDS));
+    }
+    if let Some(content_type) = content_type {
+        message.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    // Content-Length is the byte length of exactly the body appended below
+    message.push_str(&format!(
+        "User-Agent: {}\r\nContent-Length: {}\r\n\r\n{}",
+        USER_AGENT,
+        body.len(),
+        body
+    ));
+    if params.compact { compact_headers(&message) } else { message }
+}
+
+// Rewrite the header names of a serialized message to their compact forms
+fn compact_headers(message: &str) -> String {
+    let (head, body) = message.split_once("\r\n\r\n").unwrap_or((message, ""));
+    let mut lines = head.split("\r\n");
+    let mut compacted = lines.next().unwrap_or("").to_string();
+
+    for line in lines {
+        compacted.push_str("\r\n");
+        let compact = line.split_once(':').and_then(|(name, value)| {
+            let (_, compact) = COMPACT_FORMS.iter().find(|(full, _)| full.eq_ignore_ascii_case(name.trim()))?;
+            Some(format!("{}: {}", compact, value.trim_start()))
+        });
+        compacted.push_str(compact.as_deref().unwrap_or(line));
+    }
+
+    format!("{}\r\n\r\n{}", compacted, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(cseq: u32, to_tag: Option<&'static str>) -> RequestParams<'static> {
+        RequestParams {
+            uri: "sip:bob@example.com",
+            local_addr: "10.0.0.2:5060",
+            branch: "z9hG4bKtest",
+            route: "",
+            from_uri: "sip:alice@example.com",
+            from_name: "",
+            from_tag: "abc",
+            to_uri: "sip:bob@example.com",
+            to_tag,
+            call_id: "call-1",
+            cseq,
+            compact: false,
+        }
+    }
+
+    // Headers end in CRLF (no bare LF) and Content-Length counts body bytes
+    fn assert_well_formed(message: &str) {
+        let (head, body) = message.split_once("\r\n\r\n").expect("missing header terminator");
+        assert!(!message.replace("\r\n", "").contains('\n'), "bare LF in {:?}", message);
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .expect("missing Content-Length")
+            .parse()
+            .unwrap();
+        assert_eq!(length, body.len());
+        assert_eq!(head.lines().filter(|line| line.starts_with("Content-Length:")).count(), 1);
+    }
+
+    #[test]
+    fn test_register_headers() {
+        let mut p = params(1, None);
+        p.uri = "sip:example.com";
+        p.to_uri = "sip:alice@example.com";
+        let message = build_register(&p, "sip:alice@10.0.0.2:5060", 3600, None);
+        assert_well_formed(&message);
+        assert!(message.starts_with("REGISTER sip:example.com SIP/2.0\r\nVia: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKtest\r\n"));
+        assert!(message.contains("\r\nTo: <sip:alice@example.com>\r\n"));
+        assert!(message.contains("\r\nCSeq: 1 REGISTER\r\nContact: <sip:alice@10.0.0.2:5060>\r\nMax-Forwards: 70\r\nExpires: 3600\r\nAllow: INVITE, ACK, BYE, CANCEL, OPTIONS, NOTIFY, MESSAGE\r\nSupported: replaces\r\nUser-Agent:"));
+        assert!(message.ends_with("Content-Length: 0\r\n\r\n"));
+
+        let message = build_register(&p, "sip:alice@10.0.0.2:5060", 0, Some("Digest username=\"alice\""));
+        assert_well_formed(&message);
+        assert!(message.contains("\r\nSupported: replaces\r\nAuthorization: Digest username=\"alice\"\r\nUser-Agent:"));
+    }
+
+    #[test]
+    fn test_invite_carries_sdp() {
+        let sdp = "v=0\r\no=- 1 1 IN IP4 10.0.0.2\r\ns=-\r\n";
+        let p = RequestParams { route: "Route: <sip:proxy.example.com;lr>\r\n", ..params(1, None) };
+        let message = build_invite(&p, "sip:alice@10.0.0.2:5060", "P-Preferred-Identity: <sip:100@example.com>\r\n", sdp);
+        assert_well_formed(&message);
+        assert!(message.contains(";branch=z9hG4bKtest\r\nRoute: <sip:proxy.example.com;lr>\r\nFrom: <sip:alice@example.com>;tag=abc\r\n"));
+        assert!(message.contains("Contact: <sip:alice@10.0.0.2:5060>\r\nAllow: INVITE, ACK, BYE, CANCEL, OPTIONS, NOTIFY, MESSAGE\r\nSupported: replaces\r\nP-Preferred-Identity: <sip:100@example.com>\r\nMax-Forwards: 70\r\n"));
+        assert!(message.contains("Content-Type: application/sdp\r\n"));
+        assert!(message.ends_with(&format!("Content-Length: {}\r\n\r\n{}", sdp.len(), sdp)));
+    }
+
+    #[test]
+    fn test_content_length_counts_bytes() {
+        // Non-ASCII session name: more bytes than characters
+        let sdp = "v=0\r\ns=Téléphone ☎ Ωμέγα\r\nt=0 0\r\n";
+        let message = build_invite(&params(1, None), "sip:alice@10.0.0.2:5060", "", sdp);
+        assert_well_formed(&message);
+        assert_ne!(sdp.chars().count(), sdp.len());
+        assert!(message.contains(&format!("\r\nContent-Length: {}\r\n", sdp.len())));
+
+        let message = build_info(&params(2, Some("xyz")), "text/plain", "ü");
+        assert!(message.ends_with("Content-Length: 2\r\n\r\nü"));
+    }
+
+    #[test]
+    fn test_in_dialog_requests() {
+        let p = params(2, Some("xyz"));
+        for (message, method) in [(build_ack(&p), "ACK"), (build_bye(&p), "BYE")] {
+            assert_well_formed(&message);
+            assert!(message.starts_with(&format!("{} sip:bob@example.com SIP/2.0\r\n", method)));
+            assert!(message.contains("\r\nTo: <sip:bob@example.com>;tag=xyz\r\n"));
+            assert!(message.contains(&format!("\r\nCSeq: 2 {}\r\nMax-Forwards: 70\r\n", method)));
+            assert!(!message.contains("Content-Type"));
+        }
+
+        let message = build_info(&p, "application/dtmf-relay", "Signal=5\r\nDuration=150\r\n");
+        assert_well_formed(&message);
+        assert!(message.contains("Content-Type: application/dtmf-relay\r\n"));
+        assert!(message.contains("Content-Length: 24\r\n"));
+    }
+
+    #[test]
+    fn test_subscribe_headers() {
+        let message = build_subscribe(&params(3, None), "sip:alice@10.0.0.2:5060", "message-summary", "application/simple-message-summary", 3600);
+        assert_well_formed(&message);
+        assert!(message.contains("CSeq: 3 SUBSCRIBE\r\nContact: <sip:alice@10.0.0.2:5060>\r\nMax-Forwards: 70\r\nEvent: message-summary\r\nAccept: application/simple-message-summary\r\nExpires: 3600\r\nUser-Agent:"));
+        assert_eq!(message.matches("Max-Forwards").count(), 1);
+    }
+
+    #[test]
+    fn test_cancel_matches_invite() {
+        let p = RequestParams { route: "Route: <sip:proxy.example.com;lr>\r\n", cseq: 2, ..params(1, None) };
+        let invite = build_invite(&p, "sip:alice@10.0.0.2:5060", "", "v=0\r\n");
+        let cancel = build_cancel(&invite);
+        assert_well_formed(&cancel);
+        assert!(cancel.starts_with("CANCEL sip:bob@example.com SIP/2.0\r\nVia: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKtest\r\n"));
+        assert!(cancel.contains("\r\nRoute: <sip:proxy.example.com;lr>\r\n"));
+        assert!(cancel.contains("\r\nFrom: <sip:alice@example.com>;tag=abc\r\nTo: <sip:bob@example.com>\r\nCall-ID: call-1\r\nCSeq: 2 CANCEL\r\n"));
+        assert!(!cancel.contains("Contact") && !cancel.contains("Content-Type") && !cancel.contains("v=0"));
+    }
+
+    #[test]
+    fn test_non2xx_ack_matches_invite() {
+        let p = RequestParams { route: "Route: <sip:proxy.example.com;lr>\r\n", cseq: 2, ..params(1, None) };
+        let invite = build_invite(&p, "sip:alice@10.0.0.2:5060", "", "v=0\r\n");
+        let response = "SIP/2.0 486 Busy Here\r\n\
+                        Via: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKtest\r\n\
+                        From: <sip:alice@example.com>;tag=abc\r\n\
+                        To: <sip:bob@example.com>;tag=busy\r\n\
+                        Call-ID: call-1\r\n\
+                        CSeq: 2 INVITE\r\n\r\n";
+        let ack = build_non2xx_ack(&invite, response);
+        assert_well_formed(&ack);
+        // Same transaction: the INVITE's branch and CSeq number
+        assert!(ack.starts_with("ACK sip:bob@example.com SIP/2.0\r\nVia: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKtest\r\n"));
+        assert!(ack.contains("\r\nRoute: <sip:proxy.example.com;lr>\r\n"));
+        assert!(ack.contains("\r\nTo: <sip:bob@example.com>;tag=busy\r\nCall-ID: call-1\r\nCSeq: 2 ACK\r\n"));
+        assert!(!ack.contains("v=0"));
+    }
+
+    #[test]
+    fn test_compact_headers() {
+        let sdp = "v=0\r\n";
+        let p = RequestParams { compact: true, ..params(1, None) };
+        let invite = build_invite(&p, "sip:alice@10.0.0.2:5060", "", sdp);
+        assert!(invite.starts_with("INVITE sip:bob@example.com SIP/2.0\r\nv: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKtest\r\n"));
+        assert!(invite.contains("\r\nf: <sip:alice@example.com>;tag=abc\r\nt: <sip:bob@example.com>\r\ni: call-1\r\nCSeq: 1 INVITE\r\nm: <sip:alice@10.0.0.2:5060>\r\n"));
+        assert!(invite.contains("\r\nk: replaces\r\n"));
+        assert!(invite.ends_with(&format!("c: application/sdp\r\nUser-Agent: {}\r\nl: {}\r\n\r\n{}", USER_AGENT, sdp.len(), sdp)));
+
+        // Authorization lands in the header block, and CANCEL follows the INVITE
+        assert!(insert_header(&invite, "Authorization", "Digest x").contains("\r\nAuthorization: Digest x\r\nc: application/sdp\r\n"));
+        let cancel = build_cancel(&invite);
+        assert!(cancel.contains("\r\nt: <sip:bob@example.com>\r\ni: call-1\r\nCSeq: 1 CANCEL\r\n"));
+        assert!(cancel.ends_with("l: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_header_name_is() {
+        assert!(header_name_is("Call-ID", "call-id"));
+        assert!(header_name_is("i", "Call-ID"));
+        assert!(header_name_is("I", "Call-ID"));
+        assert!(header_name_is(" To", "t"));
+        assert!(header_name_is("Content-Type", "c"));
+        assert!(!header_name_is("t", "From"));
+        assert!(!header_name_is("X-Custom", "x"));
+    }
+
+    #[test]
+    fn test_insert_header() {
+        let bye = build_bye(&params(2, Some("xyz")));
+        let with_auth = insert_header(&bye, "Authorization", "Digest x");
+        assert_well_formed(&with_auth);
+        assert!(with_auth.contains("\r\nAuthorization: Digest x\r\nContent-Length: 0\r\n"));
+
+        let info = build_info(&params(2, None), "application/dtmf-relay", "Signal=1\r\n");
+        let with_auth = insert_header(&info, "Proxy-Authorization", "Digest y");
+        assert_well_formed(&with_auth);
+        assert!(with_auth.contains("\r\nProxy-Authorization: Digest y\r\nContent-Type:"));
+
+        assert_eq!(insert_header("OPTIONS sip:x SIP/2.0\r\n\r\n", "Accept", "application/sdp"), "OPTIONS sip:x SIP/2.0\r\nAccept: application/sdp\r\n\r\n");
+    }
+
+    #[test]
+    fn test_cseq_parse() {
+        assert_eq!(CSeq::parse(" 2 invite"), Some(CSeq { number: 2, method: "INVITE".to_string() }));
+        assert_eq!(CSeq::parse("4294967295 BYE").unwrap().number, u32::MAX);
+        assert_eq!(CSeq::parse("INVITE"), None);
+        assert_eq!(CSeq::parse("2"), None);
+        assert_eq!(CSeq::parse("-1 ACK"), None);
+        assert_eq!(CSeq::parse("1 INVITE extra"), None);
+
+        let response = "SIP/2.0 200 OK\r\nCall-ID: a\r\ncseq: 7 INFO\r\n\r\nCSeq: 9 BYE\r\n";
+        assert_eq!(CSeq::of(response).unwrap().to_string(), "7 INFO");
+        assert_eq!(CSeq::of("SIP/2.0 200 OK\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_build_retry() {
+        let info = build_info(&params(4, Some("xyz")), "application/dtmf-relay", "Signal=1\r\n");
+        let retry = build_retry(&info, "z9hG4bKnew", "Proxy-Authorization", "Digest y");
+        assert_well_formed(&retry);
+        assert!(retry.contains("\r\nVia: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKnew\r\n"));
+        assert!(retry.contains("\r\nCSeq: 5 INFO\r\n"));
+        assert!(retry.contains("\r\nProxy-Authorization: Digest y\r\nContent-Type:"));
+        assert!(retry.ends_with("\r\n\r\nSignal=1\r\n"));
+
+        let mut p = params(1, None);
+        p.compact = true;
+        let retry = build_retry(&build_bye(&p), "z9hG4bKnew", "Authorization", "Digest x");
+        assert!(retry.contains("\r\nv: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKnew\r\n"));
+        assert!(retry.contains("\r\nCSeq: 2 BYE\r\n"));
+        assert!(retry.contains("\r\nAuthorization: Digest x\r\nl: 0\r\n"));
+
+        // Answering a second challenge replaces the first credentials
+        let again = build_retry(&retry, "z9hG4bKagain", "Authorization", "Digest z");
+        assert!(again.contains("\r\nCSeq: 3 BYE\r\n"));
+        assert_eq!(again.matches("Authorization:").count(), 1);
+        assert!(again.contains("\r\nAuthorization: Digest z\r\n"));
+    }
+
+    #[test]
+    fn test_from_display_name() {
+        let p = RequestParams { from_name: "Alice Smith", ..params(1, None) };
+        let invite = build_invite(&p, "sip:alice@10.0.0.2:5060", "", "");
+        assert_well_formed(&invite);
+        assert!(invite.contains("\r\nFrom: \"Alice Smith\" <sip:alice@example.com>;tag=abc\r\n"));
+
+        let p = RequestParams { from_name: "  ", ..params(1, None) };
+        assert!(build_bye(&p).contains("\r\nFrom: <sip:alice@example.com>;tag=abc\r\n"));
+    }
+
+    #[test]
+    fn test_quoted_display_name() {
+        assert_eq!(quoted_display_name("Alice"), "\"Alice\"");
+        assert_eq!(quoted_display_name(" Bob \"The Builder\" "), "\"Bob \\\"The Builder\\\"\"");
+        assert_eq!(quoted_display_name("C:\\Users"), "\"C:\\\\Users\"");
+        assert_eq!(quoted_display_name("Line\r\nBreak"), "\"LineBreak\"");
+        assert_eq!(quoted_display_name("Zoë"), "\"Zoë\"");
+    }
+}
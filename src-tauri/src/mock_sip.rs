@@ -0,0 +1,333 @@
+//! In-process mock SIP server for signaling tests
+//!
+//! The server listens on an ephemeral UDP port on 127.0.0.1 and answers each
+//! incoming request with a scripted list of status codes, e.g. `100, 180, 200`
+//! for an INVITE or `401` followed by `200` for a REGISTER. Rules are consumed
+//! in order per method; requests without a remaining rule get a plain 200 OK
+//! (ACK never gets a response). 503 and 480 responses carry a short
+//! `Retry-After`. A challenge to a request that already has credentials
+//! says the nonce went stale and carries a new one. An INVITE scripted
+//! without a final response stays pending, and a CANCEL for it ends it
+//! with 487. Every received request is recorded so tests
+//! can assert on what the engine actually sent. Like a caller's UA, the
+//! mock ACKs each 2xx to an INVITE it sent (see `set_auto_ack`).
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// Realm advertised in the mock's digest challenges
+pub const MOCK_REALM: &str = "mock.test";
+
+/// Nonce advertised in the mock's digest challenges
+pub const MOCK_NONCE: &str = "c0ffee";
+
+/// Nonce advertised (with stale=true) when challenging an authenticated request
+pub const MOCK_STALE_NONCE: &str = "decaf";
+
+/// Retry-After (seconds) sent with 503 and 480 responses
+pub const MOCK_RETRY_AFTER: u64 = 1;
+
+pub struct MockSipServer {
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    rules: Arc<Mutex<HashMap<String, VecDeque<Vec<u16>>>>>,
+    received: Arc<Mutex<Vec<String>>>,
+    auto_ack: Arc<AtomicBool>,
+    task: tokio::task::JoinHandle<()>,
+    // Advertised in the INVITE answer; media sent to it can be read back
+    rtp_socket: UdpSocket,
+}
+
+impl MockSipServer {
+    /// Start a mock server on an ephemeral port
+    pub async fn start() -> Self {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("bind mock SIP socket"));
+        let rtp_socket = UdpSocket::bind("127.0.0.1:0").await.expect("bind mock RTP socket");
+        let addr = socket.local_addr().unwrap();
+        let rtp_addr = rtp_socket.local_addr().unwrap();
+
+        let rules: Arc<Mutex<HashMap<String, VecDeque<Vec<u16>>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let auto_ack = Arc::new(AtomicBool::new(true));
+
+        let task_socket = socket.clone();
+        let task_rules = rules.clone();
+        let task_received = received.clone();
+        let task_auto_ack = auto_ack.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 8192];
+            let mut to_tag_counter = 0u32;
+            // INVITEs still waiting for a final response, by Call-ID
+            let mut pending_invites: HashMap<String, (String, String)> = HashMap::new();
+
+            while let Ok((size, from)) = task_socket.recv_from(&mut buf).await {
+                let request = String::from_utf8_lossy(&buf[..size]).to_string();
+                let method = request.split_whitespace().next().unwrap_or("").to_string();
+                task_received.lock().unwrap().push(request.clone());
+
+                // CRLF keepalive ping: answer with a pong like an edge proxy
+                if request == "\r\n\r\n" {
+                    let _ = task_socket.send_to(b"\r\n", from).await;
+                    continue;
+                }
+
+                if method.starts_with("SIP/") {
+                    if task_auto_ack.load(Ordering::Relaxed) {
+                        if let Some(ack) = build_ack(&request) {
+                            let _ = task_socket.send_to(ack.as_bytes(), from).await;
+                        }
+                    }
+                    continue;
+                }
+                if method == "ACK" || method.is_empty() {
+                    continue;
+                }
+
+                let statuses = task_rules
+                    .lock()
+                    .unwrap()
+                    .get_mut(&method)
+                    .and_then(|queue| queue.pop_front())
+                    .unwrap_or_else(|| vec![200]);
+
+                to_tag_counter += 1;
+                let to_tag = format!("mock{}", to_tag_counter);
+
+                let call_id = header(&request, "Call-ID").unwrap_or("").to_string();
+                if method == "INVITE" && statuses.iter().all(|&status| status < 200) {
+                    pending_invites.insert(call_id.clone(), (request.clone(), to_tag.clone()));
+                }
+
+                for &status in &statuses {
+                    let response = build_response(&request, &method, status, &to_tag, addr, rtp_addr);
+                    let _ = task_socket.send_to(response.as_bytes(), from).await;
+                }
+
+                if method == "CANCEL" {
+                    if let Some((invite, invite_tag)) = pending_invites.remove(&call_id) {
+                        let response = build_response(&invite, "INVITE", 487, &invite_tag, addr, rtp_addr);
+                        let _ = task_socket.send_to(response.as_bytes(), from).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            socket,
+            rules,
+            received,
+            auto_ack,
+            task,
+            rtp_socket,
+        }
+    }
+
+    /// Answer the next `method` request with the given status codes, in order
+    pub fn respond(&self, method: &str, statuses: &[u16]) -> &Self {
+        self.rules
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_default()
+            .push_back(statuses.to_vec());
+        self
+    }
+
+    /// Whether 2xx responses to INVITEs sent with `send_request` are ACKed
+    /// automatically (on by default)
+    pub fn set_auto_ack(&self, enabled: bool) {
+        self.auto_ack.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Address to use as the engine's server (`127.0.0.1:<port>`)
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Media address advertised in the INVITE answer
+    pub fn rtp_addr(&self) -> SocketAddr {
+        self.rtp_socket.local_addr().unwrap()
+    }
+
+    /// Send a request (e.g. a re-INVITE) to the engine; responses to it are
+    /// recorded alongside requests and can be awaited with `wait_for("SIP/2.0", n)`
+    pub async fn send_request(&self, to: SocketAddr, request: &str) {
+        self.socket.send_to(request.as_bytes(), to).await.expect("send mock request");
+    }
+
+    /// Wait (up to 2s) for the next RTP packet sent to the mock's media port
+    pub async fn recv_rtp(&self) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        let received = tokio::time::timeout(std::time::Duration::from_secs(2), self.rtp_socket.recv(&mut buf)).await;
+        match received {
+            Ok(Ok(size)) => Some(buf[..size].to_vec()),
+            _ => None,
+        }
+    }
+
+    /// All requests received so far, in arrival order
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Requests received so far with the given method
+    pub fn received_method(&self, method: &str) -> Vec<String> {
+        self.received()
+            .into_iter()
+            .filter(|request| request.split_whitespace().next() == Some(method))
+            .collect()
+    }
+
+    /// Wait (up to 2s) until `count` requests with the given method have arrived
+    pub async fn wait_for(&self, method: &str, count: usize) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(2);
+        loop {
+            let requests = self.received_method(method);
+            if requests.len() >= count || tokio::time::Instant::now() >= deadline {
+                return requests;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Drop for MockSipServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Trying",
+        180 => "Ringing",
+        183 => "Session Progress",
+        200 => "OK",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        407 => "Proxy Authentication Required",
+        480 => "Temporarily Unavailable",
+        481 => "Call/Transaction Does Not Exist",
+        486 => "Busy Here",
+        487 => "Request Terminated",
+        491 => "Request Pending",
+        503 => "Service Unavailable",
+        603 => "Decline",
+        _ => "Unknown",
+    }
+}
+
+// Header value of the first line named `name` (case-insensitive)
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().find_map(|line| {
+        let (header_name, value) = line.split_once(':')?;
+        if header_name.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// ACK for a 2xx response to an INVITE (None for any other response)
+pub fn build_ack(response: &str) -> Option<String> {
+    let status = response.split_whitespace().nth(1)?;
+    let cseq = header(response, "CSeq")?;
+    let (number, method) = cseq.split_once(' ')?;
+    if !status.starts_with('2') || method.trim() != "INVITE" {
+        return None;
+    }
+    let contact = header(response, "Contact").unwrap_or("");
+    let target = contact.trim_start_matches('<').split('>').next().unwrap_or(contact);
+    Some(format!(
+        "ACK {} SIP/2.0\r\n\
+         Via: {}\r\n\
+         From: {}\r\n\
+         To: {}\r\n\
+         Call-ID: {}\r\n\
+         CSeq: {} ACK\r\n\
+         Content-Length: 0\r\n\r\n",
+        target,
+        header(response, "Via").unwrap_or(""),
+        header(response, "From").unwrap_or(""),
+        header(response, "To").unwrap_or(""),
+        header(response, "Call-ID").unwrap_or(""),
+        number.trim()
+    ))
+}
+
+fn build_response(
+    request: &str,
+    method: &str,
+    status: u16,
+    to_tag: &str,
+    addr: SocketAddr,
+    rtp_addr: SocketAddr,
+) -> String {
+    let mut response = format!("SIP/2.0 {} {}\r\n", status, reason_phrase(status));
+
+    for line in request.lines() {
+        if line.starts_with("Via:") || line.starts_with("Record-Route:") {
+            response.push_str(line);
+            response.push_str("\r\n");
+        }
+    }
+
+    let to = header(request, "To").unwrap_or("");
+    let to = if status > 100 && !to.contains("tag=") {
+        format!("{};tag={}", to, to_tag)
+    } else {
+        to.to_string()
+    };
+
+    response.push_str(&format!("From: {}\r\n", header(request, "From").unwrap_or("")));
+    response.push_str(&format!("To: {}\r\n", to));
+    response.push_str(&format!("Call-ID: {}\r\n", header(request, "Call-ID").unwrap_or("")));
+    response.push_str(&format!("CSeq: {}\r\n", header(request, "CSeq").unwrap_or("")));
+
+    let (nonce, stale) = if header(request, "Authorization").is_some() || header(request, "Proxy-Authorization").is_some() {
+        (MOCK_STALE_NONCE, ", stale=true")
+    } else {
+        (MOCK_NONCE, "")
+    };
+    if status == 401 {
+        response.push_str(&format!(
+            "WWW-Authenticate: Digest realm=\"{}\", nonce=\"{}\"{}, algorithm=MD5\r\n",
+            MOCK_REALM, nonce, stale
+        ));
+    } else if status == 407 {
+        response.push_str(&format!(
+            "Proxy-Authenticate: Digest realm=\"{}\", nonce=\"{}\"{}, algorithm=MD5\r\n",
+            MOCK_REALM, nonce, stale
+        ));
+    } else if status == 503 || status == 480 {
+        response.push_str(&format!("Retry-After: {}\r\n", MOCK_RETRY_AFTER));
+    }
+
+    let body = if (200..300).contains(&status) && method == "INVITE" {
+        response.push_str(&format!("Contact: <sip:mock@{}>\r\n", addr));
+        response.push_str("Content-Type: application/sdp\r\n");
+        format!(
+            "v=0\r\n\
+             o=- 1 1 IN IP4 {ip}\r\n\
+             s=Mock\r\n\
+             c=IN IP4 {ip}\r\n\
+             t=0 0\r\n\
+             m=audio {port} RTP/AVP 0\r\n\
+             a=rtpmap:0 PCMU/8000\r\n\
+             a=sendrecv\r\n",
+            ip = rtp_addr.ip(),
+            port = rtp_addr.port()
+        )
+    } else {
+        String::new()
+    };
+
+    response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    response
+}
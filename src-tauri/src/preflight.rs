@@ -0,0 +1,100 @@
+//! Readiness check run before registering
+//!
+//! Each check reuses what a call would do for real: enumerate the audio
+//! devices, bind a UDP socket, and resolve the server the way the SIP engine
+//! does (through the outbound proxy if one is set). A failed check never
+//! stops the others; the report says what failed and why.
+
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use crate::audio::AudioManager;
+use crate::sip;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PreflightReport {
+    pub audio_input_ok: bool,
+    pub audio_output_ok: bool,
+    pub socket_ok: bool,
+    pub dns_ok: bool,
+    /// One line per check, in the order above
+    pub details: Vec<String>,
+}
+
+/// Run every check against `server` (host, host:port or IP)
+pub async fn run(server: &str) -> PreflightReport {
+    let mut report = PreflightReport::default();
+
+    match AudioManager::new() {
+        Ok(manager) => {
+            report.audio_input_ok = check_devices(&mut report.details, "input", manager.list_input_devices());
+            report.audio_output_ok = check_devices(&mut report.details, "output", manager.list_output_devices());
+        }
+        Err(e) => {
+            report.details.push(format!("Audio input: {}", e));
+            report.details.push(format!("Audio output: {}", e));
+        }
+    }
+
+    match UdpSocket::bind("0.0.0.0:0").await.and_then(|socket| socket.local_addr()) {
+        Ok(addr) => {
+            report.socket_ok = true;
+            report.details.push(format!("UDP socket: bound port {}", addr.port()));
+        }
+        Err(e) => report.details.push(format!("UDP socket: {}", e)),
+    }
+
+    let server = server.trim();
+    if server.is_empty() {
+        report.details.push("Server: none configured".to_string());
+    } else {
+        match sip::check_server(server).await {
+            Ok(addr) => {
+                report.dns_ok = true;
+                report.details.push(format!("Server: {} resolves to {}", server, addr));
+            }
+            Err(e) => report.details.push(format!("Server: {}", e)),
+        }
+    }
+
+    println!("[Preflight] {}", report.details.join("; "));
+    report
+}
+
+fn check_devices(
+    details: &mut Vec<String>,
+    kind: &str,
+    devices: Result<Vec<String>, crate::error::SipError>,
+) -> bool {
+    match devices {
+        Ok(devices) if !devices.is_empty() => {
+            details.push(format!("Audio {}: {} device(s)", kind, devices.len()));
+            true
+        }
+        Ok(_) => {
+            details.push(format!("Audio {}: no devices found", kind));
+            false
+        }
+        Err(e) => {
+            details.push(format!("Audio {}: {}", kind, e));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_preflight_report() {
+        let report = run("127.0.0.1:5060").await;
+        assert!(report.socket_ok);
+        assert!(report.dns_ok);
+        assert_eq!(report.details.len(), 4);
+        assert!(report.details[3].contains("127.0.0.1:5060"));
+
+        let report = run("").await;
+        assert!(!report.dns_ok);
+        assert_eq!(report.details[3], "Server: none configured");
+    }
+}
@@ -1,34 +1,205 @@
+use rubato::{Resampler as _, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
 use std::sync::Mutex;
 
+/// Resampler for call audio: rubato's band-limited one when it can be
+/// built, otherwise the linear fallback
+pub enum Resampler {
+    HighQuality(Box<AudioResampler>),
+    Simple(SimpleResampler),
+}
+
+impl Resampler {
+    /// Create a resampler between `input_rate` (audio device) and
+    /// `output_rate` (network), logging which implementation was chosen
+    pub fn new(input_rate: u32, output_rate: u32, chunk_size: usize) -> Self {
+        match AudioResampler::new(input_rate, output_rate, chunk_size) {
+            Ok(resampler) => {
+                tracing::info!("[Resample] ✓ High-quality resampler created (rubato)");
+                println!("[Resample] ✓ High-quality resampler created (rubato)");
+                Self::HighQuality(Box::new(resampler))
+            }
+            Err(e) => {
+                tracing::warn!("[Resample] Failed to create rubato resampler: {}", e);
+                println!("[Resample] ⚠ Failed to create rubato resampler: {}", e);
+                println!("[Resample] Falling back to simple resampler (linear interpolation)");
+                Self::Simple(SimpleResampler::new(input_rate, output_rate))
+            }
+        }
+    }
+
+    /// Short name of the implementation in use, for logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HighQuality(_) => "rubato",
+            Self::Simple(_) => "linear",
+        }
+    }
+
+    /// Device rate to network rate (TX path)
+    pub fn downsample(&self, input: &[i16]) -> Result<Vec<i16>, String> {
+        match self {
+            Self::HighQuality(resampler) => resampler.downsample(input),
+            Self::Simple(resampler) => resampler.downsample(input),
+        }
+    }
+
+    /// Network rate to device rate (RX path)
+    pub fn upsample(&self, input: &[i16]) -> Result<Vec<i16>, String> {
+        match self {
+            Self::HighQuality(resampler) => resampler.upsample(input),
+            Self::Simple(resampler) => resampler.upsample(input),
+        }
+    }
+
+    /// Stretch (factor above 1.0) or shrink the upsampled output slightly
+    ///
+    /// Used to follow the sender's clock when it runs a little faster or
+    /// slower than the output device.
+    pub fn set_upsample_adjust(&self, factor: f64) {
+        match self {
+            Self::HighQuality(resampler) => resampler.set_upsample_adjust(factor),
+            Self::Simple(resampler) => resampler.set_upsample_adjust(factor),
+        }
+    }
+}
+
+/// Largest relative change `set_upsample_adjust` may make to the rubato ratio
+const MAX_ADJUST_RELATIVE: f64 = 1.01;
+
+/// Band-limited (windowed sinc) resampler built on rubato
+///
+/// rubato converts fixed-size chunks, so input is queued until a whole chunk
+/// (`chunk_size` samples at the device rate, the matching amount at the
+/// network rate) is there. At most one chunk is held back per direction.
+pub struct AudioResampler {
+    downsampler: Mutex<ChunkedResampler>,
+    upsampler: Mutex<ChunkedResampler>,
+}
+
+impl AudioResampler {
+    /// Create a new audio resampler
+    ///
+    /// # Arguments
+    /// * `input_rate` - Input sample rate (typically 48000 Hz for audio devices)
+    /// * `output_rate` - Output sample rate (typically 8000 Hz for VoIP)
+    /// * `chunk_size` - Device samples converted at a time (960 = 20ms at 48kHz)
+    pub fn new(input_rate: u32, output_rate: u32, chunk_size: usize) -> Result<Self, String> {
+        if input_rate == 0 || output_rate == 0 {
+            return Err(format!("Invalid sample rates {}Hz ↔ {}Hz", input_rate, output_rate));
+        }
+        let network_chunk = chunk_size * output_rate as usize / input_rate as usize;
+        if network_chunk == 0 {
+            return Err(format!("Chunk of {} samples is too small for {}Hz ↔ {}Hz", chunk_size, input_rate, output_rate));
+        }
+
+        let ratio = output_rate as f64 / input_rate as f64;
+        Ok(Self {
+            downsampler: Mutex::new(ChunkedResampler::new(ratio, chunk_size)?),
+            upsampler: Mutex::new(ChunkedResampler::new(1.0 / ratio, network_chunk)?),
+        })
+    }
+
+    /// Downsample audio from high sample rate to low sample rate (e.g., 48kHz → 8kHz)
+    /// Used for TX path: Microphone → Network
+    pub fn downsample(&self, input: &[i16]) -> Result<Vec<i16>, String> {
+        self.downsampler.lock()
+            .map_err(|e| format!("Failed to lock resampler: {}", e))?
+            .process(input)
+    }
+
+    /// Upsample audio from low sample rate to high sample rate (e.g., 8kHz → 48kHz)
+    /// Used for RX path: Network → Speaker
+    pub fn upsample(&self, input: &[i16]) -> Result<Vec<i16>, String> {
+        self.upsampler.lock()
+            .map_err(|e| format!("Failed to lock resampler: {}", e))?
+            .process(input)
+    }
+
+    /// Stretch (factor above 1.0) or shrink the upsampled output slightly
+    pub fn set_upsample_adjust(&self, factor: f64) {
+        if let Ok(mut upsampler) = self.upsampler.lock() {
+            if let Err(e) = upsampler.resampler.set_resample_ratio_relative(factor, true) {
+                tracing::warn!("[Resample] Rate adjustment {} rejected: {}", factor, e);
+            }
+        }
+    }
+}
+
+/// One direction of `AudioResampler`: the rubato resampler and the samples
+/// waiting for a full chunk
+struct ChunkedResampler {
+    resampler: SincFixedIn<f32>,
+    pending: Vec<f32>,
+}
+
+impl ChunkedResampler {
+    fn new(ratio: f64, chunk_size: usize) -> Result<Self, String> {
+        let parameters = SincInterpolationParameters {
+            sinc_len: 64,
+            f_cutoff: 0.95,
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let resampler = SincFixedIn::new(ratio, MAX_ADJUST_RELATIVE, parameters, chunk_size, 1)
+            .map_err(|e| e.to_string())?;
+        Ok(Self { resampler, pending: Vec::with_capacity(chunk_size * 2) })
+    }
+
+    fn process(&mut self, input: &[i16]) -> Result<Vec<i16>, String> {
+        self.pending.extend(input.iter().map(|&s| s as f32 / 32768.0));
+
+        let mut output = Vec::new();
+        while self.pending.len() >= self.resampler.input_frames_next() {
+            let frames = self.resampler.input_frames_next();
+            let converted = self.resampler
+                .process(&[&self.pending[..frames]], None)
+                .map_err(|e| e.to_string())?;
+            self.pending.drain(..frames);
+            output.extend(converted[0].iter().map(|&s| (s * 32768.0).clamp(-32768.0, 32767.0) as i16));
+        }
+
+        tracing::debug!("[Resample] Resampled {} → {} samples", input.len(), output.len());
+        Ok(output)
+    }
+}
+
 /// Audio resampler using linear interpolation
 /// Handles conversion between 48kHz (typical audio device) and 8kHz (VoIP standard)
-/// Simple but effective - works with any buffer size
-pub struct AudioResampler {
+/// Simple but effective - works with any buffer size; the fallback when
+/// `AudioResampler` can't be built
+pub struct SimpleResampler {
     input_rate: u32,
     output_rate: u32,
     /// Position tracker for downsampling (to maintain phase across chunks)
     downsample_position: Mutex<f64>,
+    /// Position tracker for upsampling, in input samples
+    upsample_position: Mutex<f64>,
+    /// Factor on the upsampled length (1.0 = exact rate), for clock drift
+    upsample_adjust: Mutex<f64>,
 }
 
-impl AudioResampler {
+impl SimpleResampler {
     /// Create a new audio resampler
     /// 
     /// # Arguments
     /// * `input_rate` - Input sample rate (typically 48000 Hz for audio devices)
     /// * `output_rate` - Output sample rate (typically 8000 Hz for VoIP)
-    /// * `_chunk_size` - Unused, kept for API compatibility
-    pub fn new(input_rate: u32, output_rate: u32, _chunk_size: usize) -> Result<Self, String> {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
         tracing::info!(
             "[Resample] Created resampler: {}Hz ↔ {}Hz (linear interpolation)",
             input_rate,
             output_rate
         );
 
-        Ok(Self {
-            input_rate,
-            output_rate,
+        // A zero rate would make the ratios infinite
+        Self {
+            input_rate: input_rate.max(1),
+            output_rate: output_rate.max(1),
             downsample_position: Mutex::new(0.0),
-        })
+            upsample_position: Mutex::new(0.0),
+            upsample_adjust: Mutex::new(1.0),
+        }
     }
 
     /// Downsample audio from high sample rate to low sample rate (e.g., 48kHz → 8kHz)
@@ -83,6 +254,16 @@ impl AudioResampler {
         Ok(output)
     }
 
+    /// Stretch (factor above 1.0) or shrink the upsampled output slightly
+    ///
+    /// Used to follow the sender's clock when it runs a little faster or
+    /// slower than the output device.
+    pub fn set_upsample_adjust(&self, factor: f64) {
+        if let Ok(mut adjust) = self.upsample_adjust.lock() {
+            *adjust = factor;
+        }
+    }
+
     /// Upsample audio from low sample rate to high sample rate (e.g., 8kHz → 48kHz)
     /// Used for RX path: Network → Speaker
     /// 
@@ -96,14 +277,18 @@ impl AudioResampler {
             return Ok(Vec::new());
         }
 
-        let ratio = self.output_rate as f64 / self.input_rate as f64;
-        let output_len = (input.len() as f64 * ratio).floor() as usize;
-        let mut output = Vec::with_capacity(output_len);
+        let adjust = *self.upsample_adjust.lock()
+            .map_err(|e| format!("Failed to lock adjustment: {}", e))?;
+        let ratio = self.input_rate as f64 / self.output_rate as f64 * adjust;
+        let step = 1.0 / ratio;
+        let mut output = Vec::with_capacity((input.len() as f64 * ratio).ceil() as usize);
 
-        for i in 0..output_len {
-            let src_pos = i as f64 / ratio;
-            let src_idx = src_pos.floor() as usize;
-            let frac = src_pos - src_pos.floor();
+        let mut position = self.upsample_position.lock()
+            .map_err(|e| format!("Failed to lock position: {}", e))?;
+
+        while *position < input.len() as f64 {
+            let src_idx = (*position).floor() as usize;
+            let frac = *position - (*position).floor();
 
             if src_idx + 1 < input.len() {
                 // Linear interpolation
@@ -111,11 +296,16 @@ impl AudioResampler {
                 let sample2 = input[src_idx + 1] as f64;
                 let interpolated = sample1 + (sample2 - sample1) * frac;
                 output.push(interpolated.clamp(-32768.0, 32767.0) as i16);
-            } else if src_idx < input.len() {
+            } else {
                 output.push(input[src_idx]);
             }
+
+            *position += step;
         }
 
+        // Keep fractional part for next chunk
+        *position -= input.len() as f64;
+
         tracing::debug!(
             "[Resample] Upsampled {} → {} samples",
             input.len(),
@@ -134,6 +324,8 @@ mod tests {
     fn test_audio_resampler_creation() {
         let resampler = AudioResampler::new(48000, 8000, 960);
         assert!(resampler.is_ok());
+        assert!(AudioResampler::new(48000, 0, 960).is_err());
+        assert!(AudioResampler::new(48000, 8000, 4).is_err());
     }
 
     #[test]
@@ -150,8 +342,22 @@ mod tests {
     }
 
     #[test]
-    fn test_downsample_variable_sizes() {
+    fn test_downsample_queues_partial_chunks() {
         let resampler = AudioResampler::new(48000, 8000, 960).unwrap();
+        let input: Vec<i16> = vec![1000; 480];
+
+        // Nothing comes out until a whole chunk is there (the first one is
+        // short by the filter delay)
+        assert!(resampler.downsample(&input).unwrap().is_empty());
+        assert!(!resampler.downsample(&input).unwrap().is_empty());
+        assert!(resampler.downsample(&input).unwrap().is_empty());
+        let output = resampler.downsample(&input).unwrap();
+        assert!((159..=161).contains(&output.len()), "got {}", output.len());
+    }
+
+    #[test]
+    fn test_downsample_variable_sizes() {
+        let resampler = SimpleResampler::new(48000, 8000);
         
         // Test with 480 samples (10ms)
         let input1: Vec<i16> = (0..480).map(|i| (i * 100) as i16).collect();
@@ -171,12 +377,52 @@ mod tests {
         // Create 160 samples at 8kHz (20ms)
         let input: Vec<i16> = (0..160).map(|i| (i * 100) as i16).collect();
         
+        // Should produce 960 samples at 48kHz (20ms) once the filter is primed
+        resampler.upsample(&input).unwrap();
         let output = resampler.upsample(&input).unwrap();
-        
-        // Should produce ~960 samples at 48kHz (20ms)
+        assert!((959..=961).contains(&output.len()), "got {}", output.len());
+
+        let output = SimpleResampler::new(48000, 8000).upsample(&input).unwrap();
         assert!(output.len() >= 900 && output.len() <= 1000);
     }
 
+    #[test]
+    fn test_upsample_adjust() {
+        let resampler = SimpleResampler::new(48000, 8000);
+        let input: Vec<i16> = vec![1000; 160];
+
+        // The fraction carries over, so 0.1% more comes out over many chunks
+        resampler.set_upsample_adjust(1.001);
+        let total: usize = (0..100).map(|_| resampler.upsample(&input).unwrap().len()).sum();
+        assert!((96090..=96100).contains(&total), "got {}", total);
+
+        resampler.set_upsample_adjust(0.999);
+        let total: usize = (0..100).map(|_| resampler.upsample(&input).unwrap().len()).sum();
+        assert!((95900..=95910).contains(&total), "got {}", total);
+
+        let total = |factor: f64| {
+            let resampler = AudioResampler::new(48000, 8000, 960).unwrap();
+            resampler.set_upsample_adjust(factor);
+            (0..100).map(|_| resampler.upsample(&input).unwrap().len()).sum::<usize>()
+        };
+        let extra = total(1.001) - total(1.0);
+        assert!((90..=100).contains(&extra), "got {}", extra);
+    }
+
+    #[test]
+    fn test_resampler_falls_back() {
+        let resampler = Resampler::new(48000, 8000, 960);
+        assert_eq!(resampler.name(), "rubato");
+
+        // Too small a chunk for rubato at 11.025kHz: linear takes over and
+        // still converts
+        let resampler = Resampler::new(48000, 11025, 2);
+        assert_eq!(resampler.name(), "linear");
+        let output = resampler.downsample(&vec![1000; 960]).unwrap();
+        assert!((219..=221).contains(&output.len()), "got {}", output.len());
+        assert!(!resampler.upsample(&output).unwrap().is_empty());
+    }
+
     #[test]
     fn test_empty_input() {
         let resampler = AudioResampler::new(48000, 8000, 960).unwrap();
@@ -0,0 +1,240 @@
+//! Ringing for incoming calls
+//!
+//! The ring plays on its own output device and volume, so a call can ring
+//! out loud on the laptop speakers while it is taken on a headset. The sound
+//! is the user's ringtone (a PCM WAV file) or a built-in two-tone ring.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use crate::audio::AudioManager;
+use crate::error::SipError;
+use crate::settings::Ringer;
+
+/// Built-in ring: 440 + 480 Hz, two seconds on and four off
+const RING_FREQUENCIES_HZ: [f32; 2] = [440.0, 480.0];
+const RING_ON_MS: u32 = 2000;
+const RING_OFF_MS: u32 = 4000;
+const RING_AMPLITUDE: f32 = 8000.0;
+
+/// How often the ringer thread checks whether to stop
+const RING_POLL_MS: u64 = 50;
+
+/// A ring in progress; it stops when this is dropped
+pub struct Ringing {
+    stop: Arc<AtomicBool>,
+}
+
+impl Ringing {
+    /// A ring that makes no sound (no audio hardware)
+    pub fn silent() -> Self {
+        Self { stop: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl Drop for Ringing {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Start ringing on the ringer's device (the default output if it's unset
+/// or missing) at its volume
+pub fn play(ringer: &Ringer) -> Result<Ringing, SipError> {
+    let ringing = Ringing::silent();
+    let stop = ringing.stop.clone();
+    let ringer = ringer.clone();
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel::<Result<(), SipError>>();
+
+    // cpal streams can't leave the thread that built them
+    std::thread::Builder::new()
+        .name("ringer".to_string())
+        .spawn(move || {
+            let stream = AudioManager::new().and_then(|mut manager| {
+                let preferred = Some(ringer.device.as_str()).filter(|name| !name.is_empty());
+                let device = manager.open_output(preferred)?;
+                let sample_rate = manager.output_sample_rate()?;
+                println!("[Ringer] Ringing on '{}'", device);
+                manager.start_loop(ring_sound(&ringer.ringtone_path, sample_rate), ringer.volume)
+            });
+            let stream = match stream {
+                Ok(stream) => {
+                    let _ = ready_tx.send(Ok(()));
+                    stream
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            while !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(RING_POLL_MS));
+            }
+            drop(stream);
+            println!("[Ringer] Ringing stopped");
+        })
+        .map_err(|e| SipError::Audio(format!("Failed to spawn ringer thread: {}", e)))?;
+
+    ready_rx
+        .recv()
+        .map_err(|_| SipError::Audio("Ringer thread exited during setup".to_string()))??;
+    Ok(ringing)
+}
+
+// The ringtone file resampled to `sample_rate`, or the built-in ring when
+// there is no file or it can't be read
+fn ring_sound(path: &str, sample_rate: u32) -> Vec<i16> {
+    if path.is_empty() {
+        return ring_tone(sample_rate);
+    }
+    let ringtone = std::fs::read(path)
+        .map_err(|e| SipError::Audio(format!("Failed to read ringtone {}: {}", path, e)))
+        .and_then(|bytes| parse_wav(&bytes));
+    match ringtone {
+        Ok((samples, rate)) if !samples.is_empty() => resample_linear(&samples, rate, sample_rate),
+        Ok(_) => {
+            println!("[Ringer] Ringtone {} is empty, using the built-in ring", path);
+            ring_tone(sample_rate)
+        }
+        Err(e) => {
+            println!("[Ringer] {}, using the built-in ring", e);
+            ring_tone(sample_rate)
+        }
+    }
+}
+
+/// One cycle of the built-in ring at `sample_rate`
+pub fn ring_tone(sample_rate: u32) -> Vec<i16> {
+    let on = (sample_rate as u64 * RING_ON_MS as u64 / 1000) as usize;
+    let off = (sample_rate as u64 * RING_OFF_MS as u64 / 1000) as usize;
+    let mut samples: Vec<i16> = (0..on)
+        .map(|n| {
+            let t = n as f32 / sample_rate as f32;
+            let mix: f32 = RING_FREQUENCIES_HZ
+                .iter()
+                .map(|frequency| (2.0 * std::f32::consts::PI * frequency * t).sin())
+                .sum();
+            (mix / RING_FREQUENCIES_HZ.len() as f32 * RING_AMPLITUDE) as i16
+        })
+        .collect();
+    samples.resize(on + off, 0);
+    samples
+}
+
+/// Mono samples and sample rate of a 16-bit PCM WAV file (channels are mixed down)
+pub fn parse_wav(bytes: &[u8]) -> Result<(Vec<i16>, u32), SipError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(SipError::Audio("Ringtone is not a WAV file".to_string()));
+    }
+
+    let mut format = None;
+    let mut chunks = &bytes[12..];
+    while chunks.len() >= 8 {
+        let id = &chunks[0..4];
+        let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        let body = &chunks[8..chunks.len().min(8 + len)];
+
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let tag = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                if tag != 1 || bits != 16 || channels == 0 {
+                    return Err(SipError::Audio("Ringtone must be 16-bit PCM WAV".to_string()));
+                }
+                format = Some((channels as usize, rate));
+            }
+            b"data" => {
+                let (channels, rate) = format
+                    .ok_or_else(|| SipError::Audio("Ringtone has no format chunk".to_string()))?;
+                let samples = body
+                    .chunks_exact(2 * channels)
+                    .map(|frame| {
+                        let sum: i32 = frame
+                            .chunks_exact(2)
+                            .map(|sample| i32::from(i16::from_le_bytes([sample[0], sample[1]])))
+                            .sum();
+                        (sum / channels as i32) as i16
+                    })
+                    .collect();
+                return Ok((samples, rate));
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even length
+        let next = 8 + len + (len & 1);
+        chunks = chunks.get(next..).unwrap_or_default();
+    }
+
+    Err(SipError::Audio("Ringtone has no audio data".to_string()))
+}
+
+// Linear-interpolation resampling; plenty for a ringtone
+fn resample_linear(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || from_rate == 0 {
+        return samples.to_vec();
+    }
+    let len = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    (0..len)
+        .map(|n| {
+            let position = n as f64 * from_rate as f64 / to_rate as f64;
+            let index = position as usize;
+            let fraction = position - index as f64;
+            let a = f64::from(samples[index]);
+            let b = f64::from(*samples.get(index + 1).unwrap_or(&samples[index]));
+            (a + (b - a) * fraction).round() as i16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16-bit PCM WAV with a LIST chunk before the data
+    fn wav(channels: u16, rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        bytes.extend_from_slice(b"fmt \x10\0\0\0");
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&rate.to_le_bytes());
+        bytes.extend_from_slice(&(rate * 2 * channels as u32).to_le_bytes());
+        bytes.extend_from_slice(&(2 * channels).to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_wav() {
+        assert_eq!(parse_wav(&wav(1, 8000, &[1, -2, 300])).unwrap(), (vec![1, -2, 300], 8000));
+        // Stereo is mixed down
+        assert_eq!(parse_wav(&wav(2, 44100, &[100, 300, -50, -150])).unwrap(), (vec![200, -100], 44100));
+
+        assert!(parse_wav(b"ID3\x04 not a wav").is_err());
+        let mut eight_bit = wav(1, 8000, &[0]);
+        eight_bit[34] = 8;
+        assert!(parse_wav(&eight_bit).is_err());
+    }
+
+    #[test]
+    fn test_ring_sound() {
+        // Two seconds of ring, four of silence
+        let tone = ring_tone(8000);
+        assert_eq!(tone.len(), 48000);
+        assert!(tone[..16000].iter().any(|&s| s.abs() > 1000));
+        assert!(tone[16000..].iter().all(|&s| s == 0));
+
+        assert_eq!(resample_linear(&[0, 100], 8000, 16000), vec![0, 50, 100, 100]);
+        assert_eq!(resample_linear(&[0, 100, 200, 300], 16000, 8000), vec![0, 200]);
+
+        // A missing ringtone falls back to the built-in ring
+        assert_eq!(ring_sound("/nonexistent/ring.wav", 8000), tone);
+    }
+}
@@ -1,6 +1,9 @@
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
+use crate::error::SipError;
+use crate::srtp::{SrtpContext, SrtpKeys};
+use crate::stun::{self, TurnRelay};
 
 /// RTP packet structure (RFC 3550)
 #[derive(Debug, Clone)]
@@ -65,9 +68,9 @@ impl RtpPacket {
     }
 
     /// Parse RTP packet from bytes
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SipError> {
         if bytes.len() < 12 {
-            return Err("RTP packet too short".to_string());
+            return Err(SipError::Protocol("RTP packet too short".to_string()));
         }
 
         let version = (bytes[0] >> 6) & 0x03;
@@ -82,12 +85,40 @@ impl RtpPacket {
         let timestamp = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
         let ssrc = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
 
-        let header_len = 12 + (csrc_count as usize * 4);
+        if version != 2 {
+            return Err(SipError::Protocol(format!("Unsupported RTP version {}", version)));
+        }
+
+        let mut header_len = 12 + (csrc_count as usize * 4);
         if bytes.len() < header_len {
-            return Err("RTP packet header incomplete".to_string());
+            return Err(SipError::Protocol("RTP packet header incomplete".to_string()));
+        }
+
+        // Header extension (RFC 3550 5.3.1): profile and length words, then
+        // `length` 32-bit words of data that are not payload
+        if extension {
+            if bytes.len() < header_len + 4 {
+                return Err(SipError::Protocol("RTP extension header truncated".to_string()));
+            }
+            let words = u16::from_be_bytes([bytes[header_len + 2], bytes[header_len + 3]]) as usize;
+            header_len += 4 + words * 4;
+            if bytes.len() < header_len {
+                return Err(SipError::Protocol("RTP extension data truncated".to_string()));
+            }
         }
 
-        let payload = bytes[header_len..].to_vec();
+        // Padding (RFC 3550 5.1): the last byte counts the padding octets,
+        // itself included, that follow the payload
+        let mut payload_end = bytes.len();
+        if padding {
+            let pad_count = bytes[bytes.len() - 1] as usize;
+            if pad_count == 0 || pad_count > bytes.len() - header_len {
+                return Err(SipError::Protocol(format!("Invalid RTP padding length {}", pad_count)));
+            }
+            payload_end -= pad_count;
+        }
+
+        let payload = bytes[header_len..payload_end].to_vec();
 
         Ok(Self {
             version,
@@ -111,29 +142,23 @@ pub mod g711 {
 
     /// Encode 16-bit linear PCM to μ-law
     pub fn encode_ulaw(sample: i16) -> u8 {
-        let mut sample = sample;
-        
-        // Get the sign bit
-        let sign = if sample < 0 {
-            sample = -sample;
-            0x80
+        // Work in i32 so that negating i16::MIN cannot overflow
+        let (sign, magnitude) = if sample < 0 {
+            (0x80u8, -(sample as i32))
         } else {
-            0x00
+            (0x00u8, sample as i32)
         };
 
-        // Clip the magnitude
-        if sample > CLIP {
-            sample = CLIP;
-        }
-
-        // Add bias
-        sample = sample + BIAS;
+        // Clip the magnitude and add bias; the biased value always has
+        // bit 7 set and fits in 15 bits
+        let biased = magnitude.min(CLIP as i32) + BIAS as i32;
 
-        // Find the exponent (position of highest set bit)
-        let exponent = (7 - sample.leading_zeros().saturating_sub(9)) as u8;
+        // Exponent is the position of the highest set bit above bit 7 (0..=7)
+        let highest_bit = 31 - biased.leading_zeros();
+        let exponent = (highest_bit - 7) as u8;
 
         // Get the mantissa (4 bits after the exponent bit)
-        let mantissa = ((sample >> (exponent + 3)) & 0x0F) as u8;
+        let mantissa = ((biased >> (exponent + 3)) & 0x0F) as u8;
 
         // Combine sign, exponent, and mantissa
         let ulaw = sign | (exponent << 4) | mantissa;
@@ -163,46 +188,52 @@ pub mod g711 {
         }
     }
 
+    /// Upper bound of each A-law segment for 13-bit magnitudes
+    const SEG_AEND: [i16; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
     /// Encode 16-bit linear PCM to A-law
+    ///
+    /// Codeword layout before the even-bit inversion (XOR 0x55):
+    /// bit 7 = sign (1 = positive, 0 = negative), bits 6-4 = segment,
+    /// bits 3-0 = step within the segment. This matches the ITU-T G.711
+    /// reference, where 0 encodes to 0xD5 and -1 to 0x55.
     pub fn encode_alaw(sample: i16) -> u8 {
-        let mut sample = sample;
-        
-        // Get the sign bit
-        let sign = if sample < 0 {
-            sample = -sample;
-            0x00
+        // A-law works on 13-bit magnitudes
+        let pcm = sample >> 3;
+
+        // Negative values use the one's complement magnitude so that
+        // -1 and 0 land in the two smallest codewords
+        let (mask, magnitude) = if pcm >= 0 {
+            (0xD5u8, pcm)
         } else {
-            0x80
+            (0x55u8, -pcm - 1)
         };
 
-        // Clip the magnitude
-        if sample > CLIP {
-            sample = CLIP;
-        }
-
-        let mut alaw: u8;
+        let segment = match SEG_AEND.iter().position(|&end| magnitude <= end) {
+            Some(segment) => segment as u8,
+            // Out of range: clip to the largest magnitude
+            None => return 0x7F ^ mask,
+        };
 
-        if sample < 256 {
-            alaw = (sample >> 4) as u8;
+        let step = if segment < 2 {
+            (magnitude >> 1) & 0x0F
         } else {
-            // Find the exponent
-            let exponent = (7 - sample.leading_zeros().saturating_sub(9)) as u8;
-            let mantissa = ((sample >> (exponent + 3)) & 0x0F) as u8;
-            alaw = (exponent << 4) | mantissa;
-        }
+            (magnitude >> segment) & 0x0F
+        } as u8;
 
-        sign | alaw ^ 0x55
+        ((segment << 4) | step) ^ mask
     }
 
     /// Decode A-law to 16-bit linear PCM
     pub fn decode_alaw(alaw: u8) -> i16 {
         let alaw = alaw ^ 0x55;
         
+        // Bit 7 set means positive (see encode_alaw)
         let sign = (alaw & 0x80) != 0;
         let exponent = ((alaw >> 4) & 0x07) as u32;
         let mantissa = (alaw & 0x0F) as i16;
 
-        let mut sample = if exponent == 0 {
+        let sample = if exponent == 0 {
             (mantissa << 4) + 8
         } else {
             ((mantissa << 4) + 0x108) << (exponent - 1)
@@ -220,86 +251,443 @@ pub mod g711 {
 #[derive(Debug)]
 pub struct RtpSession {
     socket: Arc<UdpSocket>,
-    remote_addr: std::net::SocketAddr,
+    // Remote address and codec can change mid-call (re-INVITE)
+    remote_addr: Arc<Mutex<std::net::SocketAddr>>,
+    // Where the peer's RTP actually comes from (symmetric RTP, RFC 4961);
+    // behind NAT this differs from the SDP address and is where we send
+    learned_addr: Arc<Mutex<Option<std::net::SocketAddr>>>,
     local_port: u16,
     ssrc: u32,
     sequence_number: Arc<Mutex<u16>>,
     timestamp: Arc<Mutex<u32>>,
-    payload_type: u8, // 0 = PCMU, 8 = PCMA
+    payload_type: Arc<Mutex<u8>>, // 0 = PCMU, 8 = PCMA
+    // RTP clock rate of the payload type (from a=rtpmap), drives timestamps
+    clock_rate: Arc<Mutex<u32>>,
+    // SRTP contexts for outgoing (our key) and incoming (peer's key) packets
+    srtp_tx: Arc<Mutex<Option<SrtpContext>>>,
+    srtp_rx: Arc<Mutex<Option<SrtpContext>>>,
+    // Which remote source (SSRC) we are playing
+    rx_source: Arc<Mutex<SourceTracker>>,
+    // Payload type the peer uses for RFC 4733 telephone-events (DTMF)
+    event_payload_type: Arc<Mutex<Option<u8>>>,
+    // Interarrival jitter of the current source
+    rx_jitter: Arc<Mutex<JitterEstimator>>,
+    // Turns the peer's telephone-event packets into digits
+    rx_dtmf: Arc<Mutex<DtmfDetector>>,
+    // Reference point for packet arrival times
+    started: std::time::Instant,
+    // TURN relay media goes through, when one was allocated for the call
+    relay: Option<Arc<TurnRelay>>,
+    // Address advertised in our SDP when it isn't the local one (relayed
+    // or STUN-mapped)
+    advertised_addr: Option<std::net::SocketAddr>,
+    // Audio bandwidth limit (kbit/s) agreed in SDP, None if neither side set one
+    bandwidth_kbps: Arc<Mutex<Option<u32>>>,
+    // Negotiated direction; changes when the call is put on hold and resumed
+    direction: Arc<Mutex<MediaDirection>>,
 }
 
 impl RtpSession {
-    /// Create a new RTP session
+    /// Create a new RTP session on its own socket bound to `local_ip`
+    ///
+    /// Calls bind theirs while gathering the SDP address and hand it to
+    /// `with_socket`; this is for sessions with plain host media.
+    #[cfg(test)]
     pub async fn new(
+        local_ip: std::net::IpAddr,
         local_port: u16,
         remote_addr: std::net::SocketAddr,
         payload_type: u8,
-    ) -> Result<Self, String> {
+        clock_rate: u32,
+    ) -> Result<Self, SipError> {
         // Bind UDP socket for RTP
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", local_port))
+        let bind_addr = std::net::SocketAddr::new(local_ip, local_port);
+        let socket = UdpSocket::bind(bind_addr)
             .await
-            .map_err(|e| format!("Failed to bind RTP socket: {}", e))?;
+            .map_err(|e| SipError::Network(format!("Failed to bind RTP socket to {}: {}", bind_addr, e)))?;
+
+        println!("[RTP] Socket bound to {}", bind_addr);
+
+        Self::with_socket(socket, None, None, remote_addr, payload_type, clock_rate).await
+    }
+
+    /// Create a session on an already bound socket, the one the call's
+    /// media address was gathered on. With a TURN relay, media to and from
+    /// `remote_addr` goes through it on a bound channel.
+    pub async fn with_socket(
+        socket: UdpSocket,
+        relay: Option<Arc<TurnRelay>>,
+        advertised_addr: Option<std::net::SocketAddr>,
+        remote_addr: std::net::SocketAddr,
+        payload_type: u8,
+        clock_rate: u32,
+    ) -> Result<Self, SipError> {
+        let local_port = socket.local_addr()
+            .map_err(|e| SipError::Network(format!("Failed to get RTP socket address: {}", e)))?
+            .port();
 
-        println!("[RTP] Socket bound to 0.0.0.0:{}", local_port);
         println!("[RTP] Remote address: {}", remote_addr);
 
+        if crate::settings::load_qos().unwrap_or_default().media && set_dscp(&socket, DSCP_EF) {
+            println!("[QoS] RTP marked DSCP EF");
+        }
+
+        if let Some(relay) = &relay {
+            println!("[TURN] Relaying media to {} through {}", remote_addr, relay.server());
+            socket.send_to(&relay.channel_bind_request(remote_addr)?, relay.server()).await
+                .map_err(|e| SipError::Network(format!("Failed to bind TURN channel: {}", e)))?;
+        }
+
         // Generate random SSRC
         let ssrc = rand::random::<u32>();
 
         Ok(Self {
             socket: Arc::new(socket),
-            remote_addr,
+            remote_addr: Arc::new(Mutex::new(remote_addr)),
+            learned_addr: Arc::new(Mutex::new(None)),
             local_port,
             ssrc,
             sequence_number: Arc::new(Mutex::new(rand::random_u16())),
             timestamp: Arc::new(Mutex::new(0)),
-            payload_type,
+            payload_type: Arc::new(Mutex::new(payload_type)),
+            clock_rate: Arc::new(Mutex::new(clock_rate)),
+            srtp_tx: Arc::new(Mutex::new(None)),
+            srtp_rx: Arc::new(Mutex::new(None)),
+            rx_source: Arc::new(Mutex::new(SourceTracker::default())),
+            event_payload_type: Arc::new(Mutex::new(None)),
+            rx_jitter: Arc::new(Mutex::new(JitterEstimator::default())),
+            rx_dtmf: Arc::new(Mutex::new(DtmfDetector::default())),
+            started: std::time::Instant::now(),
+            relay,
+            advertised_addr,
+            bandwidth_kbps: Arc::new(Mutex::new(None)),
+            direction: Arc::new(Mutex::new(MediaDirection::SendRecv)),
         })
     }
 
     /// Send RTP packet with audio payload
-    pub async fn send_audio(&self, audio_data: &[u8]) -> Result<(), String> {
+    pub async fn send_audio(&self, audio_data: &[u8]) -> Result<(), SipError> {
         let mut seq = self.sequence_number.lock().await;
         let mut ts = self.timestamp.lock().await;
 
+        let payload_type = *self.payload_type.lock().await;
+
         let packet = RtpPacket::new(
-            self.payload_type,
+            payload_type,
             *seq,
             *ts,
             self.ssrc,
             audio_data.to_vec(),
         );
 
-        let bytes = packet.to_bytes();
-        
-        self.socket
-            .send_to(&bytes, self.remote_addr)
-            .await
-            .map_err(|e| format!("Failed to send RTP packet: {}", e))?;
+        self.send_packet(&packet).await?;
 
         // Increment sequence number
         *seq = seq.wrapping_add(1);
         
-        // Increment timestamp (160 samples for 20ms at 8kHz)
-        *ts = ts.wrapping_add(160);
+        // Advance by one frame in RTP clock units (160 for 20ms at 8kHz)
+        let clock_rate = *self.clock_rate.lock().await;
+        *ts = ts.wrapping_add(timestamp_increment(clock_rate, FRAME_DURATION_MS));
+
+        Ok(())
+    }
+
+    /// Send a DTMF digit as an RFC 4733 telephone-event lasting `duration_ms`
+    ///
+    /// Event packets go out every 20ms with the same timestamp and a growing
+    /// duration; the final (end) packet is sent three times as the RFC
+    /// recommends. Fails if the peer didn't negotiate telephone-event.
+    pub async fn send_telephone_event(&self, event: u8, duration_ms: u32) -> Result<(), SipError> {
+        let event_pt = self.event_payload_type.lock().await
+            .ok_or_else(|| SipError::Protocol("Remote party does not support RFC 4733 DTMF".to_string()))?;
+
+        // Telephone-event uses an 8kHz clock in practice; durations are in those units
+        let step = timestamp_increment(8000, FRAME_DURATION_MS);
+        let total = timestamp_increment(8000, duration_ms).max(step);
+        let timestamp = *self.timestamp.lock().await;
+
+        let mut elapsed = step;
+        let mut first = true;
+        loop {
+            let end = elapsed >= total;
+            let duration = elapsed.min(total).min(u16::MAX as u32) as u16;
+            let payload = telephone_event_payload(event, end, duration);
+
+            for _ in 0..if end { 3 } else { 1 } {
+                let mut seq = self.sequence_number.lock().await;
+                let mut packet = RtpPacket::new(event_pt, *seq, timestamp, self.ssrc, payload.to_vec());
+                packet.marker = first;
+                self.send_packet(&packet).await?;
+                *seq = seq.wrapping_add(1);
+                first = false;
+            }
+
+            if end {
+                return Ok(());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(FRAME_DURATION_MS as u64)).await;
+            elapsed += step;
+        }
+    }
 
+    // Protect (when SRTP is on) and send one packet to the current remote address
+    async fn send_packet(&self, packet: &RtpPacket) -> Result<(), SipError> {
+        let mut remote_addr = self.remote_addr().await;
+        let mut bytes = packet.to_bytes();
+        if let Some(srtp) = self.srtp_tx.lock().await.as_mut() {
+            bytes = srtp.protect(&bytes)?;
+        }
+
+        // Relayed media goes to the TURN server, framed for the peer's channel
+        if let Some(relay) = &self.relay {
+            bytes = relay.wrap(&bytes)
+                .ok_or_else(|| SipError::Network("No TURN channel bound for the peer".to_string()))?;
+            remote_addr = relay.server();
+        }
+
+        self.socket
+            .send_to(&bytes, remote_addr)
+            .await
+            .map_err(|e| SipError::Network(format!("Failed to send RTP packet: {}", e)))?;
         Ok(())
     }
 
-    /// Receive RTP packet
-    pub async fn receive_audio(&self) -> Result<Vec<u8>, String> {
+    /// Receive the next audio payload or completed DTMF digit. Telephone-event
+    /// packets never come back as audio.
+    pub async fn receive_media(&self) -> Result<RxPayload, SipError> {
         let mut buf = vec![0u8; 2048];
         
-        let (size, _) = self.socket
-            .recv_from(&mut buf)
-            .await
-            .map_err(|e| format!("Failed to receive RTP packet: {}", e))?;
+        loop {
+            let (size, source) = self.socket
+                .recv_from(&mut buf)
+                .await
+                .map_err(|e| SipError::Network(format!("Failed to receive RTP packet: {}", e)))?;
+
+            // Media from the TURN server is unwrapped; its own responses stop there
+            let (mut data, source) = match &self.relay {
+                Some(relay) if source == relay.server() => match relay.unwrap(&buf[..size]) {
+                    Some(relayed) => relayed,
+                    None => continue,
+                },
+                _ => (buf[..size].to_vec(), source),
+            };
+
+            // Late STUN responses are not media
+            if stun::is_stun(&data) {
+                continue;
+            }
 
-        buf.truncate(size);
+            if let Some(srtp) = self.srtp_rx.lock().await.as_mut() {
+                data = match srtp.unprotect(&data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        // Drop forged/corrupt packets instead of ending the stream
+                        tracing::warn!("[SRTP] Dropping packet: {}", e);
+                        continue;
+                    }
+                };
+            }
 
-        let packet = RtpPacket::from_bytes(&buf)?;
-        
-        Ok(packet.payload)
+            let packet = match RtpPacket::from_bytes(&data) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    // One malformed packet shouldn't end the stream
+                    tracing::warn!("[RTP] Dropping packet from {}: {}", source, e);
+                    continue;
+                }
+            };
+
+            match self.rx_source.lock().await.check(packet.ssrc, packet.sequence_number) {
+                SourceCheck::Accept => self.learn_remote_addr(source, false).await,
+                SourceCheck::Switched { from } => {
+                    tracing::info!("[RTP] Remote source switched from SSRC {:08x} to {:08x}", from, packet.ssrc);
+                    println!("[RTP] Remote source switched from SSRC {:08x} to {:08x}", from, packet.ssrc);
+                    // The new source has its own timestamp base
+                    *self.rx_jitter.lock().await = JitterEstimator::default();
+                    self.learn_remote_addr(source, true).await;
+                }
+                SourceCheck::Drop => continue,
+            }
+
+            // Telephone-events repeat their start timestamp, so they stay out
+            // of the jitter estimate too
+            if Some(packet.payload_type) == *self.event_payload_type.lock().await {
+                match self.rx_dtmf.lock().await.packet(packet.timestamp, &packet.payload) {
+                    Some(event) => return Ok(RxPayload::Dtmf(event)),
+                    None => continue,
+                }
+            }
+
+            // Arrival time in RTP clock units, as RFC 3550 compares it to the timestamp
+            let clock_rate = self.clock_rate().await;
+            let arrival = (self.started.elapsed().as_micros() * clock_rate as u128 / 1_000_000) as u32;
+            self.rx_jitter.lock().await.update(packet.timestamp, arrival);
+
+            return Ok(RxPayload::Audio { payload_type: packet.payload_type, payload: packet.payload });
+        }
+    }
+
+    // Send to where the peer's packets come from. The first valid packet
+    // sets the address; only a new source (SSRC switch) may move it later.
+    async fn learn_remote_addr(&self, source: std::net::SocketAddr, switched: bool) {
+        // Relayed media always reaches the peer through its channel
+        if self.relay.is_some() {
+            return;
+        }
+
+        let mut learned = self.learned_addr.lock().await;
+        if (learned.is_some() && !switched) || *learned == Some(source) {
+            return;
+        }
+
+        let declared = *self.remote_addr.lock().await;
+        if source != declared {
+            tracing::info!("[RTP] Peer sends from {} (SDP said {}), sending there", source, declared);
+            println!("[RTP] Peer sends from {} (SDP said {}), sending there", source, declared);
+        }
+        *learned = Some(source);
+    }
+
+    /// Address outgoing RTP goes to: the learned source once the peer has
+    /// sent a valid packet, the SDP address until then
+    pub async fn remote_addr(&self) -> std::net::SocketAddr {
+        match *self.learned_addr.lock().await {
+            Some(learned) => learned,
+            None => *self.remote_addr.lock().await,
+        }
+    }
+
+    /// Encrypt outgoing and decrypt incoming packets (SRTP)
+    pub async fn set_srtp(&self, tx: SrtpContext, rx: SrtpContext) {
+        *self.srtp_tx.lock().await = Some(tx);
+        *self.srtp_rx.lock().await = Some(rx);
+        println!("[SRTP] Media encryption enabled");
+    }
+
+    /// Switch incoming packets to new peer keys (sent in a re-INVITE);
+    /// unchanged keys keep the current rollover state
+    pub async fn update_srtp_rx(&self, keys: &SrtpKeys) {
+        let mut rx = self.srtp_rx.lock().await;
+        if rx.as_ref().map(|context| context.keys() != keys).unwrap_or(true) {
+            println!("[SRTP] Peer changed its media key");
+            *rx = Some(SrtpContext::new(keys));
+        }
+    }
+
+    /// Whether media is encrypted with SRTP
+    pub async fn is_secure(&self) -> bool {
+        self.srtp_tx.lock().await.is_some()
+    }
+
+    /// Point the session at a new remote address and/or codec
+    /// (used when the peer renegotiates media with a re-INVITE)
+    pub async fn retarget(&self, remote_addr: std::net::SocketAddr, payload_type: u8, clock_rate: u32) {
+        let mut current_addr = self.remote_addr.lock().await;
+        let mut current_pt = self.payload_type.lock().await;
+
+        if *current_addr != remote_addr || *current_pt != payload_type {
+            println!("[RTP] Retargeting {} (PT {}) → {} (PT {})", *current_addr, *current_pt, remote_addr, payload_type);
+        }
+
+        // A new SDP address means the peer moved; learn its source again
+        if *current_addr != remote_addr {
+            *self.learned_addr.lock().await = None;
+            self.bind_relay_channel(remote_addr).await;
+        }
+
+        *current_addr = remote_addr;
+        *current_pt = payload_type;
+        *self.clock_rate.lock().await = clock_rate;
+    }
+
+    /// Address advertised in our SDP, if not the local IP and port
+    pub fn advertised_addr(&self) -> Option<std::net::SocketAddr> {
+        self.advertised_addr
+    }
+
+    // Point the relay at a new peer address (no-op without a relay)
+    async fn bind_relay_channel(&self, peer: std::net::SocketAddr) {
+        let Some(relay) = &self.relay else { return };
+        let sent = match relay.channel_bind_request(peer) {
+            Ok(request) => self.socket.send_to(&request, relay.server()).await.map(|_| ()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = sent {
+            tracing::warn!("[TURN] Failed to bind channel to {}: {}", peer, e);
+        }
+    }
+
+    /// Keep the TURN allocation and channel alive; call periodically
+    pub async fn maintain_relay(&self) {
+        let Some(relay) = &self.relay else { return };
+        match relay.due_refreshes() {
+            Ok(requests) => {
+                for request in requests {
+                    if let Err(e) = self.socket.send_to(&request, relay.server()).await {
+                        tracing::warn!("[TURN] Failed to refresh relay: {}", e);
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("[TURN] Failed to build relay refresh: {}", e),
+        }
+    }
+
+    /// Give the TURN allocation back at the end of the call
+    pub async fn release_relay(&self) {
+        let Some(relay) = &self.relay else { return };
+        if let Ok(request) = relay.refresh_request(0) {
+            let _ = self.socket.send_to(&request, relay.server()).await;
+            println!("[TURN] Released relay {}", relay.relayed_addr());
+        }
+    }
+
+    /// Current payload type (0 = PCMU, 8 = PCMA)
+    pub async fn payload_type(&self) -> u8 {
+        *self.payload_type.lock().await
+    }
+
+    /// RTP clock rate of the current payload type
+    pub async fn clock_rate(&self) -> u32 {
+        *self.clock_rate.lock().await
+    }
+
+    /// Record the bandwidth limit agreed for the call
+    pub async fn set_bandwidth_kbps(&self, kbps: Option<u32>) {
+        *self.bandwidth_kbps.lock().await = kbps;
+    }
+
+    /// Bandwidth limit agreed for the call (kbit/s), if any
+    pub async fn bandwidth_kbps(&self) -> Option<u32> {
+        *self.bandwidth_kbps.lock().await
+    }
+
+    /// Set the negotiated media direction (hold and resume change it)
+    pub async fn set_direction(&self, direction: MediaDirection) {
+        *self.direction.lock().await = direction;
+    }
+
+    /// Negotiated media direction: whether we send and expect to receive
+    pub async fn direction(&self) -> MediaDirection {
+        *self.direction.lock().await
+    }
+
+    /// Interarrival jitter of incoming packets in milliseconds
+    pub async fn jitter_ms(&self) -> f64 {
+        let clock_rate = self.clock_rate().await;
+        if clock_rate == 0 {
+            return 0.0;
+        }
+        self.rx_jitter.lock().await.jitter() * 1000.0 / clock_rate as f64
+    }
+
+    /// The peer's telephone-event payload type, if it negotiated one
+    pub async fn event_payload_type(&self) -> Option<u8> {
+        *self.event_payload_type.lock().await
+    }
+
+    /// Set the peer's telephone-event payload type (None if it has none)
+    pub async fn set_event_payload_type(&self, payload_type: Option<u8>) {
+        *self.event_payload_type.lock().await = payload_type;
     }
 
     /// Get local port
@@ -313,42 +701,829 @@ impl RtpSession {
     }
 }
 
-/// Parse SDP to extract remote RTP address and port
-pub fn parse_sdp(sdp: &str) -> Result<(String, u16, u8), String> {
-    let mut remote_ip: Option<String> = None;
-    let mut remote_port: Option<u16> = None;
-    let mut payload_type: u8 = 0; // Default to PCMU
+/// DSCP Expedited Forwarding, for voice media (RFC 4594)
+pub const DSCP_EF: u8 = 46;
+
+/// DSCP Class Selector 3, for call signaling (RFC 4594)
+pub const DSCP_CS3: u8 = 24;
+
+/// Mark everything sent on `socket` with a DSCP code point. QoS is best
+/// effort: where the OS refuses (no privilege, IPv6, unsupported platform)
+/// the failure is logged and traffic goes out unmarked.
+pub fn set_dscp(socket: &UdpSocket, dscp: u8) -> bool {
+    // DSCP is the top six bits of the IPv4 TOS byte
+    match socket2::SockRef::from(socket).set_tos_v4(u32::from(dscp) << 2) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("[QoS] Could not set DSCP {}: {}", dscp, e);
+            println!("[QoS] ⚠ Could not set DSCP {}, sending unmarked: {}", dscp, e);
+            false
+        }
+    }
+}
+
+/// Consecutive packets from a new SSRC before we accept it as a source
+/// switch (e.g. the PBX moving from ringback to the answering phone) rather
+/// than stray or injected packets
+pub const SSRC_SWITCH_PACKETS: u32 = 3;
+
+/// Largest forward gap in sequence numbers still taken as loss rather than
+/// a restart of the sender's numbering (RFC 3550 A.1)
+const MAX_DROPOUT: u16 = 3000;
+/// How far behind the highest sequence number a packet counts as merely
+/// reordered rather than a restart (RFC 3550 A.1)
+const MAX_MISORDER: u16 = 100;
+
+/// What to do with an incoming packet, given its SSRC and sequence number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceCheck {
+    /// From the current source and newer than anything played
+    Accept,
+    /// A new source took over; sequence tracking restarted with this packet
+    Switched { from: u32 },
+    /// Duplicate, late, after a sequence jump that isn't confirmed yet, or
+    /// from a source we haven't switched to
+    Drop,
+}
 
-    for line in sdp.lines() {
-        let line = line.trim();
-        
-        // Connection line: c=IN IP4 <address>
-        if line.starts_with("c=") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 {
-                remote_ip = Some(parts[2].to_string());
+/// Locks incoming media to one SSRC and tracks its sequence numbers
+#[derive(Debug, Default)]
+pub struct SourceTracker {
+    ssrc: Option<u32>,
+    highest_seq: u16,
+    // After a big jump: the sequence number that would confirm the sender
+    // restarted its numbering
+    bad_seq: Option<u16>,
+    // Another SSRC we've been seeing, and how many packets in a row
+    candidate: Option<(u32, u32)>,
+}
+
+impl SourceTracker {
+    pub fn check(&mut self, ssrc: u32, seq: u16) -> SourceCheck {
+        let current = match self.ssrc {
+            Some(current) => current,
+            None => {
+                // First packet of the call
+                self.ssrc = Some(ssrc);
+                self.highest_seq = seq;
+                return SourceCheck::Accept;
             }
+        };
+
+        if ssrc == current {
+            self.candidate = None;
+            return self.check_sequence(seq);
         }
-        
-        // Media line: m=audio <port> RTP/AVP <payload_types>
-        if line.starts_with("m=audio") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 4 {
-                remote_port = parts[1].parse().ok();
-                // Get first payload type
-                if let Some(pt) = parts.get(3) {
-                    payload_type = pt.parse().unwrap_or(0);
-                }
+
+        let count = match self.candidate {
+            Some((candidate, count)) if candidate == ssrc => count + 1,
+            _ => 1,
+        };
+
+        if count >= SSRC_SWITCH_PACKETS {
+            self.ssrc = Some(ssrc);
+            self.highest_seq = seq;
+            self.bad_seq = None;
+            self.candidate = None;
+            SourceCheck::Switched { from: current }
+        } else {
+            self.candidate = Some((ssrc, count));
+            SourceCheck::Drop
+        }
+    }
+
+    // Sequence number validation for the current source (RFC 3550 A.1): play
+    // packets newer than the last one, and resync after two in a row
+    // following a jump too big to be loss (the sender restarted)
+    fn check_sequence(&mut self, seq: u16) -> SourceCheck {
+        let delta = seq.wrapping_sub(self.highest_seq);
+        if delta == 0 {
+            return SourceCheck::Drop;
+        }
+        if delta < MAX_DROPOUT {
+            self.highest_seq = seq;
+            self.bad_seq = None;
+            return SourceCheck::Accept;
+        }
+        if delta > MAX_MISORDER.wrapping_neg() {
+            // Duplicate or reordered: already past it
+            return SourceCheck::Drop;
+        }
+
+        if self.bad_seq == Some(seq) {
+            self.highest_seq = seq;
+            self.bad_seq = None;
+            SourceCheck::Accept
+        } else {
+            self.bad_seq = Some(seq.wrapping_add(1));
+            SourceCheck::Drop
+        }
+    }
+}
+
+/// Interarrival jitter estimate (RFC 3550 section 6.4.1)
+#[derive(Debug, Default)]
+pub struct JitterEstimator {
+    // Transit time (arrival - RTP timestamp) of the previous packet
+    last_transit: Option<u32>,
+    // Smoothed jitter in RTP clock units
+    jitter: f64,
+}
+
+impl JitterEstimator {
+    /// Add a packet's RTP timestamp and arrival time (both in clock units)
+    pub fn update(&mut self, rtp_timestamp: u32, arrival: u32) {
+        let transit = arrival.wrapping_sub(rtp_timestamp);
+        if let Some(last) = self.last_transit {
+            let delta = (transit.wrapping_sub(last) as i32).unsigned_abs() as f64;
+            self.jitter += (delta - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    /// Current jitter in RTP clock units
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+}
+
+/// Silence on the receive side before we tell the UI audio has stopped
+pub const RX_SILENCE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Nothing received for this long while we keep sending counts as one-way audio
+pub const ONE_WAY_AUDIO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Shortest RTP timeout accepted, so a gap that only trips the silence and
+/// one-way warnings never ends a call
+pub const MIN_RTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// When one-way audio was noticed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OneWayAudio {
+    /// No packet has arrived since the call was set up (usually NAT)
+    Startup,
+    /// Packets were arriving and then stopped
+    MidCall,
+}
+
+/// Change in whether incoming audio is flowing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxActivityChange {
+    /// Nothing received for longer than `RX_SILENCE_TIMEOUT`
+    Stopped { silent: std::time::Duration },
+    /// Packets arrived again after a silence
+    Resumed,
+}
+
+/// Tracks when the last packet arrived so the RX task can spot one-way audio
+#[derive(Debug)]
+pub struct RxActivity {
+    // Last packet, or when the session started if nothing arrived yet
+    last_packet: std::time::Instant,
+    silent: bool,
+    received_any: bool,
+    one_way_reported: bool,
+}
+
+impl RxActivity {
+    pub fn new(now: std::time::Instant) -> Self {
+        Self { last_packet: now, silent: false, received_any: false, one_way_reported: false }
+    }
+
+    /// Record a received packet
+    pub fn packet(&mut self, now: std::time::Instant) -> Option<RxActivityChange> {
+        self.last_packet = now;
+        self.received_any = true;
+        self.one_way_reported = false;
+        if std::mem::take(&mut self.silent) {
+            Some(RxActivityChange::Resumed)
+        } else {
+            None
+        }
+    }
+
+    /// Check for silence; reports it once per gap
+    pub fn check(&mut self, now: std::time::Instant) -> Option<RxActivityChange> {
+        let silent = now.saturating_duration_since(self.last_packet);
+        if self.silent || silent <= RX_SILENCE_TIMEOUT {
+            return None;
+        }
+        self.silent = true;
+        Some(RxActivityChange::Stopped { silent })
+    }
+
+    /// Check for one-way audio: nothing received for `ONE_WAY_AUDIO_TIMEOUT`
+    /// while `sending`. Reported once per gap.
+    pub fn check_one_way(&mut self, now: std::time::Instant, sending: bool) -> Option<OneWayAudio> {
+        let silent = now.saturating_duration_since(self.last_packet);
+        if !sending || self.one_way_reported || silent <= ONE_WAY_AUDIO_TIMEOUT {
+            return None;
+        }
+        self.one_way_reported = true;
+        Some(if self.received_any { OneWayAudio::MidCall } else { OneWayAudio::Startup })
+    }
+
+    /// Whether nothing has arrived for `timeout` (at least `MIN_RTP_TIMEOUT`),
+    /// i.e. the remote side is gone and the call should be ended
+    pub fn is_dead(&self, now: std::time::Instant, timeout: std::time::Duration) -> bool {
+        now.saturating_duration_since(self.last_packet) > timeout.max(MIN_RTP_TIMEOUT)
+    }
+
+    /// Whether packets are currently arriving
+    pub fn is_flowing(&self) -> bool {
+        !self.silent
+    }
+}
+
+/// Duration of the audio carried in each packet we send
+pub const FRAME_DURATION_MS: u32 = 20;
+
+/// RTP timestamp advance for one frame at the given clock rate
+/// (160 for 20ms at 8kHz, 960 for 20ms of Opus at 48kHz)
+pub fn timestamp_increment(clock_rate: u32, frame_ms: u32) -> u32 {
+    clock_rate * frame_ms / 1000
+}
+
+/// Cuts captured audio into whole packets of `frame_len` samples
+///
+/// Audio devices deliver whatever block size they like (441 samples at
+/// 44.1kHz is common), but every packet we send advances the timestamp by
+/// one 20ms frame, so each must carry exactly that much audio. What doesn't
+/// fill a frame waits for the next block.
+#[derive(Debug, Default)]
+pub struct Framer {
+    pending: Vec<i16>,
+}
+
+impl Framer {
+    /// Add samples and take every frame that is now complete
+    pub fn push(&mut self, samples: &[i16], frame_len: usize) -> Vec<Vec<i16>> {
+        if frame_len == 0 {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(samples);
+        let whole = self.pending.len() / frame_len * frame_len;
+        let frames = self.pending.drain(..whole).collect::<Vec<_>>();
+        frames.chunks(frame_len).map(<[i16]>::to_vec).collect()
+    }
+
+    /// Drop leftover samples (when sending pauses)
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Clock rate of a static payload type (RFC 3551); every static audio
+/// type we might see is 8kHz except L16 (44.1kHz). G.722 is 8000 by
+/// definition even though it samples at 16kHz.
+fn static_clock_rate(payload_type: u8) -> u32 {
+    match payload_type {
+        10 | 11 => 44100,
+        _ => 8000,
+    }
+}
+
+/// Payload type mapped to telephone-event (RFC 4733) in the SDP, if any
+pub fn telephone_event_payload_type(sdp: &str) -> Option<u8> {
+    sdp.lines().map(|line| line.trim()).find_map(|line| {
+        let (pt, encoding) = line.strip_prefix("a=rtpmap:")?.split_once(' ')?;
+        if encoding.to_ascii_lowercase().starts_with("telephone-event/") {
+            pt.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// RFC 4733 event code for a DTMF key (0-9, *, #, A-D)
+pub fn dtmf_event_code(digit: char) -> Option<u8> {
+    match digit.to_ascii_uppercase() {
+        '0'..='9' => Some(digit as u8 - b'0'),
+        '*' => Some(10),
+        '#' => Some(11),
+        c @ 'A'..='D' => Some(12 + (c as u8 - b'A')),
+        _ => None,
+    }
+}
+
+/// DTMF key for an RFC 4733 event code
+pub fn dtmf_digit(event: u8) -> Option<char> {
+    match event {
+        0..=9 => Some((b'0' + event) as char),
+        10 => Some('*'),
+        11 => Some('#'),
+        12..=15 => Some((b'A' + event - 12) as char),
+        _ => None,
+    }
+}
+
+/// What `RtpSession::receive_media` got from the network
+#[derive(Debug, Clone, PartialEq)]
+pub enum RxPayload {
+    /// Encoded audio and the payload type it arrived with
+    Audio { payload_type: u8, payload: Vec<u8> },
+    /// A key the far end pressed (RFC 4733 telephone-event)
+    Dtmf(DtmfEvent),
+}
+
+/// A received DTMF key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DtmfEvent {
+    pub digit: char,
+    /// Length of the key press in RTP clock units
+    pub duration: u16,
+}
+
+/// Collapses the packets of each telephone-event into one key press
+///
+/// All packets of an event share its RTP timestamp. The digit is reported
+/// when the first end packet arrives; the end packet's retransmissions are
+/// ignored. If every end packet was lost, the event is reported when the
+/// next one starts.
+#[derive(Debug, Default)]
+pub struct DtmfDetector {
+    // Event in progress: timestamp, digit, latest duration
+    current: Option<(u32, char, u16)>,
+    // Timestamp of the last event already reported
+    reported: Option<u32>,
+}
+
+impl DtmfDetector {
+    /// Feed one telephone-event payload; returns a key once it is complete
+    pub fn packet(&mut self, timestamp: u32, payload: &[u8]) -> Option<DtmfEvent> {
+        if payload.len() < 4 || self.reported == Some(timestamp) {
+            return None;
+        }
+        let digit = dtmf_digit(payload[0])?;
+        let end = payload[1] & 0x80 != 0;
+        let duration = u16::from_be_bytes([payload[2], payload[3]]);
+
+        // A new event while the previous one never saw its end: report that
+        // one first. This packet is taken up again by the next one of its
+        // event (end packets are sent three times).
+        if let Some((previous, previous_digit, previous_duration)) = self.current {
+            if previous != timestamp {
+                self.reported = Some(previous);
+                self.current = if end { None } else { Some((timestamp, digit, duration)) };
+                return Some(DtmfEvent { digit: previous_digit, duration: previous_duration });
             }
         }
+
+        if end {
+            self.current = None;
+            self.reported = Some(timestamp);
+            return Some(DtmfEvent { digit, duration });
+        }
+        self.current = Some((timestamp, digit, duration));
+        None
     }
+}
 
-    let ip = remote_ip.ok_or("No connection address in SDP")?;
-    let port = remote_port.ok_or("No media port in SDP")?;
+// event (8) | E (1) R (1) volume (6) | duration (16); volume is -10 dBm0
+fn telephone_event_payload(event: u8, end: bool, duration: u16) -> [u8; 4] {
+    let volume = 10u8;
+    let duration = duration.to_be_bytes();
+    [event, if end { 0x80 | volume } else { volume }, duration[0], duration[1]]
+}
 
-    println!("[RTP] Parsed SDP: {}:{}, payload type: {}", ip, port, payload_type);
+/// Clock rate for a payload type from its `a=rtpmap:<pt> <name>/<rate>` line,
+/// falling back to the static payload type table
+pub fn rtpmap_clock_rate(sdp: &str, payload_type: u8) -> u32 {
+    let prefix = format!("a=rtpmap:{} ", payload_type);
+    sdp.lines()
+        .map(|line| line.trim())
+        .find_map(|line| {
+            let encoding = line.strip_prefix(&prefix)?;
+            encoding.split('/').nth(1)?.trim().parse().ok()
+        })
+        .unwrap_or_else(|| static_clock_rate(payload_type))
+}
 
-    Ok((ip, port, payload_type))
+/// Audio codecs (and the DTMF event format) we know by name
+///
+/// Payload types are the static ones where RFC 3551 assigns one, otherwise
+/// the dynamic number we put in our own offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Pcmu,
+    Pcma,
+    G722,
+    Opus,
+    TelephoneEvent,
+}
+
+impl Codec {
+    const ALL: [Codec; 5] = [Codec::Pcmu, Codec::Pcma, Codec::G722, Codec::Opus, Codec::TelephoneEvent];
+
+    pub fn payload_type(self) -> u8 {
+        match self {
+            Codec::Pcmu => 0,
+            Codec::Pcma => 8,
+            Codec::G722 => 9,
+            Codec::Opus => 111,
+            Codec::TelephoneEvent => 101,
+        }
+    }
+
+    /// RTP clock rate (G.722 keeps 8000 for historical reasons, RFC 3551 4.5.2)
+    pub fn clock_rate(self) -> u32 {
+        match self {
+            Codec::Opus => 48000,
+            _ => 8000,
+        }
+    }
+
+    /// Encoding name as written in `a=rtpmap`
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Pcmu => "PCMU",
+            Codec::Pcma => "PCMA",
+            Codec::G722 => "G722",
+            Codec::Opus => "opus",
+            Codec::TelephoneEvent => "telephone-event",
+        }
+    }
+
+    pub fn from_payload_type(payload_type: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.payload_type() == payload_type)
+    }
+
+    /// Codec by encoding name (case-insensitive)
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Whether the media path can encode and decode it
+    pub fn is_supported(self) -> bool {
+        SUPPORTED_CODECS.contains(&self)
+    }
+
+    /// Encode linear samples, None if the media path can't
+    pub fn encode(self, samples: &[i16]) -> Option<Vec<u8>> {
+        match self {
+            Codec::Pcmu => Some(samples.iter().map(|&s| g711::encode_ulaw(s)).collect()),
+            Codec::Pcma => Some(samples.iter().map(|&s| g711::encode_alaw(s)).collect()),
+            _ => None,
+        }
+    }
+
+    /// Decode a payload to linear samples, None if the media path can't
+    pub fn decode(self, payload: &[u8]) -> Option<Vec<i16>> {
+        match self {
+            Codec::Pcmu => Some(payload.iter().map(|&b| g711::decode_ulaw(b)).collect()),
+            Codec::Pcma => Some(payload.iter().map(|&b| g711::decode_alaw(b)).collect()),
+            _ => None,
+        }
+    }
+}
+
+/// Codecs the media path can encode and decode, in default preference order
+pub const SUPPORTED_CODECS: &[Codec] = &[Codec::Pcmu, Codec::Pcma];
+
+/// Payload type of a supported codec, by name (case-insensitive)
+pub fn codec_payload_type(name: &str) -> Option<u8> {
+    Codec::from_name(name).filter(|codec| codec.is_supported()).map(Codec::payload_type)
+}
+
+/// Packets in a row (one second of 20 ms frames) in another payload type
+/// than negotiated before the mismatch counts as persistent
+pub const PT_MISMATCH_PACKETS: u32 = 50;
+
+/// Picks the codec for each received audio packet
+///
+/// Some gateways negotiate PCMU and then send PCMA (or the other way round).
+/// A packet in another supported codec at the negotiated clock rate is
+/// decoded as what it says it is. Anything else (comfort noise, a codec we
+/// can't decode) is dropped rather than played as G.711 noise. A persistent
+/// mismatch is logged once per run.
+#[derive(Debug, Default)]
+pub struct RxCodec {
+    mismatched: u32,
+}
+
+impl RxCodec {
+    pub fn packet(&mut self, negotiated: u8, received: u8) -> Option<Codec> {
+        let negotiated_codec = Codec::from_payload_type(negotiated);
+        if received == negotiated {
+            self.mismatched = 0;
+            return negotiated_codec;
+        }
+
+        self.mismatched += 1;
+        if self.mismatched == PT_MISMATCH_PACKETS {
+            tracing::warn!("[RTP] Peer negotiated PT {} but keeps sending PT {}", negotiated, received);
+            println!("[RTP] Peer negotiated PT {} but keeps sending PT {}", negotiated, received);
+        }
+        Codec::from_payload_type(received)
+            .filter(|codec| codec.is_supported())
+            .filter(|codec| negotiated_codec.is_some_and(|n| n.clock_rate() == codec.clock_rate()))
+    }
+}
+
+/// Pick the codec to answer an offer with: our most preferred payload type
+/// that the offer contains
+pub fn choose_payload_type(offered: &[u8], preferences: &[u8]) -> Option<u8> {
+    preferences.iter().copied().find(|pt| offered.contains(pt))
+}
+
+/// List the payload types offered on the audio m= line, in preference order
+pub fn offered_payload_types(sdp: &str) -> Vec<u8> {
+    sdp.lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("m=audio"))
+        .map(|line| {
+            line.split_whitespace()
+                .skip(3)
+                .filter_map(|pt| pt.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parse SDP to extract remote RTP address, port, payload type and its clock rate
+///
+/// Only the first active `m=audio` section is used; video and other media
+/// are skipped. A media-level `c=` overrides the session-level one, and if
+/// the section carries ICE candidates the best reachable one (reflexive,
+/// then relay, then host) is used instead.
+pub fn parse_sdp(sdp: &str) -> Result<(String, u16, u8, u32), SipError> {
+    let sections = media_sections(sdp_part(sdp));
+    let session_ip = connection_address(&sections[0]);
+
+    let audio = audio_section(&sections).ok_or_else(|| SipError::Protocol("No media port in SDP".to_string()))?;
+
+    let port = media_port(audio).ok_or_else(|| SipError::Protocol("No media port in SDP".to_string()))?;
+    // audio_section only falls back to a rejected stream when all of them are
+    if port == 0 {
+        return Err(SipError::Protocol("Every audio stream in the SDP is rejected (port 0)".to_string()));
+    }
+    let ip = connection_address(audio)
+        .or(session_ip)
+        .ok_or_else(|| SipError::Protocol("No connection address in SDP".to_string()))?;
+
+    let (ip, port) = best_ice_candidate(audio).unwrap_or((ip, port));
+
+    // Default to PCMU when the m= line lists no formats
+    let payload_type = audio[0]
+        .split_whitespace()
+        .nth(3)
+        .and_then(|pt| pt.parse().ok())
+        .unwrap_or(Codec::Pcmu.payload_type());
+
+    // rtpmap lines of other media sections may reuse the payload type number
+    let clock_rate = rtpmap_clock_rate(&audio.join("\r\n"), payload_type);
+
+    println!("[RTP] Parsed SDP: {}:{}, payload type: {} ({} Hz)", ip, port, payload_type, clock_rate);
+
+    Ok((ip, port, payload_type, clock_rate))
+}
+
+/// SDP media direction (RFC 3264 6.1)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaDirection {
+    #[default]
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl MediaDirection {
+    fn from_flags(sends: bool, receives: bool) -> Self {
+        match (sends, receives) {
+            (true, true) => Self::SendRecv,
+            (true, false) => Self::SendOnly,
+            (false, true) => Self::RecvOnly,
+            (false, false) => Self::Inactive,
+        }
+    }
+
+    /// The `a=` attribute value
+    pub fn attribute(self) -> &'static str {
+        match self {
+            Self::SendRecv => "sendrecv",
+            Self::SendOnly => "sendonly",
+            Self::RecvOnly => "recvonly",
+            Self::Inactive => "inactive",
+        }
+    }
+
+    pub fn sends(self) -> bool {
+        matches!(self, Self::SendRecv | Self::SendOnly)
+    }
+
+    pub fn receives(self) -> bool {
+        matches!(self, Self::SendRecv | Self::RecvOnly)
+    }
+
+    /// This direction put on hold (RFC 3264 8.4): sendonly if we were
+    /// sending, inactive otherwise
+    pub fn hold(self) -> Self {
+        Self::from_flags(self.sends(), false)
+    }
+
+    /// Our direction once the peer answered this offer with `answer`: we
+    /// only send what it receives and only receive what it sends
+    pub fn negotiate(self, answer: MediaDirection) -> Self {
+        Self::from_flags(self.sends() && answer.receives(), self.receives() && answer.sends())
+    }
+}
+
+/// Direction of the audio stream in an SDP body: the audio section's
+/// attribute, else the session-level one, else sendrecv
+pub fn parse_direction(sdp: &str) -> MediaDirection {
+    let sections = media_sections(sdp_part(sdp));
+    let direction = |lines: &[&str]| {
+        lines.iter().find_map(|line| match *line {
+            "a=sendrecv" => Some(MediaDirection::SendRecv),
+            "a=sendonly" => Some(MediaDirection::SendOnly),
+            "a=recvonly" => Some(MediaDirection::RecvOnly),
+            "a=inactive" => Some(MediaDirection::Inactive),
+            _ => None,
+        })
+    };
+    audio_section(&sections)
+        .and_then(|audio| direction(audio))
+        .or_else(|| direction(&sections[0]))
+        .unwrap_or_default()
+}
+
+/// Audio bandwidth limit (kbit/s) in an SDP body: the audio section's `b=`
+/// lines, else the session-level ones. `b=AS:` is in kbit/s, `b=TIAS:` in
+/// bit/s (RFC 3890); when both are given the tighter one wins.
+pub fn parse_bandwidth(sdp: &str) -> Option<u32> {
+    let sections = media_sections(sdp_part(sdp));
+    let bandwidth = |lines: &[&str]| {
+        lines
+            .iter()
+            .filter_map(|line| {
+                let (modifier, value) = line.strip_prefix("b=")?.split_once(':')?;
+                let value: u32 = value.trim().parse().ok()?;
+                match modifier {
+                    "AS" => Some(value),
+                    "TIAS" => Some(value.div_ceil(1000)),
+                    _ => None,
+                }
+            })
+            .min()
+    };
+    audio_section(&sections)
+        .and_then(|audio| bandwidth(audio))
+        .or_else(|| bandwidth(&sections[0]))
+}
+
+/// Bandwidth (kbit/s) a codec takes on the wire at 20 ms framing, counting
+/// the 40 bytes of IPv4/UDP/RTP headers on each of the 50 packets a second
+pub fn codec_bandwidth_kbps(payload_type: u8) -> u32 {
+    // Supported codecs are G.711: one byte per sample
+    let payload_kbps = static_clock_rate(payload_type) * 8 / 1000;
+    payload_kbps + 40 * 8 * (1000 / FRAME_DURATION_MS) / 1000
+}
+
+/// Codec preferences with the ones that fit in `limit_kbps` moved first
+/// (otherwise in the same order)
+pub fn prefer_within_bandwidth(preferences: &[u8], limit_kbps: Option<u32>) -> Vec<u8> {
+    let Some(limit) = limit_kbps else {
+        return preferences.to_vec();
+    };
+    let (fits, too_big): (Vec<u8>, Vec<u8>) =
+        preferences.iter().partition(|pt| codec_bandwidth_kbps(**pt) <= limit);
+    fits.into_iter().chain(too_big).collect()
+}
+
+/// Call-specific lines of an SDP offer beyond the codecs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OfferOptions<'a> {
+    /// Offer SRTP (RTP/SAVP) with these keys
+    pub srtp_keys: Option<&'a SrtpKeys>,
+    /// Advertise a `b=AS:` limit (kbit/s)
+    pub bandwidth_kbps: Option<u32>,
+}
+
+/// SDP offer for one audio stream: `codecs` in preference order on the
+/// m= line, each with its rtpmap, then ptime and the direction
+pub fn build_sdp_offer(
+    local_ip: &str,
+    rtp_port: u16,
+    codecs: &[Codec],
+    direction: MediaDirection,
+    ptime: u32,
+    options: OfferOptions,
+) -> String {
+    let session_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let address_type = if local_ip.contains(':') { "IP6" } else { "IP4" };
+    let (formats, rtpmaps) = sdp_formats(codecs);
+    let (profile, crypto) = match options.srtp_keys {
+        Some(keys) => ("RTP/SAVP", format!("{}\r\n", keys.crypto_attribute(1))),
+        None => ("RTP/AVP", String::new()),
+    };
+    let bandwidth = options.bandwidth_kbps.map(|kbps| format!("b=AS:{}\r\n", kbps)).unwrap_or_default();
+
+    format!(
+        "v=0\r\n\
+         o=- {session_id} {session_id} IN {address_type} {local_ip}\r\n\
+         s=Platypus Phone Call\r\n\
+         c=IN {address_type} {local_ip}\r\n\
+         t=0 0\r\n\
+         m=audio {rtp_port} {profile} {formats}\r\n\
+         {bandwidth}\
+         {rtpmaps}\
+         {crypto}\
+         a=ptime:{ptime}\r\n\
+         a={}\r\n",
+        direction.attribute()
+    )
+}
+
+/// `m=audio` payload list and the matching rtpmap lines for some codecs
+pub fn sdp_formats(codecs: &[Codec]) -> (String, String) {
+    let formats: Vec<String> = codecs.iter().map(|codec| codec.payload_type().to_string()).collect();
+    let rtpmaps = codecs
+        .iter()
+        .map(|codec| format!("a=rtpmap:{} {}/{}\r\n", codec.payload_type(), codec.name(), codec.clock_rate()))
+        .collect();
+    (formats.join(" "), rtpmaps)
+}
+
+// The audio section media goes to. A port of 0 marks a rejected stream;
+// use it only if nothing else is there.
+fn audio_section<'a>(sections: &'a [Vec<&'a str>]) -> Option<&'a Vec<&'a str>> {
+    let mut audio_sections = sections[1..].iter().filter(|section| section[0].starts_with("m=audio "));
+    let first = audio_sections.clone().next();
+    audio_sections.find(|section| media_port(section) != Some(0)).or(first)
+}
+
+/// The SDP in a message body, which may be one part of a multipart body
+/// (e.g. SDP next to an ISUP or ICE-related part)
+pub fn sdp_part(body: &str) -> &str {
+    if body.trim_start().starts_with("v=") {
+        return body;
+    }
+
+    // The SDP part starts at its v= line and ends at the next boundary
+    let Some(start) = body.find("\nv=0").map(|index| index + 1) else {
+        return body;
+    };
+    let sdp = &body[start..];
+    match sdp.find("\n--") {
+        Some(end) => &sdp[..end + 1],
+        None => sdp,
+    }
+}
+
+// Split SDP into the session-level lines (always the first entry) and one
+// entry per media section, each starting with its m= line
+fn media_sections(sdp: &str) -> Vec<Vec<&str>> {
+    let mut sections = vec![Vec::new()];
+    for line in sdp.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        if line.starts_with("m=") {
+            sections.push(Vec::new());
+        }
+        sections.last_mut().unwrap().push(line);
+    }
+    sections
+}
+
+// Address of the first c= line in a block of SDP lines
+fn connection_address(lines: &[&str]) -> Option<String> {
+    lines
+        .iter()
+        .find_map(|line| line.strip_prefix("c="))
+        .and_then(|line| line.split_whitespace().nth(2))
+        .map(|ip| ip.to_string())
+}
+
+// Port of a media section ("m=audio <port>[/<count>] <proto> <formats>")
+fn media_port(section: &[&str]) -> Option<u16> {
+    section.first()?.split_whitespace().nth(1)?.split('/').next()?.parse().ok()
+}
+
+// Address of the preferred RTP (component 1) UDP ICE candidate:
+// a=candidate:<foundation> <component> <transport> <priority> <address> <port> typ <type> ...
+fn best_ice_candidate(section: &[&str]) -> Option<(String, u16)> {
+    section
+        .iter()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.strip_prefix("a=candidate:")?.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "1" || !fields[2].eq_ignore_ascii_case("udp") || fields[6] != "typ" {
+                return None;
+            }
+            let rank = match fields[7] {
+                "srflx" | "prflx" => 2,
+                "relay" => 1,
+                "host" => 0,
+                _ => return None,
+            };
+            let priority: u32 = fields[3].parse().ok()?;
+            let port: u16 = fields[5].parse().ok()?;
+            Some(((rank, priority), (fields[4].to_string(), port)))
+        })
+        .max_by_key(|(key, _)| *key)
+        .map(|(_, address)| address)
 }
 
 // Helper function to generate random numbers (simple implementation)
@@ -380,6 +1555,21 @@ mod rand {
 mod tests {
     use super::*;
 
+    const LOCALHOST: std::net::IpAddr = std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_set_dscp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(set_dscp(&socket, DSCP_EF));
+        assert_eq!(socket2::SockRef::from(&socket).tos_v4().unwrap(), u32::from(DSCP_EF) << 2);
+    }
+
+    #[tokio::test]
+    async fn test_session_binds_to_local_ip() {
+        let session = RtpSession::new(LOCALHOST, 0, "127.0.0.1:9".parse().unwrap(), 0, 8000).await.unwrap();
+        assert_eq!(session.socket().local_addr().unwrap().ip(), LOCALHOST);
+    }
+
     #[test]
     fn test_rtp_packet_serialization() {
         let packet = RtpPacket::new(0, 1234, 5678, 9012, vec![1, 2, 3, 4]);
@@ -394,20 +1584,139 @@ mod tests {
         assert_eq!(parsed.payload, vec![1, 2, 3, 4]);
     }
 
+    #[test]
+    fn test_rtp_packet_with_extension() {
+        let mut bytes = RtpPacket::new(0, 1, 160, 42, vec![0xAA; 4]).to_bytes();
+        bytes[0] |= 0x10;
+        // Profile 0xBEDE (RFC 8285 one-byte headers), two words of data
+        let extension = [0xBE, 0xDE, 0x00, 0x02, 0x10, 0xFF, 0x00, 0x00, 0x21, 0x01, 0x02, 0x00];
+        bytes.splice(12..12, extension);
+
+        let parsed = RtpPacket::from_bytes(&bytes).unwrap();
+        assert!(parsed.extension);
+        assert_eq!(parsed.payload, vec![0xAA; 4]);
+
+        // Extension length pointing past the end of the packet
+        bytes[15] = 0x09;
+        assert!(RtpPacket::from_bytes(&bytes).is_err());
+        assert!(RtpPacket::from_bytes(&bytes[..14]).is_err());
+    }
+
+    #[test]
+    fn test_rtp_packet_with_padding() {
+        let mut bytes = RtpPacket::new(0, 1, 160, 42, vec![0xAA; 4]).to_bytes();
+        bytes[0] |= 0x20;
+        bytes.extend_from_slice(&[0, 0, 3]);
+
+        let parsed = RtpPacket::from_bytes(&bytes).unwrap();
+        assert!(parsed.padding);
+        assert_eq!(parsed.payload, vec![0xAA; 4]);
+
+        // Padding that would eat into the header, and a zero pad count
+        *bytes.last_mut().unwrap() = 8;
+        assert!(RtpPacket::from_bytes(&bytes).is_err());
+        *bytes.last_mut().unwrap() = 0;
+        assert!(RtpPacket::from_bytes(&bytes).is_err());
+
+        // All padding, no payload
+        let mut empty = RtpPacket::new(0, 1, 160, 42, vec![]).to_bytes();
+        empty[0] |= 0x20;
+        empty.extend_from_slice(&[0, 2]);
+        assert!(RtpPacket::from_bytes(&empty).unwrap().payload.is_empty());
+    }
+
+    #[test]
+    fn test_rtp_packet_rejects_malformed() {
+        let bytes = RtpPacket::new(0, 1, 160, 42, vec![0xAA; 4]).to_bytes();
+        assert!(RtpPacket::from_bytes(&bytes[..11]).is_err());
+
+        let mut version_1 = bytes.clone();
+        version_1[0] = (version_1[0] & 0x3F) | 0x40;
+        assert!(RtpPacket::from_bytes(&version_1).is_err());
+
+        // Claims three CSRCs but carries none
+        let mut csrcs = bytes[..14].to_vec();
+        csrcs[0] |= 0x03;
+        assert!(RtpPacket::from_bytes(&csrcs).is_err());
+    }
+
     #[test]
     fn test_g711_ulaw_codec() {
-        let samples = vec![0i16, 100, -100, 1000, -1000, 10000, -10000];
+        let samples = vec![0i16, 1, -1, 50, -50, 100, -100, 200, -200, 1000, -1000, 10000, -10000, i16::MAX, i16::MIN];
         
         for sample in samples {
             let encoded = g711::encode_ulaw(sample);
             let decoded = g711::decode_ulaw(encoded);
             
-            // G.711 is lossy, so we check if it's close enough
-            let diff = (sample - decoded).abs();
-            assert!(diff < 100, "Sample {} decoded to {} (diff: {})", sample, decoded, diff);
+            // G.711 is lossy: the error is bounded by half a quantization
+            // step, which is 4 near zero and grows with the segment
+            let diff = (sample as i32 - decoded as i32).abs();
+            let tolerance = (sample as i32).abs() / 32 + 8;
+            assert!(diff <= tolerance, "Sample {} decoded to {} (diff: {})", sample, decoded, diff);
         }
     }
 
+    #[test]
+    fn test_g711_ulaw_reference_codewords() {
+        // Values from the ITU-T G.711 reference implementation
+        assert_eq!(g711::encode_ulaw(0), 0xFF);
+        assert_eq!(g711::encode_ulaw(-1), 0x7F);
+        assert_eq!(g711::encode_ulaw(100), 0xF2);
+        assert_eq!(g711::encode_ulaw(-100), 0x72);
+        assert_eq!(g711::encode_ulaw(1000), 0xCE);
+        assert_eq!(g711::encode_ulaw(i16::MAX), 0x80);
+        assert_eq!(g711::encode_ulaw(i16::MIN), 0x00);
+
+        assert_eq!(g711::decode_ulaw(0xFF), 0);
+        assert_eq!(g711::decode_ulaw(0xF2), 104);
+        assert_eq!(g711::decode_ulaw(0xCE), 988);
+        assert_eq!(g711::decode_ulaw(0x80), 32124);
+        assert_eq!(g711::decode_ulaw(0x00), -32124);
+    }
+
+    #[test]
+    fn test_g711_alaw_codec() {
+        let samples = vec![0i16, 1, -1, 100, -100, 1000, -1000, 10000, -10000, i16::MAX, i16::MIN];
+
+        for sample in samples {
+            let encoded = g711::encode_alaw(sample);
+            let decoded = g711::decode_alaw(encoded);
+
+            // A-law error is bounded by half a quantization step, which
+            // grows with the segment (1/32 of the magnitude plus the
+            // 16-unit step of the two lowest segments)
+            let diff = (sample as i32 - decoded as i32).abs();
+            let tolerance = (sample as i32).abs() / 32 + 16;
+            assert!(diff <= tolerance, "Sample {} decoded to {} (diff: {})", sample, decoded, diff);
+
+            // Sign must survive the round trip
+            if (sample as i32).abs() > 16 {
+                assert_eq!(sample.signum(), decoded.signum(), "Sample {} changed sign", sample);
+            }
+        }
+    }
+
+    #[test]
+    fn test_g711_alaw_reference_codewords() {
+        // Values from the ITU-T G.711 reference implementation
+        assert_eq!(g711::encode_alaw(0), 0xD5);
+        assert_eq!(g711::encode_alaw(-1), 0x55);
+        assert_eq!(g711::encode_alaw(i16::MAX), 0xAA);
+        assert_eq!(g711::encode_alaw(i16::MIN), 0x2A);
+
+        assert_eq!(g711::decode_alaw(0xD5), 8);
+        assert_eq!(g711::decode_alaw(0x55), -8);
+        assert_eq!(g711::decode_alaw(0xAA), 32256);
+        assert_eq!(g711::decode_alaw(0x2A), -32256);
+    }
+
+    #[test]
+    fn test_offered_payload_types() {
+        let sdp = "v=0\r\nc=IN IP4 10.0.0.1\r\nm=audio 4000 RTP/AVP 9 8 0 101\r\n";
+        assert_eq!(offered_payload_types(sdp), vec![9, 8, 0, 101]);
+        assert!(offered_payload_types("v=0\r\n").is_empty());
+    }
+
     #[test]
     fn test_sdp_parsing() {
         let sdp = "v=0\r\n\
@@ -417,9 +1726,593 @@ mod tests {
                    t=0 0\r\n\
                    m=audio 12345 RTP/AVP 0 8 101\r\n";
 
-        let (ip, port, pt) = parse_sdp(sdp).unwrap();
+        let (ip, port, pt, clock_rate) = parse_sdp(sdp).unwrap();
         assert_eq!(ip, "192.168.1.100");
         assert_eq!(port, 12345);
         assert_eq!(pt, 0);
+        assert_eq!(clock_rate, 8000);
+    }
+
+    #[test]
+    fn test_media_direction() {
+        let sdp = "v=0\r\nc=IN IP4 10.0.0.1\r\na=sendonly\r\nm=audio 4000 RTP/AVP 0\r\n";
+        assert_eq!(parse_direction(sdp), MediaDirection::SendOnly);
+        // Media-level attribute wins over the session-level one
+        assert_eq!(parse_direction(&format!("{}a=inactive\r\n", sdp)), MediaDirection::Inactive);
+        assert_eq!(parse_direction("v=0\r\nm=audio 4000 RTP/AVP 0\r\n"), MediaDirection::SendRecv);
+
+        use MediaDirection::*;
+        assert_eq!(RecvOnly.negotiate(SendOnly), RecvOnly);
+        assert_eq!(RecvOnly.negotiate(SendRecv), RecvOnly);
+        assert_eq!(SendRecv.negotiate(RecvOnly), SendOnly);
+        assert_eq!(SendRecv.negotiate(Inactive), Inactive);
+        assert_eq!(SendRecv.hold(), SendOnly);
+        assert_eq!(RecvOnly.hold(), Inactive);
+        assert!(!RecvOnly.sends() && RecvOnly.receives());
+        assert_eq!(serde_json::to_value(RecvOnly).unwrap(), "recvonly");
+    }
+
+    #[test]
+    fn test_parse_bandwidth() {
+        let sdp = "v=0\r\nc=IN IP4 10.0.0.1\r\nb=AS:256\r\nm=audio 4000 RTP/AVP 0\r\n";
+        assert_eq!(parse_bandwidth(sdp), Some(256));
+        // Media-level wins; TIAS is in bit/s and the tighter form is used
+        assert_eq!(parse_bandwidth(&format!("{}b=AS:90\r\nb=TIAS:64000\r\n", sdp)), Some(64));
+        assert_eq!(parse_bandwidth(&format!("{}b=TIAS:64500\r\n", sdp)), Some(65));
+        assert_eq!(parse_bandwidth("v=0\r\nb=CT:1000\r\nm=audio 4000 RTP/AVP 0\r\n"), None);
+
+        assert_eq!(codec_bandwidth_kbps(0), 80);
+        assert_eq!(prefer_within_bandwidth(&[0, 8], Some(64)), vec![0, 8]);
+        assert_eq!(prefer_within_bandwidth(&[8, 0], None), vec![8, 0]);
+    }
+
+    #[test]
+    fn test_sdp_parsing_with_ice() {
+        // Video first (reusing PT 96), media-level c= and ICE candidates
+        let sdp = "v=0\r\n\
+                   o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+                   s=-\r\n\
+                   c=IN IP4 0.0.0.0\r\n\
+                   t=0 0\r\n\
+                   a=ice-options:trickle\r\n\
+                   m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+                   a=rtpmap:96 VP8/90000\r\n\
+                   m=audio 50002 RTP/AVP 96 0\r\n\
+                   c=IN IP4 192.168.1.20\r\n\
+                   a=rtpmap:96 opus/48000/2\r\n\
+                   a=ice-ufrag:8hhY\r\n\
+                   a=ice-pwd:asd88fgpdd777uzjYhagZg\r\n\
+                   a=candidate:1 1 udp 2130706431 192.168.1.20 50002 typ host\r\n\
+                   a=candidate:1 2 udp 2130706430 192.168.1.20 50003 typ host\r\n\
+                   a=candidate:2 1 udp 16777215 198.51.100.7 61000 typ relay raddr 192.168.1.20 rport 50002\r\n\
+                   a=candidate:3 1 udp 1694498815 203.0.113.9 40400 typ srflx raddr 192.168.1.20 rport 50002\r\n\
+                   a=candidate:4 1 tcp 1518280447 203.0.113.9 9 typ host tcptype active\r\n\
+                   a=x-unknown-attribute\r\n";
+
+        let (ip, port, pt, clock_rate) = parse_sdp(sdp).unwrap();
+        assert_eq!((ip.as_str(), port), ("203.0.113.9", 40400));
+        assert_eq!(pt, 96);
+        assert_eq!(clock_rate, 48000);
+
+        // Without candidates the media-level c= wins over the session one
+        let plain: String = sdp.lines().filter(|line| !line.starts_with("a=candidate")).map(|line| format!("{}\n", line)).collect();
+        let (ip, port, _, _) = parse_sdp(&plain).unwrap();
+        assert_eq!((ip.as_str(), port), ("192.168.1.20", 50002));
+
+        // A rejected audio stream is skipped for the next one
+        let rejected = "c=IN IP4 10.0.0.1\r\nm=audio 0 RTP/AVP 0\r\nm=audio 4002 RTP/AVP 8\r\n";
+        assert_eq!(parse_sdp(rejected).unwrap().1, 4002);
+        // ... with the active stream's own c= line, past a video stream
+        let mixed = "v=0\r\nc=IN IP4 10.0.0.1\r\nt=0 0\r\n\
+                     m=audio 0 RTP/AVP 0\r\nc=IN IP4 10.0.0.2\r\n\
+                     m=video 5000 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n\
+                     m=audio 4004 RTP/AVP 8 101\r\nc=IN IP4 10.0.0.3\r\n";
+        assert_eq!(parse_sdp(mixed).unwrap(), ("10.0.0.3".to_string(), 4004, 8, 8000));
+        // Nothing to send to when every audio stream is rejected
+        let all_rejected = "c=IN IP4 10.0.0.1\r\nm=audio 0 RTP/AVP 0\r\nm=video 5000 RTP/AVP 96\r\nm=audio 0 RTP/AVP 8\r\n";
+        assert!(parse_sdp(all_rejected).is_err());
+        assert!(parse_sdp("c=IN IP4 10.0.0.1\r\nm=video 4000 RTP/AVP 96\r\n").is_err());
+    }
+
+    #[test]
+    fn test_sdp_in_multipart_body() {
+        let body = "--unique-boundary-1\r\n\
+                    Content-Type: application/sdp\r\n\
+                    \r\n\
+                    v=0\r\n\
+                    c=IN IP4 10.1.2.3\r\n\
+                    m=audio 7078 RTP/AVP 8\r\n\
+                    \r\n\
+                    --unique-boundary-1\r\n\
+                    Content-Type: application/isup; version=itu-t92+\r\n\
+                    \r\n\
+                    m=video 1 x\r\n\
+                    --unique-boundary-1--\r\n";
+
+        assert!(sdp_part(body).starts_with("v=0\r\n"));
+        assert!(!sdp_part(body).contains("isup"));
+        let (ip, port, pt, _) = parse_sdp(body).unwrap();
+        assert_eq!((ip.as_str(), port, pt), ("10.1.2.3", 7078, 8));
+    }
+
+    #[test]
+    fn test_rtpmap_clock_rates() {
+        let sdp = "v=0\r\n\
+                   c=IN IP4 192.168.1.100\r\n\
+                   m=audio 12345 RTP/AVP 0 9 96 111\r\n\
+                   a=rtpmap:0 PCMU/8000\r\n\
+                   a=rtpmap:9 G722/8000\r\n\
+                   a=rtpmap:96 L16/16000\r\n\
+                   a=rtpmap:111 opus/48000/2\r\n";
+
+        assert_eq!(rtpmap_clock_rate(sdp, 0), 8000);
+        assert_eq!(rtpmap_clock_rate(sdp, 9), 8000);
+        assert_eq!(rtpmap_clock_rate(sdp, 96), 16000);
+        assert_eq!(rtpmap_clock_rate(sdp, 111), 48000);
+        // No rtpmap: static table
+        assert_eq!(rtpmap_clock_rate(sdp, 8), 8000);
+
+        let opus = "c=IN IP4 10.0.0.1\r\nm=audio 4000 RTP/AVP 111\r\na=rtpmap:111 opus/48000/2\r\n";
+        assert_eq!(parse_sdp(opus).unwrap().3, 48000);
+    }
+
+    #[test]
+    fn test_source_tracker_follows_sustained_switch() {
+        let mut tracker = SourceTracker::default();
+        assert_eq!(tracker.check(0xAAAA, 100), SourceCheck::Accept);
+        assert_eq!(tracker.check(0xAAAA, 101), SourceCheck::Accept);
+
+        // The answering phone's stream replaces the ringback stream
+        for seq in 5000..5000 + SSRC_SWITCH_PACKETS as u16 - 1 {
+            assert_eq!(tracker.check(0xBBBB, seq), SourceCheck::Drop);
+        }
+        let seq = 5000 + SSRC_SWITCH_PACKETS as u16 - 1;
+        assert_eq!(tracker.check(0xBBBB, seq), SourceCheck::Switched { from: 0xAAAA });
+
+        // Sequence tracking restarted for the new source
+        assert_eq!(tracker.check(0xBBBB, seq + 1), SourceCheck::Accept);
+        // and the old one is now the stranger
+        assert_eq!(tracker.check(0xAAAA, 102), SourceCheck::Drop);
+    }
+
+    #[test]
+    fn test_source_tracker_follows_srtp_switch() {
+        // One key for both streams, as when a B2BUA swaps ringback for the answer
+        let keys = crate::srtp::SrtpKeys::generate().unwrap();
+        let mut ringback = SrtpContext::new(&keys);
+        let mut answer = SrtpContext::new(&keys);
+        let mut receiver = SrtpContext::new(&keys);
+        let mut tracker = SourceTracker::default();
+
+        // The ringback stream has run far enough that the answer's low
+        // sequence numbers would look like a rollover if they shared its index
+        for seq in 60000..60010u16 {
+            let packet = RtpPacket::new(0, seq, seq as u32 * 160, 0xAAAA, vec![0xAB; 160]).to_bytes();
+            let data = receiver.unprotect(&ringback.protect(&packet).unwrap()).unwrap();
+            let packet = RtpPacket::from_bytes(&data).unwrap();
+            assert_ne!(tracker.check(packet.ssrc, packet.sequence_number), SourceCheck::Drop);
+        }
+
+        let mut switched = false;
+        for seq in 5000..5000 + SSRC_SWITCH_PACKETS as u16 + 1 {
+            let packet = RtpPacket::new(0, seq, seq as u32 * 160, 0xBBBB, vec![0xCD; 160]).to_bytes();
+            let data = receiver.unprotect(&answer.protect(&packet).unwrap()).unwrap();
+            assert_eq!(data, packet);
+            let packet = RtpPacket::from_bytes(&data).unwrap();
+            switched |= tracker.check(packet.ssrc, packet.sequence_number) == SourceCheck::Switched { from: 0xAAAA };
+        }
+        assert!(switched);
+    }
+
+    #[test]
+    fn test_source_tracker_ignores_sporadic_packets() {
+        let mut tracker = SourceTracker::default();
+        let mut seq = 1u16;
+        assert_eq!(tracker.check(0xAAAA, seq), SourceCheck::Accept);
+
+        // Stray packets interleaved with the real stream never add up to a switch
+        for _ in 0..10 {
+            assert_eq!(tracker.check(0xDEAD, 7), SourceCheck::Drop);
+            seq += 1;
+            assert_eq!(tracker.check(0xAAAA, seq), SourceCheck::Accept);
+        }
+    }
+
+    #[test]
+    fn test_source_tracker_drops_duplicates_and_wraps() {
+        let mut tracker = SourceTracker::default();
+        assert_eq!(tracker.check(1, 65534), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 65534), SourceCheck::Drop);
+        assert_eq!(tracker.check(1, 65535), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 0), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 65535), SourceCheck::Drop);
+    }
+
+    #[test]
+    fn test_source_tracker_resyncs_after_restart() {
+        let mut tracker = SourceTracker::default();
+        assert_eq!(tracker.check(1, 40000), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 40001), SourceCheck::Accept);
+
+        // The sender restarts its numbering: one packet alone isn't enough...
+        assert_eq!(tracker.check(1, 10), SourceCheck::Drop);
+        // ...but the next one in order confirms it
+        assert_eq!(tracker.check(1, 11), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 12), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 40002), SourceCheck::Drop);
+
+        // A stray far-off packet followed by the normal stream changes nothing
+        assert_eq!(tracker.check(1, 30000), SourceCheck::Drop);
+        assert_eq!(tracker.check(1, 13), SourceCheck::Accept);
+        assert_eq!(tracker.check(1, 30001), SourceCheck::Drop);
+
+        // Reordering and ordinary loss are not restarts
+        assert_eq!(tracker.check(1, 8), SourceCheck::Drop);
+        assert_eq!(tracker.check(1, 500), SourceCheck::Accept);
+    }
+
+    #[test]
+    fn test_dtmf_detector_reports_each_key_once() {
+        let mut detector = DtmfDetector::default();
+
+        // '5': three progress packets, then the end packet sent three times
+        let mut keys = Vec::new();
+        for duration in [160u16, 320, 480] {
+            keys.extend(detector.packet(8000, &telephone_event_payload(5, false, duration)));
+        }
+        for _ in 0..3 {
+            keys.extend(detector.packet(8000, &telephone_event_payload(5, true, 640)));
+        }
+        assert_eq!(keys, vec![DtmfEvent { digit: '5', duration: 640 }]);
+
+        // '#' whose end packets were all lost is reported when '1' starts
+        assert_eq!(detector.packet(16000, &telephone_event_payload(11, false, 160)), None);
+        assert_eq!(
+            detector.packet(24000, &telephone_event_payload(1, false, 160)),
+            Some(DtmfEvent { digit: '#', duration: 160 })
+        );
+        assert_eq!(
+            detector.packet(24000, &telephone_event_payload(1, true, 800)),
+            Some(DtmfEvent { digit: '1', duration: 800 })
+        );
+
+        // Not a DTMF event, or too short
+        assert_eq!(detector.packet(32000, &telephone_event_payload(40, true, 160)), None);
+        assert_eq!(detector.packet(32000, &[1, 0x80]), None);
+        assert_eq!(dtmf_digit(15), Some('D'));
+    }
+
+    #[tokio::test]
+    async fn test_receive_media_separates_dtmf() {
+        let session = RtpSession::new(LOCALHOST, 0, "127.0.0.1:9".parse().unwrap(), 0, 8000).await.unwrap();
+        session.set_event_payload_type(Some(101)).await;
+        let target = format!("127.0.0.1:{}", session.socket().local_addr().unwrap().port());
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let packets = [
+            RtpPacket::new(101, 1, 160, 7, telephone_event_payload(9, false, 160).to_vec()),
+            RtpPacket::new(101, 2, 160, 7, telephone_event_payload(9, true, 320).to_vec()),
+            RtpPacket::new(101, 3, 160, 7, telephone_event_payload(9, true, 320).to_vec()),
+            RtpPacket::new(0, 4, 480, 7, vec![0x55; 4]),
+        ];
+        for packet in &packets {
+            sender.send_to(&packet.to_bytes(), &target).await.unwrap();
+        }
+
+        assert_eq!(session.receive_media().await.unwrap(), RxPayload::Dtmf(DtmfEvent { digit: '9', duration: 320 }));
+        assert_eq!(session.receive_media().await.unwrap(), RxPayload::Audio { payload_type: 0, payload: vec![0x55; 4] });
+    }
+
+    #[tokio::test]
+    async fn test_receive_media_adopts_new_source() {
+        let session = RtpSession::new(LOCALHOST, 0, "127.0.0.1:9".parse().unwrap(), 0, 8000).await.unwrap();
+        let target = format!("127.0.0.1:{}", session.socket().local_addr().unwrap().port());
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let send = |ssrc: u32, seq: u16, byte: u8| {
+            RtpPacket::new(0, seq, seq as u32 * 160, ssrc, vec![byte; 4]).to_bytes()
+        };
+
+        sender.send_to(&send(1, 10, 0x11), &target).await.unwrap();
+        assert_eq!(session.receive_media().await.unwrap(), RxPayload::Audio { payload_type: 0, payload: vec![0x11; 4] });
+
+        // Only the packet that completes the switch is played
+        for seq in 0..SSRC_SWITCH_PACKETS as u16 {
+            sender.send_to(&send(2, 300 + seq, 0x22 + seq as u8), &target).await.unwrap();
+        }
+        let expected = 0x22 + SSRC_SWITCH_PACKETS as u8 - 1;
+        assert_eq!(session.receive_media().await.unwrap(), RxPayload::Audio { payload_type: 0, payload: vec![expected; 4] });
+    }
+
+    #[tokio::test]
+    async fn test_sends_to_learned_source() {
+        // The SDP address is not where the peer's packets come from (NAT)
+        let session = RtpSession::new(LOCALHOST, 0, "127.0.0.1:9".parse().unwrap(), 0, 8000).await.unwrap();
+        let target = format!("127.0.0.1:{}", session.socket().local_addr().unwrap().port());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(session.remote_addr().await, "127.0.0.1:9".parse().unwrap());
+
+        let packet = RtpPacket::new(0, 1, 160, 7, vec![0x11; 4]).to_bytes();
+        peer.send_to(&packet, &target).await.unwrap();
+        session.receive_media().await.unwrap();
+        assert_eq!(session.remote_addr().await, peer.local_addr().unwrap());
+
+        session.send_audio(&[0x22; 4]).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (size, _) = tokio::time::timeout(std::time::Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RtpPacket::from_bytes(&buf[..size]).unwrap().payload, vec![0x22; 4]);
+
+        // A re-INVITE to a new address starts learning again
+        session.retarget("127.0.0.1:11".parse().unwrap(), 0, 8000).await;
+        assert_eq!(session.remote_addr().await, "127.0.0.1:11".parse().unwrap());
+    }
+
+    #[test]
+    fn test_jitter_estimator() {
+        // Packets arriving exactly on schedule have no jitter
+        let mut estimator = JitterEstimator::default();
+        for i in 0..10u32 {
+            estimator.update(i * 160, 5000 + i * 160);
+        }
+        assert_eq!(estimator.jitter(), 0.0);
+
+        // One packet 80 units late moves the estimate by 1/16 of the deviation
+        estimator.update(10 * 160, 5000 + 10 * 160 + 80);
+        assert_eq!(estimator.jitter(), 5.0);
+
+        // Timestamps wrapping around 2^32 aren't a huge jump
+        let mut estimator = JitterEstimator::default();
+        estimator.update(u32::MAX - 79, 1000);
+        estimator.update(80, 1160);
+        assert_eq!(estimator.jitter(), 0.0);
+    }
+
+    #[test]
+    fn test_rx_activity_reports_silence_once() {
+        let start = std::time::Instant::now();
+        let mut activity = RxActivity::new(start);
+        let later = |ms| start + std::time::Duration::from_millis(ms);
+
+        assert_eq!(activity.check(later(1500)), None);
+        assert_eq!(activity.packet(later(1900)), None);
+        assert_eq!(activity.check(later(3900)), None);
+
+        let silent = std::time::Duration::from_millis(2100);
+        assert_eq!(activity.check(later(4000)), Some(RxActivityChange::Stopped { silent }));
+        assert!(!activity.is_flowing());
+        assert_eq!(activity.check(later(6000)), None);
+
+        assert_eq!(activity.packet(later(6500)), Some(RxActivityChange::Resumed));
+        assert!(activity.is_flowing());
+    }
+
+    #[test]
+    fn test_rx_activity_dead_after_timeout() {
+        let start = std::time::Instant::now();
+        let mut activity = RxActivity::new(start);
+        let later = |secs| start + std::time::Duration::from_secs(secs);
+        let timeout = std::time::Duration::from_secs(30);
+
+        assert!(!activity.is_dead(later(30), timeout));
+        assert!(activity.is_dead(later(31), timeout));
+        activity.packet(later(31));
+        assert!(!activity.is_dead(later(40), timeout));
+
+        // Too short a timeout is raised to the minimum
+        assert!(!activity.is_dead(later(40), std::time::Duration::from_secs(1)));
+        assert!(activity.is_dead(later(42), std::time::Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_one_way_audio_startup_and_mid_call() {
+        let start = std::time::Instant::now();
+        let mut activity = RxActivity::new(start);
+        let later = |ms| start + std::time::Duration::from_millis(ms);
+
+        // Only while we are sending, and only past the timeout
+        assert_eq!(activity.check_one_way(later(3500), false), None);
+        assert_eq!(activity.check_one_way(later(2500), true), None);
+        assert_eq!(activity.check_one_way(later(3500), true), Some(OneWayAudio::Startup));
+        assert_eq!(activity.check_one_way(later(5000), true), None);
+
+        activity.packet(later(6000));
+        assert_eq!(activity.check_one_way(later(8000), true), None);
+        assert_eq!(activity.check_one_way(later(9500), true), Some(OneWayAudio::MidCall));
+    }
+
+    #[test]
+    fn test_codec_mapping() {
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_payload_type(codec.payload_type()), Some(codec));
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Codec::from_name(" pcma "), Some(Codec::Pcma));
+        assert_eq!(Codec::from_payload_type(18), None);
+        assert_eq!(Codec::G722.clock_rate(), 8000);
+        assert_eq!(Codec::Opus.clock_rate(), 48000);
+
+        // Only G.711 goes through the media path
+        assert!(Codec::Pcma.is_supported());
+        assert!(!Codec::Opus.is_supported());
+        assert_eq!(Codec::G722.encode(&[0; 160]), None);
+        let samples = [1000i16, -1000];
+        let decoded = Codec::Pcmu.decode(&Codec::Pcmu.encode(&samples).unwrap()).unwrap();
+        assert!(decoded.iter().zip(samples).all(|(a, b)| (a - b).abs() < 40));
+        assert_eq!(Codec::Pcma.encode(&samples).unwrap(), vec![g711::encode_alaw(1000), g711::encode_alaw(-1000)]);
+    }
+
+    #[test]
+    fn test_choose_payload_type_by_preference() {
+        assert_eq!(codec_payload_type("pcma"), Some(8));
+        assert_eq!(codec_payload_type("opus"), None);
+        assert_eq!(Codec::from_payload_type(0).map(Codec::name), Some("PCMU"));
+
+        // Our order wins over the offerer's
+        assert_eq!(choose_payload_type(&[0, 8, 101], &[8, 0]), Some(8));
+        assert_eq!(choose_payload_type(&[9, 0, 101], &[8, 0]), Some(0));
+        assert_eq!(choose_payload_type(&[0, 101], &[8]), None);
+    }
+
+    #[test]
+    fn test_build_sdp_offer() {
+        let codecs = [Codec::Pcma, Codec::Pcmu, Codec::TelephoneEvent];
+        let sdp = build_sdp_offer("192.0.2.10", 4000, &codecs, MediaDirection::SendOnly, 20, OfferOptions::default());
+
+        // Every payload type on the m= line has its rtpmap, in the same order
+        let formats = offered_payload_types(&sdp);
+        assert_eq!(formats, vec![8, 0, 101]);
+        let rtpmaps: Vec<u8> = sdp
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+            .filter_map(|map| map.split(' ').next()?.parse().ok())
+            .collect();
+        assert_eq!(rtpmaps, formats);
+        assert!(sdp.contains("a=rtpmap:101 telephone-event/8000\r\n"));
+
+        // o=<username> <sess-id> <sess-version> IN IP4 <address>
+        let origin: Vec<&str> = sdp.lines().find_map(|line| line.strip_prefix("o=")).unwrap().split(' ').collect();
+        assert_eq!(origin.len(), 6);
+        assert!(origin[1].parse::<u64>().is_ok() && origin[2].parse::<u64>().is_ok());
+        assert_eq!(origin[3..], ["IN", "IP4", "192.0.2.10"]);
+
+        assert_eq!(parse_direction(&sdp), MediaDirection::SendOnly);
+        assert!(sdp.contains("a=ptime:20\r\n"));
+        assert_eq!(parse_sdp(&sdp).unwrap(), ("192.0.2.10".to_string(), 4000, 8, 8000));
+        assert!(sdp.contains("m=audio 4000 RTP/AVP 8 0 101\r\n"));
+        assert_eq!(parse_bandwidth(&sdp), None);
+
+        // SRTP and a bandwidth limit
+        let keys = SrtpKeys::generate().unwrap();
+        let options = OfferOptions { srtp_keys: Some(&keys), bandwidth_kbps: Some(64) };
+        let sdp = build_sdp_offer("2001:db8::1", 4002, &[Codec::Pcmu], MediaDirection::SendRecv, 20, options);
+        assert!(sdp.contains("m=audio 4002 RTP/SAVP 0\r\n"));
+        assert!(sdp.contains(&keys.crypto_attribute(1)));
+        assert!(sdp.contains("c=IN IP6 2001:db8::1\r\n"));
+        assert_eq!(parse_bandwidth(&sdp), Some(64));
+        assert!(sdp.ends_with("a=sendrecv\r\n"));
+    }
+
+    #[test]
+    fn test_rx_codec_follows_received_payload_type() {
+        let mut rx = RxCodec::default();
+        assert_eq!(rx.packet(0, 0), Some(Codec::Pcmu));
+
+        // Negotiated PCMU, sent PCMA: decode as PCMA
+        for _ in 0..PT_MISMATCH_PACKETS {
+            assert_eq!(rx.packet(0, 8), Some(Codec::Pcma));
+        }
+        assert_eq!(rx.mismatched, PT_MISMATCH_PACKETS);
+
+        // A matching packet ends the run
+        assert_eq!(rx.packet(0, 0), Some(Codec::Pcmu));
+        assert_eq!(rx.mismatched, 0);
+
+        // Comfort noise and codecs we can't decode are dropped, not played
+        // as the negotiated codec
+        assert_eq!(rx.packet(8, 13), None);
+        assert_eq!(rx.packet(8, 9), None);
+        assert_eq!(rx.packet(0, 111), None);
+        // Another supported codec at a different clock rate
+        assert_eq!(rx.packet(111, 0), None);
+    }
+
+    #[test]
+    fn test_telephone_event_payload_type() {
+        let sdp = "m=audio 4000 RTP/AVP 0 96\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:96 telephone-event/8000\r\n";
+        assert_eq!(telephone_event_payload_type(sdp), Some(96));
+        assert_eq!(telephone_event_payload_type("m=audio 4000 RTP/AVP 0\r\n"), None);
+    }
+
+    #[test]
+    fn test_dtmf_event_codes() {
+        assert_eq!(dtmf_event_code('0'), Some(0));
+        assert_eq!(dtmf_event_code('9'), Some(9));
+        assert_eq!(dtmf_event_code('*'), Some(10));
+        assert_eq!(dtmf_event_code('#'), Some(11));
+        assert_eq!(dtmf_event_code('d'), Some(15));
+        assert_eq!(dtmf_event_code('x'), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_telephone_event() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let session = RtpSession::new(LOCALHOST, 0, receiver.local_addr().unwrap(), 0, 8000).await.unwrap();
+
+        // Not negotiated
+        assert!(session.send_telephone_event(5, 60).await.is_err());
+
+        session.set_event_payload_type(Some(101)).await;
+        session.send_telephone_event(5, 60).await.unwrap();
+
+        let mut packets = Vec::new();
+        let mut buf = vec![0u8; 2048];
+        while let Ok(Ok(size)) =
+            tokio::time::timeout(std::time::Duration::from_millis(100), receiver.recv(&mut buf)).await
+        {
+            packets.push(RtpPacket::from_bytes(&buf[..size]).unwrap());
+        }
+
+        // 20ms and 40ms updates, then the 60ms end packet three times
+        assert_eq!(packets.len(), 5);
+        assert!(packets[0].marker);
+        assert!(packets[1..].iter().all(|p| !p.marker));
+        assert!(packets.iter().all(|p| p.payload_type == 101 && p.timestamp == packets[0].timestamp));
+        assert_eq!(packets[0].payload, vec![5, 10, 0, 160]);
+        assert_eq!(packets[1].payload, vec![5, 10, 1, 64]);
+        assert_eq!(packets[4].payload, vec![5, 0x80 | 10, 1, 224]);
+        assert_eq!(packets[4].sequence_number, packets[0].sequence_number.wrapping_add(4));
+    }
+
+    #[test]
+    fn test_timestamp_increment() {
+        assert_eq!(timestamp_increment(8000, FRAME_DURATION_MS), 160);
+        assert_eq!(timestamp_increment(16000, FRAME_DURATION_MS), 320);
+        assert_eq!(timestamp_increment(48000, FRAME_DURATION_MS), 960);
+    }
+
+    #[test]
+    fn test_framer_emits_whole_frames() {
+        let mut framer = Framer::default();
+        let frame_len = timestamp_increment(8000, FRAME_DURATION_MS) as usize;
+
+        // Irregular device blocks after resampling to 8kHz
+        let mut next = 0i16;
+        let mut frames = Vec::new();
+        for len in [73, 147, 160, 13, 441, 0, 166, 280] {
+            let block: Vec<i16> = (0..len).map(|_| { next += 1; next }).collect();
+            frames.extend(framer.push(&block, frame_len));
+        }
+
+        // 1280 samples in: eight frames, nothing lost or reordered
+        assert_eq!(frames.len(), 8);
+        assert!(frames.iter().all(|frame| frame.len() == 160));
+        let samples: Vec<i16> = frames.concat();
+        assert_eq!(samples, (1..=1280).collect::<Vec<i16>>());
+
+        // Leftovers wait for the next block, unless sending paused
+        assert!(framer.push(&[1; 100], frame_len).is_empty());
+        assert_eq!(framer.push(&[2; 60], frame_len), vec![[vec![1; 100], vec![2; 60]].concat()]);
+        framer.push(&[3; 100], frame_len);
+        framer.clear();
+        assert!(framer.push(&[4; 100], frame_len).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_audio_advances_timestamp_by_clock_rate() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let session = RtpSession::new(LOCALHOST, 0, receiver.local_addr().unwrap(), 111, 48000).await.unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        session.send_audio(&[0; 10]).await.unwrap();
+        let size = receiver.recv(&mut buf).await.unwrap();
+        let first = RtpPacket::from_bytes(&buf[..size]).unwrap();
+        session.send_audio(&[0; 10]).await.unwrap();
+        let size = receiver.recv(&mut buf).await.unwrap();
+        let second = RtpPacket::from_bytes(&buf[..size]).unwrap();
+
+        assert_eq!(second.timestamp.wrapping_sub(first.timestamp), 960);
     }
 }
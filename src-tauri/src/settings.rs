@@ -1,9 +1,17 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Layout version of settings.json. Bump it and add a step to
+/// `migrate_settings` when a change can't be covered by serde defaults.
+pub const SETTINGS_VERSION: u32 = 1;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppSettings {
+    /// Layout version the file was written with (0 = before versioning)
+    #[serde(default)]
+    pub version: u32,
     pub server: String,
     pub username: String,
     #[serde(default)]
@@ -12,16 +20,407 @@ pub struct AppSettings {
     pub audio_input_device: String,
     #[serde(default)]
     pub audio_output_device: String,
+    /// Offer SRTP (RTP/SAVP with SDES keys) on outgoing calls
+    #[serde(default)]
+    pub secure_media: bool,
+    /// STUN server (`host[:port]`) used to learn the public media address
+    #[serde(default)]
+    pub stun_server: String,
+    /// TURN server (`host[:port]`) that relays media when set
+    #[serde(default)]
+    pub turn_server: String,
+    #[serde(default)]
+    pub turn_user: String,
+    #[serde(default)]
+    pub turn_password_encrypted: String,
+    /// Proxy every request is sent to, whatever the server/domain field says
+    /// (empty to send straight to the server)
+    #[serde(default)]
+    pub outbound_proxy: String,
+    /// P-Preferred-Identity sent on outgoing calls, for trunks that take
+    /// the caller ID from it (empty to leave it out)
+    #[serde(default)]
+    pub preferred_identity: String,
+    /// Display name sent in the From header of calls and registrations
+    #[serde(default)]
+    pub display_name: String,
+    /// How E.164 numbers (+15551234567) are put into the request-URI
+    #[serde(default)]
+    pub dial_as: DialAs,
+    /// How in-call DTMF is sent
+    #[serde(default)]
+    pub dtmf_mode: DtmfMode,
+    /// Do not disturb: reject inbound calls without ringing
+    #[serde(default)]
+    pub dnd: bool,
+    /// Callers whose calls are answered automatically (intercoms, door phones)
+    #[serde(default)]
+    pub auto_answer: AutoAnswer,
+    /// Where, how loud and with what sound incoming calls ring
+    #[serde(default)]
+    pub ringer: Ringer,
+    /// Send requests with compact header names (f:, t:, i:, v:, ...) for
+    /// servers that misbehave with the long forms
+    #[serde(default)]
+    pub compact_headers: bool,
+    /// Codec names in the order we offer and accept them
+    #[serde(default = "default_codec_preferences")]
+    pub codec_preferences: Vec<String>,
+    /// Seconds to wait for each REGISTER response
+    #[serde(default = "default_register_timeout")]
+    pub register_timeout_secs: u64,
+    /// Seconds to wait for each INVITE response (covers post-dial delay)
+    #[serde(default = "default_invite_timeout")]
+    pub invite_timeout_secs: u64,
+    /// Seconds an outgoing call may ring before it is cancelled as unanswered
+    #[serde(default = "default_answer_timeout")]
+    pub answer_timeout_secs: u64,
+    /// Seconds to wait for the BYE response before dropping the call anyway
+    #[serde(default = "default_bye_timeout")]
+    pub bye_timeout_secs: u64,
+    /// Seconds to wait for the unregister response
+    #[serde(default = "default_unregister_timeout")]
+    pub unregister_timeout_secs: u64,
+    /// Seconds without incoming RTP before a call is considered dead and hung up
+    #[serde(default = "default_rtp_timeout")]
+    pub rtp_timeout_secs: u64,
+    /// Seconds an answered call may last before it is hung up (0 = unlimited)
+    #[serde(default)]
+    pub max_call_duration_secs: u64,
+    /// Hang up a call whose audio can't be set up instead of leaving it silent
+    #[serde(default)]
+    pub require_media: bool,
+    /// Send CRLF keepalive pings to the server to hold the NAT binding open
+    #[serde(default)]
+    pub keepalive_enabled: bool,
+    /// Seconds between keepalive pings
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval_secs: u64,
+    /// Playback latency (ms) the call audio buffer is held near
+    #[serde(default = "default_playout_target")]
+    pub playout_target_ms: u32,
+    /// Follow the sender's clock by slightly resampling received audio, so
+    /// the playback buffer doesn't creep over long calls
+    #[serde(default)]
+    pub drift_compensation: bool,
+    /// Offer RFC 4733 telephone-events in our SDP; when off, DTMF always
+    /// goes out as SIP INFO
+    #[serde(default = "default_offer_telephone_event")]
+    pub offer_telephone_event: bool,
+    /// Mark RTP packets with DSCP EF so routers prioritize call audio
+    #[serde(default)]
+    pub qos_media: bool,
+    /// Mark SIP signaling with DSCP CS3
+    #[serde(default)]
+    pub qos_signaling: bool,
+    /// Audio bandwidth (kbit/s) advertised as `b=AS:` in our SDP (0 = no limit)
+    #[serde(default)]
+    pub bandwidth_limit_kbps: u32,
+    /// Address family used to reach the server when it has both
+    #[serde(default)]
+    pub ip_version_preference: IpVersionPreference,
+    /// Gain and volume last set for each audio device, by device name
+    /// (`DEFAULT_DEVICE_KEY` for the system default)
+    #[serde(default)]
+    pub device_levels: HashMap<String, DeviceLevels>,
+}
+
+/// Key `device_levels` uses for the system default device
+pub const DEFAULT_DEVICE_KEY: &str = "default";
+
+/// Levels remembered for one audio device, as linear factors (1.0 = unchanged).
+/// The input gain applies when it is the microphone, the volume when it is
+/// the speaker.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DeviceLevels {
+    #[serde(default = "default_level")]
+    pub input_gain: f32,
+    #[serde(default = "default_level")]
+    pub output_volume: f32,
+}
+
+impl Default for DeviceLevels {
+    fn default() -> Self {
+        Self {
+            input_gain: default_level(),
+            output_volume: default_level(),
+        }
+    }
+}
+
+fn default_level() -> f32 {
+    1.0
+}
+
+/// Request-URI form used when dialing an E.164 number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialAs {
+    /// `sip:+15551234567@server`
+    #[default]
+    Sip,
+    /// `sip:+15551234567@server;user=phone`, which many PSTN gateways require
+    UserPhone,
+    /// `tel:+15551234567`
+    Tel,
+}
+
+/// Address family preference for dual-stack servers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpVersionPreference {
+    /// Either family, IPv4 first (the SIP socket is IPv4 unless IPv6 is forced)
+    #[default]
+    Auto,
+    /// IPv4 addresses only
+    Ipv4,
+    /// IPv6 addresses only; signaling and media use IPv6 sockets
+    Ipv6,
+}
+
+/// How dialpad keys are sent during a call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DtmfMode {
+    /// RFC 4733 (RFC 2833) telephone-events in the RTP stream
+    Rfc2833,
+    /// SIP INFO requests with an application/dtmf-relay body
+    Info,
+    /// RFC 2833 when the peer negotiated telephone-event, INFO otherwise
+    #[default]
+    Auto,
+}
+
+fn default_codec_preferences() -> Vec<String> {
+    crate::rtp::SUPPORTED_CODECS.iter().map(|codec| codec.name().to_string()).collect()
+}
+
+fn default_register_timeout() -> u64 {
+    10
+}
+
+fn default_invite_timeout() -> u64 {
+    30
+}
+
+fn default_answer_timeout() -> u64 {
+    60
+}
+
+fn default_bye_timeout() -> u64 {
+    5
+}
+
+fn default_unregister_timeout() -> u64 {
+    3
+}
+
+fn default_rtp_timeout() -> u64 {
+    30
+}
+
+fn default_keepalive_interval() -> u64 {
+    30
+}
+
+fn default_offer_telephone_event() -> bool {
+    true
+}
+
+fn default_playout_target() -> u32 {
+    crate::audio::DEFAULT_PLAYOUT_TARGET_MS
+}
+
+/// STUN/TURN servers used to reach the account's media through NAT
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaRelay {
+    #[serde(default)]
+    pub stun_server: String,
+    #[serde(default)]
+    pub turn_server: String,
+    #[serde(default)]
+    pub turn_user: String,
+    #[serde(default)]
+    pub turn_password: String,
+}
+
+/// Response timeouts used by the SIP engine
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SipTimeouts {
+    pub register_secs: u64,
+    pub invite_secs: u64,
+    #[serde(default = "default_answer_timeout")]
+    pub answer_secs: u64,
+    pub bye_secs: u64,
+    pub unregister_secs: u64,
+    #[serde(default = "default_rtp_timeout")]
+    pub rtp_secs: u64,
+}
+
+impl Default for SipTimeouts {
+    fn default() -> Self {
+        Self {
+            register_secs: default_register_timeout(),
+            invite_secs: default_invite_timeout(),
+            answer_secs: default_answer_timeout(),
+            bye_secs: default_bye_timeout(),
+            unregister_secs: default_unregister_timeout(),
+            rtp_secs: default_rtp_timeout(),
+        }
+    }
+}
+
+/// DSCP marking of call traffic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct QosMarking {
+    /// EF (46) on RTP
+    pub media: bool,
+    /// CS3 (24) on SIP
+    pub signaling: bool,
+}
+
+/// Auto-answer: calls whose From URI matches one of `patterns` are answered
+/// after `delay_secs` unless the user answers or rejects them first.
+/// Patterns compare case-insensitively, ignore the `sip:` scheme, and may use
+/// `*` as a wildcard (`door@example.com`, `*@intercom.local`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AutoAnswer {
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub delay_secs: u64,
+}
+
+impl AutoAnswer {
+    /// Whether a caller's From URI matches one of the patterns
+    pub fn matches(&self, uri: &str) -> bool {
+        let uri = strip_sip_scheme(uri).to_ascii_lowercase();
+        self.patterns.iter().any(|pattern| {
+            let pattern = strip_sip_scheme(pattern.trim()).to_ascii_lowercase();
+            !pattern.is_empty() && wildcard_match(&pattern, &uri)
+        })
+    }
+}
+
+/// How incoming calls ring. The ring can use another output device than
+/// the call audio (speakers while the call goes to a headset).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ringer {
+    /// Output device for the ring (empty = system default)
+    #[serde(default)]
+    pub device: String,
+    /// Ring volume, 0.0 (silent) to 1.0
+    #[serde(default = "default_ring_volume")]
+    pub volume: f32,
+    /// 16-bit PCM WAV file to ring with (empty = built-in ring)
+    #[serde(default)]
+    pub ringtone_path: String,
+}
+
+impl Default for Ringer {
+    fn default() -> Self {
+        Self {
+            device: String::new(),
+            volume: default_ring_volume(),
+            ringtone_path: String::new(),
+        }
+    }
+}
+
+fn default_ring_volume() -> f32 {
+    0.8
+}
+
+fn strip_sip_scheme(uri: &str) -> &str {
+    let lower = uri.get(..5).map(str::to_ascii_lowercase).unwrap_or_default();
+    if lower == "sips:" {
+        &uri[5..]
+    } else if lower.starts_with("sip:") {
+        &uri[4..]
+    } else {
+        uri
+    }
+}
+
+// Glob match where `*` stands for any run of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the whole text must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// CRLF keepalive configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KeepaliveSettings {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for KeepaliveSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: default_keepalive_interval(),
+        }
+    }
 }
 
 impl Default for AppSettings {
     fn default() -> Self {
         Self {
+            version: SETTINGS_VERSION,
             server: String::new(),
             username: String::new(),
             password_encrypted: String::new(),
             audio_input_device: String::new(),
             audio_output_device: String::new(),
+            secure_media: false,
+            stun_server: String::new(),
+            turn_server: String::new(),
+            turn_user: String::new(),
+            turn_password_encrypted: String::new(),
+            outbound_proxy: String::new(),
+            preferred_identity: String::new(),
+            display_name: String::new(),
+            dial_as: DialAs::Sip,
+            dtmf_mode: DtmfMode::Auto,
+            dnd: false,
+            auto_answer: AutoAnswer::default(),
+            ringer: Ringer::default(),
+            compact_headers: false,
+            codec_preferences: default_codec_preferences(),
+            register_timeout_secs: default_register_timeout(),
+            invite_timeout_secs: default_invite_timeout(),
+            answer_timeout_secs: default_answer_timeout(),
+            bye_timeout_secs: default_bye_timeout(),
+            unregister_timeout_secs: default_unregister_timeout(),
+            rtp_timeout_secs: default_rtp_timeout(),
+            max_call_duration_secs: 0,
+            require_media: false,
+            keepalive_enabled: false,
+            keepalive_interval_secs: default_keepalive_interval(),
+            playout_target_ms: default_playout_target(),
+            drift_compensation: false,
+            offer_telephone_event: default_offer_telephone_event(),
+            qos_media: false,
+            qos_signaling: false,
+            bandwidth_limit_kbps: 0,
+            ip_version_preference: IpVersionPreference::Auto,
+            device_levels: HashMap::new(),
         }
     }
 }
@@ -47,14 +446,20 @@ fn obfuscate_password(password: &str) -> String {
 fn deobfuscate_password(encrypted: &str) -> Result<String, String> {
     const KEY: &[u8] = b"PlatypusPhoneKey2024";
     
-    // Decode from hex
-    let bytes: Result<Vec<u8>, _> = (0..encrypted.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&encrypted[i..i + 2], 16))
+    // Decode from hex; a corrupted settings file must not be able to panic here
+    if encrypted.len() % 2 != 0 {
+        return Err("Failed to decode password: odd number of hex digits".to_string());
+    }
+    if !encrypted.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("Failed to decode password: not a hex string".to_string());
+    }
+
+    let bytes: Vec<u8> = encrypted
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| (hex_value(pair[0]) << 4) | hex_value(pair[1]))
         .collect();
     
-    let bytes = bytes.map_err(|e| format!("Failed to decode password: {}", e))?;
-    
     // XOR decrypt
     let decrypted: Vec<u8> = bytes
         .iter()
@@ -65,11 +470,40 @@ fn deobfuscate_password(encrypted: &str) -> Result<String, String> {
     String::from_utf8(decrypted).map_err(|e| format!("Invalid UTF-8: {}", e))
 }
 
+/// Value of an ASCII hex digit (already validated)
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Get the app data directory
+#[cfg(not(test))]
+fn settings_dir() -> Result<PathBuf, String> {
+    tauri::api::path::app_data_dir(&tauri::Config::default())
+        .ok_or_else(|| "Failed to get app data directory".to_string())
+}
+
+/// Tests never read or write the real settings: the test binary keeps its
+/// own file in the temp directory
+#[cfg(test)]
+fn settings_dir() -> Result<PathBuf, String> {
+    Ok(std::env::temp_dir().join(format!("platypus-phone-test-{}", std::process::id())))
+}
+
+/// Forget everything tests saved, so the next test starts from the defaults
+#[cfg(test)]
+pub fn clear_test_settings() {
+    if let Ok(path) = get_settings_path() {
+        let _ = fs::remove_file(path);
+    }
+}
+
 /// Get the path to the settings file
 fn get_settings_path() -> Result<PathBuf, String> {
-    // Get the app data directory
-    let app_dir = tauri::api::path::app_data_dir(&tauri::Config::default())
-        .ok_or_else(|| "Failed to get app data directory".to_string())?;
+    let app_dir = settings_dir()?;
     
     // Create directory if it doesn't exist
     fs::create_dir_all(&app_dir)
@@ -89,17 +523,76 @@ fn load_settings() -> Result<AppSettings, String> {
     let json = fs::read_to_string(&settings_path)
         .map_err(|e| format!("Failed to read settings file: {}", e))?;
     
-    let settings: AppSettings = serde_json::from_str(&json)
-        .map_err(|e| format!("Failed to parse settings file: {}", e))?;
+    let (settings, migrated) = parse_settings(&json)?;
     
     tracing::info!("Loaded settings from: {}", settings_path.display());
+    if migrated {
+        tracing::info!("Migrated settings to version {}", SETTINGS_VERSION);
+        save_settings(&settings)?;
+    }
     Ok(settings)
 }
 
+/// Parse settings JSON, upgrading older layouts to the current version.
+/// Also returns whether a migration ran (and the file should be rewritten).
+fn parse_settings(json: &str) -> Result<(AppSettings, bool), String> {
+    let mut value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format!("Failed to parse settings file: {}", e))?;
+
+    let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    let migrated = version < SETTINGS_VERSION;
+    if migrated {
+        migrate_settings(&mut value, version)?;
+    } else if version > SETTINGS_VERSION {
+        // Written by a newer build: read what we understand and leave the rest alone
+        tracing::warn!("Settings file is version {}, newer than {}", version, SETTINGS_VERSION);
+    }
+
+    let settings = serde_json::from_value(value)
+        .map_err(|e| format!("Failed to parse settings file: {}", e))?;
+    Ok((settings, migrated))
+}
+
+/// Upgrade a settings object one version at a time, starting at `from`
+fn migrate_settings(value: &mut serde_json::Value, from: u32) -> Result<(), String> {
+    let settings = value
+        .as_object_mut()
+        .ok_or_else(|| "Settings file is not a JSON object".to_string())?;
+
+    if from < 1 {
+        // v0 files predate the version field; the layout is otherwise the same
+        settings.insert("version".to_string(), serde_json::Value::from(1));
+    }
+
+    Ok(())
+}
+
+/// Copy fields we don't know about (written by a newer build) from the file
+/// on disk into what we're about to save, so saving doesn't drop them
+fn keep_unknown_fields(value: &mut serde_json::Value, existing: serde_json::Value) {
+    if let (Some(settings), serde_json::Value::Object(existing)) = (value.as_object_mut(), existing) {
+        for (key, field) in existing {
+            settings.entry(key).or_insert(field);
+        }
+    }
+}
+
 /// Save all settings to disk
 fn save_settings(settings: &AppSettings) -> Result<(), String> {
     let settings_path = get_settings_path()?;
-    let json = serde_json::to_string_pretty(&settings)
+    let mut value = serde_json::to_value(settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+
+    if settings.version > SETTINGS_VERSION {
+        if let Some(existing) = fs::read_to_string(&settings_path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+        {
+            keep_unknown_fields(&mut value, existing);
+        }
+    }
+
+    let json = serde_json::to_string_pretty(&value)
         .map_err(|e| format!("Failed to serialize settings: {}", e))?;
     
     fs::write(&settings_path, json)
@@ -149,6 +642,383 @@ pub fn load_audio_devices() -> Result<(String, String), String> {
     Ok((settings.audio_input_device, settings.audio_output_device))
 }
 
+// Device names as stored in device_levels; an empty name is the default device
+fn device_key(device: &str) -> String {
+    match device.trim() {
+        "" => DEFAULT_DEVICE_KEY.to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Levels remembered for an audio device (unchanged levels if none were set)
+pub fn load_device_levels(device: &str) -> Result<DeviceLevels, String> {
+    Ok(load_settings()?
+        .device_levels
+        .get(&device_key(device))
+        .copied()
+        .unwrap_or_default())
+}
+
+/// Remember the microphone gain for an input device
+pub fn save_input_gain(device: &str, gain: f32) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.device_levels.entry(device_key(device)).or_default().input_gain = gain;
+    save_settings(&settings)
+}
+
+/// Remember the speaker volume for an output device
+pub fn save_output_volume(device: &str, volume: f32) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.device_levels.entry(device_key(device)).or_default().output_volume = volume;
+    save_settings(&settings)
+}
+
+/// Save the secure media (SRTP) preference
+pub fn save_secure_media(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.secure_media = enabled;
+    save_settings(&settings)
+}
+
+/// Load the secure media (SRTP) preference
+pub fn load_secure_media() -> Result<bool, String> {
+    Ok(load_settings()?.secure_media)
+}
+
+/// Save the STUN/TURN servers (empty strings to disable)
+pub fn save_media_relay(relay: &MediaRelay) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.stun_server = relay.stun_server.trim().to_string();
+    settings.turn_server = relay.turn_server.trim().to_string();
+    settings.turn_user = relay.turn_user.clone();
+    settings.turn_password_encrypted = obfuscate_password(&relay.turn_password);
+    save_settings(&settings)
+}
+
+/// Load the STUN/TURN servers
+pub fn load_media_relay() -> Result<MediaRelay, String> {
+    let settings = load_settings()?;
+    let turn_password = if settings.turn_password_encrypted.is_empty() {
+        String::new()
+    } else {
+        deobfuscate_password(&settings.turn_password_encrypted)?
+    };
+    Ok(MediaRelay {
+        stun_server: settings.stun_server,
+        turn_server: settings.turn_server,
+        turn_user: settings.turn_user,
+        turn_password,
+    })
+}
+
+/// Save the outbound proxy (empty to disable)
+pub fn save_outbound_proxy(proxy: &str) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.outbound_proxy = proxy.trim().to_string();
+    save_settings(&settings)
+}
+
+/// Load the outbound proxy
+pub fn load_outbound_proxy() -> Result<String, String> {
+    Ok(load_settings()?.outbound_proxy)
+}
+
+/// Save the P-Preferred-Identity for outgoing calls (empty to disable)
+pub fn save_preferred_identity(identity: &str) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.preferred_identity = identity.trim().to_string();
+    save_settings(&settings)
+}
+
+/// Load the P-Preferred-Identity for outgoing calls
+pub fn load_preferred_identity() -> Result<String, String> {
+    Ok(load_settings()?.preferred_identity)
+}
+
+/// Save the display name shown to the people we call
+pub fn save_display_name(name: &str) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.display_name = name.trim().to_string();
+    save_settings(&settings)
+}
+
+/// Load the display name
+pub fn load_display_name() -> Result<String, String> {
+    Ok(load_settings()?.display_name)
+}
+
+/// Save how E.164 numbers are dialed
+pub fn save_dial_as(dial_as: DialAs) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.dial_as = dial_as;
+    save_settings(&settings)
+}
+
+/// Load how E.164 numbers are dialed
+pub fn load_dial_as() -> Result<DialAs, String> {
+    Ok(load_settings()?.dial_as)
+}
+
+/// Save how in-call DTMF is sent
+pub fn save_dtmf_mode(mode: DtmfMode) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.dtmf_mode = mode;
+    save_settings(&settings)
+}
+
+/// Load how in-call DTMF is sent
+pub fn load_dtmf_mode() -> Result<DtmfMode, String> {
+    Ok(load_settings()?.dtmf_mode)
+}
+
+/// Save the do not disturb state
+pub fn save_dnd(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.dnd = enabled;
+    save_settings(&settings)
+}
+
+/// Load the do not disturb state
+pub fn load_dnd() -> Result<bool, String> {
+    Ok(load_settings()?.dnd)
+}
+
+/// Save the auto-answer callers and delay
+pub fn save_auto_answer(auto_answer: &AutoAnswer) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.auto_answer = auto_answer.clone();
+    save_settings(&settings)
+}
+
+/// Load the auto-answer callers and delay
+pub fn load_auto_answer() -> Result<AutoAnswer, String> {
+    Ok(load_settings()?.auto_answer)
+}
+
+/// Save the ring device, volume (limited to 0.0..=1.0) and ringtone
+pub fn save_ringer(ringer: &Ringer) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.ringer = Ringer {
+        volume: if ringer.volume.is_nan() { default_ring_volume() } else { ringer.volume.clamp(0.0, 1.0) },
+        ..ringer.clone()
+    };
+    save_settings(&settings)
+}
+
+/// Load the ring device, volume and ringtone
+pub fn load_ringer() -> Result<Ringer, String> {
+    Ok(load_settings()?.ringer)
+}
+
+/// Save whether requests use compact header names
+pub fn save_compact_headers(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.compact_headers = enabled;
+    save_settings(&settings)
+}
+
+/// Load whether requests use compact header names
+pub fn load_compact_headers() -> Result<bool, String> {
+    Ok(load_settings()?.compact_headers)
+}
+
+/// Save the maximum call duration in seconds (0 = unlimited)
+pub fn save_max_call_duration(secs: u64) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.max_call_duration_secs = secs;
+    save_settings(&settings)
+}
+
+/// Load the maximum call duration in seconds (0 = unlimited)
+pub fn load_max_call_duration() -> Result<u64, String> {
+    Ok(load_settings()?.max_call_duration_secs)
+}
+
+/// Save the codec preference order. Names must be codecs we implement;
+/// an empty list restores the default order.
+pub fn save_codec_preferences(codecs: Vec<String>) -> Result<(), String> {
+    if let Some(unknown) = codecs.iter().find(|name| crate::rtp::codec_payload_type(name).is_none()) {
+        return Err(format!("Unsupported codec: {}", unknown));
+    }
+    let mut settings = load_settings()?;
+    settings.codec_preferences = if codecs.is_empty() { default_codec_preferences() } else { codecs };
+    save_settings(&settings)
+}
+
+/// Load the codec preference order. Unknown names (e.g. from a newer
+/// version or a hand-edited file) are skipped with a warning.
+pub fn load_codec_preferences() -> Result<Vec<String>, String> {
+    Ok(known_codecs(load_settings()?.codec_preferences))
+}
+
+fn known_codecs(codecs: Vec<String>) -> Vec<String> {
+    let known: Vec<String> = codecs
+        .into_iter()
+        .filter(|name| {
+            let supported = crate::rtp::codec_payload_type(name).is_some();
+            if !supported {
+                tracing::warn!("[Settings] Ignoring unsupported codec '{}'", name);
+                println!("[Settings] ⚠ Ignoring unsupported codec '{}'", name);
+            }
+            supported
+        })
+        .collect();
+    if known.is_empty() {
+        default_codec_preferences()
+    } else {
+        known
+    }
+}
+
+/// Save the playback latency target (zero restores the default)
+pub fn save_playout_target_ms(target_ms: u32) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.playout_target_ms = playout_target(target_ms);
+    save_settings(&settings)
+}
+
+/// Load the playback latency target
+pub fn load_playout_target_ms() -> Result<u32, String> {
+    Ok(playout_target(load_settings()?.playout_target_ms))
+}
+
+// A playout target within the range the playout buffer works with; also
+// applied on load, since the settings file may be edited by hand
+fn playout_target(target_ms: u32) -> u32 {
+    if target_ms == 0 {
+        default_playout_target()
+    } else {
+        target_ms.clamp(crate::audio::MIN_PLAYOUT_TARGET_MS, crate::audio::MAX_PLAYOUT_TARGET_MS)
+    }
+}
+
+/// Save whether calls without working audio are hung up
+pub fn save_require_media(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.require_media = enabled;
+    save_settings(&settings)
+}
+
+/// Load whether calls without working audio are hung up
+pub fn load_require_media() -> Result<bool, String> {
+    Ok(load_settings()?.require_media)
+}
+
+/// Turn clock drift compensation for received audio on or off
+pub fn save_drift_compensation(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.drift_compensation = enabled;
+    save_settings(&settings)
+}
+
+/// Load whether clock drift compensation is on
+pub fn load_drift_compensation() -> Result<bool, String> {
+    Ok(load_settings()?.drift_compensation)
+}
+
+/// Save whether telephone-events are offered for DTMF
+pub fn save_offer_telephone_event(enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.offer_telephone_event = enabled;
+    save_settings(&settings)
+}
+
+/// Load whether telephone-events are offered for DTMF
+pub fn load_offer_telephone_event() -> Result<bool, String> {
+    Ok(load_settings()?.offer_telephone_event)
+}
+
+/// Save the keepalive settings (a zero interval restores the default)
+pub fn save_keepalive(keepalive: &KeepaliveSettings) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.keepalive_enabled = keepalive.enabled;
+    settings.keepalive_interval_secs = if keepalive.interval_secs == 0 {
+        default_keepalive_interval()
+    } else {
+        keepalive.interval_secs
+    };
+    save_settings(&settings)
+}
+
+/// Load the keepalive settings
+pub fn load_keepalive() -> Result<KeepaliveSettings, String> {
+    let settings = load_settings()?;
+    Ok(KeepaliveSettings {
+        enabled: settings.keepalive_enabled,
+        interval_secs: settings.keepalive_interval_secs,
+    })
+}
+
+/// Save which traffic gets DSCP marking
+pub fn save_qos(qos: &QosMarking) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.qos_media = qos.media;
+    settings.qos_signaling = qos.signaling;
+    save_settings(&settings)
+}
+
+/// Load which traffic gets DSCP marking
+pub fn load_qos() -> Result<QosMarking, String> {
+    let settings = load_settings()?;
+    Ok(QosMarking {
+        media: settings.qos_media,
+        signaling: settings.qos_signaling,
+    })
+}
+
+/// Save the audio bandwidth limit in kbit/s (None or 0 for no limit)
+pub fn save_bandwidth_limit(kbps: Option<u32>) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.bandwidth_limit_kbps = kbps.unwrap_or(0);
+    save_settings(&settings)
+}
+
+/// Load the audio bandwidth limit (None when unlimited)
+pub fn load_bandwidth_limit() -> Result<Option<u32>, String> {
+    Ok(Some(load_settings()?.bandwidth_limit_kbps).filter(|kbps| *kbps > 0))
+}
+
+/// Save which address family to reach the server over
+pub fn save_ip_version_preference(preference: IpVersionPreference) -> Result<(), String> {
+    let mut settings = load_settings()?;
+    settings.ip_version_preference = preference;
+    save_settings(&settings)
+}
+
+/// Load which address family to reach the server over
+pub fn load_ip_version_preference() -> Result<IpVersionPreference, String> {
+    Ok(load_settings()?.ip_version_preference)
+}
+
+/// Save the SIP response timeouts (zero values are replaced by the defaults)
+pub fn save_timeouts(timeouts: &SipTimeouts) -> Result<(), String> {
+    let defaults = SipTimeouts::default();
+    let mut settings = load_settings()?;
+
+    let or_default = |value: u64, default: u64| if value == 0 { default } else { value };
+    settings.register_timeout_secs = or_default(timeouts.register_secs, defaults.register_secs);
+    settings.invite_timeout_secs = or_default(timeouts.invite_secs, defaults.invite_secs);
+    settings.answer_timeout_secs = or_default(timeouts.answer_secs, defaults.answer_secs);
+    settings.bye_timeout_secs = or_default(timeouts.bye_secs, defaults.bye_secs);
+    settings.unregister_timeout_secs = or_default(timeouts.unregister_secs, defaults.unregister_secs);
+    settings.rtp_timeout_secs = or_default(timeouts.rtp_secs, defaults.rtp_secs);
+
+    save_settings(&settings)
+}
+
+/// Load the SIP response timeouts
+pub fn load_timeouts() -> Result<SipTimeouts, String> {
+    let settings = load_settings()?;
+    Ok(SipTimeouts {
+        register_secs: settings.register_timeout_secs,
+        invite_secs: settings.invite_timeout_secs,
+        answer_secs: settings.answer_timeout_secs,
+        bye_secs: settings.bye_timeout_secs,
+        unregister_secs: settings.unregister_timeout_secs,
+        rtp_secs: settings.rtp_timeout_secs,
+    })
+}
+
 /// Clear all saved settings
 pub fn clear_settings() -> Result<(), String> {
     let settings_path = get_settings_path()?;
@@ -162,6 +1032,55 @@ pub fn clear_settings() -> Result<(), String> {
     Ok(())
 }
 
+/// Write the settings to `path` for backup or moving to another machine.
+/// The saved password is left out of the export.
+pub fn export_settings(path: &Path) -> Result<(), String> {
+    let json = export_json(&load_settings()?)?;
+    fs::write(path, json)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    tracing::info!("Exported settings to: {}", path.display());
+    Ok(())
+}
+
+/// Replace the settings with a file written by `export_settings`, migrating
+/// older versions. The password saved on this machine is kept.
+pub fn import_settings(path: &Path) -> Result<(), String> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let current = load_settings()?;
+    let mut settings = settings_from_import(&json, &current.password_encrypted)?;
+    settings.turn_password_encrypted = current.turn_password_encrypted;
+    save_settings(&settings)?;
+    tracing::info!("Imported settings from: {}", path.display());
+    Ok(())
+}
+
+fn export_json(settings: &AppSettings) -> Result<String, String> {
+    let mut exported = settings.clone();
+    exported.password_encrypted.clear();
+    exported.turn_password_encrypted.clear();
+    serde_json::to_string_pretty(&exported)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))
+}
+
+fn settings_from_import(json: &str, password_encrypted: &str) -> Result<AppSettings, String> {
+    let version = serde_json::from_str::<serde_json::Value>(json)
+        .map_err(|e| format!("Not a settings file: {}", e))?
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    if version > SETTINGS_VERSION as u64 {
+        return Err(format!(
+            "Settings file is version {}, but this version of Platypus Phone only reads up to version {}",
+            version, SETTINGS_VERSION
+        ));
+    }
+
+    let (mut settings, _) = parse_settings(json)?;
+    settings.password_encrypted = password_encrypted.to_string();
+    Ok(settings)
+}
+
 // Keep old function name for backward compatibility
 pub fn clear_credentials() -> Result<(), String> {
     clear_settings()
@@ -191,4 +1110,189 @@ mod tests {
         let decrypted = deobfuscate_password(&encrypted).unwrap();
         assert_eq!(decrypted, password);
     }
+
+    #[test]
+    fn test_deobfuscate_rejects_malformed_input() {
+        // Odd length (e.g. a truncated file)
+        assert!(deobfuscate_password("abc").is_err());
+        // Not hex, including a sign that from_str_radix would accept
+        assert!(deobfuscate_password("zz").is_err());
+        assert!(deobfuscate_password("+1").is_err());
+        // Multi-byte characters must not split a slice mid-character
+        assert!(deobfuscate_password("é1").is_err());
+        assert!(deobfuscate_password("ab€").is_err());
+
+        // Upper-case hex still decodes
+        let encrypted = obfuscate_password("secret").to_uppercase();
+        assert_eq!(deobfuscate_password(&encrypted).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_migrate_v0_settings() {
+        let json = r#"{
+            "server": "sip.example.com",
+            "username": "alice",
+            "password_encrypted": "2316",
+            "audio_input_device": "USB Headset",
+            "secure_media": true
+        }"#;
+        let (settings, migrated) = parse_settings(json).unwrap();
+        assert!(migrated);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.server, "sip.example.com");
+        assert_eq!(settings.username, "alice");
+        assert_eq!(settings.password_encrypted, "2316");
+        assert_eq!(settings.audio_input_device, "USB Headset");
+        assert!(settings.secure_media);
+        assert_eq!(settings.register_timeout_secs, 10);
+
+        // Current files are left alone
+        let json = serde_json::to_string(&settings).unwrap();
+        let (_, migrated) = parse_settings(&json).unwrap();
+        assert!(!migrated);
+    }
+
+    #[test]
+    fn test_newer_settings_version_is_kept() {
+        let json = r#"{"version": 99, "server": "sip.example.com", "username": "alice", "profiles": [1, 2]}"#;
+        let (settings, migrated) = parse_settings(json).unwrap();
+        assert!(!migrated);
+        assert_eq!(settings.version, 99);
+        assert_eq!(settings.username, "alice");
+
+        // Saving keeps the fields this build doesn't know about
+        let mut value = serde_json::to_value(&settings).unwrap();
+        keep_unknown_fields(&mut value, serde_json::from_str(json).unwrap());
+        assert_eq!(value["profiles"], serde_json::json!([1, 2]));
+        assert_eq!(value["version"], 99);
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let settings = AppSettings {
+            server: "sip.example.com".to_string(),
+            username: "alice".to_string(),
+            password_encrypted: obfuscate_password("secret"),
+            turn_password_encrypted: obfuscate_password("relay-secret"),
+            dial_as: DialAs::Tel,
+            ..Default::default()
+        };
+
+        let json = export_json(&settings).unwrap();
+        assert!(json.contains("\"password_encrypted\": \"\""));
+        assert!(json.contains("\"turn_password_encrypted\": \"\""));
+
+        // The password on the importing machine is kept
+        let local_password = obfuscate_password("local");
+        let imported = settings_from_import(&json, &local_password).unwrap();
+        assert_eq!(imported.server, "sip.example.com");
+        assert_eq!(imported.username, "alice");
+        assert_eq!(imported.dial_as, DialAs::Tel);
+        assert_eq!(imported.password_encrypted, local_password);
+    }
+
+    #[test]
+    fn test_import_migrates_old_and_refuses_newer_versions() {
+        let imported = settings_from_import(r#"{"server": "pbx.local", "username": "bob"}"#, "").unwrap();
+        assert_eq!(imported.version, SETTINGS_VERSION);
+        assert_eq!(imported.server, "pbx.local");
+
+        let newer = format!(r#"{{"version": {}, "server": "pbx.local", "username": "bob"}}"#, SETTINGS_VERSION + 1);
+        let err = settings_from_import(&newer, "").unwrap_err();
+        assert!(err.contains("only reads up to version"), "{}", err);
+
+        assert!(settings_from_import("not json", "").is_err());
+    }
+
+    #[test]
+    fn test_dial_as_serialization() {
+        let json = r#"{"server": "sip.example.com", "username": "alice"}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.dial_as, DialAs::Sip);
+
+        assert_eq!(settings.dtmf_mode, DtmfMode::Auto);
+
+        let json = r#"{"server": "sip.example.com", "username": "alice", "dtmf_mode": "rfc2833"}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.dtmf_mode, DtmfMode::Rfc2833);
+
+        let json = r#"{"server": "sip.example.com", "username": "alice", "dial_as": "user_phone"}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.dial_as, DialAs::UserPhone);
+        assert_eq!(serde_json::to_value(DialAs::Tel).unwrap(), "tel");
+    }
+
+    #[test]
+    fn test_codec_preferences() {
+        let json = r#"{"server": "sip.example.com", "username": "alice"}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.codec_preferences, vec!["PCMU", "PCMA"]);
+
+        let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(known_codecs(names(&["opus", "PCMA", "G722", "pcmu"])), vec!["PCMA", "pcmu"]);
+        assert_eq!(known_codecs(names(&["opus"])), vec!["PCMU", "PCMA"]);
+    }
+
+    #[test]
+    fn test_device_levels() {
+        let json = r#"{"server": "s", "username": "u",
+                       "device_levels": {"USB Headset": {"input_gain": 1.5}}}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        let headset = settings.device_levels["USB Headset"];
+        assert_eq!(headset, DeviceLevels { input_gain: 1.5, output_volume: 1.0 });
+
+        assert_eq!(device_key("  "), DEFAULT_DEVICE_KEY);
+        assert_eq!(device_key("USB Headset"), "USB Headset");
+    }
+
+    #[test]
+    fn test_timeouts_default_when_missing() {
+        // Settings files written before the timeouts existed keep the old values
+        let json = r#"{"server": "sip.example.com", "username": "alice"}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.register_timeout_secs, 10);
+        assert_eq!(settings.invite_timeout_secs, 30);
+        assert_eq!(settings.answer_timeout_secs, 60);
+        assert_eq!(settings.bye_timeout_secs, 5);
+        assert_eq!(settings.unregister_timeout_secs, 3);
+        assert_eq!(settings.rtp_timeout_secs, 30);
+        assert_eq!(settings.max_call_duration_secs, 0);
+        assert_eq!(settings.playout_target_ms, 60);
+        assert_eq!(playout_target(0), 60);
+        assert_eq!(playout_target(5), 20);
+        assert_eq!(playout_target(120), 120);
+        assert_eq!(playout_target(60_000), 1000);
+        assert!(!settings.keepalive_enabled);
+        assert_eq!(settings.keepalive_interval_secs, 30);
+        assert_eq!(settings.ringer, Ringer::default());
+
+        // A ringer saved with only a device keeps the default volume
+        let ringer: Ringer = serde_json::from_str(r#"{"device": "Speakers"}"#).unwrap();
+        assert_eq!(ringer.volume, 0.8);
+        assert!(ringer.ringtone_path.is_empty());
+
+        // The UI may still send timeouts without the answer deadline
+        let json = r#"{"register_secs": 10, "invite_secs": 30, "bye_secs": 5, "unregister_secs": 3}"#;
+        let timeouts: SipTimeouts = serde_json::from_str(json).unwrap();
+        assert_eq!(timeouts.answer_secs, 60);
+        assert_eq!(timeouts.rtp_secs, 30);
+    }
+
+    #[test]
+    fn test_auto_answer_patterns() {
+        let auto_answer = AutoAnswer {
+            patterns: vec!["sip:Door@example.com".to_string(), "*@intercom.local".to_string(), " ".to_string()],
+            delay_secs: 3,
+        };
+        assert!(auto_answer.matches("sip:door@example.com"));
+        assert!(auto_answer.matches("door@EXAMPLE.com"));
+        assert!(auto_answer.matches("sips:gate@intercom.local"));
+        assert!(!auto_answer.matches("sip:door@example.com.evil"));
+        assert!(!auto_answer.matches("sip:bob@example.com"));
+        assert!(!AutoAnswer::default().matches("sip:door@example.com"));
+
+        assert!(wildcard_match("a*c*e", "abcde"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("a*a", "a"));
+    }
 }
@@ -1,11 +1,22 @@
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tokio::net::UdpSocket;
 use md5::compute as md5_compute;
-use crate::rtp::{RtpSession, g711, parse_sdp};
-use crate::audio::AudioManager;
-use crate::resample::AudioResampler;
+use sha2::{Digest, Sha256};
+use crate::rtp::{Codec, DSCP_CS3, FRAME_DURATION_MS, Framer, MediaDirection, OfferOptions, OneWayAudio, RtpSession, RxActivity, RxActivityChange, RxCodec, RxPayload, build_sdp_offer, choose_payload_type, codec_bandwidth_kbps, codec_payload_type, dtmf_event_code, offered_payload_types, parse_bandwidth, parse_direction, parse_sdp, prefer_within_bandwidth, rtpmap_clock_rate, sdp_formats, set_dscp, telephone_event_payload_type, timestamp_increment};
+use crate::audio::{AudioBackend, AudioLevels, CALL_AUDIO_RATE, CallAudio, CpalBackend, DEFAULT_PLAYOUT_TARGET_MS, DriftEstimator, apply_level};
+use crate::resample::Resampler;
+use crate::error::SipError;
+use crate::events;
+use crate::ids;
+use crate::message;
+use crate::stun::{self, TurnRelay};
+use crate::ringer::Ringing;
+use crate::srtp::{CRYPTO_SUITE, SrtpContext, SrtpKeys, is_secure_profile, parse_crypto_attribute};
+use crate::settings::{AutoAnswer, DialAs, DtmfMode, IpVersionPreference, SipTimeouts};
 
 // Dialog state for active calls
 #[derive(Clone, Debug)]
@@ -13,15 +24,164 @@ pub struct Dialog {
     call_id: String,
     from_tag: String,
     to_tag: Option<String>,
+    // CSeq of the last request we sent in the dialog. New requests take
+    // theirs from next_cseq; ACK and CANCEL echo their INVITE's instead.
     cseq: u32,
     remote_uri: String,
+    // Remote target from the peer's Contact; in-dialog requests are sent here
+    remote_target: Option<String>,
     local_uri: String,
+    // Route set learned from Record-Route in the 2xx (already in UAC order)
+    route_set: Vec<String>,
     state: CallState,
+    // Our SRTP keys when the call was offered with RTP/SAVP
+    srtp_keys: Option<SrtpKeys>,
+    // Server address the INVITE went to; ACK/BYE stay on the same instance
+    server_addr: Option<SocketAddr>,
+    // When the call was answered (entered Confirmed), for the call timer
+    connected_at: Option<std::time::Instant>,
     // RTP session (Arc makes it cloneable)
     rtp_session: Option<Arc<RtpSession>>,
+    // Capture/playback streams (closed when the last clone is dropped)
+    call_audio: Option<Arc<CallAudio>>,
     // Task handles for cleanup (not cloned)
     audio_tx_task: Option<Arc<tokio::task::JoinHandle<()>>>,
     audio_rx_task: Option<Arc<tokio::task::JoinHandle<()>>>,
+    // INVITE of an inbound call that is still ringing (answered by answer_call)
+    incoming_invite: Option<String>,
+    // INVITE of an outgoing call as last sent (after any auth retry), so an
+    // unanswered call can be cancelled
+    outgoing_invite: Option<String>,
+    // ACK we sent for the 2xx to our INVITE, with that INVITE's CSeq; resent
+    // whenever the 2xx is retransmitted (our ACK was lost)
+    sent_ack: Option<(u32, String)>,
+    // Park slot and when the call went into it; a parked call is on hold
+    // until it is retrieved
+    parked: Option<(u32, std::time::Instant)>,
+    // CSeq of an inbound INVITE we answered, our 200 OK, and what to wake
+    // when its ACK arrives; the 200 OK is retransmitted until then
+    awaiting_ack: Option<(u32, String, Arc<tokio::sync::Notify>)>,
+    // We placed the call, so the Call-ID is one we generated
+    outgoing: bool,
+    // Audio direction the call was placed or answered with (recvonly to
+    // listen in); a retrieved call goes back to it
+    direction: MediaDirection,
+    // Gives up our INVITE while it is unanswered (hangup_call, cancel_pending)
+    attempt: CancelToken,
+}
+
+impl Dialog {
+    // Request-URI for in-dialog requests: the remote target if the peer gave
+    // us a Contact, otherwise the URI we originally dialed
+    fn request_uri(&self) -> &str {
+        self.remote_target.as_deref().unwrap_or(&self.remote_uri)
+    }
+
+    // Header fields for a request inside this dialog
+    fn request_params<'a>(&'a self, local_addr: &'a str, branch: &'a str, route: &'a str, cseq: u32) -> message::RequestParams<'a> {
+        message::RequestParams {
+            uri: self.request_uri(),
+            local_addr,
+            branch,
+            route,
+            from_uri: &self.local_uri,
+            from_name: "",
+            from_tag: &self.from_tag,
+            to_uri: &self.remote_uri,
+            to_tag: self.to_tag.as_deref(),
+            call_id: &self.call_id,
+            cseq,
+            compact: use_compact_headers(),
+        }
+    }
+
+    // CSeq for a new request in the dialog
+    fn next_cseq(&mut self) -> u32 {
+        self.cseq += 1;
+        self.cseq
+    }
+
+    // Catch up with a request that went out with a higher CSeq than we
+    // handed out (an auth retry takes the next number)
+    fn observe_cseq(&mut self, sent: Option<u32>) {
+        if let Some(sent) = sent {
+            self.cseq = self.cseq.max(sent);
+        }
+    }
+
+    // Whole seconds since the call was answered (0 until then)
+    fn duration_secs(&self) -> u64 {
+        self.connected_at.map(|at| at.elapsed().as_secs()).unwrap_or(0)
+    }
+
+    // Move the call to `next` and tell the UI, if the state machine allows
+    // it. Staying put is fine and silent (a 183 after a 180, a retransmitted
+    // 200), so stray responses can't move a call backwards.
+    fn transition_to(&mut self, next: CallState) -> Result<(), String> {
+        self.change_state(next, None)
+    }
+
+    // The call is over (`reason` says why when it wasn't simply hung up).
+    // Once terminated, nothing moves it again, and ending it twice is silent.
+    fn terminate(&mut self, reason: Option<&str>) {
+        if let Err(e) = self.change_state(CallState::Terminated, reason) {
+            println!("[SIP] {}", e);
+        }
+    }
+
+    // Every call_state event comes from here, all with the same fields
+    fn change_state(&mut self, next: CallState, reason: Option<&str>) -> Result<(), String> {
+        if self.state == next {
+            return Ok(());
+        }
+        if !self.state.can_transition_to(&next) {
+            return Err(format!(
+                "Ignoring call state change {} → {} for {}",
+                self.state.as_str(),
+                next.as_str(),
+                self.call_id
+            ));
+        }
+        self.state = next;
+        events::emit("call_state", serde_json::json!({
+            "state": self.state.as_str(),
+            "call_id": self.call_id,
+            "duration_secs": self.duration_secs(),
+            "reason": reason,
+        }));
+        Ok(())
+    }
+}
+
+// SUBSCRIBE dialog for an event package (message-summary)
+#[derive(Clone, Debug)]
+struct Subscription {
+    call_id: String,
+    from_tag: String,
+    to_tag: Option<String>,
+    cseq: u32,
+}
+
+// Call-ID, From tag and CSeq of our registration. Refreshes and the
+// unregister reuse them (RFC 3261 10.2.4) so the server updates one binding
+// instead of seeing a new registration each time.
+#[derive(Clone, Debug)]
+struct Registration {
+    // Account (user@server) the binding belongs to
+    aor: String,
+    call_id: String,
+    from_tag: String,
+    cseq: u32,
+    // Final status of the latest REGISTER (None while pending or unanswered)
+    last_status: Option<u16>,
+}
+
+// Voicemail status from a message-summary NOTIFY (RFC 3842)
+#[derive(Clone, Debug, Default, PartialEq)]
+struct MessageSummary {
+    messages_waiting: bool,
+    new_messages: u32,
+    old_messages: u32,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -33,6 +193,67 @@ pub enum CallState {
     Terminated,
 }
 
+impl CallState {
+    /// Name used in `call_state` events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CallState::Idle => "IDLE",
+            CallState::Calling => "CALLING",
+            CallState::Ringing => "RINGING",
+            CallState::Confirmed => "CONFIRMED",
+            CallState::Terminated => "TERMINATED",
+        }
+    }
+
+    /// Whether a call may move from this state to `next`: forward through
+    /// Idle → Calling → Ringing → Confirmed, skipping ahead (an inbound call
+    /// starts ringing, a callee may answer without a 180), and out to
+    /// Terminated from anywhere. Nothing leaves Terminated.
+    pub fn can_transition_to(&self, next: &CallState) -> bool {
+        use CallState::*;
+        matches!(
+            (self, next),
+            (Idle, Calling | Ringing | Confirmed)
+                | (Calling, Ringing | Confirmed)
+                | (Ringing, Confirmed)
+                | (Idle | Calling | Ringing | Confirmed, Terminated)
+        )
+    }
+}
+
+// SIP responses (with their source address) from the receive loop
+type ResponseSender = mpsc::UnboundedSender<(String, SocketAddr)>;
+type ResponseReceiver = mpsc::UnboundedReceiver<(String, SocketAddr)>;
+
+// Responses are routed by Call-ID so that requests running at the same time
+// (a call, a registration, a background SUBSCRIBE) don't steal each other's
+type ResponseQueue = (ResponseSender, Arc<Mutex<ResponseReceiver>>);
+
+static PENDING_RESPONSES: Lazy<std::sync::Mutex<HashMap<String, ResponseQueue>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+// Routes responses for a Call-ID to recv_response until dropped
+struct ResponseRoute {
+    call_id: String,
+}
+
+impl ResponseRoute {
+    fn open(call_id: &str) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        PENDING_RESPONSES
+            .lock()
+            .unwrap()
+            .insert(call_id.to_string(), (tx, Arc::new(Mutex::new(rx))));
+        Self { call_id: call_id.to_string() }
+    }
+}
+
+impl Drop for ResponseRoute {
+    fn drop(&mut self) {
+        PENDING_RESPONSES.lock().unwrap().remove(&self.call_id);
+    }
+}
+
 pub struct SipEngine {
     socket: Option<Arc<UdpSocket>>,
     server: String,
@@ -41,6 +262,42 @@ pub struct SipEngine {
     registered: bool,
     local_addr: String,
     active_dialog: Option<Dialog>,
+    // The call out of focus: a second inbound call ringing while we talk
+    // (call waiting), or the call swap_calls put on hold
+    waiting_dialog: Option<Dialog>,
+    receive_task: Option<tokio::task::JoinHandle<()>>,
+    // Resolved server address, keyed by the server string it was resolved from
+    resolved_server: Option<(String, SocketAddr)>,
+    // Where a STUN server last saw our media coming from
+    public_addr: Option<SocketAddr>,
+    // Call-ID and CSeq shared by REGISTERs for the current account
+    registration: Option<Registration>,
+    // Voicemail (message-waiting) subscription and its refresh timer
+    mwi_subscription: Option<Subscription>,
+    mwi_refresh_task: Option<tokio::task::JoinHandle<()>>,
+    // Gives up the registration attempt in flight (cancel_pending)
+    register_attempt: Option<CancelToken>,
+    // Pending registration retry after a 503/480 with Retry-After
+    register_retry_task: Option<tokio::task::JoinHandle<()>>,
+    // CRLF pings that keep the NAT binding to the server open
+    keepalive_task: Option<tokio::task::JoinHandle<()>>,
+    // Notices the machine waking from sleep while registered
+    resume_watch_task: Option<tokio::task::JoinHandle<()>>,
+    // Hangs up the current call at the maximum call duration
+    call_limit_task: Option<tokio::task::JoinHandle<()>>,
+    // Do not disturb: inbound calls are rejected without ringing
+    dnd: bool,
+    // Callers whose calls are answered automatically, and the pending answer
+    auto_answer: AutoAnswer,
+    auto_answer_task: Option<tokio::task::JoinHandle<()>>,
+    // Ring for the incoming call; stops when dropped
+    ringing: Option<Ringing>,
+    // Where call audio comes from and goes to (cpal unless overridden)
+    audio_backend: Arc<dyn AudioBackend>,
+    // Microphone gain and speaker volume applied by the media tasks
+    audio_levels: Arc<AudioLevels>,
+    // Input and output device of the current call (empty = system default)
+    call_devices: (String, String),
 }
 
 impl Default for SipEngine {
@@ -53,6 +310,25 @@ impl Default for SipEngine {
             registered: false,
             local_addr: String::new(),
             active_dialog: None,
+            waiting_dialog: None,
+            receive_task: None,
+            resolved_server: None,
+            public_addr: None,
+            registration: None,
+            mwi_subscription: None,
+            mwi_refresh_task: None,
+            register_attempt: None,
+            register_retry_task: None,
+            keepalive_task: None,
+            resume_watch_task: None,
+            call_limit_task: None,
+            dnd: false,
+            auto_answer: AutoAnswer::default(),
+            auto_answer_task: None,
+            ringing: None,
+            audio_backend: Arc::new(CpalBackend),
+            audio_levels: Arc::new(AudioLevels::default()),
+            call_devices: (String::new(), String::new()),
         }
     }
 }
@@ -60,7 +336,7 @@ impl Default for SipEngine {
 static SIP_ENGINE: Lazy<Arc<Mutex<SipEngine>>> =
     Lazy::new(|| Arc::new(Mutex::new(SipEngine::default())));
 
-pub async fn init_pjsip() -> Result<(), String> {
+pub async fn init_pjsip() -> Result<(), SipError> {
     let mut engine = SIP_ENGINE.lock().await;
 
     if engine.socket.is_some() {
@@ -69,1471 +345,7345 @@ pub async fn init_pjsip() -> Result<(), String> {
 
     println!("[SIP] Initializing SIP stack");
 
+    // Forcing IPv6 needs an IPv6 socket; otherwise servers are reached over IPv4
+    let ipv6 = crate::settings::load_ip_version_preference().unwrap_or_default() == IpVersionPreference::Ipv6;
+    let (bind_addr, probe_target, fallback_ip) = if ipv6 {
+        ("[::]:0", "[2001:4860:4860::8888]:80", std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST))
+    } else {
+        ("0.0.0.0:0", "8.8.8.8:80", std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST))
+    };
+
     // Create UDP socket on ephemeral port
-    let socket = UdpSocket::bind("0.0.0.0:0").await
-        .map_err(|e| format!("Failed to create UDP socket: {}", e))?;
+    let socket = UdpSocket::bind(bind_addr).await
+        .map_err(|e| SipError::Network(format!("Failed to create UDP socket: {}", e)))?;
 
     let actual_local_addr = socket.local_addr()
-        .map_err(|e| format!("Failed to get local address: {}", e))?;
+        .map_err(|e| SipError::Network(format!("Failed to get local address: {}", e)))?;
 
     // Get the actual local IP address by connecting to a public DNS server
-    let local_ip = match std::net::UdpSocket::bind("0.0.0.0:0") {
+    let local_ip = match std::net::UdpSocket::bind(bind_addr) {
         Ok(test_socket) => {
-            match test_socket.connect("8.8.8.8:80") {
+            match test_socket.connect(probe_target) {
                 Ok(_) => {
                     test_socket.local_addr()
-                        .map(|addr| addr.ip().to_string())
-                        .unwrap_or_else(|_| "127.0.0.1".to_string())
+                        .map(|addr| addr.ip())
+                        .unwrap_or(fallback_ip)
                 }
-                Err(_) => "127.0.0.1".to_string()
+                Err(_) => fallback_ip
             }
         }
-        Err(_) => "127.0.0.1".to_string()
+        Err(_) => fallback_ip
     };
     
-    let local_addr = format!("{}:{}", local_ip, actual_local_addr.port());
+    // IPv6 hosts come out bracketed, as Via and Contact need them
+    let local_addr = SocketAddr::new(local_ip, actual_local_addr.port()).to_string();
+
+    if crate::settings::load_qos().unwrap_or_default().signaling && set_dscp(&socket, DSCP_CS3) {
+        println!("[QoS] SIP signaling marked DSCP CS3");
+    }
 
     println!("[SIP] UDP socket created");
     println!("[SIP] Actual bind address: {}", actual_local_addr);
     println!("[SIP] Advertised address: {}", local_addr);
 
-    engine.socket = Some(Arc::new(socket));
+    let socket = Arc::new(socket);
+
+    engine.receive_task = Some(tokio::spawn(receive_loop(socket.clone())));
+    engine.socket = Some(socket);
     engine.local_addr = local_addr;
+    // The new socket may speak another address family
+    engine.resolved_server = None;
+    engine.dnd = crate::settings::load_dnd().unwrap_or(false);
+    engine.auto_answer = crate::settings::load_auto_answer().unwrap_or_default();
 
     println!("[SIP] SIP stack initialized successfully");
 
     Ok(())
 }
 
-pub async fn register_account(
-    server: &str,
-    user: &str,
-    password: &str,
-) -> Result<(), String> {
-    let mut engine = SIP_ENGINE.lock().await;
+// Receive every datagram on the SIP socket: responses are handed to the
+// request waiting on their Call-ID, requests go to handle_request
+async fn receive_loop(socket: Arc<UdpSocket>) {
+    let mut buf = vec![0u8; 8192];
 
-    let socket = engine
-        .socket
-        .as_ref()
-        .ok_or("SIP not initialized")?
-        .clone();
+    loop {
+        let (size, from_addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors from earlier sends surface here on some platforms
+                println!("[SIP] Receive error: {}", e);
+                continue;
+            }
+        };
 
-    println!("[SIP] Registering account:");
-    println!("  Server: {}", server);
-    println!("  User: {}", user);
+        let message = String::from_utf8_lossy(&buf[..size]).to_string();
 
-    // Store credentials
-    engine.server = server.to_string();
-    engine.user = user.to_string();
-    engine.password = password.to_string();
+        if message.trim().is_empty() {
+            // CRLF keepalive (RFC 5626 4.4.1): a double CRLF is a ping and gets
+            // a single CRLF pong. A lone CRLF is the pong to one of our pings;
+            // answering it would just bounce it back and forth.
+            if message.starts_with("\r\n\r\n") {
+                if let Err(e) = socket.send_to(b"\r\n", from_addr).await {
+                    println!("[SIP] Failed to answer keepalive ping: {}", e);
+                }
+            }
+            continue;
+        }
 
-    let local_addr = engine.local_addr.clone();
-    
-    // Release the lock before async operations
-    drop(engine);
+        if message.starts_with("SIP/2.0 ") {
+            let call_id = header_value(&message, "Call-ID").unwrap_or_default();
+            let route = PENDING_RESPONSES.lock().unwrap().get(&call_id).map(|(tx, _)| tx.clone());
+            match route {
+                Some(tx) => {
+                    let _ = tx.send((message, from_addr));
+                }
+                None if message.starts_with("SIP/2.0 2")
+                    && message::CSeq::of(&message).is_some_and(|cseq| cseq.method == "INVITE") =>
+                {
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        resend_ack(&socket, &message).await;
+                    });
+                }
+                None => {
+                    println!("[SIP] Ignoring response for unknown Call-ID {}: {}",
+                        call_id, message.lines().next().unwrap_or(""));
+                }
+            }
+        } else {
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                handle_request(&socket, &message, from_addr).await;
+            });
+        }
+    }
 
-    // Build initial REGISTER message (without auth)
-    let from_uri = format!("sip:{}@{}", user, server);
-    let to_uri = from_uri.clone();
-    let contact_uri = format!("sip:{}@{}", user, local_addr);
-    let call_id = uuid::Uuid::new_v4().to_string();
-    let branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-    let tag = uuid::Uuid::new_v4().simple().to_string();
+}
 
-    // Build raw SIP REGISTER message
-    let register_msg = format!(
-        "REGISTER sip:{} SIP/2.0\r\n\
-         Via: SIP/2.0/UDP {};branch={}\r\n\
-         From: <{}>;tag={}\r\n\
-         To: <{}>\r\n\
-         Call-ID: {}\r\n\
-         CSeq: 1 REGISTER\r\n\
-         Contact: <{}>\r\n\
-         Max-Forwards: 70\r\n\
-         Expires: 3600\r\n\
-         User-Agent: Platypus-Phone/0.1.0\r\n\
-         Content-Length: 0\r\n\
-         \r\n",
-        server,
-        local_addr,
-        branch,
-        from_uri,
-        tag,
-        to_uri,
-        call_id,
-        contact_uri
-    );
+// Wait for the next response with this Call-ID (a ResponseRoute must be open)
+async fn recv_response(call_id: &str) -> Result<(String, SocketAddr), SipError> {
+    let responses = PENDING_RESPONSES
+        .lock()
+        .unwrap()
+        .get(call_id)
+        .map(|(_, rx)| rx.clone())
+        .ok_or_else(|| SipError::Internal(format!("No pending request for Call-ID {}", call_id)))?;
 
-    println!("[SIP] Sending initial REGISTER to {}", server);
-    println!("[SIP] Message:\n{}", register_msg);
-
-    // Resolve server address (DNS lookup if needed)
-    println!("[SIP] Resolving server address: {}", server);
-    let server_addr: std::net::SocketAddr = if server.contains(':') {
-        // Already has port
-        match server.parse() {
-            Ok(addr) => addr,
-            Err(_e) => {
-                println!("[SIP] Failed to parse address directly, trying DNS lookup...");
-                // Try DNS lookup
-                let parts: Vec<&str> = server.split(':').collect();
-                let host = parts[0];
-                let port: u16 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(5060);
-                
-                let addrs = tokio::net::lookup_host(format!("{}:{}", host, port)).await
-                    .map_err(|e| format!("DNS lookup failed: {}", e))?;
-                
-                addrs.into_iter().next()
-                    .ok_or_else(|| format!("No addresses found for {}", host))?
-            }
-        }
-    } else {
-        // Need to add port and possibly do DNS lookup
-        println!("[SIP] Performing DNS lookup for {}...", server);
-        let lookup_addr = format!("{}:5060", server);
-        
-        let addrs = tokio::net::lookup_host(&lookup_addr).await
-            .map_err(|e| format!("DNS lookup failed for {}: {}", server, e))?;
-        
-        let resolved = addrs.into_iter().next()
-            .ok_or_else(|| format!("No addresses found for {}", server))?;
-        
-        println!("[SIP] Resolved {} to {}", server, resolved);
-        resolved
-    };
+    let mut responses = responses.lock().await;
+    responses
+        .recv()
+        .await
+        .ok_or_else(|| SipError::Network("SIP receive loop stopped".to_string()))
+}
 
-    println!("[SIP] Target address: {}", server_addr);
-    println!("[SIP] Sending {} bytes...", register_msg.len());
+// Handle a request sent to us by the server or the remote party
+async fn handle_request(socket: &UdpSocket, request: &str, from_addr: SocketAddr) {
+    let method = request.split_whitespace().next().unwrap_or("");
+    println!("[SIP] Received {} request from {}", method, from_addr);
 
-    // Send initial REGISTER request
-    match socket.send_to(register_msg.as_bytes(), server_addr).await {
-        Ok(sent_bytes) => {
-            println!("[SIP] ✓ REGISTER sent successfully ({} bytes to {})", sent_bytes, server_addr);
+    match method {
+        "INVITE" if header_value(request, "Replaces").is_some() => {
+            if let Err(e) = handle_replaces_invite(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle INVITE with Replaces: {}", e);
+            }
         }
-        Err(_e) => {
-            println!("[SIP] ✗ Failed to send REGISTER: {}", _e);
-            return Err(format!("Failed to send REGISTER: {}", _e));
+        // No To tag: the INVITE starts a new call rather than refreshing ours
+        "INVITE" if header_value(request, "To").as_deref().and_then(header_tag).is_none() => {
+            if let Err(e) = handle_incoming_invite(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle incoming call: {}", e);
+            }
         }
-    }
-    
-    println!("[SIP] ✓ REGISTER sent ({} bytes to {})", register_msg.len(), server_addr);
-    println!("[SIP] Waiting for server response...");
-    
-    // Listen for response with timeout
-    let mut buf = vec![0u8; 4096];
-    let response_result = tokio::time::timeout(
-        std::time::Duration::from_secs(10),
-        socket.recv_from(&mut buf)
-    ).await;
-    
-    match response_result {
-        Ok(Ok((size, from_addr))) => {
-            buf.truncate(size);
-            let response_str = String::from_utf8_lossy(&buf);
-            println!("[SIP] Received response from {} ({} bytes):", from_addr, size);
-            println!("{}", response_str);
-            
-            // Check response code
-            if response_str.contains("SIP/2.0 401") || response_str.contains("SIP/2.0 407") {
-                println!("[SIP] Authentication required (401/407)");
-                
-                // Parse authentication parameters
-                let auth_params = parse_auth_header(&response_str)?;
-                
-                // Calculate digest response
-                let auth_header = calculate_digest_response(
-                    user,
-                    password,
-                    "REGISTER",
-                    &format!("sip:{}", server),
-                    &auth_params,
-                )?;
-                
-                println!("[SIP] Authorization header: {}", auth_header);
-                
-                // Build authenticated REGISTER with same Call-ID and tag but new branch and CSeq
-                let branch2 = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-                let auth_register_msg = format!(
-                    "REGISTER sip:{} SIP/2.0\r\n\
-                     Via: SIP/2.0/UDP {};branch={}\r\n\
-                     From: <{}>;tag={}\r\n\
-                     To: <{}>\r\n\
-                     Call-ID: {}\r\n\
-                     CSeq: 2 REGISTER\r\n\
-                     Contact: <{}>\r\n\
-                     Max-Forwards: 70\r\n\
-                     Expires: 3600\r\n\
-                     Authorization: {}\r\n\
-                     User-Agent: Platypus-Phone/0.1.0\r\n\
-                     Content-Length: 0\r\n\
-                     \r\n",
-                    server,
-                    local_addr,
-                    branch2,
-                    from_uri,
-                    tag,
-                    to_uri,
-                    call_id,
-                    contact_uri,
-                    auth_header
-                );
-                
-                println!("[SIP] Sending authenticated REGISTER...");
-                
-                socket.send_to(auth_register_msg.as_bytes(), server_addr).await
-                    .map_err(|e| format!("Failed to send authenticated REGISTER: {}", e))?;
-                
-                println!("[SIP] ✓ Authenticated REGISTER sent ({} bytes)", auth_register_msg.len());
-                println!("[SIP] Waiting for final response...");
-                
-                // Wait for final response
-                let mut final_buf = vec![0u8; 4096];
-                let final_response_result = tokio::time::timeout(
-                    std::time::Duration::from_secs(10),
-                    socket.recv_from(&mut final_buf)
-                ).await;
-                
-                match final_response_result {
-                    Ok(Ok((final_size, final_from))) => {
-                        final_buf.truncate(final_size);
-                        let final_str = String::from_utf8_lossy(&final_buf);
-                        println!("[SIP] Final response from {} ({} bytes):", final_from, final_size);
-                        println!("{}", final_str);
-                        
-                        if final_str.contains("SIP/2.0 200") {
-                            println!("[SIP] ✓✓✓ Registration successful! ✓✓✓");
-                            let mut engine = SIP_ENGINE.lock().await;
-                            engine.registered = true;
-                            Ok(())
-                        } else {
-                            Err(format!("Registration failed: {}", 
-                                final_str.lines().next().unwrap_or("Unknown error")))
-                        }
-                    }
-                    Ok(Err(e)) => Err(format!("Error receiving final response: {}", e)),
-                    Err(_) => Err("Timeout waiting for final response (10s)".to_string()),
-                }
-            } else if response_str.contains("SIP/2.0 200") {
-                println!("[SIP] ✓✓✓ Registration successful (no auth required)! ✓✓✓");
-                let mut engine = SIP_ENGINE.lock().await;
-                engine.registered = true;
-                Ok(())
-            } else {
-                Err(format!("Unexpected response: {}", 
-                    response_str.lines().next().unwrap_or("Unknown")))
+        "INVITE" => {
+            if let Err(e) = handle_reinvite(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle re-INVITE: {}", e);
+            }
+        }
+        "ACK" => handle_ack(request).await,
+        "BYE" => {
+            if let Err(e) = handle_bye(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle BYE: {}", e);
+            }
+        }
+        "CANCEL" => {
+            if let Err(e) = handle_cancel(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle CANCEL: {}", e);
+            }
+        }
+        "OPTIONS" => {
+            if let Err(e) = handle_options(socket, request, from_addr).await {
+                println!("[SIP] Failed to answer OPTIONS: {}", e);
+            }
+        }
+        "NOTIFY" => {
+            if let Err(e) = handle_notify(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle NOTIFY: {}", e);
+            }
+        }
+        "MESSAGE" => {
+            if let Err(e) = handle_message(socket, request, from_addr).await {
+                println!("[SIP] Failed to handle MESSAGE: {}", e);
             }
         }
-        Ok(Err(e)) => Err(format!("Socket error receiving response: {}", e)),
-        Err(_) => {
-            println!("[SIP] ✗ Timeout waiting for server response (10s)");
-            println!("[SIP] This could mean:");
-            println!("  - Server is not responding");
-            println!("  - Firewall is blocking UDP port 5060");
-            println!("  - Server address is incorrect");
-            println!("  - Network connectivity issue");
-            Err("Timeout waiting for server response (10s)".to_string())
+        // Methods answered here are advertised in message::ALLOW; anything
+        // else is refused so the sender stops retransmitting (RFC 3261 8.2.1)
+        _ => {
+            println!("[SIP] Rejecting {} request (not supported)", method);
+            let allow = format!("Allow: {}\r\n", message::ALLOW.join(", "));
+            let response = build_response(request, "405 Method Not Allowed", None, &allow, "");
+            if let Err(e) = send_response(socket, &response, from_addr).await {
+                println!("[SIP] Failed to reject {}: {}", method, e);
+            }
         }
     }
 }
 
-// Parse authentication parameters from WWW-Authenticate header
-fn parse_auth_header(response: &str) -> Result<std::collections::HashMap<String, String>, String> {
-    let mut params = std::collections::HashMap::new();
-    
-    // Find WWW-Authenticate or Proxy-Authenticate line
-    let auth_line = response
-        .lines()
-        .find(|line| line.starts_with("WWW-Authenticate:") || line.starts_with("Proxy-Authenticate:"))
-        .ok_or("No authentication header found")?;
+// Handle an INVITE that starts a new call: ring if we are free and tell the
+// UI who is calling. During a call, a second caller waits (call_waiting)
+// until swap_calls takes it. The INVITE is kept until answer_call, a hangup,
+// or a CANCEL.
+async fn handle_incoming_invite(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let call_id = header_value(request, "Call-ID").unwrap_or_default();
 
-    println!("[SIP] Auth header: {}", auth_line);
+    let mut engine = SIP_ENGINE.lock().await;
+    let contact = format!("Contact: <sip:{}@{}>\r\n", engine.user, engine.local_addr);
 
-    // Parse Digest parameters
-    if let Some(digest_part) = auth_line.split("Digest ").nth(1) {
-        for param in digest_part.split(',') {
-            let param = param.trim();
-            if let Some((key, value)) = param.split_once('=') {
-                let value = value.trim_matches('"');
-                params.insert(key.trim().to_string(), value.to_string());
-            }
+    // Retransmission of an INVITE we are already handling
+    let known = engine.active_dialog.iter().chain(engine.waiting_dialog.iter()).find(|dialog| dialog.call_id == call_id);
+    if let Some(dialog) = known {
+        // Answered, but our 200 OK hasn't reached the caller yet: send it
+        // again (RFC 3261 13.3.1.4)
+        if let Some((_, response, _)) = dialog.awaiting_ack.as_ref().filter(|(cseq, _, _)| response_cseq(request) == Some(*cseq)) {
+            let response = response.clone();
+            drop(engine);
+            println!("[SIP] INVITE retransmitted after we answered, resending 200 OK");
+            return send_response(socket, &response, from_addr).await;
         }
+        if dialog.incoming_invite.is_none() {
+            return Ok(());
+        }
+        let response = build_response(request, "180 Ringing", Some(&dialog.from_tag), &contact, "");
+        drop(engine);
+        return send_response(socket, &response, from_addr).await;
     }
 
-    Ok(params)
-}
+    // Do not disturb: turn the call away as busy, but let the UI log who called,
+    // whether or not we are in a call
+    if engine.dnd {
+        drop(engine);
+        let identity = caller_identity(request);
+        println!("[SIP] Do not disturb, rejecting incoming call from {}", identity.uri);
+        let response = build_response(request, "486 Busy Here", Some(&ids::tag()), "", "");
+        send_response(socket, &response, from_addr).await?;
 
-// Calculate MD5 digest response for authentication
-fn calculate_digest_response(
-    username: &str,
-    password: &str,
-    method: &str,
-    uri: &str,
-    params: &std::collections::HashMap<String, String>,
-) -> Result<String, String> {
-    let realm = params.get("realm").ok_or("Missing realm")?;
-    let nonce = params.get("nonce").ok_or("Missing nonce")?;
-    let default_algo = "MD5".to_string();
-    let algorithm = params.get("algorithm").unwrap_or(&default_algo);
-    let qop = params.get("qop");
+        events::emit("missed_call", serde_json::json!({
+            "call_id": call_id,
+            "identity": identity,
+            "reason": "dnd",
+        }));
+        return Ok(());
+    }
 
-    println!("[SIP] Calculating digest:");
-    println!("  Realm: {}", realm);
-    println!("  Nonce: {}", nonce);
-    println!("  Algorithm: {}", algorithm);
+    // One call can wait behind a call that is up; anything else (a call
+    // still being set up or parked, a second waiting call) is turned away,
+    // but the UI still learns who called
+    let waiting_behind = match engine.active_dialog.as_ref() {
+        Some(active) if active.state == CallState::Confirmed
+            && active.parked.is_none()
+            && engine.waiting_dialog.is_none() =>
+        {
+            Some(active.call_id.clone())
+        }
+        Some(_) => {
+            drop(engine);
+            let identity = caller_identity(request);
+            println!("[SIP] Already in a call, rejecting incoming call from {}", identity.uri);
+            let local_tag = ids::tag();
+            let response = build_response(request, "486 Busy Here", Some(&local_tag), "", "");
+            send_response(socket, &response, from_addr).await?;
 
-    // Calculate HA1 = MD5(username:realm:password)
-    let ha1_input = format!("{}:{}:{}", username, realm, password);
-    let ha1 = format!("{:x}", md5_compute(ha1_input.as_bytes()));
+            events::emit("missed_call", serde_json::json!({
+                "call_id": call_id,
+                "identity": identity,
+                "reason": "busy",
+            }));
+            return Ok(());
+        }
+        None => None,
+    };
 
-    // Calculate HA2 = MD5(method:uri)
-    let ha2_input = format!("{}:{}", method, uri);
-    let ha2 = format!("{:x}", md5_compute(ha2_input.as_bytes()));
+    // We need the offer to answer (INVITEs without SDP aren't supported)
+    let offer = message_body(request);
+    if choose_payload_type(&offered_payload_types(offer), &preferred_payload_types()).is_none() {
+        drop(engine);
+        println!("[SIP] Incoming call has no supported codec, rejecting");
+        let response = build_response(request, "488 Not Acceptable Here", None, "", "");
+        return send_response(socket, &response, from_addr).await;
+    }
 
-    // Calculate response
-    let response = if let Some(qop_val) = qop {
-        // With qop
-        let nc = "00000001";
-        let cnonce = format!("{:x}", md5_compute(uuid::Uuid::new_v4().to_string().as_bytes()));
-        let response_input = format!("{}:{}:{}:{}:{}:{}", ha1, nonce, nc, cnonce, qop_val, ha2);
-        let response = format!("{:x}", md5_compute(response_input.as_bytes()));
-        
-        format!(
-            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}, qop={}, nc={}, cnonce=\"{}\"",
-            username, realm, nonce, uri, response, algorithm, qop_val, nc, cnonce
-        )
-    } else {
-        // Without qop
-        let response_input = format!("{}:{}:{}", ha1, nonce, ha2);
-        let response = format!("{:x}", md5_compute(response_input.as_bytes()));
-        
-        format!(
-            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
-            username, realm, nonce, uri, response, algorithm
-        )
+    // We are the UAS: our tag goes in from_tag and the caller's in to_tag
+    let local_tag = ids::tag();
+    let from = header_value(request, "From").unwrap_or_default();
+    let remote_uri = name_addr_uri(&from).unwrap_or_default();
+    let mut dialog = Dialog {
+        call_id: call_id.clone(),
+        from_tag: local_tag.clone(),
+        to_tag: header_tag(&from),
+        cseq: 1,
+        remote_uri: remote_uri.clone(),
+        remote_target: extract_contact_uri(request),
+        local_uri: header_value(request, "To").as_deref().and_then(name_addr_uri).unwrap_or_default(),
+        route_set: extract_record_route(request),
+        state: CallState::Idle,
+        srtp_keys: None,
+        server_addr: Some(from_addr),
+        connected_at: None,
+        rtp_session: None,
+        call_audio: None,
+        audio_tx_task: None,
+        audio_rx_task: None,
+        incoming_invite: Some(request.to_string()),
+        outgoing_invite: None,
+        sent_ack: None,
+        parked: None,
+        awaiting_ack: None,
+        outgoing: false,
+        direction: MediaDirection::SendRecv,
+        attempt: CancelToken::default(),
     };
+    dialog.transition_to(CallState::Ringing).map_err(SipError::Internal)?;
+    if waiting_behind.is_some() {
+        engine.waiting_dialog = Some(dialog);
+    } else {
+        engine.active_dialog = Some(dialog);
+    }
+    drop(engine);
 
-    Ok(response)
-}
+    let response = build_response(request, "180 Ringing", Some(&local_tag), &contact, "");
+    send_response(socket, &response, from_addr).await?;
 
-// Generic function to send SIP request with automatic auth retry
-async fn send_with_auth(
-    socket: &UdpSocket,
-    initial_request: &str,
-    method: &str,
-    uri: &str,
-    username: &str,
-    password: &str,
-    server_addr: std::net::SocketAddr,
-    timeout_secs: u64,
-) -> Result<String, String> {
-    // Send initial request
-    socket.send_to(initial_request.as_bytes(), server_addr).await
-        .map_err(|e| format!("Failed to send {}: {}", method, e))?;
+    let identity = caller_identity(request);
+    println!("[SIP] Incoming call from {} (via {})", identity.uri, identity.source);
+    let diversion = diversion_chain(request);
+    if let Some(original) = diversion.first() {
+        println!("[SIP] Call was forwarded {} time(s), originally to {}", diversion.len(), original.uri);
+    }
 
-    println!("[SIP] ✓ {} sent ({} bytes)", method, initial_request.len());
+    // No ringer and no auto-answer over the call in progress
+    if let Some(active_call_id) = waiting_behind {
+        println!("[SIP] Call {} is waiting behind {}", call_id, active_call_id);
+        events::emit("call_waiting", serde_json::json!({
+            "call_id": call_id,
+            "active_call_id": active_call_id,
+            "remote_uri": remote_uri,
+            "identity": identity,
+            "diversion": diversion,
+        }));
+        return Ok(());
+    }
 
-    // Wait for responses - may receive 100 Trying before 401
-    let mut buf = vec![0u8; 4096];
-    let mut auth_challenge: Option<String> = None;
-    
-    // Keep listening for responses until we get a final response or auth challenge
-    loop {
-        let response_result = tokio::time::timeout(
-            std::time::Duration::from_secs(timeout_secs),
-            socket.recv_from(&mut buf)
-        ).await;
+    events::emit("incoming_call", serde_json::json!({
+        "call_id": call_id,
+        "remote_uri": remote_uri,
+        "identity": identity,
+        "diversion": diversion,
+    }));
+    // Opening the ringer device can take a while; the receive loop (and a
+    // CANCEL right behind this INVITE) must not wait for it
+    tokio::spawn(start_ringing(call_id.clone()));
 
-        match response_result {
-            Ok(Ok((size, _))) => {
-                buf.truncate(size);
-                let response_str = String::from_utf8_lossy(&buf).to_string();
-                
-                println!("[SIP] Received response: {}", response_str.lines().next().unwrap_or(""));
-                
-                // Check if this is a provisional response (1xx)
-                if response_str.contains("SIP/2.0 100") || 
-                   response_str.contains("SIP/2.0 180") || 
-                   response_str.contains("SIP/2.0 183") {
-                    println!("[SIP] Provisional response, waiting for final response...");
-                    buf = vec![0u8; 4096]; // Reset buffer
-                    continue; // Keep waiting
-                }
-                
-                // Check if authentication is required
-                if response_str.contains("SIP/2.0 401") || response_str.contains("SIP/2.0 407") {
-                    println!("[SIP] Authentication required (401/407), retrying with auth...");
-                    auth_challenge = Some(response_str);
-                    break;
-                }
-                
-                // Any other response (2xx, 4xx, 5xx, 6xx) - return it
-                return Ok(response_str);
-            }
-            Ok(Err(e)) => return Err(format!("Socket error: {}", e)),
-            Err(_) => return Err(format!("Timeout waiting for {} response", method)),
-        }
+    let auto_answer = SIP_ENGINE.lock().await.auto_answer.clone();
+    if auto_answer.matches(&remote_uri) {
+        println!("[SIP] Auto-answering call from {} in {}s", remote_uri, auto_answer.delay_secs);
+        events::emit("auto_answer_pending", serde_json::json!({
+            "call_id": call_id,
+            "delay_secs": auto_answer.delay_secs,
+        }));
+        schedule_auto_answer(call_id, std::time::Duration::from_secs(auto_answer.delay_secs)).await;
     }
-    
-    // If we got here, we have an auth challenge
-    if let Some(challenge) = auth_challenge {
-        // Parse auth parameters
-        let auth_params = parse_auth_header(&challenge)?;
-        
-        // Calculate digest
-        let auth_header = calculate_digest_response(
-            username,
-            password,
-            method,
-            uri,
-            &auth_params,
-        )?;
-        
-        // Rebuild request with Authorization header
-        // Find where to insert the Authorization header (before Content-Type or Content-Length)
-        let auth_request = if let Some(content_pos) = initial_request.find("Content-Type:") {
-            format!(
-                "{}Authorization: {}\r\n{}",
-                &initial_request[..content_pos],
-                auth_header,
-                &initial_request[content_pos..]
-            )
-        } else if let Some(content_pos) = initial_request.find("Content-Length:") {
-            format!(
-                "{}Authorization: {}\r\n{}",
-                &initial_request[..content_pos],
-                auth_header,
-                &initial_request[content_pos..]
-            )
-        } else if let Some(user_agent_pos) = initial_request.find("User-Agent:") {
-            // Insert after User-Agent line
-            if let Some(line_end) = initial_request[user_agent_pos..].find("\r\n") {
-                let insert_pos = user_agent_pos + line_end + 2;
-                format!(
-                    "{}Authorization: {}\r\n{}",
-                    &initial_request[..insert_pos],
-                    auth_header,
-                    &initial_request[insert_pos..]
-                )
-            } else {
-                return Err("Failed to parse request for auth insertion".to_string());
-            }
-        } else {
-            return Err("Failed to find insertion point for Authorization header".to_string());
-        };
-        
-        // Also need to update CSeq
-        let auth_request = auth_request.replace(
-            &format!("CSeq: 1 {}", method),
-            &format!("CSeq: 2 {}", method)
-        );
-        
-        // Update branch parameter
-        let new_branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-        let auth_request = if let Some(via_start) = auth_request.find("Via: ") {
-            if let Some(branch_start) = auth_request[via_start..].find("branch=") {
-                let abs_branch_start = via_start + branch_start + 7; // 7 = len("branch=")
-                if let Some(branch_end) = auth_request[abs_branch_start..].find(|c| c == ';' || c == '\r') {
-                    let abs_branch_end = abs_branch_start + branch_end;
-                    format!(
-                        "{}{}{}",
-                        &auth_request[..abs_branch_start],
-                        new_branch,
-                        &auth_request[abs_branch_end..]
-                    )
-                } else {
-                    auth_request
-                }
-            } else {
-                auth_request
-            }
-        } else {
-            auth_request
-        };
-        
-        println!("[SIP] Sending authenticated {}...", method);
-        println!("[SIP] Auth request (first 10 lines):");
-        for (i, line) in auth_request.lines().take(10).enumerate() {
-            println!("[SIP]   {}: {}", i+1, line);
-        }
-        
-        // Send authenticated request
-        socket.send_to(auth_request.as_bytes(), server_addr).await
-            .map_err(|e| format!("Failed to send authenticated {}: {}", method, e))?;
-        
-        println!("[SIP] ✓ Authenticated {} sent ({} bytes)", method, auth_request.len());
-        
-        // Wait for final response (may get provisional responses again)
-        loop {
-            let mut final_buf = vec![0u8; 4096];
-            let final_result = tokio::time::timeout(
-                std::time::Duration::from_secs(timeout_secs),
-                socket.recv_from(&mut final_buf)
-            ).await;
-            
-            match final_result {
-                Ok(Ok((final_size, _))) => {
-                    final_buf.truncate(final_size);
-                    let final_response = String::from_utf8_lossy(&final_buf).to_string();
-                    
-                    println!("[SIP] Received response: {}", final_response.lines().next().unwrap_or(""));
-                    
-                    // Skip provisional responses
-                    if final_response.contains("SIP/2.0 100") || 
-                       final_response.contains("SIP/2.0 180") || 
-                       final_response.contains("SIP/2.0 183") {
-                        println!("[SIP] Provisional response, waiting for final response...");
-                        continue;
-                    }
-                    
-                    // Return any final response
-                    return Ok(final_response);
-                }
-                Ok(Err(e)) => return Err(format!("Socket error: {}", e)),
-                Err(_) => return Err(format!("Timeout waiting for authenticated {} response", method)),
-            }
+
+    Ok(())
+}
+
+// Ring for the incoming call `call_id` on the ringer's device. A ring that
+// can't play is only logged; the call still comes in.
+async fn start_ringing(call_id: String) {
+    let ringer = crate::settings::load_ringer().unwrap_or_default();
+    let backend = SIP_ENGINE.lock().await.audio_backend.clone();
+    // Opening the device blocks
+    let ringing = match tokio::task::spawn_blocking(move || backend.ring(&ringer)).await {
+        Ok(Ok(ringing)) => ringing,
+        Ok(Err(e)) => {
+            tracing::warn!("[SIP] Can't ring: {}", e);
+            println!("[SIP] Can't ring: {}", e);
+            return;
         }
+        Err(_) => return,
+    };
+
+    // The call may have been answered or cancelled in the meantime
+    let mut engine = SIP_ENGINE.lock().await;
+    let still_ringing = engine
+        .active_dialog
+        .as_ref()
+        .is_some_and(|dialog| dialog.call_id == call_id && dialog.incoming_invite.is_some());
+    if still_ringing {
+        engine.ringing = Some(ringing);
     }
-    
-    Err("No auth challenge received".to_string())
 }
 
-// Start RTP media session after call is established
-async fn start_rtp_media(response_sdp: &str, local_port: u16) -> Result<(Arc<RtpSession>, tokio::task::JoinHandle<()>, tokio::task::JoinHandle<()>), String> {
-tracing::info!("[RTP] Starting RTP media session...");
-println!("[RTP] Starting RTP media session...");
+// Answer the ringing call `call_id` once `delay` has passed, unless it was
+// answered, rejected or cancelled in the meantime
+async fn schedule_auto_answer(call_id: String, delay: std::time::Duration) {
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
 
-// Parse remote SDP
-let (remote_ip, remote_port, payload_type) = parse_sdp(response_sdp)?;
+        {
+            let mut engine = SIP_ENGINE.lock().await;
+            engine.auto_answer_task.take();
+            let ringing = engine
+                .active_dialog
+                .as_ref()
+                .is_some_and(|dialog| dialog.call_id == call_id && dialog.incoming_invite.is_some());
+            if !ringing {
+                return;
+            }
+        }
 
-tracing::info!("[RTP] Remote endpoint: {}:{}", remote_ip, remote_port);
-tracing::info!("[RTP] Payload type: {} ({})", payload_type,
-if payload_type == 0 { "PCMU" } else if payload_type == 8 { "PCMA" } else { "Unknown" });
+        println!("[SIP] Auto-answering call {}", call_id);
+        events::emit("auto_answered", serde_json::json!({ "call_id": call_id }));
+        if let Err(e) = answer_call(None).await {
+            println!("[SIP] Auto-answer failed: {}", e);
+        }
+    });
 
-println!("[RTP] Remote endpoint: {}:{}", remote_ip, remote_port);
-println!("[RTP] Payload type: {} ({})", payload_type,
-if payload_type == 0 { "PCMU" } else if payload_type == 8 { "PCMA" } else { "Unknown" });
+    if let Some(old_task) = SIP_ENGINE.lock().await.auto_answer_task.replace(task) {
+        old_task.abort();
+    }
+}
 
-// Create remote address
-let remote_addr: std::net::SocketAddr = format!("{}:{}", remote_ip, remote_port)
-.parse()
-.map_err(|e| format!("Invalid remote address: {}", e))?;
+/// Stop a pending auto-answer so the call rings normally. Returns whether
+/// one was pending.
+pub async fn cancel_auto_answer() -> bool {
+    match SIP_ENGINE.lock().await.auto_answer_task.take() {
+        Some(task) => {
+            task.abort();
+            println!("[SIP] Auto-answer cancelled");
+            true
+        }
+        None => false,
+    }
+}
 
-// Create RTP session
-let rtp_session = Arc::new(
-RtpSession::new(local_port, remote_addr, payload_type).await?
-);
+/// Replace the auto-answer callers and delay used for new calls
+pub async fn set_auto_answer(auto_answer: AutoAnswer) {
+    SIP_ENGINE.lock().await.auto_answer = auto_answer;
+}
 
-tracing::info!("[RTP] ✓ RTP session created");
-println!("[RTP] ✓ RTP session created");
+// SIP timers (RFC 3261 17.1.1.1): round-trip estimate and the cap on
+// retransmission intervals
+const SIP_T1: std::time::Duration = std::time::Duration::from_millis(500);
+const SIP_T2: std::time::Duration = std::time::Duration::from_secs(4);
 
-// Initialize audio manager
-tracing::info!("[Audio] Initializing audio devices...");
-println!("[Audio] Initializing audio devices...");
+// How long our 200 OK to an INVITE is retransmitted waiting for the ACK
+const ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(16); // 32 * T1
 
-let mut audio_manager = match AudioManager::new() {
-    Ok(mgr) => {
-        tracing::info!("[Audio] ✓ AudioManager created");
-        mgr
-    }
-    Err(e) => {
-        tracing::error!("[Audio] ✗ Failed to create AudioManager: {}", e);
-        println!("[Audio] ✗ Failed to create AudioManager: {}", e);
-        return Err(e);
+// ACK from the caller. The one for the 200 OK that answered their call
+// (same Call-ID, our tag, the INVITE's CSeq) stops its retransmission;
+// ACKs for our 200 OK to a re-INVITE need nothing.
+async fn handle_ack(request: &str) {
+    let call_id = header_value(request, "Call-ID").unwrap_or_default();
+    let to_tag = header_value(request, "To").as_deref().and_then(header_tag);
+    let cseq = response_cseq(request);
+
+    let mut engine = SIP_ENGINE.lock().await;
+    let engine = &mut *engine;
+    // A call swapped out for the waiting one may still be owed its ACK
+    let dialog = engine.active_dialog.iter_mut().chain(engine.waiting_dialog.iter_mut()).find(|dialog| dialog.call_id == call_id);
+    let Some(dialog) = dialog else {
+        return;
+    };
+    let matches = dialog.awaiting_ack.as_ref().is_some_and(|(invite_cseq, _, _)| cseq == Some(*invite_cseq))
+        && to_tag.as_deref() == Some(dialog.from_tag.as_str());
+    if matches {
+        if let Some((_, _, acked)) = dialog.awaiting_ack.take() {
+            println!("[SIP] ✓ ACK received for {}", call_id);
+            acked.notify_one();
+        }
+    } else if dialog.awaiting_ack.is_some() {
+        println!("[SIP] Ignoring ACK that doesn't match our 200 OK (CSeq {:?}, to-tag {:?})", cseq, to_tag);
     }
-};
+}
 
-tracing::info!("[Audio] Calling init_input()...");
-println!("[Audio] Calling init_input()...");
-match audio_manager.init_input() {
-    Ok(_) => {
-        tracing::info!("[Audio] ✓ Input device initialized");
-        println!("[Audio] ✓ Input device initialized");
+// Send our 200 OK to an INVITE again on Timer G (T1, doubling up to T2)
+// until `acked` fires (RFC 3261 13.3.1.4). False if no ACK came within
+// ACK_TIMEOUT.
+async fn retransmit_until_acked(socket: &UdpSocket, response: &str, addr: SocketAddr, acked: &tokio::sync::Notify) -> bool {
+    let deadline = tokio::time::Instant::now() + ACK_TIMEOUT;
+    let mut interval = SIP_T1;
+    loop {
+        let wait = interval.min(deadline.saturating_duration_since(tokio::time::Instant::now()));
+        if tokio::time::timeout(wait, acked.notified()).await.is_ok() {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        println!("[SIP] No ACK yet, resending 200 OK");
+        if let Err(e) = socket.send_to(response.as_bytes(), addr).await {
+            println!("[SIP] Failed to resend 200 OK: {}", e);
+        }
+        interval = (interval * 2).min(SIP_T2);
     }
-    Err(e) => {
-        tracing::error!("[Audio] ✗ Failed to init input: {}", e);
-        println!("[Audio] ✗ Failed to init input: {}", e);
-        return Err(e);
+}
+
+// BYE from the far end (RFC 3261 15.1.2): the call, active or waiting
+// behind it, is over. 481 if we have no such dialog.
+async fn handle_bye(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let call_id = header_value(request, "Call-ID").unwrap_or_default();
+
+    let mut engine = SIP_ENGINE.lock().await;
+    let ours = |dialog: &Option<Dialog>| dialog.as_ref().is_some_and(|dialog| dialog.call_id == call_id);
+    let dialog = if ours(&engine.active_dialog) {
+        if let Some(task) = engine.call_limit_task.take() {
+            task.abort();
+        }
+        if let Some(task) = engine.auto_answer_task.take() {
+            task.abort();
+        }
+        engine.ringing = None;
+        engine.active_dialog.take()
+    } else if ours(&engine.waiting_dialog) {
+        engine.waiting_dialog.take()
+    } else {
+        None
+    };
+    drop(engine);
+    let Some(mut dialog) = dialog else {
+        let response = build_response(request, "481 Call/Transaction Does Not Exist", None, "", "");
+        return send_response(socket, &response, from_addr).await;
+    };
+
+    println!("[SIP] Remote party hung up call {}", call_id);
+
+    // Our 200 OK may still be going out waiting for an ACK that won't come
+    if let Some((_, _, acked)) = dialog.awaiting_ack.take() {
+        acked.notify_one();
     }
+    let response = build_response(request, "200 OK", Some(&dialog.from_tag), "", "");
+    let result = send_response(socket, &response, from_addr).await;
+
+    stop_dialog_media(&dialog).await;
+    dialog.terminate(Some("remote"));
+    println!("[SIP] ✓ Call ended by the remote party ({}s)", dialog.duration_secs());
+    result
 }
 
-tracing::info!("[Audio] Calling init_output()...");
-match audio_manager.init_output() {
-Ok(_) => tracing::info!("[Audio] ✓ Output device initialized"),
-Err(e) => {
-tracing::error!("[Audio] ✗ Failed to init output: {}", e);
-return Err(e);
+// OPTIONS (a capability query or a proxy's keepalive, RFC 3261 11.2):
+// 200 OK with what we accept
+async fn handle_options(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let capabilities = format!(
+        "Allow: {}\r\nSupported: {}\r\nAccept: application/sdp\r\n",
+        message::ALLOW.join(", "),
+        message::SUPPORTED.join(", ")
+    );
+    let response = build_response(request, "200 OK", Some(&ids::tag()), &capabilities, "");
+    send_response(socket, &response, from_addr).await
 }
+
+// CANCEL of an inbound call that hasn't been answered yet (RFC 3261 9.2)
+async fn handle_cancel(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let call_id = header_value(request, "Call-ID").unwrap_or_default();
+
+    let mut engine = SIP_ENGINE.lock().await;
+    let ringing = |dialog: &Option<Dialog>| {
+        matches!(dialog.as_ref(), Some(dialog) if dialog.call_id == call_id && dialog.incoming_invite.is_some())
+    };
+    // The caller may be the one ringing or one waiting behind our call
+    let dialog = if ringing(&engine.active_dialog) {
+        engine.ringing = None;
+        engine.active_dialog.take()
+    } else if ringing(&engine.waiting_dialog) {
+        engine.waiting_dialog.take()
+    } else {
+        None
+    };
+    drop(engine);
+    let Some(mut dialog) = dialog else {
+        let response = build_response(request, "481 Call/Transaction Does Not Exist", None, "", "");
+        return send_response(socket, &response, from_addr).await;
+    };
+
+    println!("[SIP] Caller cancelled call {}", call_id);
+
+    let response = build_response(request, "200 OK", Some(&dialog.from_tag), "", "");
+    send_response(socket, &response, from_addr).await?;
+
+    let invite = dialog.incoming_invite.as_deref().unwrap_or_default();
+    let response = build_response(invite, "487 Request Terminated", Some(&dialog.from_tag), "", "");
+    send_response(socket, &response, from_addr).await?;
+
+    dialog.terminate(Some("cancelled"));
+    Ok(())
 }
 
-// Start audio capture
-tracing::info!("[Audio] Starting audio capture...");
-let (input_stream, mut audio_rx) = match audio_manager.start_capture() {
-Ok(result) => {
-tracing::info!("[Audio] ✓ Audio capture started");
-result
+// Who an inbound call is from, as shown to the user
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct CallerIdentity {
+    uri: String,
+    display_name: Option<String>,
+    // Header the identity was taken from
+    source: &'static str,
 }
-Err(e) => {
-tracing::error!("[Audio] ✗ Failed to start capture: {}", e);
-return Err(e);
+
+// Carriers put the real caller in P-Asserted-Identity (RFC 3325) or the older
+// Remote-Party-ID, so those win over From. A Remote-Party-ID describing the
+// called party (party=called) is not caller ID.
+fn caller_identity(request: &str) -> CallerIdentity {
+    let asserted = header_values(request, "P-Asserted-Identity")
+        .into_iter()
+        .map(|value| (value, "P-Asserted-Identity"));
+    let remote_party = header_values(request, "Remote-Party-ID")
+        .into_iter()
+        .filter(|value| match header_param(value, "party") {
+            Some(party) => party.eq_ignore_ascii_case("calling"),
+            None => true,
+        })
+        .map(|value| (value, "Remote-Party-ID"));
+    let from = header_value(request, "From")
+        .map(|value| (value, "From"));
+
+    asserted
+        .chain(remote_party)
+        .chain(from)
+        .find_map(|(value, source)| {
+            Some(CallerIdentity {
+                uri: name_addr_uri(&value)?,
+                display_name: display_name(&value),
+                source,
+            })
+        })
+        .unwrap_or(CallerIdentity {
+            uri: String::new(),
+            display_name: None,
+            source: "From",
+        })
 }
-};
 
-// Start audio playback
-tracing::info!("[Audio] Starting audio playback...");
-let (output_stream, audio_tx) = match audio_manager.start_playback() {
-Ok(result) => {
-tracing::info!("[Audio] ✓ Audio playback started");
-result
+// One hop of call forwarding: the party that redirected the call and why
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct DiversionHop {
+    uri: String,
+    // RFC 5806 reason ("user-busy", "no-answer", "unconditional", ...)
+    reason: Option<String>,
+    // Number of diversions this hop stands for, when the server says
+    counter: Option<u32>,
 }
-Err(e) => {
-tracing::error!("[Audio] ✗ Failed to start playback: {}", e);
-return Err(e);
+
+// How an inbound call was forwarded to us, original called party first.
+// Diversion (RFC 5806) lists the most recent redirection first; without it,
+// fall back to History-Info (RFC 7044), whose entries are the targets the
+// request was sent to in order, ending with us.
+fn diversion_chain(request: &str) -> Vec<DiversionHop> {
+    let diversion = header_values(request, "Diversion");
+    if !diversion.is_empty() {
+        let mut chain: Vec<DiversionHop> = diversion
+            .iter()
+            .filter_map(|value| {
+                Some(DiversionHop {
+                    uri: name_addr_uri(value)?,
+                    reason: header_param(value, "reason"),
+                    counter: header_param(value, "counter").and_then(|counter| counter.parse().ok()),
+                })
+            })
+            .collect();
+        chain.reverse();
+        return chain;
+    }
+
+    let mut entries: Vec<(f64, String)> = header_values(request, "History-Info")
+        .iter()
+        .filter_map(|value| {
+            let index = header_param(value, "index").unwrap_or_default();
+            Some((history_index_key(&index), name_addr_uri(value)?))
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // The cause on each retargeted URI (RFC 4458) says why the previous
+    // target diverted; the last entry is us
+    entries
+        .windows(2)
+        .map(|pair| DiversionHop {
+            uri: strip_uri_params(&pair[0].1),
+            reason: history_cause(&pair[1].1).map(|cause| diversion_reason(cause).to_string()),
+            counter: None,
+        })
+        .collect()
 }
-};
 
-tracing::info!("[Audio] ✓ Audio devices initialized");
-println!("[Audio] ✓ Audio devices initialized");
-    
-    // Keep streams alive by leaking them (they'll be cleaned up when tasks abort)
-    // This is necessary because Stream is not Send and cannot be moved into tokio::spawn
-    std::mem::forget(input_stream);
-    std::mem::forget(output_stream);
-    
-    // Create high-quality resampler for audio processing
-    // Assuming 48kHz audio device (typical) and 8kHz VoIP (standard)
-    // Chunk size: 960 samples = 20ms at 48kHz
-    tracing::info!("[Resample] Creating audio resampler (48kHz ↔ 8kHz)");
-    println!("[Resample] Creating audio resampler (48kHz ↔ 8kHz)");
-    
-    let resampler = match AudioResampler::new(48000, 8000, 960) {
-        Ok(r) => {
-            tracing::info!("[Resample] ✓ High-quality resampler created");
-            println!("[Resample] ✓ High-quality resampler created (using rubato)");
-            Arc::new(r)
-        }
-        Err(e) => {
-            tracing::warn!("[Resample] Failed to create rubato resampler: {}", e);
-            println!("[Resample] ⚠ Failed to create rubato resampler: {}", e);
-            println!("[Resample] Falling back to simple resampler");
-            return Err(format!("Failed to create resampler: {}", e));
-        }
-    };
-    
-    // Spawn TX task: Microphone → Downsample → Encode → RTP → Network
-    let rtp_tx = rtp_session.clone();
-    let tx_payload_type = payload_type; // Capture for move
-    let tx_resampler = resampler.clone();
-    let tx_task = tokio::spawn(async move {
-        tracing::info!("[Audio] TX task started (Mic → RTP with high-quality resampling)");
-        println!("[Audio] TX task started (Mic → RTP with high-quality resampling)");
-        let mut packet_count = 0u64;
-        
-        while let Some(samples) = audio_rx.recv().await {
-            tracing::debug!("[Audio] TX: Received {} samples from mic", samples.len());
-            
-            // High-quality downsampling: 48kHz → 8kHz using rubato
-            let downsampled = match tx_resampler.downsample(&samples) {
-                Ok(d) => d,
-                Err(e) => {
-                    tracing::error!("[Resample] TX downsample error: {}", e);
-                    eprintln!("[Resample] TX downsample error: {}", e);
-                    continue; // Skip this packet
-                }
-            };
-            
-            tracing::debug!("[Audio] TX: Downsampled {} → {} samples", samples.len(), downsampled.len());
-            
-            // Encode samples to G.711
-            let encoded: Vec<u8> = if tx_payload_type == 0 {
-                // PCMU (μ-law)
-                downsampled.iter().map(|&s| g711::encode_ulaw(s)).collect()
-            } else {
-                // PCMA (A-law)
-                downsampled.iter().map(|&s| g711::encode_alaw(s)).collect()
-            };
-            
-            // Send RTP packet
-            if let Err(e) = rtp_tx.send_audio(&encoded).await {
-                tracing::error!("[RTP] TX error: {}", e);
-                eprintln!("[RTP] TX error: {}", e);
-                break;
-            }
-            
-            packet_count += 1;
-            if packet_count % 50 == 0 {
-                tracing::info!("[RTP] Sent {} packets", packet_count);
-                println!("[RTP] Sent {} packets", packet_count);
-            }
-        }
-        
-        tracing::info!("[Audio] TX task ended");
-        println!("[Audio] TX task ended");
-    });
-    
-    // Spawn RX task: Network → RTP → Decode → Upsample → Speaker
-    let rtp_rx = rtp_session.clone();
-    let rx_payload_type = payload_type; // Capture for move
-    let rx_resampler = resampler.clone();
-    let rx_task = tokio::spawn(async move {
-        tracing::info!("[Audio] RX task started (RTP → Speaker with high-quality resampling)");
-        println!("[Audio] RX task started (RTP → Speaker with high-quality resampling)");
-        let mut packet_count = 0u64;
-        
-        loop {
-            match rtp_rx.receive_audio().await {
-                Ok(encoded) => {
-                    tracing::debug!("[Audio] RX: Received {} encoded bytes", encoded.len());
-                    
-                    // Decode G.711 to PCM
-                    let decoded: Vec<i16> = if rx_payload_type == 0 {
-                        // PCMU (μ-law)
-                        encoded.iter().map(|&b| g711::decode_ulaw(b)).collect()
-                    } else {
-                        // PCMA (A-law)
-                        encoded.iter().map(|&b| g711::decode_alaw(b)).collect()
-                    };
-                    
-                    tracing::debug!("[Audio] RX: Decoded to {} samples", decoded.len());
-                    
-                    // High-quality upsampling: 8kHz → 48kHz using rubato
-                    let upsampled = match rx_resampler.upsample(&decoded) {
-                        Ok(u) => u,
-                        Err(e) => {
-                            tracing::error!("[Resample] RX upsample error: {}", e);
-                            eprintln!("[Resample] RX upsample error: {}", e);
-                            continue; // Skip this packet
-                        }
-                    };
-                    
-                    tracing::debug!("[Audio] RX: Upsampled {} → {} samples", decoded.len(), upsampled.len());
-                    
-                    // Send to speaker
-                    if let Err(e) = audio_tx.send(upsampled).await {
-                        tracing::error!("[Audio] Playback error: {}", e);
-                        eprintln!("[Audio] Playback error: {}", e);
-                        break;
-                    }
-                    
-                    packet_count += 1;
-                    if packet_count % 50 == 0 {
-                        tracing::info!("[RTP] Received {} packets", packet_count);
-                        println!("[RTP] Received {} packets", packet_count);
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("[RTP] RX error: {}", e);
-                    eprintln!("[RTP] RX error: {}", e);
-                    break;
-                }
-            }
-        }
-        
-        tracing::info!("[Audio] RX task ended");
-        println!("[Audio] RX task ended");
-    });
-    
-    println!("[RTP] ✓✓✓ RTP media session active! ✓✓✓");
-    
-    Ok((rtp_session, tx_task, rx_task))
+// Sort key for a History-Info index ("1", "1.1", "1.1.2"): entries come in
+// order already, but the index is authoritative
+fn history_index_key(index: &str) -> f64 {
+    index
+        .split('.')
+        .enumerate()
+        .map(|(depth, part)| part.trim().parse::<f64>().unwrap_or(0.0) / 100f64.powi(depth as i32))
+        .sum()
 }
 
-pub async fn make_call(number: &str) -> Result<(), String> {
-    let mut engine = SIP_ENGINE.lock().await;
+// cause of a retargeted URI, as a URI parameter (sip:bob@host;cause=486) or
+// inside an escaped Reason header (?Reason=SIP%3Bcause%3D486)
+fn history_cause(uri: &str) -> Option<u16> {
+    let uri = uri.replace("%3B", ";").replace("%3b", ";").replace("%3D", "=").replace("%3d", "=");
+    uri.split(['?', ';', '&']).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("cause") {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
 
-    if !engine.registered {
-        return Err("Not registered".to_string());
+// RFC 4458 cause codes as RFC 5806 Diversion reasons
+fn diversion_reason(cause: u16) -> &'static str {
+    match cause {
+        302 => "unconditional",
+        408 => "no-answer",
+        486 => "user-busy",
+        480 | 487 => "deflection",
+        503 => "unavailable",
+        _ => "unknown",
     }
+}
 
-    let socket = engine.socket.as_ref().ok_or("SIP not initialized")?.clone();
-    let server = engine.server.clone();
+// URI without its parameters and headers
+fn strip_uri_params(uri: &str) -> String {
+    uri.split(['?', ';']).next().unwrap_or(uri).to_string()
+}
+
+// Handle an INVITE inside the confirmed dialog: answer the new offer and move
+// the running RTP session to the new address/codec without tearing down the call
+async fn handle_reinvite(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let call_id = header_value(request, "Call-ID").unwrap_or_default();
+
+    let mut guard = SIP_ENGINE.lock().await;
+    let engine = &mut *guard;
     let user = engine.user.clone();
     let local_addr = engine.local_addr.clone();
 
-    println!("[SIP] Making call to: {}", number);
-    println!("[SIP] From: {}@{}", user, server);
-
-    // Build destination URI
-    let dest_uri = if number.starts_with("sip:") {
-        number.to_string()
-    } else {
-        format!("sip:{}@{}", number, server)
+    // The call may be the one on hold behind the active call (call waiting)
+    let active = engine.active_dialog.iter_mut().map(|dialog| (dialog, false));
+    let waiting = engine.waiting_dialog.iter_mut().map(|dialog| (dialog, true));
+    let found = active.chain(waiting).find(|(dialog, _)| dialog.call_id == call_id && dialog.state == CallState::Confirmed);
+    let Some((dialog, waiting)) = found else {
+        drop(guard);
+        println!("[SIP] INVITE does not match a confirmed dialog (Call-ID: {})", call_id);
+        let response = build_response(request, "481 Call/Transaction Does Not Exist", None, "", "");
+        return send_response(socket, &response, from_addr).await;
     };
 
-    println!("[SIP] Destination URI: {}", dest_uri);
+    println!("[SIP] re-INVITE for Call-ID {}", call_id);
 
-    // Create dialog for this call
-    let call_id = uuid::Uuid::new_v4().to_string();
-    let from_tag = uuid::Uuid::new_v4().simple().to_string();
-    let from_uri = format!("sip:{}@{}", user, server);
-    
-    let dialog = Dialog {
-        call_id: call_id.clone(),
-        from_tag: from_tag.clone(),
-        to_tag: None,
-        cseq: 1,
-        remote_uri: dest_uri.clone(),
-        local_uri: from_uri.clone(),
-        state: CallState::Calling,
-        rtp_session: None,
-        audio_tx_task: None,
-        audio_rx_task: None,
+    // re-INVITE is a target refresh request (RFC 3261 12.2.2)
+    if let Some(remote_target) = extract_contact_uri(request) {
+        dialog.remote_target = Some(remote_target);
+    }
+
+    let rtp_session = dialog.rtp_session.clone();
+    let srtp_keys = dialog.srtp_keys.clone();
+    // What we want before the peer's offer: on hold while parked or waiting
+    let held = waiting || dialog.parked.is_some();
+    let local_direction = if held { dialog.direction.hold() } else { dialog.direction };
+    drop(guard);
+
+    let rtp_session = match rtp_session {
+        Some(session) => session,
+        None => {
+            let response = build_response(request, "488 Not Acceptable Here", None, "", "");
+            send_response(socket, &response, from_addr).await?;
+            return Err(SipError::Protocol("re-INVITE received but the call has no media session".to_string()));
+        }
     };
-    
-    engine.active_dialog = Some(dialog);
-    drop(engine);
 
-    // Generate SDP (Session Description Protocol)
-    let local_ip = local_addr.split(':').next().unwrap_or("127.0.0.1");
-    
-    // Allocate RTP port dynamically by binding to port 0 and getting the assigned port
-    let rtp_port = {
-        let temp_socket = std::net::UdpSocket::bind("0.0.0.0:0")
-            .map_err(|e| format!("Failed to allocate RTP port: {}", e))?;
-        let port = temp_socket.local_addr()
-            .map_err(|e| format!("Failed to get RTP port: {}", e))?
-            .port();
-        drop(temp_socket); // Release the socket so RtpSession can bind to it
-        port
+    let offer = message_body(request);
+
+    // An offer-less re-INVITE keeps the current media; answer with what we use now
+    let (payload_type, direction) = if offer.trim().is_empty() {
+        (rtp_session.payload_type().await, local_direction)
+    } else {
+        match apply_remote_offer(&rtp_session, offer).await {
+            Ok(Some(pt)) => (pt, local_direction.negotiate(parse_direction(offer))),
+            Ok(None) => {
+                let response = build_response(request, "488 Not Acceptable Here", None, "", "");
+                send_response(socket, &response, from_addr).await?;
+                return Ok(());
+            }
+            // The transaction still needs a final response
+            Err(e) => {
+                let response = build_response(request, "488 Not Acceptable Here", None, "", "");
+                send_response(socket, &response, from_addr).await?;
+                return Err(e);
+            }
+        }
     };
-    
-    tracing::info!("[SIP] Allocated RTP port: {}", rtp_port);
-    println!("[SIP] Allocated RTP port: {}", rtp_port);
-    
-    let session_id = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    
-    let sdp = format!(
-        "v=0\r\n\
-         o=- {} {} IN IP4 {}\r\n\
-         s=Platypus Phone Call\r\n\
-         c=IN IP4 {}\r\n\
-         t=0 0\r\n\
-         m=audio {} RTP/AVP 0 8 101\r\n\
-         a=rtpmap:0 PCMU/8000\r\n\
-         a=rtpmap:8 PCMA/8000\r\n\
-         a=rtpmap:101 telephone-event/8000\r\n\
-         a=sendrecv\r\n",
-        session_id,
-        session_id,
-        local_ip,
-        local_ip,
-        rtp_port
-    );
 
-    // Build INVITE request
-    let branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-    let contact_uri = format!("sip:{}@{}", user, local_addr);
-    
-    let invite_msg = format!(
-        "INVITE {} SIP/2.0\r\n\
-         Via: SIP/2.0/UDP {};branch={}\r\n\
-         From: <{}>;tag={}\r\n\
-         To: <{}>\r\n\
-         Call-ID: {}\r\n\
-         CSeq: 1 INVITE\r\n\
-         Contact: <{}>\r\n\
-         Max-Forwards: 70\r\n\
-         Content-Type: application/sdp\r\n\
-         User-Agent: Platypus-Phone/0.1.0\r\n\
-         Content-Length: {}\r\n\
-         \r\n\
-         {}",
-        dest_uri,
-        local_addr,
-        branch,
-        from_uri,
-        from_tag,
-        dest_uri,
-        call_id,
-        contact_uri,
-        sdp.len(),
-        sdp
-    );
+    let local_ip = &local_host(&local_addr);
+    let srtp_keys = if rtp_session.is_secure().await { srtp_keys } else { None };
+    let (media_ip, media_port) = session_media_addr(&rtp_session, local_ip);
+    let sdp = build_sdp_answer(&media_ip, media_port, payload_type, srtp_keys.as_ref(), direction);
+    let contact = format!("Contact: <sip:{}@{}>\r\n", user, local_addr);
+    let response = build_response(request, "200 OK", None, &contact, &sdp);
 
-    println!("[SIP] Sending INVITE...");
-    println!("[SIP] Message:\n{}", invite_msg);
+    // The peer putting us on hold (sendonly/inactive) stops our audio too;
+    // a call we hold stays silent whatever the peer offers
+    rtp_session.set_direction(if held { MediaDirection::Inactive } else { direction }).await;
+    send_response(socket, &response, from_addr).await?;
+    println!("[SIP] ✓ re-INVITE answered (PT {}, {})", payload_type, direction.attribute());
 
-    // Resolve server address
-    let server_addr: std::net::SocketAddr = if server.contains(':') {
-        match server.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                let parts: Vec<&str> = server.split(':').collect();
-                let host = parts[0];
-                let port: u16 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(5060);
-                let addrs = tokio::net::lookup_host(format!("{}:{}", host, port)).await
-                    .map_err(|e| format!("DNS lookup failed: {}", e))?;
-                addrs.into_iter().next()
-                    .ok_or_else(|| format!("No addresses found for {}", host))?
-            }
+    Ok(())
+}
+
+// Point a running RTP session at the media described by a new SDP offer.
+// Returns the payload type to answer with, or None if the offer is not
+// acceptable (none of our codecs, no active audio stream, or it drops
+// encryption) and should get a 488.
+async fn apply_remote_offer(rtp_session: &RtpSession, offer: &str) -> Result<Option<u8>, SipError> {
+    let payload_type = match choose_payload_type(&offered_payload_types(offer), &preferred_payload_types()) {
+        Some(pt) => pt,
+        None => {
+            println!("[SIP] Offer has no supported codec, rejecting");
+            return Ok(None);
         }
-    } else {
-        let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host(format!("{}:5060", server)).await
-            .map_err(|e| format!("DNS lookup failed: {}", e))?
-            .collect();
-        *addrs.first()
-            .ok_or_else(|| format!("No addresses found for {}", server))?
     };
 
-    // Get password for auth
-    let password = {
-        let engine = SIP_ENGINE.lock().await;
-        engine.password.clone()
+    let (remote_ip, remote_port, _, _) = match parse_sdp(offer) {
+        Ok(media) => media,
+        Err(e) => {
+            // e.g. the peer rejected (port 0) every audio stream
+            println!("[SIP] Offer has no usable audio stream ({}), rejecting", e);
+            return Ok(None);
+        }
     };
+    let remote_ip: std::net::IpAddr = remote_ip
+        .parse()
+        .map_err(|e| SipError::Protocol(format!("Invalid remote address: {}", e)))?;
 
-    // Send INVITE with auth handling
-    let first_response = send_with_auth(
-        &socket,
-        &invite_msg,
-        "INVITE",
-        &dest_uri,
-        &user,
-        &password,
-        server_addr,
-        30,
-    ).await?;
+    // Keep encrypting; pick up the peer's key if it sent a new one
+    if rtp_session.is_secure().await {
+        match parse_crypto_attribute(offer) {
+            Some(remote_keys) => rtp_session.update_srtp_rx(&remote_keys).await,
+            None => {
+                println!("[SRTP] Offer drops encryption, rejecting");
+                return Ok(None);
+            }
+        }
+    }
 
-    println!("[SIP] First response:");
-    println!("{}", first_response);
+    if remote_ip.is_unspecified() {
+        // 0.0.0.0 is the old-style hold offer; keep sending to the known address
+        println!("[SIP] Offer has 0.0.0.0, keeping current remote address");
+    } else {
+        let clock_rate = rtpmap_clock_rate(offer, payload_type);
+        rtp_session.retarget(SocketAddr::new(remote_ip, remote_port), payload_type, clock_rate).await;
+    }
+    rtp_session.set_event_payload_type(telephone_event_payload_type(offer).filter(|_| offer_telephone_event())).await;
+    Ok(Some(payload_type))
+}
+
+// Dialog named by the Replaces header of an inbound INVITE (RFC 3891).
+// From the recipient's point of view, to-tag is our local tag and from-tag
+// the remote one.
+#[derive(Debug, Clone, PartialEq)]
+struct Replaces {
+    call_id: String,
+    to_tag: String,
+    from_tag: String,
+    early_only: bool,
+}
+
+// "call-id;to-tag=abc;from-tag=def[;early-only]"
+fn parse_replaces(value: &str) -> Option<Replaces> {
+    let mut parts = value.split(';');
+    let call_id = parts.next()?.trim();
+    if call_id.is_empty() {
+        return None;
+    }
+
+    let mut to_tag = None;
+    let mut from_tag = None;
+    let mut early_only = false;
+    for param in parts {
+        let param = param.trim();
+        match param.split_once('=') {
+            Some((name, tag)) if name.trim().eq_ignore_ascii_case("to-tag") => to_tag = Some(tag.trim().to_string()),
+            Some((name, tag)) if name.trim().eq_ignore_ascii_case("from-tag") => from_tag = Some(tag.trim().to_string()),
+            None if param.eq_ignore_ascii_case("early-only") => early_only = true,
+            _ => {}
+        }
+    }
+
+    Some(Replaces {
+        call_id: call_id.to_string(),
+        to_tag: to_tag?,
+        from_tag: from_tag?,
+        early_only,
+    })
+}
+
+// Handle an INVITE with Replaces (call pickup, attended transfer completion):
+// answer it right away with the media of the dialog it names, then hang up
+// that dialog. Unknown dialogs get 481.
+async fn handle_replaces_invite(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let replaces = match header_value(request, "Replaces").as_deref().and_then(parse_replaces) {
+        Some(replaces) => replaces,
+        None => {
+            let response = build_response(request, "400 Bad Request", None, "", "");
+            return send_response(socket, &response, from_addr).await;
+        }
+    };
+
+    println!("[SIP] INVITE replaces dialog {}", replaces.call_id);
+
+    let engine = SIP_ENGINE.lock().await;
+    let user = engine.user.clone();
+    let local_addr = engine.local_addr.clone();
+
+    let old_dialog = match engine.active_dialog.as_ref() {
+        Some(dialog)
+            if dialog.call_id == replaces.call_id
+                && dialog.from_tag == replaces.to_tag
+                && dialog.to_tag.as_deref() == Some(replaces.from_tag.as_str()) =>
+        {
+            dialog.clone()
+        }
+        _ => {
+            drop(engine);
+            println!("[SIP] Replaces names no dialog of ours, rejecting");
+            let response = build_response(request, "481 Call/Transaction Does Not Exist", None, "", "");
+            return send_response(socket, &response, from_addr).await;
+        }
+    };
+    drop(engine);
+
+    // early-only may only replace a call that hasn't been answered (RFC 3891 3)
+    if old_dialog.state != CallState::Confirmed || replaces.early_only {
+        let status = if old_dialog.state == CallState::Confirmed {
+            "486 Busy Here"
+        } else {
+            "481 Call/Transaction Does Not Exist"
+        };
+        let response = build_response(request, status, None, "", "");
+        return send_response(socket, &response, from_addr).await;
+    }
+
+    // The new call takes over the running media session
+    let offer = message_body(request);
+    let payload_type = match &old_dialog.rtp_session {
+        Some(rtp_session) if !offer.trim().is_empty() => apply_remote_offer(rtp_session, offer).await?,
+        _ => None,
+    };
+    let (rtp_session, payload_type) = match (old_dialog.rtp_session.clone(), payload_type) {
+        (Some(rtp_session), Some(payload_type)) => (rtp_session, payload_type),
+        _ => {
+            let response = build_response(request, "488 Not Acceptable Here", None, "", "");
+            return send_response(socket, &response, from_addr).await;
+        }
+    };
+
+    let local_tag = ids::tag();
+    let local_ip = &local_host(&local_addr);
+    let srtp_keys = if rtp_session.is_secure().await { old_dialog.srtp_keys.clone() } else { None };
+    let (media_ip, media_port) = session_media_addr(&rtp_session, local_ip);
+    let sdp = build_sdp_answer(&media_ip, media_port, payload_type, srtp_keys.as_ref(), MediaDirection::SendRecv);
+    let contact = format!("Contact: <sip:{}@{}>\r\n", user, local_addr);
+    let response = build_response(request, "200 OK", Some(&local_tag), &contact, &sdp);
+
+    // We are the UAS of the new dialog: our tag goes in from_tag and the
+    // peer's in to_tag, matching how in-dialog requests are built
+    let mut new_dialog = Dialog {
+        call_id: header_value(request, "Call-ID").unwrap_or_default(),
+        from_tag: local_tag,
+        to_tag: header_value(request, "From").as_deref().and_then(header_tag),
+        cseq: 1,
+        remote_uri: header_value(request, "From").as_deref().and_then(name_addr_uri).unwrap_or_default(),
+        remote_target: extract_contact_uri(request),
+        local_uri: header_value(request, "To").as_deref().and_then(name_addr_uri).unwrap_or_default(),
+        route_set: extract_record_route(request),
+        state: CallState::Idle,
+        srtp_keys: old_dialog.srtp_keys.clone(),
+        server_addr: Some(from_addr),
+        connected_at: Some(std::time::Instant::now()),
+        rtp_session: Some(rtp_session),
+        call_audio: old_dialog.call_audio.clone(),
+        audio_tx_task: old_dialog.audio_tx_task.clone(),
+        audio_rx_task: old_dialog.audio_rx_task.clone(),
+        incoming_invite: None,
+        outgoing_invite: None,
+        sent_ack: None,
+        parked: None,
+        awaiting_ack: None,
+        outgoing: false,
+        direction: old_dialog.direction,
+        attempt: CancelToken::default(),
+    };
+    let remote_uri = new_dialog.remote_uri.clone();
+    // The old call ends for the UI before the new one comes up
+    let mut old_dialog = old_dialog;
+    old_dialog.terminate(Some("replaced"));
+    new_dialog.transition_to(CallState::Confirmed).map_err(SipError::Internal)?;
+    SIP_ENGINE.lock().await.active_dialog = Some(new_dialog);
+
+    send_response(socket, &response, from_addr).await?;
+    println!("[SIP] ✓ Replacing call answered (PT {})", payload_type);
+
+    events::emit("call_replaced", serde_json::json!({
+        "replaced_call_id": old_dialog.call_id,
+        "remote_uri": remote_uri,
+    }));
+
+    // The media now belongs to the new call, so only the signaling is torn down
+    send_bye(socket, &old_dialog).await
+}
+
+// Payload types of the configured codec preferences, most preferred first
+fn preferred_payload_types() -> Vec<u8> {
+    let codecs = crate::settings::load_codec_preferences().unwrap_or_default();
+    let mut payload_types: Vec<u8> = Vec::new();
+    for pt in codecs.iter().filter_map(|name| codec_payload_type(name)) {
+        if !payload_types.contains(&pt) {
+            payload_types.push(pt);
+        }
+    }
+    if payload_types.is_empty() {
+        payload_types = crate::rtp::SUPPORTED_CODECS.iter().map(|codec| codec.payload_type()).collect();
+    }
+    payload_types
+}
+
+// Whether our SDP offers telephone-events for DTMF
+fn offer_telephone_event() -> bool {
+    crate::settings::load_offer_telephone_event().unwrap_or(true)
+}
+
+// Codecs for an SDP body from the given payload types, with
+// telephone-event appended only when it's offered
+fn sdp_codecs(payload_types: &[u8], telephone_event: bool) -> Vec<Codec> {
+    let mut codecs: Vec<Codec> = payload_types
+        .iter()
+        .filter_map(|pt| Codec::from_payload_type(*pt))
+        .filter(|codec| *codec != Codec::TelephoneEvent)
+        .collect();
+    if telephone_event {
+        codecs.push(Codec::TelephoneEvent);
+    }
+    codecs
+}
+
+// `b=AS:` line for a bandwidth limit (empty when unlimited)
+fn bandwidth_line(limit_kbps: Option<u32>) -> String {
+    match limit_kbps {
+        Some(kbps) => format!("b=AS:{}\r\n", kbps),
+        None => String::new(),
+    }
+}
+
+// Bandwidth limit for a call: the tighter of ours and the peer's
+fn negotiated_bandwidth(remote_sdp: &str) -> Option<u32> {
+    let local = crate::settings::load_bandwidth_limit().unwrap_or(None);
+    match (local, parse_bandwidth(remote_sdp)) {
+        (Some(local), Some(remote)) => Some(local.min(remote)),
+        (local, remote) => local.or(remote),
+    }
+}
+
+// Host part of our "ip:port" signaling address (without IPv6 brackets)
+fn local_host(local_addr: &str) -> String {
+    match local_addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => local_addr.split(':').next().unwrap_or("127.0.0.1").to_string(),
+    }
+}
+
+// SDP address type (o= and c= lines) for an address
+fn sdp_address_type(ip: &str) -> &'static str {
+    if ip.contains(':') { "IP6" } else { "IP4" }
+}
+
+// SDP answer for a single codec (plus telephone-event when offered) on our RTP port,
+// with our SRTP key when the media is encrypted
+fn build_sdp_answer(
+    local_ip: &str,
+    rtp_port: u16,
+    payload_type: u8,
+    srtp_keys: Option<&SrtpKeys>,
+    direction: MediaDirection,
+) -> String {
+    let session_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (formats, rtpmaps) = sdp_formats(&sdp_codecs(&[payload_type], offer_telephone_event()));
+    let (profile, crypto) = match srtp_keys {
+        Some(keys) => ("RTP/SAVP", format!("{}\r\n", keys.crypto_attribute(1))),
+        None => ("RTP/AVP", String::new()),
+    };
+
+    format!(
+        "v=0\r\n\
+         o=- {} {} IN {} {}\r\n\
+         s=Platypus Phone Call\r\n\
+         c=IN {} {}\r\n\
+         t=0 0\r\n\
+         m=audio {} {} {}\r\n\
+         {}\
+         {}\
+         {}\
+         a={}\r\n",
+        session_id,
+        session_id,
+        sdp_address_type(local_ip),
+        local_ip,
+        sdp_address_type(local_ip),
+        local_ip,
+        rtp_port,
+        profile,
+        formats,
+        bandwidth_line(crate::settings::load_bandwidth_limit().unwrap_or(None)),
+        rtpmaps,
+        crypto,
+        direction.attribute()
+    )
+}
+
+// Build a response to an inbound request, echoing Via/From/To/Call-ID/CSeq.
+// `to_tag` is added to the To header when the request didn't carry one (a new
+// dialog). `extra_headers` must be complete lines ending in CRLF.
+fn build_response(request: &str, status: &str, to_tag: Option<&str>, extra_headers: &str, body: &str) -> String {
+    let mut response = format!("SIP/2.0 {}\r\n", status);
+
+    for line in request.lines() {
+        if let Some((name, _)) = line.split_once(':') {
+            let name = name.trim();
+            if message::header_name_is(name, "Via") || message::header_name_is(name, "Record-Route") {
+                response.push_str(line);
+                response.push_str("\r\n");
+            }
+        }
+    }
+
+    for name in ["From", "To", "Call-ID", "CSeq"] {
+        if let Some(value) = header_value(request, name) {
+            match to_tag {
+                Some(tag) if name == "To" && !value.contains("tag=") => {
+                    response.push_str(&format!("To: {};tag={}\r\n", value, tag));
+                }
+                _ => response.push_str(&format!("{}: {}\r\n", name, value)),
+            }
+        }
+    }
+
+    response.push_str(extra_headers);
+    if !body.is_empty() {
+        response.push_str("Content-Type: application/sdp\r\n");
+    }
+    response.push_str(&format!("User-Agent: {}\r\n", message::USER_AGENT));
+    response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    response
+}
+
+async fn send_response(socket: &UdpSocket, response: &str, to_addr: SocketAddr) -> Result<(), SipError> {
+    println!("[SIP] Sending response: {}", response.lines().next().unwrap_or(""));
+    socket.send_to(response.as_bytes(), to_addr).await
+        .map_err(|e| SipError::Network(format!("Failed to send response: {}", e)))?;
+    Ok(())
+}
+
+// Value of the first header with the given name (case-insensitive, full or
+// compact form)
+fn header_value(message: &str, name: &str) -> Option<String> {
+    message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (header_name, value) = line.split_once(':')?;
+            if message::header_name_is(header_name, name) {
+                Some(value.trim().to_string())
+            } else {
+                None
+            }
+        })
+}
+
+// Every value of a header, whether repeated or comma-separated
+fn header_values(message: &str, name: &str) -> Vec<String> {
+    message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (header_name, value) = line.split_once(':')?;
+            message::header_name_is(header_name, name).then_some(value)
+        })
+        .flat_map(split_header_values)
+        .collect()
+}
+
+// Name shown to the people we call (the From display name)
+fn local_display_name() -> String {
+    crate::settings::load_display_name().unwrap_or_default()
+}
+
+// Whether requests go out with compact header names (f:, t:, i:, v:, ...)
+fn use_compact_headers() -> bool {
+    crate::settings::load_compact_headers().unwrap_or(false)
+}
+
+// Body of a SIP message (everything after the blank line)
+fn message_body(message: &str) -> &str {
+    message
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body)
+        .unwrap_or("")
+}
+
+pub async fn register_account(
+    server: &str,
+    user: &str,
+    password: &str,
+) -> Result<(), SipError> {
+    // An explicit registration supersedes any retry still waiting
+    cancel_register_retry().await;
+
+    events::emit("registration_state", serde_json::json!({
+        "registered": false,
+        "state": "REGISTERING",
+        "message": format!("Registering {}@{}", user, server),
+    }));
+
+    let attempt = CancelToken::default();
+    SIP_ENGINE.lock().await.register_attempt = Some(attempt.clone());
+    let result = cancellable(&attempt, register_once(server, user, password)).await;
+    {
+        let mut engine = SIP_ENGINE.lock().await;
+        if engine.register_attempt.as_ref().is_some_and(|current| current.same(&attempt)) {
+            engine.register_attempt = None;
+        }
+    }
+    if result.is_err() {
+        // A failed attempt must not leave an earlier registration looking live
+        SIP_ENGINE.lock().await.registered = false;
+    }
+    emit_registration_result(&result, server, user).await;
+    if let Err(SipError::Unavailable { retry_after, .. }) = &result {
+        schedule_register_retry(*retry_after).await;
+    }
+    result
+}
+
+/// Shortest wait before retrying a registration the server turned away,
+/// whatever its Retry-After says
+const MIN_REGISTER_RETRY_SECS: u64 = 1;
+
+/// Registration retries after 503/480 before giving up
+const MAX_REGISTER_RETRIES: u32 = 10;
+
+// Wait before registration retry `attempt` (counting from 0): the server's
+// Retry-After, but never less than a floor that doubles with each attempt
+// so a server answering `Retry-After: 0` isn't hammered
+fn register_retry_delay(retry_after: u64, attempt: u32) -> u64 {
+    retry_after.max(MIN_REGISTER_RETRY_SECS << attempt.min(8))
+}
+
+// Retry registration with the stored credentials after Retry-After seconds,
+// for as long as the server keeps answering 503/480 with Retry-After (up to
+// MAX_REGISTER_RETRIES times)
+async fn schedule_register_retry(retry_after: u64) {
+    let mut delay = register_retry_delay(retry_after, 0);
+    emit_register_retry(delay);
+
+    let task = tokio::spawn(async move {
+        for attempt in 1..=MAX_REGISTER_RETRIES {
+            tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+
+            let (server, user, password) = {
+                let engine = SIP_ENGINE.lock().await;
+                (engine.server.clone(), engine.user.clone(), engine.password.clone())
+            };
+
+            println!("[SIP] Retrying registration with {}", server);
+            let result = register_once(&server, &user, &password).await;
+            match &result {
+                Err(SipError::Unavailable { retry_after, .. }) if attempt < MAX_REGISTER_RETRIES => {
+                    delay = register_retry_delay(*retry_after, attempt);
+                    emit_register_retry(delay);
+                }
+                _ => {
+                    if let Err(e) = &result {
+                        println!("[SIP] Registration retry failed: {}", e);
+                    }
+                    emit_registration_result(&result, &server, &user).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    SIP_ENGINE.lock().await.register_retry_task = Some(task);
+}
+
+// Final state of a registration attempt; failures carry the reason so the
+// UI can show why the account is not registered
+async fn emit_registration_result(result: &Result<(), SipError>, server: &str, user: &str) {
+    let e = match result {
+        Ok(()) => {
+            events::emit("registration_state", serde_json::json!({
+                "registered": true,
+                "state": "REGISTERED",
+                "message": format!("Registered as {}@{}", user, server),
+            }));
+            return;
+        }
+        Err(e) => e,
+    };
+    events::emit("registration_state", serde_json::json!({
+        "registered": false,
+        "state": "FAILED",
+        "message": e.to_string(),
+        "reason": e.to_string(),
+    }));
+
+    // Giving up is the user's choice, not a failure to explain
+    if *e == SipError::Cancelled {
+        return;
+    }
+    let status = SIP_ENGINE.lock().await.registration.as_ref().and_then(|registration| registration.last_status);
+    let category = RegistrationFailure::classify(status, e);
+    println!("[SIP] Registration failed ({:?}, status {:?})", category, status);
+    events::emit("registration_failed", serde_json::json!({
+        "category": category,
+        "status": status,
+        "retryable": category == RegistrationFailure::Transient,
+        "message": e.to_string(),
+    }));
+}
+
+/// Why a registration failed, so the UI can tell "ask for a new password"
+/// from "try again later"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RegistrationFailure {
+    /// The server didn't accept the credentials (401/407) or doesn't know the account (404)
+    BadCredentials,
+    /// The credentials are fine but the account may not register (403); retrying won't help
+    Forbidden,
+    /// No answer, or the server is busy or timed out upstream (408/480/503/504)
+    Transient,
+    /// Any other failure response or unusable answer
+    ServerError,
+}
+
+impl RegistrationFailure {
+    /// Category for the final REGISTER status, or for the error when there was no response
+    fn classify(status: Option<u16>, error: &SipError) -> Self {
+        match status {
+            Some(401 | 404 | 407) => Self::BadCredentials,
+            Some(403) => Self::Forbidden,
+            Some(408 | 480 | 503 | 504) => Self::Transient,
+            Some(300..) => Self::ServerError,
+            _ => match error {
+                SipError::AuthFailed(_) => Self::BadCredentials,
+                SipError::Timeout(_) | SipError::Network(_) | SipError::Unavailable { .. } => Self::Transient,
+                _ => Self::ServerError,
+            },
+        }
+    }
+}
+
+fn emit_register_retry(delay: u64) {
+    let next_attempt = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_secs() + delay)
+        .unwrap_or(0);
+
+    println!("[SIP] Server unavailable, retrying registration in {}s", delay);
+    events::emit("registration_retry", serde_json::json!({
+        "retry_after": delay,
+        "next_attempt": next_attempt,
+    }));
+}
+
+async fn cancel_register_retry() {
+    if let Some(task) = SIP_ENGINE.lock().await.register_retry_task.take() {
+        task.abort();
+    }
+}
+
+// Registration state for the next REGISTER to `aor`: the stored one with
+// the CSeq incremented, or a new Call-ID if the account changed. It is
+// stored right away since the CSeq is used once the request goes out.
+async fn next_registration(aor: &str) -> Registration {
+    let mut engine = SIP_ENGINE.lock().await;
+    let registration = match engine.registration.take() {
+        Some(mut registration) if registration.aor == aor => {
+            registration.cseq += 1;
+            registration.last_status = None;
+            registration
+        }
+        _ => Registration {
+            aor: aor.to_string(),
+            call_id: ids::call_id(),
+            from_tag: ids::tag(),
+            cseq: 1,
+            last_status: None,
+        },
+    };
+    engine.registration = Some(registration.clone());
+    registration
+}
+
+// The auth retry bumps the CSeq; continue from what the server last saw,
+// and remember how it answered
+async fn update_registration(response: &str) {
+    let mut engine = SIP_ENGINE.lock().await;
+    if let Some(registration) = engine.registration.as_mut() {
+        if let Some(cseq) = response_cseq(response) {
+            registration.cseq = registration.cseq.max(cseq);
+        }
+        registration.last_status = Some(response_status(response));
+    }
+}
+
+// CSeq number of a message
+fn response_cseq(response: &str) -> Option<u32> {
+    message::CSeq::of(response).map(|cseq| cseq.number)
+}
+
+async fn register_once(
+    server: &str,
+    user: &str,
+    password: &str,
+) -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let mut engine = SIP_ENGINE.lock().await;
+
+    let socket = engine
+        .socket
+        .as_ref()
+        .ok_or(SipError::NotInitialized)?
+        .clone();
+
+    println!("[SIP] Registering account:");
+    println!("  Server: {}", server);
+    println!("  User: {}", user);
+
+    // Store credentials; a (re-)registration always looks the server up again
+    engine.resolved_server = None;
+    engine.server = server.to_string();
+    engine.user = user.to_string();
+    engine.password = password.to_string();
+
+    let local_addr = engine.local_addr.clone();
+    
+    // Release the lock before async operations
+    drop(engine);
+
+    // Build initial REGISTER message (without auth)
+    let from_uri = format!("sip:{}@{}", user, server);
+    let to_uri = from_uri.clone();
+    let contact_uri = format!("sip:{}@{}", user, local_addr);
+    let registration = next_registration(&from_uri).await;
+    let _route = ResponseRoute::open(&registration.call_id);
+    let proxy_route = outbound_proxy_route();
+
+    // Build raw SIP REGISTER message
+    let request_uri = format!("sip:{}", server);
+    let from_name = local_display_name();
+    let compact = use_compact_headers();
+    let build_register = |branch: &str| {
+        let params = message::RequestParams {
+            uri: &request_uri,
+            local_addr: &local_addr,
+            branch,
+            route: &proxy_route,
+            from_uri: &from_uri,
+            from_name: &from_name,
+            from_tag: &registration.from_tag,
+            to_uri: &to_uri,
+            to_tag: None,
+            call_id: &registration.call_id,
+            cseq: registration.cseq,
+            compact,
+        };
+        message::build_register(&params, &contact_uri, 3600, None)
+    };
+
+    println!("[SIP] Sending initial REGISTER to {}", server);
+
+    // Fresh lookup; every request after this one goes to whichever of the
+    // server's addresses answered
+    let target = signaling_target(server);
+    let candidates = lookup_server(&target).await?;
+    let (response, server_addr) = send_with_failover(
+        &socket,
+        &candidates,
+        timeouts.register_secs,
+        (user, password),
+        build_register,
+    )
+    .await
+    .inspect_err(|e| {
+        if matches!(e, SipError::Timeout(_)) {
+            println!("[SIP] ✗ No response from {} - check the server address, firewall (UDP 5060) and network", server);
+        }
+    })?;
+    SIP_ENGINE.lock().await.resolved_server = Some((target, server_addr));
+
+    update_registration(&response).await;
+
+    let status_line = response.lines().next().unwrap_or("Unknown");
+    println!("[SIP] Final response: {}", status_line);
+
+    if let Some(retry_after) = retry_after(&response) {
+        return Err(SipError::Unavailable {
+            message: format!("Registration failed: {}", status_line),
+            retry_after,
+        });
+    }
+
+    match response_status(&response) {
+        200..=299 => {
+            println!("[SIP] ✓✓✓ Registration successful! ✓✓✓");
+            let mut engine = SIP_ENGINE.lock().await;
+            engine.registered = true;
+            spawn_mwi_subscription();
+            start_keepalive(&mut engine);
+            start_resume_watch(&mut engine);
+            Ok(())
+        }
+        401 | 403 | 407 => Err(SipError::AuthFailed(format!("Registration failed: {}", status_line))),
+        _ => Err(SipError::Rejected(format!("Unexpected response: {}", status_line))),
+    }
+}
+
+// Send a request to each address in turn until one answers, as a new
+// transaction with its own branch each time (RFC 3263 4.3). `build` makes
+// the request for a branch. Returns the final response and who sent it.
+async fn send_with_failover(
+    socket: &UdpSocket,
+    candidates: &[SocketAddr],
+    timeout_secs: u64,
+    (user, password): (&str, &str),
+    build: impl Fn(&str) -> String,
+) -> Result<(String, SocketAddr), SipError> {
+    let mut last_error = SipError::Network("No server addresses to try".to_string());
+    for (i, &addr) in candidates.iter().enumerate() {
+        if i > 0 {
+            println!("[SIP] No response from {}, trying {}", candidates[i - 1], addr);
+        }
+        let request = build(&ids::branch());
+        println!("[SIP] Message:\n{}", request);
+
+        match Transaction::new(socket, addr, timeout_secs).authenticate(user, password).send(&request).await {
+            Ok(response) => return Ok((response, addr)),
+            Err(e @ (SipError::Timeout(_) | SipError::Network(_))) => last_error = e,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error)
+}
+
+// Retry-After delay (seconds) of a 503/480 response, if the server gave one
+fn retry_after(response: &str) -> Option<u64> {
+    let status = response.lines().next()?.split_whitespace().nth(1)?;
+    if status != "503" && status != "480" {
+        return None;
+    }
+
+    // "Retry-After: 120 (in a meeting);duration=3600" -> 120
+    let value = header_value(response, "Retry-After")?;
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+// Header lines of a message, with folded continuation lines (starting with
+// whitespace) joined onto the header they continue
+fn unfolded_headers(message: &str) -> Vec<String> {
+    let mut headers: Vec<String> = Vec::new();
+    for line in message.lines().skip(1).take_while(|line| !line.is_empty()) {
+        match headers.last_mut() {
+            Some(last) if line.starts_with([' ', '\t']) => {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+            _ => headers.push(line.to_string()),
+        }
+    }
+    headers
+}
+
+// Parse every Digest challenge in the WWW-Authenticate and Proxy-Authenticate
+// headers, in the order the server sent them
+fn parse_auth_header(response: &str) -> Result<Vec<std::collections::HashMap<String, String>>, SipError> {
+    let auth_lines: Vec<String> = unfolded_headers(response)
+        .into_iter()
+        .filter(|line| {
+            line.split_once(':').is_some_and(|(name, _)| {
+                message::header_name_is(name, "WWW-Authenticate") || message::header_name_is(name, "Proxy-Authenticate")
+            })
+        })
+        .collect();
+    if auth_lines.is_empty() {
+        return Err(SipError::AuthFailed("No authentication header found".to_string()));
+    }
+
+    let mut challenges = Vec::new();
+    for auth_line in &auth_lines {
+        println!("[SIP] Auth header: {}", auth_line);
+
+        let (_, value) = auth_line.split_once(':').unwrap_or_default();
+        let value = value.trim();
+        let Some(digest_part) = value.get(..7).filter(|scheme| scheme.eq_ignore_ascii_case("Digest ")).map(|_| &value[7..]) else {
+            continue;
+        };
+
+        let mut params = std::collections::HashMap::new();
+        for param in split_header_values(digest_part) {
+            if let Some((key, value)) = param.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                params.insert(key.trim().to_ascii_lowercase(), value.to_string());
+            }
+        }
+        challenges.push(params);
+    }
+
+    if challenges.is_empty() {
+        return Err(SipError::AuthFailed("No Digest challenge found".to_string()));
+    }
+    Ok(challenges)
+}
+
+// Preference of a digest algorithm we can answer (higher is stronger)
+fn digest_algorithm_rank(algorithm: &str) -> Option<u8> {
+    if algorithm.eq_ignore_ascii_case("SHA-256") {
+        Some(2)
+    } else if algorithm.eq_ignore_ascii_case("MD5") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+// The challenge with the strongest algorithm we support (no algorithm means MD5)
+fn strongest_challenge(
+    challenges: &[std::collections::HashMap<String, String>],
+) -> Result<&std::collections::HashMap<String, String>, SipError> {
+    challenges
+        .iter()
+        .filter_map(|params| {
+            let algorithm = params.get("algorithm").map(String::as_str).unwrap_or("MD5");
+            digest_algorithm_rank(algorithm).map(|rank| (rank, params))
+        })
+        .fold(None, |best: Option<(u8, &std::collections::HashMap<String, String>)>, (rank, params)| match best {
+            Some((best_rank, _)) if best_rank >= rank => best,
+            _ => Some((rank, params)),
+        })
+        .map(|(_, params)| params)
+        .ok_or_else(|| SipError::AuthFailed("No supported digest algorithm offered".to_string()))
+}
+
+// Whether a challenge to an authenticated request only rejects the nonce
+// (stale=true, RFC 2617 3.2.1): the credentials were right, so answering
+// the new nonce should succeed
+fn challenge_is_stale(params: &std::collections::HashMap<String, String>) -> bool {
+    params.get("stale").is_some_and(|stale| stale.eq_ignore_ascii_case("true"))
+}
+
+// Hex digest of `input` with a challenge's algorithm (MD5 unless SHA-256)
+fn digest_hex(algorithm: &str, input: &str) -> String {
+    if algorithm.eq_ignore_ascii_case("SHA-256") {
+        Sha256::digest(input.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        format!("{:x}", md5_compute(input.as_bytes()))
+    }
+}
+
+// Calculate the digest response (MD5 or SHA-256) for authentication
+fn calculate_digest_response(
+    username: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+    params: &std::collections::HashMap<String, String>,
+) -> Result<String, SipError> {
+    let realm = params.get("realm").ok_or_else(|| SipError::AuthFailed("Missing realm".to_string()))?;
+    let nonce = params.get("nonce").ok_or_else(|| SipError::AuthFailed("Missing nonce".to_string()))?;
+    let default_algo = "MD5".to_string();
+    let algorithm = params.get("algorithm").unwrap_or(&default_algo);
+    // qop may list several options ("auth,auth-int"); we only do auth
+    let qop = params
+        .get("qop")
+        .map(|qop| qop.split(',').map(str::trim).find(|option| *option == "auth").unwrap_or(qop.as_str()));
+
+    println!("[SIP] Calculating digest:");
+    println!("  Realm: {}", realm);
+    println!("  Nonce: {}", nonce);
+    println!("  Algorithm: {}", algorithm);
+
+    // Calculate HA1 = H(username:realm:password)
+    let ha1_input = format!("{}:{}:{}", username, realm, password);
+    let ha1 = digest_hex(algorithm, &ha1_input);
+
+    // Calculate HA2 = H(method:uri)
+    let ha2_input = format!("{}:{}", method, uri);
+    let ha2 = digest_hex(algorithm, &ha2_input);
+
+    // Calculate response
+    let response = if let Some(qop_val) = qop {
+        // With qop
+        let nc = "00000001";
+        let cnonce = format!("{:x}", md5_compute(uuid::Uuid::new_v4().to_string().as_bytes()));
+        let response_input = format!("{}:{}:{}:{}:{}:{}", ha1, nonce, nc, cnonce, qop_val, ha2);
+        let response = digest_hex(algorithm, &response_input);
+        
+        format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}, qop={}, nc={}, cnonce=\"{}\"",
+            username, realm, nonce, uri, response, algorithm, qop_val, nc, cnonce
+        )
+    } else {
+        // Without qop
+        let response_input = format!("{}:{}:{}", ha1, nonce, ha2);
+        let response = digest_hex(algorithm, &response_input);
+        
+        format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+            username, realm, nonce, uri, response, algorithm
+        )
+    };
+
+    Ok(response)
+}
+
+// Challenges with stale=true answered in one transaction before giving up
+// (a server with very short nonce lifetimes shouldn't loop us forever)
+const MAX_STALE_NONCE_RETRIES: u32 = 1;
+
+// A client transaction to the server: sends a request and waits for its
+// final response, skipping provisional ones (unless asked to hand them back
+// with `until_provisional`; 100 Trying is always skipped). With credentials, a 401/407
+// challenge is answered by resending the request with a digest; a second
+// challenge is only answered when it says our nonce went stale.
+// Responses are routed by Call-ID; the caller keeps a ResponseRoute open.
+struct Transaction<'a> {
+    socket: &'a UdpSocket,
+    server_addr: SocketAddr,
+    timeout: std::time::Duration,
+    credentials: Option<(&'a str, &'a str)>,
+    provisional: bool,
+}
+
+impl<'a> Transaction<'a> {
+    fn new(socket: &'a UdpSocket, server_addr: SocketAddr, timeout_secs: u64) -> Self {
+        Self {
+            socket,
+            server_addr,
+            timeout: std::time::Duration::from_secs(timeout_secs),
+            credentials: None,
+            provisional: false,
+        }
+    }
+
+    // Answer a digest challenge with these credentials
+    fn authenticate(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    // Return the first 101-199 response instead of waiting past it, so an
+    // INVITE's caller sees the call ringing and supervises the answer itself
+    fn until_provisional(mut self) -> Self {
+        self.provisional = true;
+        self
+    }
+
+    // Send `request` and return the final response (or the first
+    // provisional one, see `until_provisional`)
+    async fn send(&self, request: &str) -> Result<String, SipError> {
+        let mut request_line = request.split_whitespace();
+        let method = request_line.next().unwrap_or("");
+        let uri = request_line.next().unwrap_or("");
+        let call_id = header_value(request, "Call-ID").unwrap_or_default();
+
+        let mut request = request.to_string();
+        let mut stale_retries = 0;
+        loop {
+            self.transmit(&request, method).await?;
+            let response = self.final_response(&call_id, method, message::CSeq::of(&request).as_ref()).await?;
+
+            let status = response_status(&response);
+            // The INVITE transaction ACKs its own failures; without the ACK
+            // the peer keeps retransmitting the response
+            if method == "INVITE" && status >= 300 {
+                self.transmit(&message::build_non2xx_ack(&request, &response), "ACK").await?;
+            }
+            let Some((username, password)) = self.credentials.filter(|_| status == 401 || status == 407) else {
+                return Ok(response);
+            };
+
+            let authenticated = header_value(&request, "Authorization").is_some()
+                || header_value(&request, "Proxy-Authorization").is_some();
+            let challenges = if authenticated {
+                // Challenged again: the credentials were wrong unless the
+                // server only rotated its nonce
+                match parse_auth_header(&response) {
+                    Ok(challenges) if stale_retries < MAX_STALE_NONCE_RETRIES
+                        && strongest_challenge(&challenges).is_ok_and(challenge_is_stale) =>
+                    {
+                        stale_retries += 1;
+                        println!("[SIP] Nonce went stale ({}), retrying {} with the new nonce...", status, method);
+                        challenges
+                    }
+                    _ => return Ok(response),
+                }
+            } else {
+                println!("[SIP] Authentication required ({}), retrying {} with auth...", status, method);
+                parse_auth_header(&response)?
+            };
+
+            let auth_params = strongest_challenge(&challenges)?;
+            let auth_header = calculate_digest_response(username, password, method, uri, auth_params)?;
+            let auth_name = if status == 407 { "Proxy-Authorization" } else { "Authorization" };
+            request = message::build_retry(&request, &ids::branch(), auth_name, &auth_header);
+
+            // A CANCEL has to match the INVITE transaction that is now pending
+            if method == "INVITE" {
+                let mut engine = SIP_ENGINE.lock().await;
+                if let Some(dialog) = engine.active_dialog.as_mut().filter(|dialog| dialog.call_id == call_id) {
+                    dialog.outgoing_invite = Some(request.clone());
+                }
+            }
+        }
+    }
+
+    async fn transmit(&self, request: &str, method: &str) -> Result<(), SipError> {
+        self.socket.send_to(request.as_bytes(), self.server_addr).await
+            .map_err(|e| SipError::Network(format!("Failed to send {}: {}", method, e)))?;
+        println!("[SIP] ✓ {} sent ({} bytes to {})", method, request.len(), self.server_addr);
+        Ok(())
+    }
+
+    // Wait out provisional responses; each wait gets the full timeout.
+    // Responses whose CSeq isn't `cseq` (a late retransmission of the
+    // challenge, or another request in the same call) are skipped.
+    async fn final_response(&self, call_id: &str, method: &str, cseq: Option<&message::CSeq>) -> Result<String, SipError> {
+        loop {
+            let (response, _) = tokio::time::timeout(self.timeout, recv_response(call_id))
+                .await
+                .map_err(|_| SipError::Timeout(format!("Timeout waiting for {} response ({}s)", method, self.timeout.as_secs())))??;
+
+            if let Some(expected) = cseq.filter(|expected| message::CSeq::of(&response).as_ref() != Some(*expected)) {
+                println!("[SIP] Ignoring response that isn't for {}: {}", expected, response.lines().next().unwrap_or(""));
+                continue;
+            }
+
+            println!("[SIP] Received response: {}", response.lines().next().unwrap_or(""));
+            let status = response_status(&response);
+            if status >= 200 || (self.provisional && status > 100) {
+                return Ok(response);
+            }
+            println!("[SIP] Provisional response, waiting for final response...");
+        }
+    }
+}
+
+// Trips one registration or call attempt run through `cancellable`. Clones
+// share the token, so whoever holds one can give that attempt up.
+#[derive(Clone, Debug)]
+struct CancelToken(Arc<tokio::sync::watch::Sender<bool>>);
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self(Arc::new(tokio::sync::watch::channel(false).0))
+    }
+}
+
+impl CancelToken {
+    // Whether the attempt was still running
+    fn cancel(&self) -> bool {
+        !self.0.send_replace(true) && self.0.receiver_count() > 0
+    }
+
+    async fn cancelled(&self) {
+        let mut cancelled = self.0.subscribe();
+        // The sender lives in self, so this only returns once tripped
+        let _ = cancelled.wait_for(|cancelled| *cancelled).await;
+    }
+
+    fn same(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// Run a registration or call attempt until it finishes or its token is
+// tripped, whichever comes first (the attempt is dropped mid-wait)
+async fn cancellable<T>(
+    token: &CancelToken,
+    attempt: impl std::future::Future<Output = Result<T, SipError>>,
+) -> Result<T, SipError> {
+    tokio::select! {
+        result = attempt => result,
+        _ = token.cancelled() => {
+            println!("[SIP] Attempt cancelled");
+            Err(SipError::Cancelled)
+        }
+    }
+}
+
+/// Cancel the registration attempt and the unanswered outgoing call in
+/// flight; they return `SipError::Cancelled` at once. Returns whether there
+/// were any.
+pub async fn cancel_pending() -> bool {
+    let engine = SIP_ENGINE.lock().await;
+    let registration = engine.register_attempt.as_ref().is_some_and(|attempt| attempt.cancel());
+    let call = engine
+        .active_dialog
+        .as_ref()
+        .filter(|dialog| dialog.outgoing && matches!(dialog.state, CallState::Calling | CallState::Ringing))
+        .is_some_and(|dialog| dialog.attempt.cancel());
+    registration || call
+}
+
+// Status code of a response (0 if the status line is malformed)
+fn response_status(response: &str) -> u16 {
+    response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0)
+}
+
+// Payload of the `media_security` event: `srtp` with the negotiated SDES
+// suite, or `plain` when the call's audio is unencrypted
+fn media_security(secure: bool) -> serde_json::Value {
+    if secure {
+        serde_json::json!({ "encryption": "srtp", "suite": CRYPTO_SUITE })
+    } else {
+        serde_json::json!({ "encryption": "plain", "suite": null })
+    }
+}
+
+// RTP session, call audio streams and the TX/RX task handles for a call
+// (no TX task when we don't send)
+type MediaHandles = (
+    Arc<RtpSession>,
+    Arc<CallAudio>,
+    Option<tokio::task::JoinHandle<()>>,
+    tokio::task::JoinHandle<()>,
+);
+
+// Start RTP media session after call is established. `direction` is the
+// negotiated one: without send there is no TX task, and without receive
+// silence is expected rather than a dead call.
+async fn start_rtp_media(
+    response_sdp: &str,
+    media: MediaEndpoint,
+    srtp_keys: Option<&SrtpKeys>,
+    direction: MediaDirection,
+) -> Result<MediaHandles, SipError> {
+tracing::info!("[RTP] Starting RTP media session...");
+println!("[RTP] Starting RTP media session...");
+
+// Parse remote SDP
+let (remote_ip, remote_port, payload_type, clock_rate) = parse_sdp(response_sdp)?;
+
+let codec = Codec::from_payload_type(payload_type).map_or("Unknown", Codec::name);
+tracing::info!("[RTP] Remote endpoint: {}:{}", remote_ip, remote_port);
+tracing::info!("[RTP] Payload type: {} ({})", payload_type, codec);
+
+println!("[RTP] Remote endpoint: {}:{}", remote_ip, remote_port);
+println!("[RTP] Payload type: {} ({})", payload_type, codec);
+
+// Create remote address
+let remote_addr = SocketAddr::new(
+remote_ip.parse().map_err(|e| SipError::Protocol(format!("Invalid remote address: {}", e)))?,
+remote_port,
+);
+
+// Create RTP session
+let rtp_session = Arc::new(
+RtpSession::with_socket(media.socket, media.relay, media.advertised, remote_addr, payload_type, clock_rate).await?
+);
+
+tracing::info!("[RTP] ✓ RTP session created");
+println!("[RTP] ✓ RTP session created");
+
+// G.711 can't go below its fixed rate, so a tighter limit is only reported
+let bandwidth = negotiated_bandwidth(response_sdp);
+if let Some(limit) = bandwidth {
+    let needed = codec_bandwidth_kbps(payload_type);
+    if needed > limit {
+        tracing::warn!("[RTP] Bandwidth limit {} kbit/s is below the {} kbit/s the codec needs", limit, needed);
+        println!("[RTP] ⚠ Bandwidth limit {} kbit/s is below the {} kbit/s the codec needs", limit, needed);
+    }
+}
+rtp_session.set_bandwidth_kbps(bandwidth).await;
+
+// DTMF goes out as telephone-events if the peer supports them
+rtp_session.set_event_payload_type(telephone_event_payload_type(response_sdp).filter(|_| offer_telephone_event())).await;
+rtp_session.set_direction(direction).await;
+
+// Encrypt media if we offered SRTP and the peer answered with its own key
+if let Some(local_keys) = srtp_keys {
+    match parse_crypto_attribute(response_sdp) {
+        Some(remote_keys) if is_secure_profile(response_sdp) => {
+            rtp_session.set_srtp(SrtpContext::new(local_keys), SrtpContext::new(&remote_keys)).await;
+        }
+        _ => {
+            tracing::warn!("[SRTP] Peer did not accept RTP/SAVP, falling back to plain RTP");
+            println!("[SRTP] Peer did not accept RTP/SAVP, falling back to plain RTP");
+        }
+    }
+}
+
+// Tell the UI whether this call's audio is encrypted
+events::emit("media_security", media_security(rtp_session.is_secure().await));
+
+// Open audio devices on the call audio thread (preferring the saved devices)
+tracing::info!("[Audio] Initializing audio devices...");
+println!("[Audio] Initializing audio devices...");
+
+let (input_device, output_device) = crate::settings::load_audio_devices().unwrap_or_default();
+let (backend, levels) = {
+    let mut engine = SIP_ENGINE.lock().await;
+    engine.call_devices = (input_device.clone(), output_device.clone());
+    (engine.audio_backend.clone(), engine.audio_levels.clone())
+};
+println!("[Audio] Using {} audio backend", backend.name());
+
+// Each device comes up at the levels last set for it
+levels.set_input_gain(device_levels(&input_device).input_gain);
+levels.set_output_volume(device_levels(&output_device).output_volume);
+
+// Opening the devices blocks until the audio thread has them running
+let started = tokio::task::spawn_blocking(move || backend.start(Some(input_device), Some(output_device)))
+    .await
+    .map_err(|e| SipError::Internal(format!("Audio start task failed: {}", e)))
+    .and_then(|result| result);
+let (call_audio, channels) = match started {
+    Ok(result) => result,
+    Err(e) => {
+        tracing::error!("[Audio] ✗ Failed to start call audio: {}", e);
+        println!("[Audio] ✗ Failed to start call audio: {}", e);
+        return Err(e);
+    }
+};
+
+let mut audio_rx = channels.capture;
+let audio_tx = channels.playback;
+
+tracing::info!("[Audio] ✓ Audio devices initialized");
+println!("[Audio] ✓ Audio devices initialized");
+    
+    // Create high-quality resampler for audio processing
+    // The backend hands over CALL_AUDIO_RATE (48kHz) whatever the device
+    // runs at; the network side is 8kHz VoIP (standard)
+    // Chunk size: 960 samples = 20ms at 48kHz
+    tracing::info!("[Resample] Creating audio resampler (48kHz ↔ 8kHz)");
+    println!("[Resample] Creating audio resampler (48kHz ↔ 8kHz)");
+    
+    // Falls back to linear interpolation if rubato can't be set up
+    let resampler = Arc::new(Resampler::new(CALL_AUDIO_RATE, 8000, 960));
+    println!("[Resample] Using the {} resampler", resampler.name());
+    
+    // Packets sent, shared with the RX task which reports media stats
+    let packets_sent = Arc::new(std::sync::atomic::AtomicU64::new(0));
+
+    // Spawn TX task: Microphone → Downsample → Encode → RTP → Network
+    let rtp_tx = rtp_session.clone();
+    let tx_resampler = resampler.clone();
+    let tx_packets = packets_sent.clone();
+    let tx_levels = levels.clone();
+    if direction != MediaDirection::SendRecv {
+        println!("[RTP] Media direction: {}", direction.attribute());
+    }
+    let tx_task = direction.sends().then(|| tokio::spawn(async move {
+        tracing::info!("[Audio] TX task started (Mic → RTP with high-quality resampling)");
+        println!("[Audio] TX task started (Mic → RTP with high-quality resampling)");
+        let mut packet_count = 0u64;
+        let mut framer = Framer::default();
+        
+        'capture: while let Some(mut samples) = audio_rx.recv().await {
+            tracing::debug!("[Audio] TX: Received {} samples from mic", samples.len());
+            // Nothing goes out while the call is on hold
+            if !rtp_tx.direction().await.sends() {
+                framer.clear();
+                continue;
+            }
+            apply_level(&mut samples, tx_levels.input_gain());
+            
+            // Downsampling: 48kHz → 8kHz
+            let downsampled = match tx_resampler.downsample(&samples) {
+                Ok(d) => d,
+                Err(e) => {
+                    tracing::error!("[Resample] TX downsample error: {}", e);
+                    eprintln!("[Resample] TX downsample error: {}", e);
+                    continue; // Skip this packet
+                }
+            };
+            
+            tracing::debug!("[Audio] TX: Downsampled {} → {} samples", samples.len(), downsampled.len());
+            
+            // Encode with the current codec (it can change on re-INVITE)
+            let Some(codec) = Codec::from_payload_type(rtp_tx.payload_type().await) else {
+                continue; // Not a codec we can send
+            };
+
+            // One packet per 20ms of audio, whatever the device's block size
+            let frame_len = timestamp_increment(codec.clock_rate(), FRAME_DURATION_MS) as usize;
+            for frame in framer.push(&downsampled, frame_len) {
+                let Some(encoded) = codec.encode(&frame) else {
+                    break; // Not a codec we can send
+                };
+
+                // Send RTP packet
+                if let Err(e) = rtp_tx.send_audio(&encoded).await {
+                    tracing::error!("[RTP] TX error: {}", e);
+                    eprintln!("[RTP] TX error: {}", e);
+                    break 'capture;
+                }
+
+                packet_count += 1;
+                tx_packets.store(packet_count, std::sync::atomic::Ordering::Relaxed);
+                if packet_count % 50 == 0 {
+                    tracing::info!("[RTP] Sent {} packets", packet_count);
+                    println!("[RTP] Sent {} packets", packet_count);
+                }
+            }
+        }
+        
+        tracing::info!("[Audio] TX task ended");
+        println!("[Audio] TX task ended");
+    }));
+    
+    // Spawn RX task: Network → RTP → Decode → Upsample → Speaker
+    let rtp_rx = rtp_session.clone();
+    let rx_resampler = resampler.clone();
+    let rx_levels = levels;
+    let rtp_timeout = std::time::Duration::from_secs(
+        crate::settings::load_timeouts().unwrap_or_default().rtp_secs,
+    );
+    // Follow the sender's clock, when enabled and the backend plays audio
+    let mut drift = crate::settings::load_drift_compensation()
+        .unwrap_or(false)
+        .then(|| call_audio.playout_depth())
+        .flatten()
+        .map(|depth| {
+            let target_ms = crate::settings::load_playout_target_ms().unwrap_or(DEFAULT_PLAYOUT_TARGET_MS);
+            println!("[Audio] Clock drift compensation on");
+            (depth, DriftEstimator::new(target_ms))
+        });
+    let rx_task = tokio::spawn(async move {
+        tracing::info!("[Audio] RX task started (RTP → Speaker with high-quality resampling)");
+        println!("[Audio] RX task started (RTP → Speaker with high-quality resampling)");
+        let mut packet_count = 0u64;
+        let mut activity = RxActivity::new(std::time::Instant::now());
+        let mut last_stats = std::time::Instant::now();
+        let mut last_sent = 0u64;
+        let mut expect_rx = direction.receives();
+        let mut rx_codec = RxCodec::default();
+        
+        loop {
+            // Hold and resume change the direction; silence from before a
+            // resume doesn't count against the peer
+            let receiving = rtp_rx.direction().await.receives();
+            if receiving && !expect_rx {
+                activity = RxActivity::new(std::time::Instant::now());
+            }
+            expect_rx = receiving;
+
+            // Wake up at least once per stats interval so silence is noticed
+            let received = tokio::time::timeout(MEDIA_STATS_INTERVAL, rtp_rx.receive_media()).await;
+            match received {
+                Ok(Ok(RxPayload::Dtmf(event))) => {
+                    activity.packet(std::time::Instant::now());
+                    // telephone-event runs on an 8kHz clock
+                    let duration_ms = event.duration as u32 / 8;
+                    tracing::info!("[RTP] DTMF received: {} ({}ms)", event.digit, duration_ms);
+                    println!("[RTP] DTMF received: {} ({}ms)", event.digit, duration_ms);
+                    events::emit("dtmf_received", serde_json::json!({
+                        "digit": event.digit.to_string(),
+                        "duration_ms": duration_ms,
+                    }));
+                }
+                Ok(Ok(RxPayload::Audio { payload_type, payload: encoded })) => {
+                    tracing::debug!("[Audio] RX: Received {} encoded bytes", encoded.len());
+
+                    if activity.packet(std::time::Instant::now()) == Some(RxActivityChange::Resumed) {
+                        tracing::info!("[RTP] Incoming audio resumed");
+                        println!("[RTP] Incoming audio resumed");
+                        events::emit("media_resumed", serde_json::json!({}));
+                    }
+                    
+                    // Decode against the current codec (it can change on
+                    // re-INVITE), trusting the packet when a peer sends the
+                    // other G.711 law than it negotiated; comfort noise and
+                    // other payload types are dropped
+                    let codec = rx_codec.packet(rtp_rx.payload_type().await, payload_type);
+                    let Some(decoded) = codec.and_then(|codec| codec.decode(&encoded)) else {
+                        continue; // Not a codec we can play
+                    };
+                    
+                    tracing::debug!("[Audio] RX: Decoded to {} samples", decoded.len());
+                    
+                    // Upsampling: 8kHz → 48kHz
+                    let mut upsampled = match rx_resampler.upsample(&decoded) {
+                        Ok(u) => u,
+                        Err(e) => {
+                            tracing::error!("[Resample] RX upsample error: {}", e);
+                            eprintln!("[Resample] RX upsample error: {}", e);
+                            continue; // Skip this packet
+                        }
+                    };
+                    
+                    tracing::debug!("[Audio] RX: Upsampled {} → {} samples", decoded.len(), upsampled.len());
+                    // A held call plays silence even if the peer keeps sending
+                    apply_level(&mut upsampled, if expect_rx { rx_levels.output_volume() } else { 0.0 });
+                    
+                    // Send to speaker
+                    if let Err(e) = audio_tx.send(upsampled).await {
+                        tracing::error!("[Audio] Playback error: {}", e);
+                        eprintln!("[Audio] Playback error: {}", e);
+                        break;
+                    }
+
+                    if let Some((ref depth, ref mut estimator)) = drift {
+                        let depth_ms = depth.load(std::sync::atomic::Ordering::Relaxed);
+                        rx_resampler.set_upsample_adjust(estimator.update(depth_ms));
+                    }
+                    
+                    packet_count += 1;
+                    if packet_count % 50 == 0 {
+                        tracing::info!("[RTP] Received {} packets", packet_count);
+                        println!("[RTP] Received {} packets", packet_count);
+                    }
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("[RTP] RX error: {}", e);
+                    eprintln!("[RTP] RX error: {}", e);
+                    break;
+                }
+                Err(_) => {} // Nothing arrived this interval
+            }
+
+            // Silence is only a fault if the peer is meant to be sending
+            if let Some(RxActivityChange::Stopped { silent }) = activity.check(std::time::Instant::now()).filter(|_| expect_rx) {
+                tracing::warn!("[RTP] No incoming audio for {}ms", silent.as_millis());
+                println!("[RTP] ⚠ No incoming audio for {}ms", silent.as_millis());
+                events::emit("media_timeout", serde_json::json!({
+                    "direction": "rx",
+                    "silent_ms": silent.as_millis() as u64,
+                }));
+            }
+
+            // The remote side is gone: end the call instead of leaving a zombie.
+            // The hangup runs on its own task since it aborts this one.
+            if expect_rx && activity.is_dead(std::time::Instant::now(), rtp_timeout) {
+                tracing::warn!("[RTP] No incoming RTP for {}s, ending call", rtp_timeout.as_secs());
+                println!("[RTP] ✗ No incoming RTP for {}s, ending call", rtp_timeout.as_secs());
+                events::emit("rtp_timeout", serde_json::json!({
+                    "timeout_secs": rtp_timeout.as_secs(),
+                    "packets_received": packet_count,
+                }));
+                tokio::spawn(end_dead_call(rtp_rx.clone()));
+                break;
+            }
+
+            // We keep sending but hear nothing: one-way audio
+            let sent = packets_sent.load(std::sync::atomic::Ordering::Relaxed);
+            let sending = sent != last_sent;
+            last_sent = sent;
+            if let Some(one_way) = activity.check_one_way(std::time::Instant::now(), sending).filter(|_| expect_rx) {
+                let phase = match one_way {
+                    OneWayAudio::Startup => "startup",
+                    OneWayAudio::MidCall => "mid_call",
+                };
+                tracing::warn!("[RTP] One-way audio ({}): sending but nothing received", phase);
+                println!("[RTP] ⚠ One-way audio ({}): sending but nothing received", phase);
+                events::emit("one_way_audio", serde_json::json!({
+                    "phase": phase,
+                    "packets_sent": sent,
+                    "packets_received": packet_count,
+                }));
+            }
+
+            if last_stats.elapsed() >= MEDIA_STATS_INTERVAL {
+                last_stats = std::time::Instant::now();
+                events::emit("media_stats", serde_json::json!({
+                    "packets_sent": packets_sent.load(std::sync::atomic::Ordering::Relaxed),
+                    "packets_received": packet_count,
+                    "jitter_ms": rtp_rx.jitter_ms().await,
+                    "audio_flowing": activity.is_flowing(),
+                    "bandwidth_kbps": rtp_rx.bandwidth_kbps().await,
+                }));
+                rtp_rx.maintain_relay().await;
+            }
+        }
+        
+        tracing::info!("[Audio] RX task ended");
+        println!("[Audio] RX task ended");
+    });
+    
+    println!("[RTP] ✓✓✓ RTP media session active! ✓✓✓");
+    
+    Ok((rtp_session, Arc::new(call_audio), tx_task, rx_task))
+}
+
+// Hang up the call whose RTP session went silent past the RTP timeout,
+// unless that call has already ended
+async fn end_dead_call(rtp_session: Arc<RtpSession>) {
+    let same_call = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .and_then(|dialog| dialog.rtp_session.as_ref())
+        .is_some_and(|session| Arc::ptr_eq(session, &rtp_session));
+    if !same_call {
+        return;
+    }
+
+    if let Err(e) = hangup_call().await {
+        println!("[SIP] Hangup after RTP timeout failed: {}", e);
+    }
+}
+
+// How often the RX task reports packet counts and jitter to the UI
+const MEDIA_STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// P-Preferred-Identity line for trunks that pick the caller ID from it
+// (RFC 3325); empty when no identity is configured
+fn preferred_identity_header(identity: &str) -> String {
+    let identity = identity.trim();
+    if identity.is_empty() {
+        String::new()
+    } else if identity.contains('<') {
+        format!("P-Preferred-Identity: {}\r\n", identity)
+    } else {
+        format!("P-Preferred-Identity: <{}>\r\n", identity)
+    }
+}
+
+// Characters people type to format phone numbers: "+1 (555) 123-4567"
+const DIAL_FORMATTING: &[char] = &[' ', '-', '(', ')', '.', '/'];
+
+// Turn what the user dialed into a request-URI, rejecting it before any
+// network I/O if it can't be a valid target. E.164 numbers are formatted
+// according to `dial_as`.
+fn normalize_dial_target(input: &str, server: &str, dial_as: DialAs) -> Result<String, SipError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(SipError::InvalidTarget("Nothing to dial".to_string()));
+    }
+
+    let lower = input.to_ascii_lowercase();
+    if lower.starts_with("sip:") || lower.starts_with("sips:") {
+        let (scheme, rest) = input.split_once(':').unwrap();
+        let (user, host) = match rest.split_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, rest),
+        };
+        if user.is_some_and(|user| !is_valid_sip_user(user)) || !is_valid_sip_host(host) {
+            return Err(SipError::InvalidTarget(format!("Invalid SIP URI: {}", input)));
+        }
+        return Ok(format!("{}:{}", scheme.to_ascii_lowercase(), rest));
+    }
+
+    if lower.starts_with("tel:") {
+        let number = normalize_phone_number(&input[4..])
+            .ok_or_else(|| SipError::InvalidTarget(format!("Invalid tel URI: {}", input)))?;
+        return Ok(phone_number_uri(&number, server, dial_as));
+    }
+
+    if let Some(number) = normalize_phone_number(input) {
+        return Ok(phone_number_uri(&number, server, dial_as));
+    }
+
+    // "bob" or "bob@example.com" without a scheme; a name needs at least one
+    // letter, otherwise it's a mistyped number
+    let (user, host) = match input.split_once('@') {
+        Some((user, host)) => (user, host),
+        None => (input, server),
+    };
+    if is_valid_sip_user(user)
+        && user.chars().any(|c| c.is_ascii_alphabetic())
+        && is_valid_sip_host(host)
+    {
+        Ok(format!("sip:{}@{}", user, host))
+    } else {
+        Err(SipError::InvalidTarget(format!("Cannot dial \"{}\"", input)))
+    }
+}
+
+// Request-URI for a normalized phone number; only E.164 numbers are
+// dialed as tel: or with user=phone, short codes and extensions stay plain
+fn phone_number_uri(number: &str, server: &str, dial_as: DialAs) -> String {
+    match dial_as {
+        DialAs::Tel if is_e164(number) => format!("tel:{}", number),
+        DialAs::UserPhone if is_e164(number) => format!("sip:{}@{};user=phone", number, server),
+        _ => format!("sip:{}@{}", number, server),
+    }
+}
+
+// "+" followed by 2-15 digits, not starting with 0 (ITU-T E.164)
+fn is_e164(number: &str) -> bool {
+    number.strip_prefix('+').is_some_and(|digits| {
+        (2..=15).contains(&digits.len())
+            && digits.chars().all(|c| c.is_ascii_digit())
+            && !digits.starts_with('0')
+    })
+}
+
+// Phone-number-style input with the formatting stripped ("+15551234567"),
+// or None if it contains anything besides digits, *, #, a leading + and
+// formatting characters
+fn normalize_phone_number(input: &str) -> Option<String> {
+    let number: String = input.trim().chars().filter(|c| !DIAL_FORMATTING.contains(c)).collect();
+    let digits = number.strip_prefix('+').unwrap_or(&number);
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit() || c == '*' || c == '#') {
+        return None;
+    }
+    if !digits.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(number)
+}
+
+// User part of a SIP URI: unreserved characters plus the few escapes people
+// actually use (RFC 3261 "user")
+fn is_valid_sip_user(user: &str) -> bool {
+    !user.is_empty()
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.!~*'()&=+$,;?/%#".contains(c))
+}
+
+// Host part of a SIP URI, optionally with port and ;parameters
+fn is_valid_sip_host(host: &str) -> bool {
+    let host = host.split(';').next().unwrap_or("");
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+        && host.chars().next().is_some_and(|c| c.is_ascii_alphanumeric() || c == '[')
+}
+
+/// Place a call. `direction` is the audio direction offered, normally
+/// sendrecv; recvonly listens in without sending anything.
+pub async fn make_call(number: &str, direction: MediaDirection) -> Result<(), SipError> {
+    let call_id = ids::call_id();
+    let secure = crate::settings::load_secure_media().unwrap_or(false);
+    let attempt = CancelToken::default();
+    let result = cancellable(&attempt, place_call(number, direction, &call_id, secure, &attempt)).await;
+    // Whatever stopped the attempt, its dialog must not block the next call
+    if let Err(ref e) = result {
+        abandon_call(&call_id, e.kind()).await;
+    }
+    result
+}
+
+// `secure` offers SRTP (RTP/SAVP). A peer that can't do it answers 488, and
+// the call is retried once with plain RTP under the same Call-ID.
+async fn place_call(
+    number: &str,
+    direction: MediaDirection,
+    call_id: &str,
+    secure: bool,
+    attempt: &CancelToken,
+) -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let mut engine = SIP_ENGINE.lock().await;
+
+    if !engine.registered {
+        return Err(SipError::NotRegistered);
+    }
+
+    // The parked call still holds the engine's only dialog
+    if engine.active_dialog.as_ref().is_some_and(|dialog| dialog.parked.is_some()) {
+        return Err(SipError::Protocol("A call is parked; retrieve or hang it up first".to_string()));
+    }
+
+    // One call at a time: a repeated tap on the call button must not send a
+    // second INVITE over the call being set up
+    if let Some(dialog) = engine.active_dialog.as_ref().filter(|dialog| {
+        matches!(dialog.state, CallState::Calling | CallState::Ringing | CallState::Confirmed)
+    }) {
+        println!("[SIP] Not calling {}: call {} is {}", number, dialog.call_id, dialog.state.as_str());
+        return Err(SipError::CallInProgress);
+    }
+
+    let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+    let server = engine.server.clone();
+    let user = engine.user.clone();
+    let local_addr = engine.local_addr.clone();
+
+    println!("[SIP] Making call to: {}", number);
+    println!("[SIP] From: {}@{}", user, server);
+
+    // Build destination URI
+    let dial_as = crate::settings::load_dial_as().unwrap_or_default();
+    let dest_uri = normalize_dial_target(number, &server, dial_as)?;
+
+    println!("[SIP] Destination URI: {}", dest_uri);
+
+    // Create dialog for this call
+    let call_id = call_id.to_string();
+    let from_tag = ids::tag();
+    let from_uri = if dial_as == DialAs::UserPhone && is_e164(&user) {
+        format!("sip:{}@{};user=phone", user, server)
+    } else {
+        format!("sip:{}@{}", user, server)
+    };
+
+    // Offer SRTP when secure media is enabled for the account
+    let srtp_keys = if secure {
+        Some(SrtpKeys::generate()?)
+    } else {
+        None
+    };
+    
+    let mut dialog = Dialog {
+        call_id: call_id.clone(),
+        from_tag: from_tag.clone(),
+        to_tag: None,
+        cseq: 1,
+        remote_uri: dest_uri.clone(),
+        remote_target: None,
+        local_uri: from_uri.clone(),
+        route_set: Vec::new(),
+        state: CallState::Idle,
+        srtp_keys: srtp_keys.clone(),
+        server_addr: None,
+        connected_at: None,
+        rtp_session: None,
+        call_audio: None,
+        audio_tx_task: None,
+        audio_rx_task: None,
+        incoming_invite: None,
+        outgoing_invite: None,
+        sent_ack: None,
+        parked: None,
+        awaiting_ack: None,
+        outgoing: true,
+        direction,
+        attempt: attempt.clone(),
+    };
+    dialog.transition_to(CallState::Calling).map_err(SipError::Internal)?;
+    
+    engine.active_dialog = Some(dialog);
+    drop(engine);
+
+    let route = ResponseRoute::open(&call_id);
+
+    // Generate SDP (Session Description Protocol)
+    let local_ip = &local_host(&local_addr);
+    
+    let media = gather_media_endpoint(local_ip).await?;
+    
+    tracing::info!("[SIP] Allocated RTP port: {}", media.port);
+    println!("[SIP] Allocated RTP port: {}", media.port);
+    
+    // Codecs in the user's preference order
+    let codecs = sdp_codecs(&preferred_payload_types(), offer_telephone_event());
+    let sdp = build_sdp_offer(
+        &media.ip,
+        media.port,
+        &codecs,
+        direction,
+        FRAME_DURATION_MS,
+        OfferOptions {
+            srtp_keys: srtp_keys.as_ref(),
+            bandwidth_kbps: crate::settings::load_bandwidth_limit().unwrap_or(None),
+        },
+    );
+
+    // Build INVITE request
+    let branch = ids::branch();
+    let contact_uri = format!("sip:{}@{}", user, local_addr);
+    let identity_header = preferred_identity_header(
+        &crate::settings::load_preferred_identity().unwrap_or_default(),
+    );
+    
+    let proxy_route = outbound_proxy_route();
+    let invite_msg = message::build_invite(
+        &message::RequestParams {
+            uri: &dest_uri,
+            local_addr: &local_addr,
+            branch: &branch,
+            route: &proxy_route,
+            from_uri: &from_uri,
+            from_name: &local_display_name(),
+            from_tag: &from_tag,
+            to_uri: &dest_uri,
+            to_tag: None,
+            call_id: &call_id,
+            cseq: 1,
+            compact: use_compact_headers(),
+        },
+        &contact_uri,
+        &identity_header,
+        &sdp,
+    );
+
+    println!("[SIP] Sending INVITE...");
+    println!("[SIP] Message:\n{}", invite_msg);
+
+    // Resolve server address (cached after the first lookup)
+    let server_addr = resolve_server(&server).await?;
+
+    // Get password for auth, and pin the dialog to this server address
+    let password = {
+        let mut engine = SIP_ENGINE.lock().await;
+        if let Some(ref mut dialog) = engine.active_dialog {
+            dialog.server_addr = Some(server_addr);
+            dialog.outgoing_invite = Some(invite_msg.clone());
+        }
+        engine.password.clone()
+    };
+
+    // Answer supervision: however many provisional responses keep coming,
+    // the call is cancelled once it has gone unanswered this long
+    let answer_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeouts.answer_secs);
+
+    // Send INVITE with auth handling. The transaction hands back the first
+    // provisional response; the invite timeout only covers getting one.
+    let invite = Transaction::new(&socket, server_addr, timeouts.invite_secs)
+        .authenticate(&user, &password)
+        .until_provisional();
+    let first_response = match invite.send(&invite_msg).await {
+        // Nothing came back at all, so there is no transaction to CANCEL
+        // (RFC 3261 9.1): the call just fails
+        Err(SipError::Timeout(_)) => {
+            println!("[SIP] No response to INVITE within {}s", timeouts.invite_secs);
+            let dialog = {
+                let mut engine = SIP_ENGINE.lock().await;
+                match engine.active_dialog {
+                    Some(ref dialog) if dialog.call_id == call_id => engine.active_dialog.take(),
+                    _ => None,
+                }
+            };
+            if let Some(mut dialog) = dialog {
+                dialog.terminate(Some("timeout"));
+            }
+            return Err(SipError::Timeout(format!("No response to INVITE within {} seconds", timeouts.invite_secs)));
+        }
+        response => response?,
+    };
+    // Later responses are only the ones to this INVITE (after any auth retry)
+    let invite_cseq = message::CSeq::of(&first_response);
+
+    println!("[SIP] First response:");
+    println!("{}", first_response);
+
+    // Check if first response needs further handling
+    if first_response.contains("SIP/2.0 200") {
+        // Call answered immediately
+        println!("[SIP] 200 OK - call answered!");
+        
+        let to_tag = extract_to_tag(&first_response);
+        println!("[SIP] To tag: {:?}", to_tag);
+        
+        let route_set = extract_route_set(&first_response);
+        let remote_target = extract_contact_uri(&first_response);
+        if !route_set.is_empty() {
+            println!("[SIP] Route set: {:?}", route_set);
+        }
+        
+        let mut engine = SIP_ENGINE.lock().await;
+        let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+        dialog.to_tag = to_tag;
+        dialog.route_set = route_set;
+        dialog.remote_target = remote_target;
+        dialog.transition_to(CallState::Confirmed).map_err(SipError::Protocol)?;
+        dialog.connected_at = Some(std::time::Instant::now());
+        dialog.observe_cseq(response_cseq(&first_response));
+        let dialog = dialog.clone();
+        drop(engine);
+        
+        send_ack(&socket, &dialog, &local_addr, server_addr, &first_response).await?;
+        
+        println!("[SIP] ✓✓✓ Call established! ✓✓✓");
+        
+        // Start RTP media session
+        match start_rtp_media(&first_response, media, srtp_keys.as_ref(), direction.negotiate(parse_direction(&first_response))).await {
+            Ok((rtp_session, call_audio, tx_task, rx_task)) => {
+                // Store RTP components in dialog
+                let mut engine = SIP_ENGINE.lock().await;
+                if let Some(ref mut dialog) = engine.active_dialog {
+                    dialog.rtp_session = Some(rtp_session);
+                    dialog.call_audio = Some(call_audio);
+                    dialog.audio_tx_task = tx_task.map(Arc::new);
+                    dialog.audio_rx_task = Some(Arc::new(rx_task));
+                }
+                println!("[SIP] ✓ RTP media active - call has audio!");
+            }
+            Err(e) => media_setup_failed(e, require_media()).await?,
+        }
+
+        start_call_duration_limit().await;
+        return Ok(());
+    } else if first_response.contains("SIP/2.0 180") || first_response.contains("SIP/2.0 183") {
+        println!("[SIP] 180/183 Ringing - waiting for answer...");
+        let mut engine = SIP_ENGINE.lock().await;
+        if let Some(ref mut dialog) = engine.active_dialog {
+            if let Err(e) = dialog.transition_to(CallState::Ringing) {
+                println!("[SIP] {}", e);
+            }
+            dialog.observe_cseq(response_cseq(&first_response));
+        }
+        drop(engine);
+    } else if first_response.contains("SIP/2.0 4") || first_response.contains("SIP/2.0 5") || first_response.contains("SIP/2.0 6") {
+        let error = call_failure(&first_response);
+        println!("[SIP] Call failed: {}", error);
+
+        // Clean up dialog
+        let mut engine = SIP_ENGINE.lock().await;
+        let dialog = engine.active_dialog.take();
+        drop(engine);
+
+        if secure && response_status(&first_response) == 488 {
+            drop(route);
+            return retry_without_srtp(number, direction, &call_id, attempt).await;
+        }
+        if let Some(mut dialog) = dialog {
+            dialog.terminate(Some(error.kind()));
+        }
+
+        return Err(error);
+    }
+
+    // Continue listening for more responses. The call is ringing, so only
+    // the answer deadline bounds the wait from here on.
+    loop {
+        let response_result = tokio::time::timeout_at(answer_deadline, recv_response(&call_id)).await;
+
+        match response_result {
+            Ok(Ok((response_str, _))) if message::CSeq::of(&response_str) != invite_cseq => {
+                println!("[SIP] Ignoring response that isn't for the INVITE: {}", response_str.lines().next().unwrap_or(""));
+                continue;
+            }
+            Ok(Ok((response_str, from_addr))) => {
+                println!("[SIP] Received response from {} ({} bytes):", from_addr, response_str.len());
+                println!("{}", response_str);
+
+                if response_str.contains("SIP/2.0 100") {
+                    println!("[SIP] 100 Trying - call is being processed");
+                    continue;
+                } else if response_str.contains("SIP/2.0 180") || response_str.contains("SIP/2.0 183") {
+                    println!("[SIP] 180/183 Ringing - remote party is being alerted");
+                    let mut engine = SIP_ENGINE.lock().await;
+                    if let Some(ref mut dialog) = engine.active_dialog {
+                        if let Err(e) = dialog.transition_to(CallState::Ringing) {
+                            println!("[SIP] {}", e);
+                        }
+                    }
+                    drop(engine);
+                    continue;
+                } else if response_str.contains("SIP/2.0 200") {
+                    println!("[SIP] 200 OK - call answered!");
+                    
+                    // Extract To tag from response
+                    let to_tag = extract_to_tag(&response_str);
+                    println!("[SIP] To tag: {:?}", to_tag);
+                    
+                    let route_set = extract_route_set(&response_str);
+                    let remote_target = extract_contact_uri(&response_str);
+                    if !route_set.is_empty() {
+                        println!("[SIP] Route set: {:?}", route_set);
+                    }
+                    
+                    // Update dialog
+                    let mut engine = SIP_ENGINE.lock().await;
+                    let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+                    dialog.to_tag = to_tag;
+                    dialog.route_set = route_set;
+                    dialog.remote_target = remote_target;
+                    dialog.transition_to(CallState::Confirmed).map_err(SipError::Protocol)?;
+                    dialog.connected_at = Some(std::time::Instant::now());
+                    let dialog = dialog.clone();
+                    drop(engine);
+                    
+                    // Send ACK
+                    send_ack(&socket, &dialog, &local_addr, server_addr, &response_str).await?;
+                    
+                    println!("[SIP] ✓✓��� Call established! ✓✓✓");
+                    // Start RTP media session
+                    match start_rtp_media(&response_str, media, srtp_keys.as_ref(), direction.negotiate(parse_direction(&response_str))).await {
+                        Ok((rtp_session, call_audio, tx_task, rx_task)) => {
+                            // Store RTP components in dialog
+                            let mut engine = SIP_ENGINE.lock().await;
+                            if let Some(ref mut dialog) = engine.active_dialog {
+                                dialog.rtp_session = Some(rtp_session);
+                                dialog.call_audio = Some(call_audio);
+                                dialog.audio_tx_task = tx_task.map(Arc::new);
+                                dialog.audio_rx_task = Some(Arc::new(rx_task));
+                            }
+                            println!("[SIP] ✓ RTP media active - call has audio!");
+                        }
+                        Err(e) => media_setup_failed(e, require_media()).await?,
+                    }
+
+                    start_call_duration_limit().await;
+                    return Ok(());
+                } else if response_str.contains("SIP/2.0 4") || response_str.contains("SIP/2.0 5") || response_str.contains("SIP/2.0 6") {
+                    let error = call_failure(&response_str);
+                    println!("[SIP] Call failed: {}", error);
+                    
+                    // Clean up dialog
+                    let mut engine = SIP_ENGINE.lock().await;
+                    let dialog = engine.active_dialog.take();
+                    drop(engine);
+                    let retry = secure && response_status(&response_str) == 488;
+
+                    if let Some(mut dialog) = dialog {
+                        if !retry {
+                            dialog.terminate(Some(error.kind()));
+                        }
+                        if let Some(invite) = dialog.outgoing_invite {
+                            let ack = message::build_non2xx_ack(&invite, &response_str);
+                            socket.send_to(ack.as_bytes(), server_addr).await
+                                .map_err(|e| SipError::Network(format!("Failed to send ACK: {}", e)))?;
+                        }
+                    }
+
+                    if retry {
+                        drop(route);
+                        return retry_without_srtp(number, direction, &call_id, attempt).await;
+                    }
+                    return Err(error);
+                }
+            }
+            Ok(Err(e)) => {
+                println!("[SIP] Socket error: {}", e);
+                return Err(e);
+            }
+            Err(_) => {
+                return cancel_unanswered_call(&socket, &call_id, &local_addr, server_addr, &timeouts).await;
+            }
+        }
+    }
+}
+
+// The peer rejected our RTP/SAVP offer (488): place the call again offering
+// plain RTP. The failed dialog is dropped without a TERMINATED event so the
+// UI just stays in its calling state.
+async fn retry_without_srtp(
+    number: &str,
+    direction: MediaDirection,
+    call_id: &str,
+    attempt: &CancelToken,
+) -> Result<(), SipError> {
+    println!("[SRTP] RTP/SAVP offer not acceptable, retrying the call with plain RTP");
+    Box::pin(place_call(number, direction, call_id, false, attempt)).await
+}
+
+// Error for a final failure response to our INVITE: the status line plus
+// any Warning text, as Unavailable when the server gave a Retry-After
+fn call_failure(response: &str) -> SipError {
+    let status_line = response.lines().next().unwrap_or("Unknown error");
+    let message = match warning_text(response) {
+        Some(warning) => format!("Call failed: {} ({})", status_line, warning),
+        None => format!("Call failed: {}", status_line),
+    };
+    match retry_after(response) {
+        Some(retry_after) => SipError::Unavailable { message, retry_after },
+        None => SipError::Rejected(message),
+    }
+}
+
+// Text of a response's Warning headers (RFC 3261 20.43), which often says
+// why media negotiation failed: `304 pbx.example.com "Media type not
+// available"` reads "304 Media type not available"
+fn warning_text(response: &str) -> Option<String> {
+    let warnings: Vec<String> = header_values(response, "Warning")
+        .iter()
+        .filter_map(|warning| {
+            let (code, rest) = warning.split_once(' ')?;
+            let text = rest.split_once('"').map(|(_, text)| text)?;
+            let text = text.strip_suffix('"').unwrap_or(text).replace("\\\"", "\"");
+            Some(format!("{} {}", code, text))
+        })
+        .collect();
+    (!warnings.is_empty()).then(|| warnings.join("; "))
+}
+
+// Give up on an outgoing call that got a provisional response but was not
+// answered in time: CANCEL the pending INVITE and wait (INVITE timeout) for
+// it to end with 487. If the callee answered just as we gave up, the call is
+// acknowledged and hung up.
+async fn cancel_unanswered_call(
+    socket: &UdpSocket,
+    call_id: &str,
+    local_addr: &str,
+    server_addr: SocketAddr,
+    timeouts: &SipTimeouts,
+) -> Result<(), SipError> {
+    println!("[SIP] Call not answered after {}s, cancelling", timeouts.answer_secs);
+    tracing::info!("[SIP] Call {} not answered after {}s, cancelling", call_id, timeouts.answer_secs);
+
+    let dialog = {
+        let mut engine = SIP_ENGINE.lock().await;
+        match engine.active_dialog {
+            Some(ref dialog) if dialog.call_id == call_id => engine.active_dialog.take(),
+            _ => None,
+        }
+    };
+
+    events::emit("call_timeout", serde_json::json!({
+        "call_id": call_id,
+        "timeout_secs": timeouts.answer_secs,
+    }));
+
+    let result = Err(SipError::Timeout(format!("Call not answered within {} seconds", timeouts.answer_secs)));
+    let Some(mut dialog) = dialog else { return result };
+    dialog.terminate(Some("timeout"));
+    cancel_invite(socket, dialog, local_addr, server_addr, timeouts).await?;
+    result
+}
+
+// CANCEL the pending INVITE of a call we gave up on and wait (INVITE
+// timeout) for it to end with 487. If the callee answered just as we gave
+// up, the call is acknowledged and hung up. Responses must be routed here.
+async fn cancel_invite(
+    socket: &UdpSocket,
+    mut dialog: Dialog,
+    local_addr: &str,
+    server_addr: SocketAddr,
+    timeouts: &SipTimeouts,
+) -> Result<(), SipError> {
+    let Some(invite) = dialog.outgoing_invite.take() else { return Ok(()) };
+    let call_id = dialog.call_id.clone();
+
+    let cancel = message::build_cancel(&invite);
+    println!("[SIP] Sending CANCEL...");
+    socket.send_to(cancel.as_bytes(), server_addr).await
+        .map_err(|e| SipError::Network(format!("Failed to send CANCEL: {}", e)))?;
+
+    // The 200 for the CANCEL itself is skipped; only the INVITE's final
+    // response closes the call
+    loop {
+        match tokio::time::timeout(
+            std::time::Duration::from_secs(timeouts.invite_secs),
+            recv_response(&call_id)
+        ).await {
+            Ok(Ok((response, _))) => {
+                let Some(cseq) = message::CSeq::of(&response).filter(|cseq| cseq.method == "INVITE") else {
+                    continue;
+                };
+                if response.starts_with("SIP/2.0 1") {
+                    continue;
+                }
+                println!("[SIP] Cancelled INVITE ended with: {}", response.lines().next().unwrap_or(""));
+
+                if !response.starts_with("SIP/2.0 2") {
+                    // Normally the 487 for our CANCEL
+                    let ack = message::build_non2xx_ack(&invite, &response);
+                    socket.send_to(ack.as_bytes(), server_addr).await
+                        .map_err(|e| SipError::Network(format!("Failed to send ACK: {}", e)))?;
+                } else {
+                    dialog.to_tag = extract_to_tag(&response);
+                    dialog.route_set = extract_route_set(&response);
+                    dialog.remote_target = extract_contact_uri(&response);
+                    dialog.observe_cseq(Some(cseq.number));
+                    send_ack(socket, &dialog, local_addr, server_addr, &response).await?;
+                    if let Err(e) = send_bye(socket, &dialog).await {
+                        println!("[SIP] BYE after late answer failed: {}", e);
+                    }
+                }
+                break;
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                println!("[SIP] No final response to the cancelled INVITE");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Clean up after a call attempt that was cancelled or failed (`reason` is
+// the error kind) and left its dialog behind. The UI gets control back at
+// once; a pending INVITE is CANCELled, or a call answered in the meantime
+// hung up, in the background.
+async fn abandon_call(call_id: &str, reason: &str) {
+    let (dialog, socket, local_addr) = {
+        let mut engine = SIP_ENGINE.lock().await;
+        let ours = engine.active_dialog.as_ref().is_some_and(|dialog| dialog.call_id == call_id);
+        if !ours {
+            return;
+        }
+        if let Some(task) = engine.call_limit_task.take() {
+            task.abort();
+        }
+        (engine.active_dialog.take(), engine.socket.clone(), engine.local_addr.clone())
+    };
+    let (Some(mut dialog), Some(socket)) = (dialog, socket) else { return };
+    let answered = dialog.state == CallState::Confirmed;
+    dialog.terminate(Some(reason));
+
+    // No server address yet means the INVITE never went out
+    let Some(server_addr) = dialog.server_addr else { return };
+    tokio::spawn(async move {
+        let result = if answered {
+            stop_dialog_media(&dialog).await;
+            send_bye(&socket, &dialog).await
+        } else {
+            let _route = ResponseRoute::open(&dialog.call_id);
+            let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+            cancel_invite(&socket, dialog, &local_addr, server_addr, &timeouts).await
+        };
+        if let Err(e) = result {
+            println!("[SIP] Ending the abandoned call failed: {}", e);
+        }
+    });
+}
+
+// Address the RTP socket binds to: the IP advertised in our SDP, or the
+// wildcard if it is not a literal address
+fn rtp_bind_ip(local_ip: &str) -> std::net::IpAddr {
+    local_ip.parse().unwrap_or_else(|_| {
+        tracing::warn!("[RTP] Local IP {} is not an address, binding RTP to all interfaces", local_ip);
+        std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)
+    })
+}
+
+// The socket a call's media will use, and the address to put in our SDP
+struct MediaEndpoint {
+    socket: UdpSocket,
+    relay: Option<Arc<TurnRelay>>,
+    // Set when the SDP carries a relayed or STUN-mapped address
+    advertised: Option<SocketAddr>,
+    ip: String,
+    port: u16,
+}
+
+// Bind the call's RTP socket and work out the address peers can reach it
+// on: a TURN relay if one is configured, else the STUN-mapped address, else
+// the host address
+async fn gather_media_endpoint(local_ip: &str) -> Result<MediaEndpoint, SipError> {
+    let socket = UdpSocket::bind(SocketAddr::new(rtp_bind_ip(local_ip), 0)).await
+        .map_err(|e| SipError::Network(format!("Failed to allocate RTP port: {}", e)))?;
+    let port = socket.local_addr()
+        .map_err(|e| SipError::Network(format!("Failed to get RTP port: {}", e)))?
+        .port();
+    let config = crate::settings::load_media_relay().unwrap_or_default();
+
+    if !config.turn_server.is_empty() {
+        let allocation = match stun::resolve(&config.turn_server).await {
+            Ok(server) => TurnRelay::allocate(&socket, server, &config.turn_user, &config.turn_password).await,
+            Err(e) => Err(e),
+        };
+        match allocation {
+            Ok(relay) => {
+                let relayed = relay.relayed_addr();
+                println!("[TURN] ✓ Media relayed at {}", relayed);
+                return Ok(MediaEndpoint {
+                    socket,
+                    relay: Some(Arc::new(relay)),
+                    advertised: Some(relayed),
+                    ip: relayed.ip().to_string(),
+                    port: relayed.port(),
+                });
+            }
+            Err(e) => {
+                tracing::warn!("[TURN] Allocation on {} failed: {}", config.turn_server, e);
+                println!("[TURN] ⚠ Allocation on {} failed: {}", config.turn_server, e);
+            }
+        }
+    }
+
+    if !config.stun_server.is_empty() {
+        let mapped = match stun::resolve(&config.stun_server).await {
+            Ok(server) => stun::binding(&socket, server).await,
+            Err(e) => Err(e),
+        };
+        match mapped {
+            Ok(mapped) => {
+                println!("[STUN] ✓ Media reachable at {}", mapped);
+                SIP_ENGINE.lock().await.public_addr = Some(mapped);
+                return Ok(MediaEndpoint {
+                    socket,
+                    relay: None,
+                    advertised: Some(mapped),
+                    ip: mapped.ip().to_string(),
+                    port: mapped.port(),
+                });
+            }
+            Err(e) => {
+                tracing::warn!("[STUN] Binding request to {} failed: {}", config.stun_server, e);
+                println!("[STUN] ⚠ Binding request to {} failed: {}", config.stun_server, e);
+            }
+        }
+    }
+
+    Ok(MediaEndpoint { socket, relay: None, advertised: None, ip: local_ip.to_string(), port })
+}
+
+// Address a running session's media is offered at in a new SDP answer
+fn session_media_addr(rtp_session: &RtpSession, local_ip: &str) -> (String, u16) {
+    match rtp_session.advertised_addr() {
+        Some(addr) => (addr.ip().to_string(), addr.port()),
+        None => (local_ip.to_string(), rtp_session.local_port()),
+    }
+}
+
+// Send ACK to confirm call establishment
+async fn send_ack(
+    socket: &UdpSocket,
+    dialog: &Dialog,
+    local_addr: &str,
+    server_addr: std::net::SocketAddr,
+    answer: &str,
+) -> Result<(), SipError> {
+    let branch = ids::branch();
+    let route = dialog_route_headers(&dialog.route_set);
+    
+    // ACK CSeq must match the INVITE it acknowledges, including an INVITE
+    // resent with credentials (CSeq 2) or a re-INVITE
+    let invite_cseq = response_cseq(answer).unwrap_or(dialog.cseq);
+    let ack_msg = message::build_ack(&dialog.request_params(local_addr, &branch, &route, invite_cseq));
+
+    println!("[SIP] Sending ACK...");
+    println!("[SIP] ACK message:\n{}", ack_msg);
+    
+    socket.send_to(ack_msg.as_bytes(), server_addr).await
+        .map_err(|e| SipError::Network(format!("Failed to send ACK: {}", e)))?;
+
+    println!("[SIP] ✓ ACK sent");
+
+    // Kept for retransmissions of the 2xx
+    let mut engine = SIP_ENGINE.lock().await;
+    if let Some(active) = engine.active_dialog.as_mut().filter(|active| active.call_id == dialog.call_id) {
+        active.sent_ack = Some((invite_cseq, ack_msg));
+    }
+    Ok(())
+}
+
+// The peer retransmits its 2xx to our INVITE until our ACK gets through
+// (RFC 3261 13.2.2.4); answer each copy with the ACK we already sent
+async fn resend_ack(socket: &UdpSocket, response: &str) {
+    let call_id = header_value(response, "Call-ID").unwrap_or_default();
+    let ack = {
+        let engine = SIP_ENGINE.lock().await;
+        engine
+            .active_dialog
+            .as_ref()
+            .filter(|dialog| dialog.call_id == call_id)
+            .and_then(|dialog| dialog.sent_ack.clone().zip(dialog.server_addr))
+    };
+    match ack {
+        Some(((invite_cseq, ack), server_addr)) if response_cseq(response) == Some(invite_cseq) => {
+            println!("[SIP] 2xx retransmitted for {}, resending ACK", call_id);
+            if let Err(e) = socket.send_to(ack.as_bytes(), server_addr).await {
+                println!("[SIP] Failed to resend ACK: {}", e);
+            }
+        }
+        _ => println!("[SIP] Ignoring 2xx for unknown INVITE (Call-ID {})", call_id),
+    }
+}
+
+// Build the route set from the Record-Route headers of a 2xx response.
+// As the UAC we use the Record-Route entries in reverse order (RFC 3261 12.1.2).
+fn extract_route_set(response: &str) -> Vec<String> {
+    let mut record_route = Vec::new();
+    for line in response.lines() {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Record-Route") {
+                record_route.extend(split_header_values(value));
+            }
+        }
+    }
+    record_route.reverse();
+    record_route
+}
+
+// Route set of a dialog we accept (UAS): the request's Record-Route entries
+// in the order received (RFC 3261 12.1.1)
+fn extract_record_route(request: &str) -> Vec<String> {
+    let mut route_set = extract_route_set(request);
+    route_set.reverse();
+    route_set
+}
+
+// Split a comma-separated header value, ignoring commas inside <...> or quotes
+fn split_header_values(value: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut in_angle = false;
+    let mut in_quotes = false;
+
+    for c in value.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angle = true,
+            '>' if !in_quotes => in_angle = false,
+            ',' if !in_angle && !in_quotes => {
+                if !current.trim().is_empty() {
+                    values.push(current.trim().to_string());
+                }
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        values.push(current.trim().to_string());
+    }
+    values
+}
+
+// Format a route set as Route header lines (empty string when there is no route)
+fn route_headers(route_set: &[String]) -> String {
+    route_set
+        .iter()
+        .map(|route| format!("Route: {}\r\n", route))
+        .collect()
+}
+
+// Route lines for an in-dialog request: the dialog's route set, or the
+// outbound proxy when the dialog has none
+fn dialog_route_headers(route_set: &[String]) -> String {
+    if route_set.is_empty() {
+        outbound_proxy_route()
+    } else {
+        route_headers(route_set)
+    }
+}
+
+// Preloaded Route line for the configured outbound proxy (RFC 3261 8.1.2),
+// empty when requests go straight to the server
+fn outbound_proxy_route() -> String {
+    let proxy = crate::settings::load_outbound_proxy().unwrap_or_default();
+    match outbound_proxy_host(&proxy) {
+        Some(host) => format!("Route: <sip:{};lr>\r\n", host),
+        None => String::new(),
+    }
+}
+
+// host[:port] of an outbound proxy setting, which may be written as
+// "proxy.example.com:5060", "sip:proxy.example.com" or "<sip:proxy.example.com;lr>"
+fn outbound_proxy_host(proxy: &str) -> Option<String> {
+    let proxy = proxy.trim().trim_start_matches('<').trim_end_matches('>');
+    let proxy = proxy
+        .strip_prefix("sip:")
+        .or_else(|| proxy.strip_prefix("SIP:"))
+        .unwrap_or(proxy);
+    let host = proxy.split(';').next().unwrap_or("").trim();
+    if is_valid_sip_host(host) {
+        Some(host.to_string())
+    } else {
+        None
+    }
+}
+
+// Extract the URI from the Contact header of a SIP message
+fn extract_contact_uri(message: &str) -> Option<String> {
+    for line in message.lines() {
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if message::header_name_is(name, "Contact") {
+                if let Some(uri) = name_addr_uri(value).filter(|uri| uri != "*") {
+                    return Some(uri);
+                }
+            }
+        }
+    }
+    None
+}
+
+// URI of a name-addr/addr-spec header value ("Bob" <sip:bob@host>;tag=x)
+fn name_addr_uri(value: &str) -> Option<String> {
+    let value = value.trim();
+    let uri = match (value.find('<'), value.find('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        // No angle brackets: URI runs until the first header parameter
+        _ => value.split(';').next().unwrap_or(value),
+    };
+    let uri = uri.trim();
+    if uri.is_empty() {
+        None
+    } else {
+        Some(uri.to_string())
+    }
+}
+
+// tag parameter of a From/To header value (outside the <...> URI)
+fn header_tag(value: &str) -> Option<String> {
+    header_param(value, "tag")
+}
+
+// Header parameter of a name-addr value (outside the <...> URI)
+fn header_param(value: &str, param_name: &str) -> Option<String> {
+    let params = match value.rfind('>') {
+        Some(end) => &value[end + 1..],
+        None => value,
+    };
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case(param_name) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// Display name of a name-addr ("Alice Smith" <sip:alice@host>)
+fn display_name(value: &str) -> Option<String> {
+    let end = value.find('<')?;
+    let name = value[..end].trim().trim_matches('"').trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// Extract To tag from SIP response
+fn extract_to_tag(response: &str) -> Option<String> {
+    let to = header_value(response, "To")?;
+    let tag_part = to.split("tag=").nth(1)?;
+    Some(tag_part.split(';').next().unwrap_or(tag_part).trim().to_string())
+}
+
+// Codec to answer with: the requested one if it was offered, otherwise
+// our most preferred offered codec that fits the caller's bandwidth
+fn answer_payload_type(offer: &str, codec: Option<&str>) -> Option<u8> {
+    let offered = offered_payload_types(offer);
+    if let Some(codec) = codec {
+        match codec_payload_type(codec) {
+            Some(pt) if offered.contains(&pt) => return Some(pt),
+            _ => {
+                tracing::warn!("[SIP] Requested codec {} was not offered, using the best available", codec);
+                println!("[SIP] ⚠ Requested codec {} was not offered, using the best available", codec);
+            }
+        }
+    }
+    let preferences = prefer_within_bandwidth(&preferred_payload_types(), parse_bandwidth(offer));
+    choose_payload_type(&offered, &preferences)
+}
+
+/// Answer the ringing call. `codec` (e.g. "PCMU") restricts the answer to
+/// that codec if the caller offered it; otherwise our preferences decide.
+pub async fn answer_call(codec: Option<&str>) -> Result<(), SipError> {
+    let mut engine = SIP_ENGINE.lock().await;
+
+    if !engine.registered {
+        return Err(SipError::NotRegistered);
+    }
+
+    let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+    let user = engine.user.clone();
+    let local_addr = engine.local_addr.clone();
+
+    let dialog = match engine.active_dialog.as_ref() {
+        Some(dialog) if dialog.incoming_invite.is_some() => dialog.clone(),
+        _ => return Err(SipError::NoActiveCall),
+    };
+    engine.ringing = None;
+    drop(engine);
+
+    println!("[SIP] Answering incoming call {}", dialog.call_id);
+
+    let invite = dialog.incoming_invite.clone().unwrap_or_default();
+    let caller_addr = dialog.server_addr.ok_or(SipError::NoActiveCall)?;
+    let offer = message_body(&invite);
+    let payload_type = answer_payload_type(offer, codec)
+        .ok_or_else(|| SipError::Protocol("Incoming call has no supported codec".to_string()))?;
+
+    // Encrypted offers are answered with our own key
+    let srtp_keys = if is_secure_profile(offer) && parse_crypto_attribute(offer).is_some() {
+        Some(SrtpKeys::generate()?)
+    } else {
+        None
+    };
+
+    let local_ip = &local_host(&local_addr);
+    let media = gather_media_endpoint(local_ip).await?;
+    let sdp = build_sdp_answer(&media.ip, media.port, payload_type, srtp_keys.as_ref(), MediaDirection::SendRecv);
+    let contact = format!("Contact: <sip:{}@{}>\r\n", user, local_addr);
+    let response = build_response(&invite, "200 OK", Some(&dialog.from_tag), &contact, &sdp);
+
+    // The caller may have cancelled while we were getting ready
+    let acked = Arc::new(tokio::sync::Notify::new());
+    {
+        let mut engine = SIP_ENGINE.lock().await;
+        match engine.active_dialog.as_mut() {
+            Some(active) if active.call_id == dialog.call_id && active.incoming_invite.is_some() => {
+                active.transition_to(CallState::Confirmed).map_err(SipError::Protocol)?;
+                active.connected_at = Some(std::time::Instant::now());
+                active.srtp_keys = srtp_keys.clone();
+                active.incoming_invite = None;
+                active.awaiting_ack = Some((response_cseq(&invite).unwrap_or(1), response.clone(), acked.clone()));
+            }
+            _ => return Err(SipError::NoActiveCall),
+        }
+    }
+
+    send_response(&socket, &response, caller_addr).await?;
+    println!("[SIP] ✓ Call answered (PT {}), waiting for ACK", payload_type);
+
+    // Media starts once the caller has confirmed the answer
+    if !retransmit_until_acked(&socket, &response, caller_addr, &acked).await {
+        println!("[SIP] No ACK for our 200 OK within {}s, ending the call", ACK_TIMEOUT.as_secs());
+        let ours = SIP_ENGINE.lock().await.active_dialog.as_ref().is_some_and(|active| active.call_id == dialog.call_id);
+        if ours {
+            if let Err(e) = hangup_call().await {
+                println!("[SIP] BYE after missing ACK failed: {}", e);
+            }
+        }
+        return Err(SipError::Timeout("No ACK for our 200 OK".to_string()));
+    }
+
+    match start_rtp_media(offer, media, srtp_keys.as_ref(), MediaDirection::SendRecv.negotiate(parse_direction(offer))).await {
+        Ok((rtp_session, call_audio, tx_task, rx_task)) => {
+            // Hand the media to the dialog first, so whatever ends the call
+            // from here on also stops it
+            let mut engine = SIP_ENGINE.lock().await;
+            match engine.active_dialog.as_mut().filter(|active| active.call_id == dialog.call_id) {
+                Some(active) => {
+                    active.rtp_session = Some(rtp_session.clone());
+                    active.call_audio = Some(call_audio);
+                    active.audio_tx_task = tx_task.map(Arc::new);
+                    active.audio_rx_task = Some(Arc::new(rx_task));
+                }
+                None => {
+                    drop(engine);
+                    println!("[SIP] Call {} ended while its media was starting", dialog.call_id);
+                    if let Some(tx_task) = tx_task {
+                        tx_task.abort();
+                    }
+                    rx_task.abort();
+                    call_audio.stop();
+                    rtp_session.release_relay().await;
+                    return Err(SipError::NoActiveCall);
+                }
+            }
+            drop(engine);
+
+            // The offer may list an unsupported codec first; send what we answered
+            if let Err(e) = apply_remote_offer(&rtp_session, offer).await {
+                media_setup_failed(e, require_media()).await?;
+            }
+            println!("[SIP] ✓ RTP media active - call has audio!");
+        }
+        Err(e) => media_setup_failed(e, require_media()).await?,
+    }
+
+    start_call_duration_limit().await;
+    Ok(())
+}
+
+// Whether a call whose media can't be started is hung up instead of left silent
+fn require_media() -> bool {
+    crate::settings::load_require_media().unwrap_or(false)
+}
+
+// Media could not be started for a call that is already up. The UI hears
+// about it either way; when media is required the call is hung up and the
+// error returned, otherwise the call stays up without audio.
+async fn media_setup_failed(error: SipError, require: bool) -> Result<(), SipError> {
+    tracing::error!("[RTP] Failed to start media: {}", error);
+    eprintln!("[RTP] Failed to start media: {}", error);
+
+    let call_id = SIP_ENGINE.lock().await.active_dialog.as_ref().map(|dialog| dialog.call_id.clone());
+    events::emit("media_setup_failed", serde_json::json!({
+        "call_id": call_id,
+        "error": error,
+        "hung_up": require,
+    }));
+
+    if !require {
+        println!("[SIP] Call established but no audio (RTP failed)");
+        return Ok(());
+    }
+
+    println!("[SIP] Call has no audio and media is required, hanging up");
+    if let Err(e) = hangup_call().await {
+        println!("[SIP] Hangup after media failure failed: {}", e);
+    }
+    Err(error)
+}
+
+// Start the maximum call duration timer for a call that was just answered
+// (max_call_duration_secs, 0 = unlimited)
+async fn start_call_duration_limit() {
+    let max_secs = crate::settings::load_max_call_duration().unwrap_or(0);
+    if max_secs > 0 {
+        schedule_call_auto_end(std::time::Duration::from_secs(max_secs)).await;
+    }
+}
+
+// Hang up the current call once `limit` has passed. hangup_call cancels the
+// timer, so a call that ends normally is never touched.
+async fn schedule_call_auto_end(limit: std::time::Duration) {
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(limit).await;
+
+        // Detach ourselves first: hangup_call aborts the limit task
+        let call_id = {
+            let mut engine = SIP_ENGINE.lock().await;
+            engine.call_limit_task.take();
+            engine.active_dialog.as_ref().map(|dialog| dialog.call_id.clone())
+        };
+        let Some(call_id) = call_id else { return };
+
+        println!("[SIP] Call reached the maximum duration of {}s, hanging up", limit.as_secs());
+        events::emit("call_auto_ended", serde_json::json!({
+            "call_id": call_id,
+            "max_duration_secs": limit.as_secs(),
+        }));
+
+        if let Err(e) = hangup_call().await {
+            println!("[SIP] Hangup at maximum call duration failed: {}", e);
+        }
+    });
+
+    if let Some(old_task) = SIP_ENGINE.lock().await.call_limit_task.replace(task) {
+        old_task.abort();
+    }
+}
+
+pub async fn hangup_call() -> Result<(), SipError> {
+    let mut engine = SIP_ENGINE.lock().await;
+
+    if !engine.registered {
+        return Err(SipError::NotRegistered);
+    }
+
+    let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+    
+    let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+    // Already being hung up (the BYE is still out)
+    if dialog.state == CallState::Terminated {
+        return Err(SipError::NoActiveCall);
+    }
+    // Our INVITE is still unanswered, so there is no dialog to BYE: that
+    // call attempt is given up and its INVITE CANCELled (see abandon_call)
+    if dialog.outgoing && matches!(dialog.state, CallState::Calling | CallState::Ringing) {
+        println!("[SIP] Hanging up call {} before it was answered", dialog.call_id);
+        dialog.attempt.cancel();
+        return Ok(());
+    }
+    dialog.terminate(None);
+    let dialog = dialog.clone();
+
+    if let Some(task) = engine.call_limit_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.auto_answer_task.take() {
+        task.abort();
+    }
+    engine.ringing = None;
+    drop(engine);
+
+    println!("[SIP] Hanging up call");
+    println!("[SIP] Call-ID: {}", dialog.call_id);
+
+    stop_dialog_media(&dialog).await;
+
+    // A call we haven't answered is declined rather than hung up
+    let result = match (&dialog.incoming_invite, dialog.server_addr) {
+        (Some(invite), Some(caller_addr)) => {
+            let response = build_response(invite, "603 Decline", Some(&dialog.from_tag), "", "");
+            send_response(&socket, &response, caller_addr).await
+        }
+        _ => send_bye(&socket, &dialog).await,
+    };
+
+    // The call is over for us either way; a failed BYE is still reported
+    // once the dialog is cleaned up
+    let mut engine = SIP_ENGINE.lock().await;
+    if engine.active_dialog.as_ref().is_some_and(|active| active.call_id == dialog.call_id) {
+        engine.active_dialog = None;
+    }
+    drop(engine);
+
+    println!("[SIP] ✓ Call ended ({}s)", dialog.duration_secs());
+    result
+}
+
+// Stop a dialog's audio tasks and streams and free its TURN relay
+async fn stop_dialog_media(dialog: &Dialog) {
+    if let Some(ref tx_task) = dialog.audio_tx_task {
+        tx_task.abort();
+        println!("[Audio] TX task aborted");
+    }
+    if let Some(ref rx_task) = dialog.audio_rx_task {
+        rx_task.abort();
+        println!("[Audio] RX task aborted");
+    }
+    if let Some(ref call_audio) = dialog.call_audio {
+        call_audio.stop();
+    }
+    if let Some(ref rtp_session) = dialog.rtp_session {
+        rtp_session.release_relay().await;
+    }
+}
+
+// Send BYE for a dialog and wait (bye timeout) for the answer. Only the
+// signaling is touched; the caller decides what happens to the media.
+// 200 and 481 (the dialog was already gone) both end the call; any other
+// final response or no response at all is an error.
+async fn send_bye(socket: &UdpSocket, dialog: &Dialog) -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let (server, user, password, local_addr) = {
+        let engine = SIP_ENGINE.lock().await;
+        (engine.server.clone(), engine.user.clone(), engine.password.clone(), engine.local_addr.clone())
+    };
+    let _route = ResponseRoute::open(&dialog.call_id);
+
+    let branch = ids::branch();
+    let route = dialog_route_headers(&dialog.route_set);
+    // The BYE ends the dialog, so the CSeq it takes is never needed again
+    let cseq = dialog.clone().next_cseq();
+    let bye_msg = message::build_bye(&dialog.request_params(&local_addr, &branch, &route, cseq));
+
+    println!("[SIP] Sending BYE...");
+    println!("[SIP] Message:\n{}", bye_msg);
+
+    // Send to the server that handled the INVITE
+    let server_addr = match dialog.server_addr {
+        Some(addr) => addr,
+        None => resolve_server(&server).await?,
+    };
+
+    let response = Transaction::new(socket, server_addr, timeouts.bye_secs)
+        .authenticate(&user, &password)
+        .send(&bye_msg)
+        .await
+        .map_err(|e| match e {
+            SipError::Timeout(_) => SipError::Timeout("No response to BYE".to_string()),
+            e => e,
+        })?;
+
+    let status_line = response.lines().next().unwrap_or("Unknown");
+    match response_status(&response) {
+        200..=299 => {
+            println!("[SIP] ✓ Call terminated successfully");
+            Ok(())
+        }
+        481 => {
+            println!("[SIP] Dialog was already gone on the server (481)");
+            Ok(())
+        }
+        _ => Err(SipError::Rejected(format!("BYE failed: {}", status_line))),
+    }
+}
+
+// Slot a parked call goes into; there is only one while the engine keeps a
+// single dialog
+const PARK_SLOT: u32 = 1;
+
+/// A call parked locally (on hold in a slot, no PBX park orbit)
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ParkedCall {
+    pub slot: u32,
+    pub call_id: String,
+    pub remote_uri: String,
+    pub parked_secs: u64,
+}
+
+/// Put the active call on hold and park it in a local slot, returning the
+/// slot. Nothing is sent or played until it is retrieved.
+pub async fn park_call() -> Result<u32, SipError> {
+    let already_parked = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .is_some_and(|dialog| dialog.parked.is_some());
+    if already_parked {
+        return Err(SipError::Protocol("Call is already parked".to_string()));
+    }
+
+    hold_active_call().await?;
+
+    let mut engine = SIP_ENGINE.lock().await;
+    let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+    dialog.parked = Some((PARK_SLOT, std::time::Instant::now()));
+
+    println!("[SIP] Call {} parked in slot {}", dialog.call_id, PARK_SLOT);
+    events::emit("call_parked", serde_json::json!({
+        "slot": PARK_SLOT,
+        "call_id": dialog.call_id,
+        "remote_uri": dialog.remote_uri,
+    }));
+    Ok(PARK_SLOT)
+}
+
+/// Calls currently parked
+pub async fn list_parked() -> Vec<ParkedCall> {
+    let engine = SIP_ENGINE.lock().await;
+    engine
+        .active_dialog
+        .iter()
+        .filter_map(|dialog| {
+            dialog.parked.map(|(slot, parked_at)| ParkedCall {
+                slot,
+                call_id: dialog.call_id.clone(),
+                remote_uri: dialog.remote_uri.clone(),
+                parked_secs: parked_at.elapsed().as_secs(),
+            })
+        })
+        .collect()
+}
+
+/// Take the call in `slot` off hold and back to normal audio
+pub async fn retrieve_parked(slot: u32) -> Result<(), SipError> {
+    let parked = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .is_some_and(|dialog| dialog.parked.is_some_and(|(parked_slot, _)| parked_slot == slot));
+    if !parked {
+        return Err(SipError::NoActiveCall);
+    }
+
+    resume_active_call().await?;
+
+    let mut engine = SIP_ENGINE.lock().await;
+    let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+    dialog.parked = None;
+
+    println!("[SIP] Call {} retrieved from slot {}", dialog.call_id, slot);
+    events::emit("call_retrieved", serde_json::json!({
+        "slot": slot,
+        "call_id": dialog.call_id,
+    }));
+    Ok(())
+}
+
+// Put the active call on hold (re-INVITE, RFC 3264 8.4) and stop its audio
+async fn hold_active_call() -> Result<(), SipError> {
+    let direction = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .map_or(MediaDirection::SendRecv, |dialog| dialog.direction);
+    send_reinvite(direction.hold()).await?;
+
+    if let Some(rtp_session) = active_rtp_session().await {
+        rtp_session.set_direction(MediaDirection::Inactive).await;
+    }
+    Ok(())
+}
+
+// Take the active call off hold, back to what it was placed or answered
+// with (not necessarily sendrecv)
+async fn resume_active_call() -> Result<(), SipError> {
+    let direction = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .map_or(MediaDirection::SendRecv, |dialog| dialog.direction);
+    let direction = send_reinvite(direction).await?;
+
+    if let Some(rtp_session) = active_rtp_session().await {
+        rtp_session.set_direction(direction).await;
+    }
+    Ok(())
+}
+
+async fn active_rtp_session() -> Option<Arc<RtpSession>> {
+    SIP_ENGINE.lock().await.active_dialog.as_ref().and_then(|dialog| dialog.rtp_session.clone())
+}
+
+/// Call waiting: put the active call on hold and bring up the other one,
+/// answering it if it is still ringing or resuming it if an earlier swap
+/// held it. With no active call (it was hung up) the other call just comes up.
+pub async fn swap_calls() -> Result<(), SipError> {
+    let (held_call_id, waiting_call_id) = {
+        let engine = SIP_ENGINE.lock().await;
+        let waiting = engine.waiting_dialog.as_ref().ok_or(SipError::NoActiveCall)?;
+        let held_call_id = match engine.active_dialog.as_ref() {
+            Some(active) if active.state != CallState::Confirmed || active.parked.is_some() => {
+                return Err(SipError::Protocol(format!(
+                    "Call {} can't be put on hold while it is {}",
+                    active.call_id,
+                    if active.parked.is_some() { "parked" } else { active.state.as_str() }
+                )));
+            }
+            active => active.map(|active| active.call_id.clone()),
+        };
+        (held_call_id, waiting.call_id.clone())
+    };
+
+    if held_call_id.is_some() {
+        hold_active_call().await?;
+    }
+
+    // The waiting caller may have given up while we were holding
+    let swapped = {
+        let mut engine = SIP_ENGINE.lock().await;
+        let engine = &mut *engine;
+        let present = engine.waiting_dialog.as_ref().is_some_and(|waiting| waiting.call_id == waiting_call_id);
+        if present {
+            std::mem::swap(&mut engine.active_dialog, &mut engine.waiting_dialog);
+        }
+        present
+    };
+    if !swapped {
+        if held_call_id.is_some() {
+            resume_active_call().await?;
+        }
+        return Err(SipError::NoActiveCall);
+    }
+
+    println!("[SIP] Swapped to call {} (holding {:?})", waiting_call_id, held_call_id);
+    events::emit("calls_swapped", serde_json::json!({
+        "active_call_id": waiting_call_id,
+        "held_call_id": held_call_id,
+    }));
+
+    let ringing = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .is_some_and(|active| active.incoming_invite.is_some());
+    if ringing {
+        answer_call(None).await
+    } else {
+        resume_active_call().await
+    }
+}
+
+// How many times a re-INVITE is tried when the peer keeps answering 491
+const REINVITE_ATTEMPTS: u32 = 3;
+
+// Random wait before retrying a re-INVITE that collided with the peer's
+// (491 Request Pending), RFC 3261 14.1: 2.1 to 4 seconds if we generated the
+// dialog's Call-ID, 0 to 2 seconds if the peer did, so the two sides don't
+// retry into each other again
+fn glare_backoff(owns_call_id: bool) -> std::time::Duration {
+    let (base, spread) = if owns_call_id { (2100, 1901) } else { (0, 2001) };
+    let mut bytes = [0u8; 2];
+    let jitter = match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u16::from_be_bytes(bytes) as u64 % spread,
+        Err(_) => spread / 2,
+    };
+    std::time::Duration::from_millis(base + jitter)
+}
+
+// Re-INVITE the confirmed call offering our current media with `direction`
+// (sendonly to hold, sendrecv to resume). Returns the direction agreed with
+// the peer's answer. On glare (491) it backs off and tries again with the
+// next CSeq.
+async fn send_reinvite(direction: MediaDirection) -> Result<MediaDirection, SipError> {
+    let mut attempt = 1;
+    loop {
+        if let Some(negotiated) = reinvite_once(direction).await? {
+            return Ok(negotiated);
+        }
+        if attempt == REINVITE_ATTEMPTS {
+            return Err(SipError::Rejected(format!(
+                "re-INVITE failed: 491 Request Pending ({} attempts)",
+                attempt
+            )));
+        }
+        let owns_call_id = SIP_ENGINE.lock().await.active_dialog.as_ref().is_some_and(|dialog| dialog.outgoing);
+        let delay = glare_backoff(owns_call_id);
+        println!("[SIP] re-INVITE collided (491), retrying in {}ms", delay.as_millis());
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// A retry with credentials went out with the next CSeq; keep the active
+// dialog `call_id` in step with the CSeq the response answers
+async fn observe_dialog_cseq(call_id: &str, response: &str) {
+    let mut engine = SIP_ENGINE.lock().await;
+    if let Some(active) = engine.active_dialog.as_mut().filter(|active| active.call_id == call_id) {
+        active.observe_cseq(response_cseq(response));
+    }
+}
+
+// One re-INVITE transaction in the active dialog, with the next CSeq.
+// None when the peer answered 491 Request Pending.
+async fn reinvite_once(direction: MediaDirection) -> Result<Option<MediaDirection>, SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let (socket, server, user, password, local_addr, dialog) = {
+        let mut engine = SIP_ENGINE.lock().await;
+        let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+        let dialog = match engine.active_dialog.as_mut() {
+            Some(dialog) if dialog.state == CallState::Confirmed => dialog,
+            _ => return Err(SipError::NoActiveCall),
+        };
+        dialog.next_cseq();
+        let dialog = dialog.clone();
+        (socket, engine.server.clone(), engine.user.clone(), engine.password.clone(), engine.local_addr.clone(), dialog)
+    };
+    let rtp_session = dialog.rtp_session.clone()
+        .ok_or_else(|| SipError::Protocol("Call has no media session".to_string()))?;
+    let _route = ResponseRoute::open(&dialog.call_id);
+
+    let local_ip = &local_host(&local_addr);
+    let srtp_keys = if rtp_session.is_secure().await { dialog.srtp_keys.clone() } else { None };
+    let (media_ip, media_port) = session_media_addr(&rtp_session, local_ip);
+    let sdp = build_sdp_answer(&media_ip, media_port, rtp_session.payload_type().await, srtp_keys.as_ref(), direction);
+
+    let branch = ids::branch();
+    let route = dialog_route_headers(&dialog.route_set);
+    let contact_uri = format!("sip:{}@{}", user, local_addr);
+    let invite_msg = message::build_invite(
+        &dialog.request_params(&local_addr, &branch, &route, dialog.cseq),
+        &contact_uri,
+        "",
+        &sdp,
+    );
+
+    println!("[SIP] Sending re-INVITE ({})...", direction.attribute());
+    println!("[SIP] Message:\n{}", invite_msg);
+
+    let server_addr = match dialog.server_addr {
+        Some(addr) => addr,
+        None => resolve_server(&server).await?,
+    };
+    let response = Transaction::new(&socket, server_addr, timeouts.invite_secs)
+        .authenticate(&user, &password)
+        .send(&invite_msg)
+        .await?;
+
+    observe_dialog_cseq(&dialog.call_id, &response).await;
+
+    match response_status(&response) {
+        200..=299 => {}
+        491 => return Ok(None),
+        _ => {
+            let status_line = response.lines().next().unwrap_or("Unknown");
+            return Err(SipError::Rejected(format!("re-INVITE failed: {}", status_line)));
+        }
+    }
+
+    send_ack(&socket, &dialog, &local_addr, server_addr, &response).await?;
+    let negotiated = direction.negotiate(parse_direction(&response));
+    println!("[SIP] ✓ re-INVITE accepted ({})", negotiated.attribute());
+    Ok(Some(negotiated))
+}
+
+/// Send a dialpad key during the call as an RFC 4733 telephone-event and play
+/// its tone locally as feedback (the tone is not sent as audio)
+pub async fn send_dtmf(digit: char) -> Result<(), SipError> {
+    let event = dtmf_event_code(digit)
+        .ok_or_else(|| SipError::Protocol(format!("Invalid DTMF digit '{}'", digit)))?;
+
+    let (rtp_session, call_audio) = {
+        let engine = SIP_ENGINE.lock().await;
+        match engine.active_dialog.as_ref() {
+            Some(dialog) if dialog.state == CallState::Confirmed => {
+                (dialog.rtp_session.clone(), dialog.call_audio.clone())
+            }
+            _ => return Err(SipError::NoActiveCall),
+        }
+    };
+
+    // auto uses RFC 4733 when the peer negotiated telephone-event, else INFO;
+    // without telephone-events in our SDP there is nothing to send them on
+    let use_info = match crate::settings::load_dtmf_mode().unwrap_or_default() {
+        _ if !offer_telephone_event() => true,
+        DtmfMode::Info => true,
+        DtmfMode::Rfc2833 => false,
+        DtmfMode::Auto => match rtp_session {
+            Some(ref rtp_session) => rtp_session.event_payload_type().await.is_none(),
+            None => true,
+        },
+    };
+
+    println!("[SIP] Sending DTMF '{}'{}", digit, if use_info { " via INFO" } else { "" });
+    if let Some(call_audio) = call_audio {
+        if let Err(e) = call_audio.play_dtmf_tone(digit) {
+            println!("[Audio] No local DTMF feedback: {}", e);
+        }
+    }
+
+    if use_info {
+        return send_info_dtmf(digit, crate::audio::DTMF_TONE_MS as u32).await;
+    }
+
+    let rtp_session = rtp_session
+        .ok_or_else(|| SipError::Protocol("Call has no media session".to_string()))?;
+    rtp_session
+        .send_telephone_event(event, crate::audio::DTMF_TONE_MS as u32)
+        .await
+}
+
+// Send a digit as a SIP INFO in the active dialog (application/dtmf-relay),
+// for gateways that don't take RFC 4733 events
+async fn send_info_dtmf(digit: char, duration_ms: u32) -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+
+    let (socket, user, password, local_addr, server, dialog) = {
+        let mut engine = SIP_ENGINE.lock().await;
+        let socket = engine.socket.clone().ok_or(SipError::NotInitialized)?;
+        let dialog = engine.active_dialog.as_mut().ok_or(SipError::NoActiveCall)?;
+        // Each new request in the dialog takes the next CSeq
+        dialog.next_cseq();
+        let dialog = dialog.clone();
+        (
+            socket,
+            engine.user.clone(),
+            engine.password.clone(),
+            engine.local_addr.clone(),
+            engine.server.clone(),
+            dialog,
+        )
+    };
+
+    let body = dtmf_relay_body(digit, duration_ms);
+    let branch = ids::branch();
+    let route = dialog_route_headers(&dialog.route_set);
+
+    let info_msg = message::build_info(
+        &dialog.request_params(&local_addr, &branch, &route, dialog.cseq),
+        "application/dtmf-relay",
+        &body,
+    );
+
+    let server_addr = match dialog.server_addr {
+        Some(addr) => addr,
+        None => resolve_server(&server).await?,
+    };
+    let _route = ResponseRoute::open(&dialog.call_id);
+
+    // In-dialog requests share the BYE timeout
+    let response = Transaction::new(&socket, server_addr, timeouts.bye_secs)
+        .authenticate(&user, &password)
+        .send(&info_msg).await?;
+    observe_dialog_cseq(&dialog.call_id, &response).await;
+
+    if response.starts_with("SIP/2.0 2") {
+        Ok(())
+    } else {
+        Err(SipError::Rejected(format!(
+            "DTMF INFO failed: {}",
+            response.lines().next().unwrap_or("Unknown error")
+        )))
+    }
+}
+
+// application/dtmf-relay body: "Signal=5\r\nDuration=160\r\n"
+fn dtmf_relay_body(digit: char, duration_ms: u32) -> String {
+    format!("Signal={}\r\nDuration={}\r\n", digit.to_ascii_uppercase(), duration_ms)
+}
+
+/// Audio samples dropped so far in the active call (zeros without a call)
+pub async fn audio_drop_counts() -> crate::audio::AudioDropCounts {
+    let engine = SIP_ENGINE.lock().await;
+    engine
+        .active_dialog
+        .as_ref()
+        .and_then(|dialog| dialog.call_audio.as_ref())
+        .map(|call_audio| call_audio.drop_counts())
+        .unwrap_or_default()
+}
+
+/// Media parameters negotiated for the active call
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MediaInfo {
+    /// Encoding name, or the payload type number for one we don't know
+    pub codec: String,
+    pub clock_rate: u32,
+    /// Milliseconds of audio per packet
+    pub ptime: u32,
+    pub local_rtp_port: u16,
+    /// Where our RTP goes (the peer's learned source once it has sent)
+    pub remote_rtp_addr: String,
+    pub encrypted: bool,
+}
+
+/// Codec, packet time, RTP addresses and encryption of the active call.
+/// NoActiveCall until the call has a media session.
+pub async fn media_info() -> Result<MediaInfo, SipError> {
+    let rtp_session = SIP_ENGINE.lock().await
+        .active_dialog
+        .as_ref()
+        .and_then(|dialog| dialog.rtp_session.clone())
+        .ok_or(SipError::NoActiveCall)?;
+
+    let payload_type = rtp_session.payload_type().await;
+    Ok(MediaInfo {
+        codec: Codec::from_payload_type(payload_type)
+            .map_or_else(|| payload_type.to_string(), |codec| codec.name().to_string()),
+        clock_rate: rtp_session.clock_rate().await,
+        ptime: FRAME_DURATION_MS,
+        local_rtp_port: rtp_session.local_port(),
+        remote_rtp_addr: rtp_session.remote_addr().await.to_string(),
+        encrypted: rtp_session.is_secure().await,
+    })
+}
+
+/// Addresses the stack works with, for NAT troubleshooting. Fields are None
+/// until known (no socket yet, no STUN answer, server not looked up).
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct NetworkInfo {
+    /// Address the SIP socket is bound to
+    pub local_addr: Option<String>,
+    /// Address we put in Via and Contact
+    pub advertised_addr: Option<String>,
+    /// Our address as a STUN server saw it (from the last call's media)
+    pub public_addr: Option<String>,
+    /// Where requests to the server go
+    pub server_resolved_addr: Option<String>,
+}
+
+/// What the stack knows about its own and the server's addresses
+pub async fn network_info() -> NetworkInfo {
+    let engine = SIP_ENGINE.lock().await;
+    NetworkInfo {
+        local_addr: engine
+            .socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+            .map(|addr| addr.to_string()),
+        advertised_addr: Some(engine.local_addr.clone()).filter(|addr| !addr.is_empty()),
+        public_addr: engine.public_addr.map(|addr| addr.to_string()),
+        server_resolved_addr: engine.resolved_server.as_ref().map(|(_, addr)| addr.to_string()),
+    }
+}
+
+/// Move the active call's playback to another output device (the default
+/// if it is not present). The dialog and RTP session are left as they are.
+/// Returns the name of the device now playing.
+pub async fn switch_output_device(name: &str) -> Result<String, SipError> {
+    let call_audio = {
+        let engine = SIP_ENGINE.lock().await;
+        engine
+            .active_dialog
+            .as_ref()
+            .and_then(|dialog| dialog.call_audio.clone())
+            .ok_or(SipError::NoActiveCall)?
+    };
+
+    // The audio thread rebuilds the stream; wait for it off the runtime
+    let requested = name.to_string();
+    let opened = tokio::task::spawn_blocking(move || call_audio.switch_output(&requested))
+        .await
+        .map_err(|e| SipError::Internal(format!("Output switch task failed: {}", e)))??;
+
+    // The device actually opened (the default if `name` is gone) plays at
+    // the volume last set for it
+    let mut engine = SIP_ENGINE.lock().await;
+    engine.call_devices.1 = opened.clone();
+    engine.audio_levels.set_output_volume(device_levels(&opened).output_volume);
+    Ok(opened)
+}
+
+// Levels remembered for a device (unchanged if none were set)
+fn device_levels(device: &str) -> crate::settings::DeviceLevels {
+    crate::settings::load_device_levels(device).unwrap_or_default()
+}
+
+/// Set the microphone gain of the current and later calls. Returns the input
+/// device it applies to (the call's, else the saved one) so it can be
+/// remembered for that device.
+pub async fn set_input_gain(gain: f32) -> String {
+    let engine = SIP_ENGINE.lock().await;
+    engine.audio_levels.set_input_gain(gain);
+    match engine.active_dialog.as_ref().and_then(|dialog| dialog.call_audio.as_ref()) {
+        Some(_) => engine.call_devices.0.clone(),
+        None => crate::settings::load_audio_devices().unwrap_or_default().0,
+    }
+}
+
+/// Set the speaker volume of the current and later calls. Returns the
+/// output device it applies to, as `set_input_gain` does.
+pub async fn set_output_volume(volume: f32) -> String {
+    let engine = SIP_ENGINE.lock().await;
+    engine.audio_levels.set_output_volume(volume);
+    match engine.active_dialog.as_ref().and_then(|dialog| dialog.call_audio.as_ref()) {
+        Some(_) => engine.call_devices.1.clone(),
+        None => crate::settings::load_audio_devices().unwrap_or_default().1,
+    }
+}
+
+/// Turn do not disturb on or off for inbound calls
+pub async fn set_dnd(enabled: bool) {
+    SIP_ENGINE.lock().await.dnd = enabled;
+    println!("[SIP] Do not disturb {}", if enabled { "on" } else { "off" });
+}
+
+/// Whether the account is registered right now, including by a background
+/// retry or refresh
+pub async fn is_registered() -> bool {
+    SIP_ENGINE.lock().await.registered
+}
+
+/// Seconds since the active call was answered; 0 when there is no
+/// answered call
+pub async fn call_duration() -> u64 {
+    let engine = SIP_ENGINE.lock().await;
+    engine.active_dialog.as_ref().map(Dialog::duration_secs).unwrap_or(0)
+}
+
+// Unregister from SIP server (send REGISTER with Expires: 0)
+pub async fn unregister() -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    cancel_register_retry().await;
+    let engine = SIP_ENGINE.lock().await;
+
+    let socket = match engine.socket.as_ref() {
+        Some(s) => s.clone(),
+        None => return Ok(()), // Not initialized, nothing to do
+    };
+
+    if !engine.registered {
+        return Ok(()); // Not registered, nothing to do
+    }
+
+    drop(engine); // Release lock
+
+    // Tell the server we no longer want voicemail notifications
+    unsubscribe_mwi().await;
+
+    let engine = SIP_ENGINE.lock().await;
+    let server = engine.server.clone();
+    let user = engine.user.clone();
+    let password = engine.password.clone();
+    let local_addr = engine.local_addr.clone();
+    drop(engine);
+
+    println!("[SIP] Unregistering from {}", server);
+
+    // Build REGISTER with Expires: 0 to unregister
+    let from_uri = format!("sip:{}@{}", user, server);
+    let to_uri = from_uri.clone();
+    let contact_uri = format!("sip:{}@{}", user, local_addr);
+    let registration = next_registration(&from_uri).await;
+    let branch = ids::branch();
+    let _route = ResponseRoute::open(&registration.call_id);
+    let proxy_route = outbound_proxy_route();
+
+    let request_uri = format!("sip:{}", server);
+    let params = message::RequestParams {
+        uri: &request_uri,
+        local_addr: &local_addr,
+        branch: &branch,
+        route: &proxy_route,
+        from_uri: &from_uri,
+        from_name: &local_display_name(),
+        from_tag: &registration.from_tag,
+        to_uri: &to_uri,
+        to_tag: None,
+        call_id: &registration.call_id,
+        cseq: registration.cseq,
+        compact: use_compact_headers(),
+    };
+    let unregister_msg = message::build_register(&params, &contact_uri, 0, None);
+
+    // Resolve server address (cached after the first lookup)
+    let server_addr = resolve_server(&server).await?;
+
+    println!("[SIP] Sending unregister (Expires: 0)");
+
+    let result = Transaction::new(&socket, server_addr, timeouts.unregister_secs)
+        .authenticate(&user, &password)
+        .send(&unregister_msg)
+        .await;
+    if let Ok(response) = &result {
+        update_registration(response).await;
+    }
+    match result {
+        Ok(response) if response_status(&response) == 200 => match binding_expires(&response, &contact_uri) {
+            Some(expires) if expires > 0 => {
+                // The server acknowledged but kept our binding; calls may
+                // still be routed here until it expires
+                tracing::warn!("[SIP] Server still lists our binding (expires={}) after unregister", expires);
+                println!("[SIP] ⚠ Server still lists our binding (expires={}) after unregister", expires);
+                events::emit("unregister_unconfirmed", serde_json::json!({ "expires": expires }));
+            }
+            _ => println!("[SIP] ✓ Unregistered successfully"),
+        },
+        Ok(response) => println!("[SIP] Unregister response: {}", response.lines().next().unwrap_or("Unknown")),
+        Err(SipError::Timeout(_)) => println!("[SIP] No response to unregister (continuing anyway)"),
+        Err(e) => return Err(e),
+    }
+
+    // Update state
+    let mut engine = SIP_ENGINE.lock().await;
+    engine.registered = false;
+    if let Some(task) = engine.keepalive_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.resume_watch_task.take() {
+        task.abort();
+    }
+
+    Ok(())
+}
+
+// Expiry a REGISTER response still gives our binding: the expires parameter
+// of our Contact, else the Expires header. None when our Contact isn't listed
+// (the binding is gone) or no expiry is given.
+fn binding_expires(response: &str, contact_uri: &str) -> Option<u32> {
+    let without_params = |uri: &str| uri.split(';').next().unwrap_or("").to_ascii_lowercase();
+    let ours = without_params(contact_uri);
+    let contact = header_values(response, "Contact")
+        .into_iter()
+        .find(|value| name_addr_uri(value).is_some_and(|uri| without_params(&uri) == ours))?;
+    header_param(&contact, "expires")
+        .or_else(|| header_value(response, "Expires"))
+        .and_then(|expires| expires.trim().parse().ok())
+}
+
+/// Go offline: unregister but keep the socket and stored credentials so
+/// `go_online` can register again without asking for them
+pub async fn go_offline() -> Result<(), SipError> {
+    println!("[SIP] Going offline");
+    unregister().await
+}
+
+/// Go online again with the credentials of the last registration, reusing
+/// the existing socket (or initializing one if the stack was shut down)
+pub async fn go_online() -> Result<(), SipError> {
+    let (initialized, server, user, password) = {
+        let engine = SIP_ENGINE.lock().await;
+        (
+            engine.socket.is_some(),
+            engine.server.clone(),
+            engine.user.clone(),
+            engine.password.clone(),
+        )
+    };
+
+    if server.is_empty() || user.is_empty() {
+        return Err(SipError::AuthFailed("No stored credentials; register an account first".to_string()));
+    }
+
+    if !initialized {
+        init_pjsip().await?;
+    }
+
+    println!("[SIP] Going online as {}@{}", user, server);
+    register_account(&server, &user, &password).await
+}
+
+// Start (or restart) CRLF keepalives to the server if they are enabled
+fn start_keepalive(engine: &mut SipEngine) {
+    let keepalive = crate::settings::load_keepalive().unwrap_or_default();
+    let task = if keepalive.enabled && keepalive.interval_secs > 0 {
+        Some(spawn_keepalive(std::time::Duration::from_secs(keepalive.interval_secs)))
+    } else {
+        None
+    };
+    if let Some(old_task) = std::mem::replace(&mut engine.keepalive_task, task) {
+        old_task.abort();
+    }
+}
+
+// Send a double-CRLF ping to the server every `interval` (RFC 5626 3.5.1),
+// which keeps the UDP NAT binding alive far more cheaply than OPTIONS
+fn spawn_keepalive(interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+    println!("[SIP] Sending CRLF keepalives every {}s", interval.as_secs());
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let (socket, server) = {
+                let engine = SIP_ENGINE.lock().await;
+                (engine.socket.clone(), engine.server.clone())
+            };
+            let Some(socket) = socket else { break };
+
+            let sent = match resolve_server(&server).await {
+                Ok(server_addr) => socket.send_to(b"\r\n\r\n", server_addr).await.map(|_| ()).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = sent {
+                tracing::warn!("[SIP] Keepalive failed: {}", e);
+                println!("[SIP] Keepalive failed: {}", e);
+            }
+        }
+    })
+}
+
+// How often the resume watch checks the wall clock
+const RESUME_WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Wall-clock time beyond the expected tick that counts as a suspend
+const RESUME_GAP_THRESHOLD: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Start (or restart) watching for system resume while registered
+fn start_resume_watch(engine: &mut SipEngine) {
+    let task = tokio::spawn(watch_for_resume(RESUME_WATCH_INTERVAL));
+    if let Some(old_task) = engine.resume_watch_task.replace(task) {
+        old_task.abort();
+    }
+}
+
+// Tokio timers run on the monotonic clock, which stands still while the
+// machine sleeps, whereas the wall clock keeps going. A tick that took far
+// longer in wall-clock time than it should have means we were suspended:
+// the NAT binding and probably our address are gone, so rebind and register
+// again rather than wait for the registration to expire on the server.
+async fn watch_for_resume(interval: std::time::Duration) {
+    let mut last = std::time::SystemTime::now();
+    loop {
+        tokio::time::sleep(interval).await;
+        let now = std::time::SystemTime::now();
+        // A wall clock stepped backwards isn't a suspend
+        let elapsed = now.duration_since(last).unwrap_or_default();
+        last = now;
+
+        if let Some(gap) = resume_gap(interval, elapsed) {
+            // Re-registering replaces this task, so run it detached
+            tokio::spawn(recover_from_resume(gap));
+            break;
+        }
+    }
+}
+
+// Time unaccounted for when a tick expected after `expected` came after
+// `elapsed` of wall-clock time, if it is long enough to be a suspend
+fn resume_gap(expected: std::time::Duration, elapsed: std::time::Duration) -> Option<std::time::Duration> {
+    elapsed.checked_sub(expected).filter(|gap| *gap >= RESUME_GAP_THRESHOLD)
+}
+
+// Rebind the socket and register again after a resume
+async fn recover_from_resume(gap: std::time::Duration) {
+    println!("[SIP] System resumed after ~{}s asleep, re-registering", gap.as_secs());
+    tracing::info!("[SIP] System resumed after ~{}s asleep", gap.as_secs());
+    events::emit("resumed", serde_json::json!({ "gap_secs": gap.as_secs() }));
+
+    let (server, user, password) = {
+        let mut engine = SIP_ENGINE.lock().await;
+        // A call's dialog is bound to the current socket, so keep it then
+        if engine.active_dialog.is_none() {
+            if let Some(task) = engine.receive_task.take() {
+                task.abort();
+            }
+            engine.socket = None;
+            engine.resolved_server = None;
+        }
+        (engine.server.clone(), engine.user.clone(), engine.password.clone())
+    };
+
+    if let Err(e) = init_pjsip().await {
+        println!("[SIP] Rebind after resume failed: {}", e);
+        return;
+    }
+    if let Err(e) = register_account(&server, &user, &password).await {
+        println!("[SIP] Re-register after resume failed: {}", e);
+    }
+}
+
+// Requested lifetime of the voicemail subscription
+const MWI_EXPIRES: u32 = 3600;
+
+// Subscribe to voicemail notifications in the background after registering
+fn spawn_mwi_subscription() {
+    tokio::spawn(async {
+        if let Err(e) = subscribe_mwi().await {
+            // Not every server supports message-summary; registration still succeeded
+            println!("[SIP] Voicemail subscription failed: {}", e);
+        }
+    });
+}
+
+// Subscribe to the message-summary event package and keep the subscription refreshed
+async fn subscribe_mwi() -> Result<(), SipError> {
+    let expires = send_mwi_subscribe(MWI_EXPIRES).await?;
+
+    let refresh_task = tokio::spawn(async move {
+        let mut expires = expires;
+        loop {
+            // Refresh well before the server expires the subscription
+            let delay = expires.saturating_sub(60).max(expires / 2).max(1);
+            tokio::time::sleep(std::time::Duration::from_secs(delay as u64)).await;
+
+            expires = match send_mwi_subscribe(MWI_EXPIRES).await {
+                Ok(granted) => granted,
+                Err(e) => {
+                    println!("[SIP] Voicemail subscription refresh failed: {}", e);
+                    // Try again in a couple of minutes
+                    120
+                }
+            };
+        }
+    });
+
+    let mut engine = SIP_ENGINE.lock().await;
+    if let Some(old_task) = engine.mwi_refresh_task.replace(refresh_task) {
+        old_task.abort();
+    }
+    Ok(())
+}
+
+// End the voicemail subscription (SUBSCRIBE with Expires: 0)
+async fn unsubscribe_mwi() {
+    let has_subscription = {
+        let mut engine = SIP_ENGINE.lock().await;
+        if let Some(task) = engine.mwi_refresh_task.take() {
+            task.abort();
+        }
+        engine.mwi_subscription.is_some()
+    };
+
+    if has_subscription {
+        if let Err(e) = send_mwi_subscribe(0).await {
+            println!("[SIP] Failed to unsubscribe from voicemail notifications: {}", e);
+        }
+        SIP_ENGINE.lock().await.mwi_subscription = None;
+    }
+}
+
+// Send one SUBSCRIBE for message-summary (new or in the existing subscription
+// dialog) and return the expiry the server granted
+async fn send_mwi_subscribe(expires: u32) -> Result<u32, SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let engine = SIP_ENGINE.lock().await;
+
+    let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+    let server = engine.server.clone();
+    let user = engine.user.clone();
+    let password = engine.password.clone();
+    let local_addr = engine.local_addr.clone();
+
+    let mut subscription = match engine.mwi_subscription.clone() {
+        Some(mut subscription) => {
+            subscription.cseq += 1;
+            subscription
+        }
+        None => Subscription {
+            call_id: ids::call_id(),
+            from_tag: ids::tag(),
+            to_tag: None,
+            cseq: 1,
+        },
+    };
+    drop(engine);
+
+    let account_uri = format!("sip:{}@{}", user, server);
+    let branch = ids::branch();
+    let proxy_route = outbound_proxy_route();
+    let contact_uri = format!("sip:{}@{}", user, local_addr);
+
+    let subscribe_msg = message::build_subscribe(
+        &message::RequestParams {
+            uri: &account_uri,
+            local_addr: &local_addr,
+            branch: &branch,
+            route: &proxy_route,
+            from_uri: &account_uri,
+            from_name: "",
+            from_tag: &subscription.from_tag,
+            to_uri: &account_uri,
+            to_tag: subscription.to_tag.as_deref(),
+            call_id: &subscription.call_id,
+            cseq: subscription.cseq,
+            compact: use_compact_headers(),
+        },
+        &contact_uri,
+        "message-summary",
+        "application/simple-message-summary",
+        expires,
+    );
+
+    println!("[SIP] Sending SUBSCRIBE for message-summary (Expires: {})", expires);
+
+    let server_addr = resolve_server(&server).await?;
+    let _route = ResponseRoute::open(&subscription.call_id);
+
+    let response = Transaction::new(&socket, server_addr, timeouts.register_secs)
+        .authenticate(&user, &password)
+        .send(&subscribe_msg).await?;
+
+    if !response.starts_with("SIP/2.0 2") {
+        return Err(SipError::Rejected(format!("SUBSCRIBE failed: {}",
+            response.lines().next().unwrap_or("Unknown"))));
+    }
+
+    // The auth retry may have bumped the CSeq; continue from what was sent
+    if let Some(cseq) = response_cseq(&response) {
+        subscription.cseq = cseq;
+    }
+    if subscription.to_tag.is_none() {
+        subscription.to_tag = extract_to_tag(&response);
+    }
+
+    let granted = header_value(&response, "Expires")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(expires);
+
+    println!("[SIP] ✓ Voicemail subscription active (Expires: {})", granted);
+
+    if expires > 0 {
+        SIP_ENGINE.lock().await.mwi_subscription = Some(subscription);
+    }
+
+    Ok(granted)
+}
+
+// Handle an inbound NOTIFY (currently only the message-summary package)
+async fn handle_notify(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let event = header_value(request, "Event").unwrap_or_default();
+
+    if !event.starts_with("message-summary") {
+        let response = build_response(request, "489 Bad Event", None, "", "");
+        return send_response(socket, &response, from_addr).await;
+    }
+
+    let response = build_response(request, "200 OK", None, "", "");
+    send_response(socket, &response, from_addr).await?;
+
+    let subscription_state = header_value(request, "Subscription-State").unwrap_or_default();
+    if subscription_state.starts_with("terminated") {
+        println!("[SIP] Voicemail subscription terminated by server");
+        let mut engine = SIP_ENGINE.lock().await;
+        engine.mwi_subscription = None;
+        if let Some(task) = engine.mwi_refresh_task.take() {
+            task.abort();
+        }
+    }
+
+    match parse_message_summary(message_body(request)) {
+        Some(summary) => {
+            println!(
+                "[SIP] Voicemail: waiting={} new={} old={}",
+                summary.messages_waiting, summary.new_messages, summary.old_messages
+            );
+            events::emit("mwi", serde_json::json!({
+                "messages_waiting": summary.messages_waiting,
+                "new_messages": summary.new_messages,
+                "old_messages": summary.old_messages,
+            }));
+        }
+        None => {
+            println!("[SIP] NOTIFY without a message summary body");
+        }
+    }
+
+    Ok(())
+}
+
+/// Send an instant message (SIP MESSAGE, RFC 3428) to a number or SIP URI
+///
+/// Succeeds once the server or the recipient accepts it with a 2xx; any
+/// other final response is returned as `Rejected`.
+pub async fn send_message(target: &str, text: &str) -> Result<(), SipError> {
+    let timeouts = crate::settings::load_timeouts().unwrap_or_default();
+    let engine = SIP_ENGINE.lock().await;
+
+    if !engine.registered {
+        return Err(SipError::NotRegistered);
+    }
+
+    let socket = engine.socket.as_ref().ok_or(SipError::NotInitialized)?.clone();
+    let server = engine.server.clone();
+    let user = engine.user.clone();
+    let password = engine.password.clone();
+    let local_addr = engine.local_addr.clone();
+    drop(engine);
+
+    let dest_uri = normalize_dial_target(target, &server, crate::settings::load_dial_as().unwrap_or_default())?;
+    let from_uri = format!("sip:{}@{}", user, server);
+    let call_id = ids::call_id();
+    let from_tag = ids::tag();
+    let branch = ids::branch();
+    let proxy_route = outbound_proxy_route();
+
+    let message_msg = message::build_message(
+        &message::RequestParams {
+            uri: &dest_uri,
+            local_addr: &local_addr,
+            branch: &branch,
+            route: &proxy_route,
+            from_uri: &from_uri,
+            from_name: &local_display_name(),
+            from_tag: &from_tag,
+            to_uri: &dest_uri,
+            to_tag: None,
+            call_id: &call_id,
+            cseq: 1,
+            compact: use_compact_headers(),
+        },
+        "text/plain;charset=UTF-8",
+        text,
+    );
+
+    println!("[SIP] Sending MESSAGE to {}", dest_uri);
+
+    let server_addr = resolve_server(&server).await?;
+    let _route = ResponseRoute::open(&call_id);
+
+    let response = Transaction::new(&socket, server_addr, timeouts.register_secs)
+        .authenticate(&user, &password)
+        .send(&message_msg).await?;
+
+    if !response.starts_with("SIP/2.0 2") {
+        return Err(SipError::Rejected(format!("MESSAGE failed: {}",
+            response.lines().next().unwrap_or("Unknown"))));
+    }
+
+    println!("[SIP] ✓ MESSAGE delivered to {}", dest_uri);
+    Ok(())
+}
+
+// Handle an inbound MESSAGE: accept plain text and pass it to the UI
+async fn handle_message(socket: &UdpSocket, request: &str, from_addr: SocketAddr) -> Result<(), SipError> {
+    let content_type = header_value(request, "Content-Type")
+        .unwrap_or_else(|| "text/plain".to_string());
+
+    if !content_type.to_ascii_lowercase().starts_with("text/plain") {
+        let response = build_response(request, "415 Unsupported Media Type", None, "Accept: text/plain\r\n", "");
+        return send_response(socket, &response, from_addr).await;
+    }
+
+    let response = build_response(request, "200 OK", None, "", "");
+    send_response(socket, &response, from_addr).await?;
+
+    let sender = caller_identity(request);
+    println!("[SIP] Message from {}", sender.uri);
+
+    events::emit("message_received", serde_json::json!({
+        "sender": sender,
+        "body": message_body(request),
+    }));
+
+    Ok(())
+}
+
+// Parse an application/simple-message-summary body
+fn parse_message_summary(body: &str) -> Option<MessageSummary> {
+    let mut summary: Option<MessageSummary> = None;
+    let mut counts = (0, 0);
+
+    for line in body.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        if name.trim().eq_ignore_ascii_case("Messages-Waiting") {
+            summary = Some(MessageSummary {
+                messages_waiting: value.eq_ignore_ascii_case("yes"),
+                ..Default::default()
+            });
+        } else if name.trim().eq_ignore_ascii_case("Voice-Message") {
+            // new/old followed by optional (urgent_new/urgent_old)
+            let totals = value.split_whitespace().next().unwrap_or("");
+            if let Some((new, old)) = totals.split_once('/') {
+                counts = (new.parse().unwrap_or(0), old.parse().unwrap_or(0));
+            }
+        }
+    }
+
+    summary.map(|summary| MessageSummary {
+        new_messages: counts.0,
+        old_messages: counts.1,
+        ..summary
+    })
+}
+
+// Resolve the server to a socket address (default port 5060)
+//
+// The result is cached on the engine so every request after the first one
+// (and every in-dialog request) goes to the same server instance without
+// another DNS round trip. register_account clears the cache.
+async fn resolve_server(server: &str) -> Result<SocketAddr, SipError> {
+    let server = signaling_target(server);
+
+    if let Some((ref cached_server, addr)) = SIP_ENGINE.lock().await.resolved_server {
+        if *cached_server == server {
+            return Ok(addr);
+        }
+    }
+
+    let addr = lookup_server(&server).await?[0];
+    SIP_ENGINE.lock().await.resolved_server = Some((server, addr));
+    Ok(addr)
+}
+
+/// Look up where requests for `server` would go, as `resolve_server` does
+/// but without touching the engine's cache
+pub async fn check_server(server: &str) -> Result<SocketAddr, SipError> {
+    Ok(lookup_server(&signaling_target(server)).await?[0])
+}
+
+// With an outbound proxy every request goes there, whatever its domain
+fn signaling_target(server: &str) -> String {
+    outbound_proxy_host(&crate::settings::load_outbound_proxy().unwrap_or_default())
+        .unwrap_or_else(|| server.to_string())
+}
+
+// Every address `server` resolves to that our socket can reach, best first
+// (never empty)
+async fn lookup_server(server: &str) -> Result<Vec<SocketAddr>, SipError> {
+    if let Ok(addr) = server.parse() {
+        return Ok(vec![addr]);
+    }
+
+    let lookup = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:5060", server)
+    };
+
+    println!("[SIP] Performing DNS lookup for {}...", server);
+    let addrs = tokio::net::lookup_host(lookup).await
+        .map_err(|e| SipError::Network(format!("DNS lookup failed for {}: {}", server, e)))?;
+
+    // A socket only reaches its own address family, whatever the preference
+    // was when it was bound
+    let socket_ipv6 = SIP_ENGINE.lock().await.socket.as_ref()
+        .and_then(|socket| socket.local_addr().ok())
+        .map(|addr| addr.is_ipv6());
+    let preference = crate::settings::load_ip_version_preference().unwrap_or_default();
+    let family = match (preference, socket_ipv6) {
+        (IpVersionPreference::Ipv4, _) | (IpVersionPreference::Auto, Some(false)) => "IPv4 ",
+        (IpVersionPreference::Ipv6, _) | (IpVersionPreference::Auto, Some(true)) => "IPv6 ",
+        (IpVersionPreference::Auto, None) => "",
+    };
+    let resolved: Vec<SocketAddr> = order_addresses(addrs, preference)
+        .into_iter()
+        .filter(|addr| match socket_ipv6 {
+            Some(ipv6) => addr.is_ipv6() == ipv6,
+            None => true,
+        })
+        .collect();
+    if resolved.is_empty() {
+        return Err(SipError::Network(format!("No {}addresses found for {}", family, server)));
+    }
+    println!("[SIP] Resolved {} to {:?}", server, resolved);
+    Ok(resolved)
+}
+
+// Resolved addresses in the order to use them: only the forced family, or
+// both families interleaved IPv4 first (RFC 8305 4, starting with the
+// family our socket speaks), keeping the resolver's order within each
+fn order_addresses(addrs: impl IntoIterator<Item = SocketAddr>, preference: IpVersionPreference) -> Vec<SocketAddr> {
+    let (v4, v6): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|addr| addr.is_ipv4());
+    match preference {
+        IpVersionPreference::Ipv4 => v4,
+        IpVersionPreference::Ipv6 => v6,
+        IpVersionPreference::Auto => {
+            let mut v6 = v6.into_iter();
+            let mut ordered: Vec<SocketAddr> = v4
+                .into_iter()
+                .flat_map(|addr| std::iter::once(addr).chain(v6.next()))
+                .collect();
+            ordered.extend(v6);
+            ordered
+        }
+    }
+}
+
+/// Replace the audio backend used for new calls (e.g. `NullBackend` when
+/// running without audio hardware)
+pub async fn set_audio_backend(backend: Arc<dyn AudioBackend>) {
+    SIP_ENGINE.lock().await.audio_backend = backend;
+}
+
+pub async fn shutdown() {
+    let mut engine = SIP_ENGINE.lock().await;
+
+    if let Some(task) = engine.mwi_refresh_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.register_retry_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.keepalive_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.resume_watch_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.call_limit_task.take() {
+        task.abort();
+    }
+    if let Some(task) = engine.auto_answer_task.take() {
+        task.abort();
+    }
+    engine.ringing = None;
+
+    if engine.socket.is_some() {
+        println!("[SIP] Shutting down SIP stack");
+        if let Some(task) = engine.receive_task.take() {
+            task.abort();
+        }
+        engine.socket = None;
+        engine.registered = false;
+    }
+}
+
+/// Recovery for a wedged stack: tear down any call locally (nothing is sent
+/// to the peer), fail pending transactions, drop the socket and every task,
+/// forget the account and initialize again. Register afterwards.
+pub async fn reset() -> Result<(), SipError> {
+    println!("[SIP] Resetting SIP stack");
+
+    let dialogs: Vec<Dialog> = {
+        let mut engine = SIP_ENGINE.lock().await;
+        engine.active_dialog.take().into_iter().chain(engine.waiting_dialog.take()).collect()
+    };
+    for dialog in &dialogs {
+        stop_dialog_media(dialog).await;
+    }
+
+    shutdown().await;
+    {
+        let mut engine = SIP_ENGINE.lock().await;
+        // The audio backend is how the app was started, not stack state
+        let audio_backend = engine.audio_backend.clone();
+        *engine = SipEngine {
+            audio_backend,
+            ..SipEngine::default()
+        };
+    }
+    // Requests still waiting see their route closed and give up
+    PENDING_RESPONSES.lock().unwrap().clear();
+
+    for mut dialog in dialogs {
+        dialog.terminate(Some("reset"));
+    }
+    events::emit("registration_state", serde_json::json!({
+        "registered": false,
+        "state": "OFFLINE",
+        "message": "SIP stack reset",
+    }));
+
+    init_pjsip().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_sip::MockSipServer;
+
+    const LOCALHOST: std::net::IpAddr = std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    // SIP_ENGINE is global, so tests that drive it must not overlap
+    static ENGINE_TEST_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+    // Fresh engine with a socket, pointed at the mock server
+    async fn reset_engine(server: &MockSipServer) {
+        shutdown().await;
+        *SIP_ENGINE.lock().await = SipEngine::default();
+        // Every engine test starts from the default settings
+        crate::settings::clear_test_settings();
+        init_pjsip().await.unwrap();
+        let mut engine = SIP_ENGINE.lock().await;
+        engine.server = server.addr().to_string();
+        engine.user = "alice".to_string();
+        engine.password = "secret".to_string();
+        // No audio hardware in CI; media runs on silence
+        engine.audio_backend = Arc::new(crate::audio::NullBackend);
+    }
+
+    async fn set_registered() {
+        SIP_ENGINE.lock().await.registered = true;
+    }
+
+    // Where the mock should send requests to reach the engine
+    async fn engine_addr() -> SocketAddr {
+        let port = SIP_ENGINE.lock().await.socket.as_ref().unwrap().local_addr().unwrap().port();
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_compact_header_round_trip() {
+        let response = "SIP/2.0 200 OK\r\n\
+                        v: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKcompact\r\n\
+                        F: <sip:alice@example.com>;tag=abc\r\n\
+                        t: <sip:bob@example.com>;tag=remote1\r\n\
+                        i: compact-call\r\n\
+                        CSeq: 1 INVITE\r\n\
+                        m: <sip:bob@10.0.0.9:5062>\r\n\
+                        c: application/sdp\r\n\
+                        l: 4\r\n\r\nv=0\n";
+
+        assert_eq!(extract_to_tag(response).as_deref(), Some("remote1"));
+        assert_eq!(header_value(response, "Call-ID").as_deref(), Some("compact-call"));
+        assert_eq!(header_value(response, "From").as_deref(), Some("<sip:alice@example.com>;tag=abc"));
+        assert_eq!(extract_contact_uri(response).as_deref(), Some("sip:bob@10.0.0.9:5062"));
+        assert_eq!(header_value(response, "content-type").as_deref(), Some("application/sdp"));
+
+        // Responses to a compact request carry its Via and dialog headers
+        let request = response.replacen("SIP/2.0 200 OK", "BYE sip:alice@10.0.0.2 SIP/2.0", 1);
+        let answer = build_response(&request, "200 OK", None, "", "");
+        assert!(answer.contains("\r\nv: SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKcompact\r\n"));
+        assert!(answer.contains("\r\nTo: <sip:bob@example.com>;tag=remote1\r\nCall-ID: compact-call\r\n"));
+    }
+
+    #[test]
+    fn test_route_set_from_record_route() {
+        let response = "SIP/2.0 200 OK\r\n\
+                        Record-Route: <sip:p2.example.com;lr>\r\n\
+                        Record-Route: <sip:p1.example.com;lr>, <sip:p0.example.com;lr>\r\n\
+                        Call-ID: abc\r\n\
+                        \r\n";
+
+        let route_set = extract_route_set(response);
+        assert_eq!(
+            route_set,
+            vec![
+                "<sip:p0.example.com;lr>".to_string(),
+                "<sip:p1.example.com;lr>".to_string(),
+                "<sip:p2.example.com;lr>".to_string(),
+            ]
+        );
+        assert_eq!(
+            route_headers(&route_set),
+            "Route: <sip:p0.example.com;lr>\r\n\
+             Route: <sip:p1.example.com;lr>\r\n\
+             Route: <sip:p2.example.com;lr>\r\n"
+        );
+    }
+
+    #[test]
+    fn test_contact_uri_extraction() {
+        let response = "SIP/2.0 200 OK\r\n\
+                        Contact: \"Bob\" <sip:bob@10.0.0.5:5062;transport=udp>;expires=60\r\n\
+                        \r\n";
+        assert_eq!(
+            extract_contact_uri(response),
+            Some("sip:bob@10.0.0.5:5062;transport=udp".to_string())
+        );
+
+        let bare = "SIP/2.0 200 OK\r\nm: sip:bob@10.0.0.5;expires=60\r\n\r\n";
+        assert_eq!(extract_contact_uri(bare), Some("sip:bob@10.0.0.5".to_string()));
+
+        assert_eq!(extract_contact_uri("SIP/2.0 200 OK\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_unregister_binding_expires() {
+        let contact = "sip:alice@10.0.0.2:5060";
+        // Registrar ignored the unregister and still lists our binding
+        let kept = "SIP/2.0 200 OK\r\n\
+                    CSeq: 3 REGISTER\r\n\
+                    Contact: <sip:alice@10.0.0.9:5070>;expires=600, <sip:alice@10.0.0.2:5060;ob>;expires=3600\r\n\
+                    Content-Length: 0\r\n\r\n";
+        assert_eq!(binding_expires(kept, contact), Some(3600));
+
+        let removed = "SIP/2.0 200 OK\r\nContact: <sip:alice@10.0.0.2:5060>;expires=0\r\n\r\n";
+        assert_eq!(binding_expires(removed, contact), Some(0));
+        // Only another device's binding is left
+        let others = "SIP/2.0 200 OK\r\nContact: <sip:alice@10.0.0.9:5070>;expires=600\r\n\r\n";
+        assert_eq!(binding_expires(others, contact), None);
+        assert_eq!(binding_expires("SIP/2.0 200 OK\r\n\r\n", contact), None);
+        // Expiry from the Expires header when the Contact has none
+        let header = "SIP/2.0 200 OK\r\nm: <sip:alice@10.0.0.2:5060>\r\nExpires: 120\r\n\r\n";
+        assert_eq!(binding_expires(header, contact), Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_network_info() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+
+        shutdown().await;
+        *SIP_ENGINE.lock().await = SipEngine::default();
+        let info = network_info().await;
+        assert_eq!(info, NetworkInfo { local_addr: None, advertised_addr: None, public_addr: None, server_resolved_addr: None });
+
+        reset_engine(&server).await;
+        let addr = server.addr().to_string();
+        register_account(&addr, "alice", "secret").await.unwrap();
+
+        let info = network_info().await;
+        let socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+        assert_eq!(info.local_addr, Some(socket.local_addr().unwrap().to_string()));
+        // Advertised with the host's IP on the same port
+        let advertised: SocketAddr = info.advertised_addr.unwrap().parse().unwrap();
+        assert_eq!(advertised.port(), socket.local_addr().unwrap().port());
+        assert_eq!(info.server_resolved_addr, Some(addr));
+        assert_eq!(info.public_addr, None);
+    }
+
+    #[test]
+    fn test_resume_gap() {
+        let secs = std::time::Duration::from_secs;
+        assert_eq!(resume_gap(secs(5), secs(5)), None);
+        // A busy runtime firing late isn't a suspend
+        assert_eq!(resume_gap(secs(5), secs(12)), None);
+        assert_eq!(resume_gap(secs(5), secs(3605)), Some(secs(3600)));
+        assert_eq!(resume_gap(secs(5), secs(0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_resume_rebinds_and_registers() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+
+        let addr = server.addr().to_string();
+        register_account(&addr, "alice", "secret").await.unwrap();
+        assert!(SIP_ENGINE.lock().await.resume_watch_task.is_some());
+        // Keep the old socket bound so the new one can't get its port
+        let old_socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+
+        recover_from_resume(std::time::Duration::from_secs(600)).await;
+
+        let registers = server.wait_for("REGISTER", 2).await;
+        assert_eq!(registers.len(), 2);
+        let new_socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+        assert!(!Arc::ptr_eq(&old_socket, &new_socket));
+        let port = new_socket.local_addr().unwrap().port();
+        assert_ne!(old_socket.local_addr().unwrap().port(), port);
+        assert!(header_value(&registers[1], "Via").unwrap().contains(&format!(":{}", port)));
+        assert!(SIP_ENGINE.lock().await.registered);
+
+        unregister().await.unwrap();
+        assert!(SIP_ENGINE.lock().await.resume_watch_task.is_none());
+    }
+
+    #[test]
+    fn test_no_record_route() {
+        let response = "SIP/2.0 200 OK\r\nCall-ID: abc\r\n\r\n";
+        assert!(extract_route_set(response).is_empty());
+        assert_eq!(route_headers(&[]), "");
+    }
+
+    #[tokio::test]
+    async fn test_register_with_digest_challenge() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[401]).respond("REGISTER", &[200]);
+        reset_engine(&server).await;
+
+        register_account(&server.addr().to_string(), "alice", "secret").await.unwrap();
+        assert!(SIP_ENGINE.lock().await.registered);
+
+        let registers = server.received_method("REGISTER");
+        assert_eq!(registers.len(), 2);
+        assert!(!registers[0].contains("Authorization:"));
+        assert!(registers[1].contains("Authorization: Digest username=\"alice\""));
+        assert!(registers[1].contains("CSeq: 2 REGISTER"));
+    }
+
+    #[tokio::test]
+    async fn test_register_after_stale_nonce() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // The second challenge comes back stale with a new nonce
+        server.respond("REGISTER", &[401]).respond("REGISTER", &[401]).respond("REGISTER", &[200]);
+        reset_engine(&server).await;
+
+        register_account(&server.addr().to_string(), "alice", "secret").await.unwrap();
+        assert!(SIP_ENGINE.lock().await.registered);
+
+        let registers = server.received_method("REGISTER");
+        assert_eq!(registers.len(), 3);
+        assert!(registers[1].contains(&format!("nonce=\"{}\"", crate::mock_sip::MOCK_NONCE)));
+        assert!(registers[2].contains(&format!("nonce=\"{}\"", crate::mock_sip::MOCK_STALE_NONCE)));
+        assert!(registers[2].contains("CSeq: 3 REGISTER"));
+        // The old credentials are replaced, not added to
+        assert_eq!(registers[2].matches("Authorization:").count(), 1);
+
+        // Only one stale retry; a server that keeps rotating fails the attempt
+        server.respond("REGISTER", &[401]).respond("REGISTER", &[401]).respond("REGISTER", &[401]);
+        let result = register_account(&server.addr().to_string(), "alice", "secret").await;
+        assert!(matches!(result, Err(SipError::AuthFailed(_))));
+        assert_eq!(server.received_method("REGISTER").len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_register_refresh_reuses_call_id() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[401]);
+        reset_engine(&server).await;
+
+        let addr = server.addr().to_string();
+        register_account(&addr, "alice", "secret").await.unwrap();
+        register_account(&addr, "alice", "secret").await.unwrap();
+        unregister().await.unwrap();
+
+        let registers = server.wait_for("REGISTER", 4).await;
+        assert_eq!(registers.len(), 4);
+        let call_id = header_value(&registers[0], "Call-ID").unwrap();
+        let from = header_value(&registers[0], "From").unwrap();
+        for (register, cseq) in registers.iter().zip(1..) {
+            assert_eq!(header_value(register, "Call-ID").unwrap(), call_id);
+            assert_eq!(header_value(register, "From").unwrap(), from);
+            assert_eq!(header_value(register, "CSeq").unwrap(), format!("{} REGISTER", cseq));
+        }
+        assert!(registers[3].contains("Expires: 0"));
+
+        // Another account starts a new registration
+        register_account(&addr, "bob", "secret").await.unwrap();
+        let registers = server.wait_for("REGISTER", 5).await;
+        assert_ne!(header_value(&registers[4], "Call-ID").unwrap(), call_id);
+        assert_eq!(header_value(&registers[4], "CSeq").unwrap(), "1 REGISTER");
+    }
+
+    #[test]
+    fn test_registration_failure_categories() {
+        let auth = SipError::AuthFailed("Registration failed".to_string());
+        let rejected = SipError::Rejected("Unexpected response".to_string());
+        let category = |response: &str, error: &SipError| {
+            RegistrationFailure::classify(Some(response_status(response)), error)
+        };
+
+        assert_eq!(category("SIP/2.0 401 Unauthorized\r\n\r\n", &auth), RegistrationFailure::BadCredentials);
+        assert_eq!(category("SIP/2.0 407 Proxy Authentication Required\r\n\r\n", &auth), RegistrationFailure::BadCredentials);
+        assert_eq!(category("SIP/2.0 404 Not Found\r\n\r\n", &rejected), RegistrationFailure::BadCredentials);
+        assert_eq!(category("SIP/2.0 403 Forbidden\r\n\r\n", &auth), RegistrationFailure::Forbidden);
+        assert_eq!(category("SIP/2.0 408 Request Timeout\r\n\r\n", &rejected), RegistrationFailure::Transient);
+        assert_eq!(category("SIP/2.0 503 Service Unavailable\r\n\r\n", &rejected), RegistrationFailure::Transient);
+        assert_eq!(category("SIP/2.0 500 Server Internal Error\r\n\r\n", &rejected), RegistrationFailure::ServerError);
+        assert_eq!(category("SIP/2.0 400 Bad Request\r\n\r\n", &rejected), RegistrationFailure::ServerError);
+
+        // No response at all
+        let timeout = SipError::Timeout("No response".to_string());
+        assert_eq!(RegistrationFailure::classify(None, &timeout), RegistrationFailure::Transient);
+        assert_eq!(RegistrationFailure::classify(None, &SipError::Network("DNS".to_string())), RegistrationFailure::Transient);
+        // The challenge couldn't be answered
+        assert_eq!(RegistrationFailure::classify(None, &auth), RegistrationFailure::BadCredentials);
+
+        assert_eq!(serde_json::to_value(RegistrationFailure::BadCredentials).unwrap(), "bad_credentials");
+    }
+
+    #[tokio::test]
+    async fn test_register_forbidden_records_status() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[403]);
+        reset_engine(&server).await;
+
+        let addr = server.addr().to_string();
+        assert!(matches!(register_account(&addr, "alice", "secret").await, Err(SipError::AuthFailed(_))));
+        let status = SIP_ENGINE.lock().await.registration.as_ref().and_then(|r| r.last_status);
+        assert_eq!(status, Some(403));
+
+        // The next attempt starts without a status
+        register_account(&addr, "alice", "secret").await.unwrap();
+        let status = SIP_ENGINE.lock().await.registration.as_ref().and_then(|r| r.last_status);
+        assert_eq!(status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn test_register_rejected_after_challenge() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[401]).respond("REGISTER", &[403]);
+        reset_engine(&server).await;
+        // A failed re-registration clears the earlier success
+        set_registered().await;
+
+        let result = register_account(&server.addr().to_string(), "alice", "wrong").await;
+        assert!(matches!(result, Err(SipError::AuthFailed(_))));
+        assert!(!SIP_ENGINE.lock().await.registered);
+    }
+
+    #[test]
+    fn test_normalize_dial_target() {
+        let server = "pbx.example.com";
+
+        assert_eq!(
+            normalize_dial_target("+1 (555) 123-4567", server, DialAs::Sip).unwrap(),
+            "sip:+15551234567@pbx.example.com"
+        );
+        assert_eq!(
+            normalize_dial_target("sip:alice@example.com", server, DialAs::Sip).unwrap(),
+            "sip:alice@example.com"
+        );
+        assert_eq!(normalize_dial_target("  bob ", server, DialAs::Sip).unwrap(), "sip:bob@pbx.example.com");
+        assert_eq!(normalize_dial_target("*97", server, DialAs::Sip).unwrap(), "sip:*97@pbx.example.com");
+        assert_eq!(
+            normalize_dial_target("tel:+44 20 7946 0958", server, DialAs::Sip).unwrap(),
+            "sip:+442079460958@pbx.example.com"
+        );
+        assert_eq!(
+            normalize_dial_target("carol@example.org", server, DialAs::Sip).unwrap(),
+            "sip:carol@example.org"
+        );
+    }
+
+    #[test]
+    fn test_normalize_dial_target_dial_as() {
+        let server = "gw.example.com";
+
+        assert_eq!(
+            normalize_dial_target("+1 555 123 4567", server, DialAs::UserPhone).unwrap(),
+            "sip:+15551234567@gw.example.com;user=phone"
+        );
+        assert_eq!(
+            normalize_dial_target("tel:+15551234567", server, DialAs::Tel).unwrap(),
+            "tel:+15551234567"
+        );
+
+        // Extensions and short codes aren't E.164 and stay plain SIP
+        assert_eq!(normalize_dial_target("1001", server, DialAs::Tel).unwrap(), "sip:1001@gw.example.com");
+        assert_eq!(
+            normalize_dial_target("*97", server, DialAs::UserPhone).unwrap(),
+            "sip:*97@gw.example.com"
+        );
+
+        // Explicit SIP URIs are dialed as given
+        assert_eq!(
+            normalize_dial_target("sip:alice@example.com", server, DialAs::Tel).unwrap(),
+            "sip:alice@example.com"
+        );
+    }
+
+    #[test]
+    fn test_is_e164() {
+        assert!(is_e164("+15551234567"));
+        assert!(is_e164("+442079460958"));
+        assert!(!is_e164("15551234567"));
+        assert!(!is_e164("+0123"));
+        assert!(!is_e164("+1234567890123456"));
+        assert!(!is_e164("+1*555"));
+    }
+
+    #[test]
+    fn test_normalize_dial_target_rejects_garbage() {
+        let server = "pbx.example.com";
+        for input in ["", "   ", "!!@@##", "hello world", "sip:", "sip:@example.com", "tel:abc", "555+1234", "+"] {
+            assert!(
+                matches!(normalize_dial_target(input, server, DialAs::Sip), Err(SipError::InvalidTarget(_))),
+                "{:?} should be rejected",
+                input
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_make_call_rejects_invalid_target_before_sending() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert!(matches!(make_call("!!@@##", MediaDirection::SendRecv).await, Err(SipError::InvalidTarget(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert!(server.received_method("INVITE").is_empty());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let unavailable = "SIP/2.0 503 Service Unavailable\r\nRetry-After: 120 (maintenance);duration=60\r\n\r\n";
+        assert_eq!(retry_after(unavailable), Some(120));
+
+        let away = "SIP/2.0 480 Temporarily Unavailable\r\nretry-after: 30\r\n\r\n";
+        assert_eq!(retry_after(away), Some(30));
+
+        // Only 503/480 are retried, and only when the server gave a delay
+        assert_eq!(retry_after("SIP/2.0 486 Busy Here\r\nRetry-After: 30\r\n\r\n"), None);
+        assert_eq!(retry_after("SIP/2.0 503 Service Unavailable\r\n\r\n"), None);
+
+        // Retries never come faster than the backoff floor
+        assert_eq!(register_retry_delay(0, 0), 1);
+        assert_eq!(register_retry_delay(0, 3), 8);
+        assert_eq!(register_retry_delay(120, 3), 120);
+        assert_eq!(register_retry_delay(0, 20), 256);
+    }
+
+    #[test]
+    fn test_media_security() {
+        let secure = media_security(true);
+        assert_eq!(secure["encryption"], "srtp");
+        assert_eq!(secure["suite"], "AES_CM_128_HMAC_SHA1_80");
+
+        let plain = media_security(false);
+        assert_eq!(plain["encryption"], "plain");
+        assert!(plain["suite"].is_null());
+    }
+
+    #[test]
+    fn test_rtp_bind_ip() {
+        assert_eq!(rtp_bind_ip("127.0.0.1"), LOCALHOST);
+        assert_eq!(rtp_bind_ip("::1"), "::1".parse::<std::net::IpAddr>().unwrap());
+        assert!(rtp_bind_ip("pbx.example.com").is_unspecified());
+    }
+
+    #[tokio::test]
+    async fn test_register_retried_after_unavailable() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[503]);
+        reset_engine(&server).await;
+
+        let result = register_account(&server.addr().to_string(), "alice", "secret").await;
+        assert!(matches!(
+            result,
+            Err(SipError::Unavailable { retry_after: crate::mock_sip::MOCK_RETRY_AFTER, .. })
+        ));
+        assert!(!SIP_ENGINE.lock().await.registered);
+
+        // The retry goes out on its own after Retry-After and succeeds; the
+        // commands gating on registration see it
+        assert_eq!(server.wait_for("REGISTER", 2).await.len(), 2);
+        server.wait_for("SUBSCRIBE", 1).await;
+        assert!(is_registered().await);
+    }
+
+    #[tokio::test]
+    async fn test_make_call_unavailable_reports_retry_after() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 480]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert!(matches!(result, Err(SipError::Unavailable { retry_after: crate::mock_sip::MOCK_RETRY_AFTER, .. })));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invite_failures_are_acked() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // Challenged, then left ringing until the answer timeout cancels it
+        server.respond("INVITE", &[401]).respond("INVITE", &[100, 180]);
+        reset_engine(&server).await;
+        set_registered().await;
+        let timeouts = SipTimeouts { answer_secs: 1, invite_secs: 2, ..SipTimeouts::default() };
+        crate::settings::save_timeouts(&timeouts).unwrap();
+
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert!(matches!(result, Err(SipError::Timeout(_))));
+
+        // The 401 and the 487 after our CANCEL are each ACKed inside their
+        // INVITE's transaction: same branch and CSeq number, the response's To tag
+        let invites = server.wait_for("INVITE", 2).await;
+        let acks = server.wait_for("ACK", 2).await;
+        assert_eq!(acks.len(), 2);
+        for (ack, invite) in acks.iter().zip(&invites) {
+            assert_eq!(header_value(ack, "Via"), header_value(invite, "Via"));
+            assert_eq!(response_cseq(ack), response_cseq(invite));
+            assert!(header_value(ack, "To").unwrap().contains(";tag=mock"));
+        }
+        assert_eq!(server.received_method("CANCEL").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ringing_call_outlives_invite_timeout() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 180]);
+        reset_engine(&server).await;
+        set_registered().await;
+        let timeouts = SipTimeouts { answer_secs: 2, invite_secs: 1, ..SipTimeouts::default() };
+        crate::settings::save_timeouts(&timeouts).unwrap();
+
+        let started = tokio::time::Instant::now();
+        let call = tokio::spawn(make_call("bob", MediaDirection::SendRecv));
+        let mut ringing = false;
+        for _ in 0..50 {
+            let state = SIP_ENGINE.lock().await.active_dialog.as_ref().map(|dialog| dialog.state.clone());
+            if state == Some(CallState::Ringing) {
+                ringing = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(ringing);
+
+        // Only the answer timeout ends the ringing call, with a CANCEL
+        let result = call.await.unwrap();
+        assert!(matches!(result, Err(SipError::Timeout(_))));
+        assert!(started.elapsed() >= std::time::Duration::from_secs(2));
+        assert_eq!(server.wait_for("CANCEL", 1).await.len(), 1);
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invite_timeout_fails_without_cancel() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // No response at all, not even 100 Trying
+        server.respond("INVITE", &[]);
+        reset_engine(&server).await;
+        set_registered().await;
+        let timeouts = SipTimeouts { answer_secs: 5, invite_secs: 1, ..SipTimeouts::default() };
+        crate::settings::save_timeouts(&timeouts).unwrap();
+        let emitted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = emitted.clone();
+        crate::events::set_sink(move |payload| sink.lock().unwrap().push(payload["type"].to_string()));
+
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert_eq!(result, Err(SipError::Timeout("No response to INVITE within 1 seconds".to_string())));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // Nothing to CANCEL before a provisional response, and it isn't an
+        // unanswered call
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(server.received_method("CANCEL").is_empty());
+        assert!(!emitted.lock().unwrap().iter().any(|event| event == "\"call_timeout\""));
+        crate::events::set_sink(|_| {});
+    }
+
+    #[tokio::test]
+    async fn test_srtp_offer_rejected_retries_plain_rtp() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[488]).respond("INVITE", &[100, 488]);
+        reset_engine(&server).await;
+        set_registered().await;
+        crate::settings::save_secure_media(true).unwrap();
+
+        // One retry only: the second 488 fails the call
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert!(matches!(result, Err(SipError::Rejected(_))));
+
+        let invites = server.wait_for("INVITE", 2).await;
+        assert_eq!(invites.len(), 2);
+        assert!(invites[0].contains("RTP/SAVP") && invites[0].contains("a=crypto:"));
+        assert!(invites[1].contains("RTP/AVP") && !invites[1].contains("a=crypto:"));
+        assert_eq!(header_value(&invites[0], "Call-ID"), header_value(&invites[1], "Call-ID"));
+        assert_eq!(server.wait_for("ACK", 2).await.len(), 2);
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_call_is_cancelled() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        let socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+        let local_addr = engine_addr().await.to_string();
+        let invite = message::build_invite(
+            &message::RequestParams {
+                uri: "sip:bob@127.0.0.1",
+                local_addr: &local_addr,
+                branch: "z9hG4bKringing",
+                route: "",
+                from_uri: "sip:alice@127.0.0.1",
+                from_name: "",
+                from_tag: "local",
+                to_uri: "sip:bob@127.0.0.1",
+                to_tag: None,
+                call_id: "unanswered",
+                cseq: 2,
+                compact: false,
+            },
+            "sip:alice@127.0.0.1",
+            "",
+            "",
+        );
+        SIP_ENGINE.lock().await.active_dialog = Some(Dialog {
+            cseq: 2,
+            server_addr: Some(server.addr()),
+            outgoing_invite: Some(invite),
+            outgoing: true,
+            ..test_dialog("unanswered", CallState::Ringing)
+        });
+
+        let _route = ResponseRoute::open("unanswered");
+        let timeouts = SipTimeouts { answer_secs: 1, invite_secs: 1, ..SipTimeouts::default() };
+        let result = cancel_unanswered_call(&socket, "unanswered", &local_addr, server.addr(), &timeouts).await;
+        assert!(matches!(result, Err(SipError::Timeout(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // The CANCEL matches the pending INVITE transaction
+        let cancels = server.wait_for("CANCEL", 1).await;
+        assert_eq!(cancels.len(), 1);
+        assert!(cancels[0].contains(";branch=z9hG4bKringing\r\n"));
+        assert!(cancels[0].contains("CSeq: 2 CANCEL\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_switch_output_device_keeps_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert!(matches!(switch_output_device("Headset").await, Err(SipError::NoActiveCall)));
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        assert_eq!(switch_output_device("Headset").await.unwrap(), "Headset");
+
+        let engine = SIP_ENGINE.lock().await;
+        let dialog = engine.active_dialog.as_ref().unwrap();
+        assert_eq!(dialog.state, CallState::Confirmed);
+        assert!(dialog.rtp_session.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_go_offline_then_online_reuses_credentials() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+
+        register_account(&server.addr().to_string(), "alice", "secret").await.unwrap();
+        let local_addr = engine_addr().await;
+
+        go_offline().await.unwrap();
+        {
+            let engine = SIP_ENGINE.lock().await;
+            assert!(!engine.registered);
+            assert!(engine.socket.is_some());
+            assert_eq!(engine.user, "alice");
+        }
+
+        go_online().await.unwrap();
+        assert!(SIP_ENGINE.lock().await.registered);
+        assert_eq!(engine_addr().await, local_addr);
+
+        // Initial REGISTER, unregister (Expires: 0), then the re-registration
+        let registers = server.wait_for("REGISTER", 3).await;
+        assert_eq!(registers.len(), 3);
+        assert!(registers[1].contains("Expires: 0"));
+        assert!(registers[2].contains("From: <sip:alice@"));
+    }
+
+    #[tokio::test]
+    async fn test_go_online_without_credentials() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        shutdown().await;
+        *SIP_ENGINE.lock().await = SipEngine::default();
+
+        assert!(matches!(go_online().await, Err(SipError::AuthFailed(_))));
+        assert!(SIP_ENGINE.lock().await.socket.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_make_call_answered_after_ringing() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 180, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+
+        {
+            let engine = SIP_ENGINE.lock().await;
+            let dialog = engine.active_dialog.as_ref().unwrap();
+            assert_eq!(dialog.state, CallState::Confirmed);
+            assert_eq!(dialog.to_tag.as_deref(), Some("mock1"));
+            assert_eq!(dialog.remote_target, Some(format!("sip:mock@{}", server.addr())));
+            assert!(dialog.connected_at.is_some());
+        }
+        assert_eq!(call_duration().await, 0);
+
+        let acks = server.wait_for("ACK", 1).await;
+        assert_eq!(acks.len(), 1);
+        assert!(acks[0].starts_with(&format!("ACK sip:mock@{} SIP/2.0", server.addr())));
+
+        hangup_call().await.unwrap();
+        assert_eq!(server.wait_for("BYE", 1).await.len(), 1);
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert_eq!(call_duration().await, 0);
+    }
+
+    #[test]
+    fn test_warning_text() {
+        let response = "SIP/2.0 488 Not Acceptable Here\r\n\
+                        Warning: 304 pbx.example.com \"Media type not available\"\r\n\
+                        Warning: 370 pbx.example.com \"Insufficient \\\"bandwidth\\\"\", 399 gw \"x\"\r\n\
+                        Content-Length: 0\r\n\r\n";
+        assert_eq!(
+            warning_text(response).unwrap(),
+            "304 Media type not available; 370 Insufficient \"bandwidth\"; 399 x"
+        );
+        assert_eq!(warning_text("SIP/2.0 486 Busy Here\r\n\r\n"), None);
+
+        let error = call_failure(response);
+        assert_eq!(
+            error,
+            SipError::Rejected("Call failed: SIP/2.0 488 Not Acceptable Here (304 Media type not available; \
+                                370 Insufficient \"bandwidth\"; 399 x)".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retransmitted_200_is_acked_again() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        let acks = server.wait_for("ACK", 1).await;
+        let call_id = header_value(&server.received_method("INVITE")[0], "Call-ID").unwrap();
+
+        // Our ACK was "lost": the callee sends its 200 again
+        let retransmission = format!(
+            "SIP/2.0 200 OK\r\nCall-ID: {}\r\nCSeq: 1 INVITE\r\nContent-Length: 0\r\n\r\n",
+            call_id
+        );
+        server.send_request(engine_addr().await, &retransmission).await;
+        let resent = server.wait_for("ACK", 2).await;
+        assert_eq!(resent[1], acks[0]);
+
+        // A 2xx for some other INVITE gets no ACK
+        server.send_request(engine_addr().await, &retransmission.replace("CSeq: 1", "CSeq: 7")).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(server.received_method("ACK").len(), 2);
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_park_and_retrieve_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert!(matches!(park_call().await, Err(SipError::NoActiveCall)));
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+
+        // Parking holds the call with a sendonly re-INVITE in the same dialog
+        assert_eq!(park_call().await.unwrap(), PARK_SLOT);
+        let invites = server.wait_for("INVITE", 2).await;
+        assert!(invites[1].contains("a=sendonly"));
+        assert!(invites[1].contains(";tag=mock1"));
+        assert!(response_cseq(&invites[1]) > response_cseq(&invites[0]));
+        let acks = server.wait_for("ACK", 2).await;
+        assert_eq!(response_cseq(&acks[1]), response_cseq(&invites[1]));
+
+        let parked = list_parked().await;
+        assert_eq!(parked.len(), 1);
+        assert_eq!(parked[0].slot, PARK_SLOT);
+        assert!(parked[0].remote_uri.starts_with("sip:bob@"));
+        assert!(matches!(park_call().await, Err(SipError::Protocol(_))));
+        assert!(matches!(make_call("carol", MediaDirection::SendRecv).await, Err(SipError::Protocol(_))));
+
+        assert_eq!(retrieve_parked(PARK_SLOT + 1).await, Err(SipError::NoActiveCall));
+        retrieve_parked(PARK_SLOT).await.unwrap();
+        let invites = server.wait_for("INVITE", 3).await;
+        assert!(invites[2].contains("a=sendrecv"));
+        assert!(list_parked().await.is_empty());
+        let rtp_session = SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().rtp_session.clone().unwrap();
+        assert_eq!(rtp_session.direction().await, MediaDirection::SendRecv);
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reinvite_glare_is_retried() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+
+        // The peer is in the middle of its own re-INVITE: 491, then 200
+        server.respond("INVITE", &[491]);
+        let started = std::time::Instant::now();
+        park_call().await.unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(2100));
+
+        let invites = server.wait_for("INVITE", 3).await;
+        let (first, retry) = (response_cseq(&invites[1]).unwrap(), response_cseq(&invites[2]).unwrap());
+        assert_eq!(retry, first + 1);
+        assert!(invites[2].contains("a=sendonly"));
+        // The 491 was ACKed before the retry
+        assert!(server.received_method("ACK").iter().any(|ack| response_cseq(ack) == Some(first)));
+        assert_eq!(list_parked().await.len(), 1);
+        hangup_call().await.unwrap();
+
+        for _ in 0..100 {
+            let delay = glare_backoff(true);
+            assert!(delay >= std::time::Duration::from_millis(2100) && delay <= std::time::Duration::from_secs(4));
+            assert!(glare_backoff(false) <= std::time::Duration::from_secs(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dialog_cseq_progression() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        let cseq = |message: &String| message::CSeq::of(message).unwrap().to_string();
+
+        // INVITE 1 is challenged, the retry with credentials is 2
+        server.respond("INVITE", &[401]);
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().cseq, 2);
+
+        // The re-INVITE takes the next number, and so does its own retry
+        server.respond("INVITE", &[401]);
+        park_call().await.unwrap();
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().cseq, 4);
+
+        hangup_call().await.unwrap();
+
+        let invites: Vec<String> = server.wait_for("INVITE", 4).await.iter().map(cseq).collect();
+        assert_eq!(invites, ["1 INVITE", "2 INVITE", "3 INVITE", "4 INVITE"]);
+        // Every INVITE is ACKed with its own CSeq number: the challenges
+        // inside their transactions, the 2xx answers end to end
+        let acks: Vec<String> = server.wait_for("ACK", 4).await.iter().map(cseq).collect();
+        assert_eq!(acks, ["1 ACK", "2 ACK", "3 ACK", "4 ACK"]);
+        let challenge_ack = &server.received_method("ACK")[0];
+        let first_invite = &server.received_method("INVITE")[0];
+        assert_eq!(header_value(challenge_ack, "Via"), header_value(first_invite, "Via"));
+        let byes: Vec<String> = server.wait_for("BYE", 1).await.iter().map(cseq).collect();
+        assert_eq!(byes, ["5 BYE"]);
+    }
+
+    #[tokio::test]
+    async fn test_media_setup_failure() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+
+        // Not required: the call stays up without audio
+        let error = SipError::Audio("No output device".to_string());
+        media_setup_failed(error.clone(), false).await.unwrap();
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_some());
+        assert!(server.received_method("BYE").is_empty());
+
+        // Required: the call is hung up and the failure reported
+        assert_eq!(media_setup_failed(error.clone(), true).await, Err(error));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert_eq!(server.wait_for("BYE", 1).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_media_info() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert_eq!(media_info().await, Err(SipError::NoActiveCall));
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+
+        let info = media_info().await.unwrap();
+        assert_eq!(info.codec, "PCMU");
+        assert_eq!(info.clock_rate, 8000);
+        assert_eq!(info.ptime, 20);
+        assert_ne!(info.local_rtp_port, 0);
+        assert_eq!(info.remote_rtp_addr, server.rtp_addr().to_string());
+        assert!(!info.encrypted);
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_pending_attempts() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // The server never answers the REGISTER and lets the call ring
+        server.respond("REGISTER", &[]).respond("INVITE", &[100, 180]);
+        reset_engine(&server).await;
+        assert!(!cancel_pending().await);
+
+        let server_addr = server.addr().to_string();
+        let register = tokio::spawn(async move { register_account(&server_addr, "alice", "secret").await });
+        server.wait_for("REGISTER", 1).await;
+        assert!(cancel_pending().await);
+        assert_eq!(register.await.unwrap(), Err(SipError::Cancelled));
+        assert!(!SIP_ENGINE.lock().await.registered);
+
+        set_registered().await;
+        let call = tokio::spawn(make_call("bob", MediaDirection::SendRecv));
+        server.wait_for("INVITE", 1).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(cancel_pending().await);
+        assert_eq!(call.await.unwrap(), Err(SipError::Cancelled));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        // The ringing INVITE is cancelled in the background
+        let cancel = &server.wait_for("CANCEL", 1).await[0];
+        assert!(cancel.contains("CSeq: 1 CANCEL"));
+    }
+
+    #[tokio::test]
+    async fn test_hangup_bye_outcomes() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]).respond("INVITE", &[100, 200]);
+        server.respond("BYE", &[481]).respond("BYE", &[500]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        // Already gone on the server: a clean hangup
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        // A second tap on the call button doesn't dial again
+        assert_eq!(make_call("bob", MediaDirection::SendRecv).await, Err(SipError::CallInProgress));
+        hangup_call().await.unwrap();
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // Any other failure is reported, but the call is still cleared
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        assert!(matches!(hangup_call().await, Err(SipError::Rejected(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert_eq!(server.wait_for("BYE", 2).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hangup_before_answer_cancels_invite() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 180]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        let call = tokio::spawn(make_call("bob", MediaDirection::SendRecv));
+        server.wait_for("INVITE", 1).await;
+        // A registration refresh the server hasn't answered yet
+        server.respond("REGISTER", &[]);
+        let server_addr = server.addr().to_string();
+        let register = tokio::spawn(async move { register_account(&server_addr, "alice", "secret").await });
+        server.wait_for("REGISTER", 1).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        hangup_call().await.unwrap();
+        assert_eq!(call.await.unwrap(), Err(SipError::Cancelled));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // CANCEL for the pending INVITE, and its 487 ACKed; never a BYE
+        let cancel = &server.wait_for("CANCEL", 1).await[0];
+        assert!(cancel.contains("CSeq: 1 CANCEL"));
+        assert_eq!(server.wait_for("ACK", 1).await.len(), 1);
+        assert!(server.received_method("BYE").is_empty());
+
+        // Only the call was given up
+        assert!(!register.is_finished());
+        assert!(cancel_pending().await);
+        assert_eq!(register.await.unwrap(), Err(SipError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn test_failed_call_does_not_block_next_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // Never answered at all, then answered
+        server.respond("INVITE", &[]).respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+        let timeouts = SipTimeouts { answer_secs: 5, invite_secs: 1, ..SipTimeouts::default() };
+        crate::settings::save_timeouts(&timeouts).unwrap();
+
+        // The server can't be resolved: the INVITE never goes out
+        SIP_ENGINE.lock().await.server = "unreachable.invalid".to_string();
+        assert!(matches!(make_call("bob", MediaDirection::SendRecv).await, Err(SipError::Network(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        SIP_ENGINE.lock().await.server = server.addr().to_string();
+
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert!(matches!(result, Err(SipError::Timeout(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        let state = SIP_ENGINE.lock().await.active_dialog.as_ref().map(|dialog| dialog.state.clone());
+        assert_eq!(state, Some(CallState::Confirmed));
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_during_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        // Held so the new socket can't land on the same port
+        let old_socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+        reset().await.unwrap();
+
+        let engine = SIP_ENGINE.lock().await;
+        assert!(engine.active_dialog.is_none());
+        assert!(!engine.registered);
+        assert!(engine.server.is_empty());
+        assert!(engine.socket.is_some());
+        assert_ne!(engine.socket.as_ref().unwrap().local_addr().unwrap().port(), old_socket.local_addr().unwrap().port());
+        drop(engine);
+        // The call is dropped locally, without a BYE
+        assert!(server.received_method("BYE").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_listen_only_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::RecvOnly).await.unwrap();
+        let invite = &server.received_method("INVITE")[0];
+        assert!(invite.contains("\r\na=recvonly\r\n"));
+        assert!(!invite.contains("a=sendrecv"));
+
+        {
+            let engine = SIP_ENGINE.lock().await;
+            let dialog = engine.active_dialog.as_ref().unwrap();
+            assert!(dialog.rtp_session.is_some());
+            assert!(dialog.audio_tx_task.is_none());
+        }
+        assert!(server.recv_rtp().await.is_none());
+        // Parking and retrieving keep it listen-only
+        park_call().await.unwrap();
+        retrieve_parked(PARK_SLOT).await.unwrap();
+        let invites = server.wait_for("INVITE", 3).await;
+        assert!(invites[1].contains("\r\na=inactive\r\n"));
+        assert!(invites[2].contains("\r\na=recvonly\r\n"));
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bye_answers_proxy_challenge() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]).respond("BYE", &[100, 407]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        hangup_call().await.unwrap();
+
+        let byes = server.wait_for("BYE", 2).await;
+        assert_eq!(byes.len(), 2);
+        assert!(!byes[0].contains("Authorization:"));
+        assert!(byes[1].contains("\r\nProxy-Authorization: Digest "));
+        let cseq = |bye: &str| header_value(bye, "CSeq").unwrap();
+        let branch = |bye: &str| header_value(bye, "Via").unwrap();
+        assert_ne!(cseq(&byes[0]), cseq(&byes[1]));
+        assert_ne!(branch(&byes[0]), branch(&byes[1]));
+    }
+
+    #[tokio::test]
+    async fn test_call_auto_ended_at_max_duration() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]).respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        schedule_call_auto_end(std::time::Duration::from_millis(100)).await;
+        assert_eq!(server.wait_for("BYE", 1).await.len(), 1);
+        for _ in 0..50 {
+            if SIP_ENGINE.lock().await.active_dialog.is_none() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // A normal hangup cancels the timer
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        schedule_call_auto_end(std::time::Duration::from_millis(200)).await;
+        hangup_call().await.unwrap();
+        assert!(SIP_ENGINE.lock().await.call_limit_task.is_none());
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(server.received_method("BYE").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("MESSAGE", &[200]).respond("MESSAGE", &[404]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        send_message("bob", "Running late, call you at 3").await.unwrap();
+        let sent = server.wait_for("MESSAGE", 1).await;
+        assert!(sent[0].starts_with("MESSAGE sip:bob@"));
+        assert!(sent[0].contains("Content-Type: text/plain;charset=UTF-8\r\n"));
+        assert!(sent[0].ends_with("\r\n\r\nRunning late, call you at 3"));
+        assert!(!sent[0].contains("Contact:"));
+
+        // The failure status is reported
+        match send_message("nobody", "hello").await {
+            Err(SipError::Rejected(reason)) => assert!(reason.contains("404")),
+            other => panic!("expected Rejected, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incoming_message_answered() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+
+        let message = |content_type: &str| format!(
+            "MESSAGE sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKmessage\r\n\
+             From: \"Bob\" <sip:bob@127.0.0.1>;tag=sender\r\n\
+             To: <sip:alice@127.0.0.1>\r\n\
+             Call-ID: incoming-message\r\n\
+             CSeq: 1 MESSAGE\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: 5\r\n\r\nhello",
+            server.addr(),
+            content_type,
+        );
+
+        server.send_request(engine_addr().await, &message("text/plain")).await;
+        server.send_request(engine_addr().await, &message("application/im-iscomposing+xml")).await;
+
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[1].starts_with("SIP/2.0 415 Unsupported Media Type"));
+        assert!(responses[1].contains("Accept: text/plain\r\n"));
+    }
+
+    #[test]
+    fn test_dialog_duration_counts_from_answer() {
+        let mut dialog = test_dialog("duration-test", CallState::Ringing);
+        assert_eq!(dialog.duration_secs(), 0);
+
+        dialog.state = CallState::Confirmed;
+        dialog.connected_at = Some(std::time::Instant::now() - std::time::Duration::from_secs(75));
+        assert_eq!(dialog.duration_secs(), 75);
+    }
+
+    #[test]
+    fn test_call_state_transitions() {
+        use CallState::*;
+        let legal = [
+            (Idle, Calling), (Idle, Ringing), (Calling, Ringing), (Calling, Confirmed),
+            (Ringing, Confirmed), (Ringing, Terminated), (Confirmed, Terminated), (Idle, Terminated),
+        ];
+        for (from, to) in &legal {
+            assert!(from.can_transition_to(to), "{:?} → {:?}", from, to);
+        }
+        let illegal = [
+            (Terminated, Confirmed), (Terminated, Ringing), (Terminated, Idle), (Confirmed, Ringing),
+            (Confirmed, Calling), (Ringing, Calling), (Calling, Idle),
+        ];
+        for (from, to) in &illegal {
+            assert!(!from.can_transition_to(to), "{:?} → {:?}", from, to);
+        }
+
+        let mut dialog = test_dialog("state-test", Idle);
+        dialog.transition_to(Calling).unwrap();
+        dialog.transition_to(Ringing).unwrap();
+        // A 183 after the 180
+        dialog.transition_to(Ringing).unwrap();
+        dialog.transition_to(Confirmed).unwrap();
+        // A late 180 can't take an answered call back to ringing
+        assert!(dialog.transition_to(Ringing).is_err());
+        assert_eq!(dialog.state, Confirmed);
+
+        dialog.terminate(Some("cancelled"));
+        let error = dialog.transition_to(Confirmed).unwrap_err();
+        assert_eq!(error, "Ignoring call state change TERMINATED → CONFIRMED for state-test");
+        assert_eq!(dialog.state, Terminated);
+        // Ending it again is a no-op
+        dialog.terminate(None);
+        assert_eq!(dialog.state, Terminated);
+    }
+
+    #[tokio::test]
+    async fn test_hangup_terminates_dialog() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        // The BYE never gets a final response
+        server.respond("BYE", &[100]);
+        reset_engine(&server).await;
+        set_registered().await;
+        crate::settings::save_timeouts(&SipTimeouts { bye_secs: 1, ..SipTimeouts::default() }).unwrap();
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        let hangup = tokio::spawn(hangup_call());
+        server.wait_for("BYE", 1).await;
+
+        // While the BYE is out the call is already over
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().state, CallState::Terminated);
+        assert_eq!(hangup_call().await, Err(SipError::NoActiveCall));
+
+        assert!(matches!(hangup.await.unwrap(), Err(SipError::Timeout(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_call_media_runs_without_audio_hardware() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 200]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        make_call("bob", MediaDirection::SendRecv).await.unwrap();
+        assert!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().rtp_session.is_some());
+
+        // Silence from the null backend goes out as PCMU (the mock answers PT 0)
+        let packet = server.recv_rtp().await.expect("no RTP from the engine");
+        let packet = crate::rtp::RtpPacket::from_bytes(&packet).unwrap();
+        assert_eq!(packet.payload_type, 0);
+        assert!(packet.payload.iter().all(|&b| b == Codec::Pcmu.encode(&[0]).unwrap()[0]));
+
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_dtmf_during_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert!(matches!(send_dtmf('5').await, Err(SipError::NoActiveCall)));
+
+        let rtp_session = set_confirmed_call(&server, "dtmf-call").await;
+        rtp_session.set_event_payload_type(Some(101)).await;
+        send_dtmf('5').await.unwrap();
+        assert!(matches!(send_dtmf('x').await, Err(SipError::Protocol(_))));
+        assert!(server.received_method("INFO").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_dtmf_falls_back_to_info() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        // No telephone-event negotiated, so auto mode uses INFO
+        set_confirmed_call(&server, "info-call").await;
+        send_dtmf('#').await.unwrap();
+        send_dtmf('a').await.unwrap();
+
+        let infos = server.wait_for("INFO", 2).await;
+        assert_eq!(infos.len(), 2);
+        assert!(infos[0].starts_with("INFO sip:bob@127.0.0.1 SIP/2.0\r\n"));
+        assert!(infos[0].contains("To: <sip:bob@127.0.0.1>;tag=remote\r\n"));
+        assert!(infos[0].contains("CSeq: 2 INFO\r\n"));
+        assert!(infos[0].contains("Content-Type: application/dtmf-relay\r\n"));
+        assert!(infos[0].ends_with("\r\n\r\nSignal=#\r\nDuration=150\r\n"));
+        assert!(infos[1].contains("CSeq: 3 INFO\r\n"));
+        assert!(infos[1].contains("Signal=A\r\n"));
+
+        server.respond("INFO", &[415]);
+        assert!(matches!(send_dtmf('1').await, Err(SipError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn test_make_call_busy() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("INVITE", &[100, 486]);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        let result = make_call("bob", MediaDirection::SendRecv).await;
+        assert!(matches!(result, Err(SipError::Rejected(ref msg)) if msg.contains("486")));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+    }
+
+    // re-INVITE from bob in the dialog set up by test_dialog
+    fn remote_reinvite(server: &MockSipServer, call_id: &str, cseq: u32, sdp: &str) -> String {
+        format!(
+            "INVITE sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKreinvite{}\r\n\
+             From: <sip:bob@127.0.0.1>;tag=remote\r\n\
+             To: <sip:alice@127.0.0.1>;tag=local\r\n\
+             Call-ID: {}\r\n\
+             CSeq: {} INVITE\r\n\
+             Contact: <sip:bob@127.0.0.1:5070>\r\n\
+             Content-Type: application/sdp\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            server.addr(),
+            cseq,
+            call_id,
+            cseq,
+            sdp.len(),
+            sdp
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reinvite_hold_from_peer() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        let rtp_session = set_confirmed_call(&server, "peer-hold").await;
+
+        let offer = |direction: &str| format!(
+            "v=0\r\no=- 2 2 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+             m=audio 5000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\na={}\r\n",
+            direction
+        );
+
+        // Put on hold: we only listen
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "peer-hold", 10, &offer("sendonly"))).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("a=recvonly\r\n"));
+        assert_eq!(rtp_session.direction().await, MediaDirection::RecvOnly);
+
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "peer-hold", 11, &offer("inactive"))).await;
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert!(responses[1].contains("a=inactive\r\n"));
+        assert_eq!(rtp_session.direction().await, MediaDirection::Inactive);
+
+        // Taken off hold
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "peer-hold", 12, &offer("sendrecv"))).await;
+        let responses = server.wait_for("SIP/2.0", 3).await;
+        assert!(responses[2].contains("a=sendrecv\r\n"));
+        assert_eq!(rtp_session.direction().await, MediaDirection::SendRecv);
+    }
+
+    #[tokio::test]
+    async fn test_reinvite_for_waiting_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_confirmed_call(&server, "active-call").await;
+
+        // A call swapped out (on hold) behind the active one
+        let held_session = Arc::new(
+            RtpSession::new(LOCALHOST, 0, "127.0.0.1:4002".parse().unwrap(), 0, 8000).await.unwrap()
+        );
+        held_session.set_direction(MediaDirection::Inactive).await;
+        SIP_ENGINE.lock().await.waiting_dialog = Some(Dialog {
+            to_tag: Some("remote".to_string()),
+            rtp_session: Some(held_session.clone()),
+            ..test_dialog("held-call", CallState::Confirmed)
+        });
+
+        // A session refresh is answered, still on hold, and stays silent
+        let sdp = "v=0\r\no=- 2 2 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+                   m=audio 5002 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n";
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "held-call", 10, sdp)).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("Call-ID: held-call"));
+        assert!(responses[0].contains("a=sendonly\r\n"));
+        assert_eq!(held_session.direction().await, MediaDirection::Inactive);
+
+        // Nothing we know
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "unknown-call", 10, sdp)).await;
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert!(responses[1].starts_with("SIP/2.0 481"));
+    }
+
+    #[tokio::test]
+    async fn test_reinvite_bad_offer_rejected() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_confirmed_call(&server, "bad-offer").await;
+
+        let sdp = "v=0\r\no=- 2 2 IN IP4 nowhere\r\ns=-\r\nc=IN IP4 nowhere\r\nt=0 0\r\n\
+                   m=audio 5000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n";
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "bad-offer", 10, sdp)).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 488"));
+        assert!(responses[0].contains("CSeq: 10 INVITE"));
+    }
+
+    #[tokio::test]
+    async fn test_reinvite_retargets_media() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        let rtp_session = Arc::new(
+            RtpSession::new(LOCALHOST, 0, "127.0.0.1:4000".parse().unwrap(), 0, 8000).await.unwrap()
+        );
+        {
+            let mut engine = SIP_ENGINE.lock().await;
+            engine.active_dialog = Some(Dialog {
+                to_tag: Some("remote".to_string()),
+                rtp_session: Some(rtp_session.clone()),
+                ..test_dialog("reinvite-test", CallState::Confirmed)
+            });
+        }
+
+        let sdp = "v=0\r\no=- 2 2 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+                   m=audio 5000 RTP/AVP 8 101\r\na=rtpmap:8 PCMA/8000\r\n";
+        server.send_request(engine_addr().await, &remote_reinvite(&server, "reinvite-test", 10, sdp)).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("CSeq: 10 INVITE"));
+        assert!(responses[0].contains("m=audio "));
+        assert!(responses[0].contains("RTP/AVP 8 101"));
+
+        assert_eq!(rtp_session.payload_type().await, 8);
+        let engine = SIP_ENGINE.lock().await;
+        let dialog = engine.active_dialog.as_ref().unwrap();
+        assert_eq!(dialog.state, CallState::Confirmed);
+        assert_eq!(dialog.remote_target.as_deref(), Some("sip:bob@127.0.0.1:5070"));
+    }
+
+    #[test]
+    fn test_parse_replaces() {
+        assert_eq!(
+            parse_replaces("425928@bobster.example.org;to-tag=7743;from-tag=6472"),
+            Some(Replaces {
+                call_id: "425928@bobster.example.org".to_string(),
+                to_tag: "7743".to_string(),
+                from_tag: "6472".to_string(),
+                early_only: false,
+            })
+        );
+        assert!(parse_replaces("abc;from-tag=1;to-tag=2;early-only").unwrap().early_only);
+
+        // Both tags are mandatory
+        assert_eq!(parse_replaces("abc;to-tag=1"), None);
+        assert_eq!(parse_replaces(";to-tag=1;from-tag=2"), None);
+    }
+
+    #[test]
+    fn test_name_addr_uri_and_tag() {
+        let from = "\"Bob\" <sip:bob@example.com;transport=udp>;tag=a1b2";
+        assert_eq!(name_addr_uri(from).as_deref(), Some("sip:bob@example.com;transport=udp"));
+        assert_eq!(header_tag(from).as_deref(), Some("a1b2"));
+
+        assert_eq!(name_addr_uri("sip:carol@example.com;tag=99").as_deref(), Some("sip:carol@example.com"));
+        assert_eq!(header_tag("sip:carol@example.com;tag=99").as_deref(), Some("99"));
+        assert_eq!(header_tag("<sip:carol@example.com>"), None);
+    }
+
+    #[test]
+    fn test_response_content_length_counts_bytes() {
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       Via: SIP/2.0/UDP 10.0.0.9:5060;branch=z9hG4bKx\r\n\
+                       From: <sip:bob@example.com>;tag=b1\r\n\
+                       To: <sip:alice@example.com>\r\n\
+                       Call-ID: bytes\r\n\
+                       CSeq: 1 INVITE\r\n\
+                       \r\n";
+        let sdp = "v=0\r\ns=Téléphone ☎\r\n";
+        let response = build_response(request, "200 OK", Some("a1"), "", sdp);
+        assert_eq!(header_value(&response, "Content-Length").as_deref(), Some("24"));
+        assert_eq!(message_body(&response).len(), 24);
+        assert_eq!(message_body(&response), sdp);
+    }
+
+    #[test]
+    fn test_caller_identity() {
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       From: \"Front Desk\" <sip:100@pbx.example.com>;tag=1\r\n\
+                       Remote-Party-ID: <sip:+15550001111@carrier.example.com>;party=called\r\n\
+                       Remote-Party-ID: \"Bob\" <sip:+15552223333@carrier.example.com>;party=calling;screen=yes\r\n\
+                       \r\n";
+        let identity = caller_identity(request);
+        assert_eq!(identity.uri, "sip:+15552223333@carrier.example.com");
+        assert_eq!(identity.display_name.as_deref(), Some("Bob"));
+        assert_eq!(identity.source, "Remote-Party-ID");
+
+        // P-Asserted-Identity wins, first of the sip/tel pair
+        let request = format!(
+            "{}\r\nP-Asserted-Identity: \"Carol\" <sip:+15554445555@carrier.example.com>, <tel:+15554445555>\r\n\r\n",
+            request.trim_end()
+        );
+        let identity = caller_identity(&request);
+        assert_eq!(identity.uri, "sip:+15554445555@carrier.example.com");
+        assert_eq!(identity.display_name.as_deref(), Some("Carol"));
+        assert_eq!(identity.source, "P-Asserted-Identity");
+
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       From: \"Front Desk\" <sip:100@pbx.example.com>;tag=1\r\n\
+                       \r\n";
+        let identity = caller_identity(request);
+        assert_eq!(identity.uri, "sip:100@pbx.example.com");
+        assert_eq!(identity.display_name.as_deref(), Some("Front Desk"));
+        assert_eq!(identity.source, "From");
+    }
+
+    #[test]
+    fn test_diversion_chain() {
+        // 100 forwarded to 200 unconditionally, 200 didn't answer so it went
+        // to us; the most recent diversion is on top
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       Diversion: <sip:200@pbx.example.com>;reason=no-answer;counter=1;privacy=off\r\n\
+                       Diversion: \"Sales\" <sip:100@pbx.example.com>;reason=unconditional\r\n\
+                       \r\n";
+        let chain = diversion_chain(request);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].uri, "sip:100@pbx.example.com");
+        assert_eq!(chain[0].reason.as_deref(), Some("unconditional"));
+        assert_eq!(chain[0].counter, None);
+        assert_eq!(chain[1].uri, "sip:200@pbx.example.com");
+        assert_eq!(chain[1].reason.as_deref(), Some("no-answer"));
+        assert_eq!(chain[1].counter, Some(1));
+
+        // Both hops in one comma-separated header
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       Diversion: <sip:200@pbx.example.com>;reason=user-busy, <sip:100@pbx.example.com>;reason=unconditional\r\n\
+                       \r\n";
+        let uris: Vec<String> = diversion_chain(request).into_iter().map(|hop| hop.uri).collect();
+        assert_eq!(uris, ["sip:100@pbx.example.com", "sip:200@pbx.example.com"]);
+
+        // History-Info when there is no Diversion
+        let request = "INVITE sip:alice@example.com SIP/2.0\r\n\
+                       History-Info: <sip:100@pbx.example.com>;index=1\r\n\
+                       History-Info: <sip:200@pbx.example.com;cause=302>;index=1.1,\
+                       <sip:alice@example.com?Reason=SIP%3Bcause%3D486>;index=1.1.1\r\n\
+                       \r\n";
+        let chain = diversion_chain(request);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].uri, "sip:100@pbx.example.com");
+        assert_eq!(chain[0].reason.as_deref(), Some("unconditional"));
+        assert_eq!(chain[1].uri, "sip:200@pbx.example.com");
+        assert_eq!(chain[1].reason.as_deref(), Some("user-busy"));
+
+        assert!(diversion_chain("INVITE sip:alice@example.com SIP/2.0\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn test_order_addresses() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:5060", "192.0.2.1:5060", "[2001:db8::2]:5060", "192.0.2.2:5060", "[2001:db8::3]:5060"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = |preference| -> Vec<String> {
+            order_addresses(addrs.clone(), preference).iter().map(|addr| addr.to_string()).collect()
+        };
+
+        assert_eq!(ordered(IpVersionPreference::Ipv4), ["192.0.2.1:5060", "192.0.2.2:5060"]);
+        assert_eq!(ordered(IpVersionPreference::Ipv6), ["[2001:db8::1]:5060", "[2001:db8::2]:5060", "[2001:db8::3]:5060"]);
+        assert_eq!(
+            ordered(IpVersionPreference::Auto),
+            ["192.0.2.1:5060", "[2001:db8::1]:5060", "192.0.2.2:5060", "[2001:db8::2]:5060", "[2001:db8::3]:5060"]
+        );
+        assert!(order_addresses(Vec::new(), IpVersionPreference::Auto).is_empty());
+
+        assert_eq!(local_host("[2001:db8::1]:5060"), "2001:db8::1");
+        assert_eq!(local_host("192.0.2.1:5060"), "192.0.2.1");
+        assert_eq!(sdp_address_type("2001:db8::1"), "IP6");
+    }
+
+    #[test]
+    fn test_bandwidth_line() {
+        assert_eq!(bandwidth_line(Some(128)), "b=AS:128\r\n");
+        assert_eq!(bandwidth_line(None), "");
+        let sdp = format!("v=0\r\nm=audio 4000 RTP/AVP 0\r\n{}", bandwidth_line(Some(96)));
+        assert_eq!(parse_bandwidth(&sdp), Some(96));
+    }
+
+    #[test]
+    fn test_answer_payload_type() {
+        let _guard = ENGINE_TEST_LOCK.blocking_lock();
+        let offer = |formats: &str| format!("v=0\r\nc=IN IP4 127.0.0.1\r\nm=audio 4000 RTP/AVP {}\r\n", formats);
+        // Requested and offered wins over preference order
+        assert_eq!(answer_payload_type(&offer("0 8 101"), Some("pcma")), Some(8));
+        // Not offered, or unknown: fall back to the best offered codec
+        assert_eq!(answer_payload_type(&offer("8 101"), Some("PCMU")), Some(8));
+        assert_eq!(answer_payload_type(&offer("8"), Some("opus")), Some(8));
+        assert_eq!(answer_payload_type(&offer("9"), Some("PCMU")), None);
+    }
+
+    #[test]
+    fn test_sdp_formats_telephone_event() {
+        let (formats, rtpmaps) = sdp_formats(&sdp_codecs(&[0, 8], true));
+        assert_eq!(formats, "0 8 101");
+        assert!(rtpmaps.contains("a=rtpmap:101 telephone-event/8000\r\n"));
+
+        // Off: no 101 in the m-line and no rtpmap for it either
+        let (formats, rtpmaps) = sdp_formats(&sdp_codecs(&[0, 8, 101], false));
+        assert_eq!(formats, "0 8");
+        assert!(!rtpmaps.contains("telephone-event"));
+        assert_eq!(rtpmaps, "a=rtpmap:0 PCMU/8000\r\na=rtpmap:8 PCMA/8000\r\n");
+
+        // Every listed payload type has its rtpmap, never a dangling 101
+        for telephone_event in [true, false] {
+            let (formats, rtpmaps) = sdp_formats(&sdp_codecs(&[9, 0, 101], telephone_event));
+            for pt in formats.split(' ') {
+                assert!(rtpmaps.contains(&format!("a=rtpmap:{} ", pt)));
+            }
+            assert_eq!(formats.split(' ').count(), rtpmaps.lines().count());
+        }
+    }
+
+    #[test]
+    fn test_parse_folded_auth_challenges() {
+        let response = "SIP/2.0 401 Unauthorized\r\n\
+                        Call-ID: abc\r\n\
+                        WWW-Authenticate: Digest realm=\"example.com\",\r\n \
+                        nonce=\"n1\", qop=\"auth,auth-int\",\r\n\
+                        \talgorithm=MD5\r\n\
+                        WWW-Authenticate: Digest realm=\"example.com\", nonce=\"n2\",\r\n    algorithm=SHA-256\r\n\
+                        Content-Length: 0\r\n\r\n";
+
+        let challenges = parse_auth_header(response).unwrap();
+        assert_eq!(challenges.len(), 2);
+        assert_eq!(challenges[0]["realm"], "example.com");
+        assert_eq!(challenges[0]["nonce"], "n1");
+        assert_eq!(challenges[0]["qop"], "auth,auth-int");
+        assert_eq!(challenges[0]["algorithm"], "MD5");
+        assert_eq!(challenges[1]["nonce"], "n2");
+
+        // SHA-256 wins over MD5 whatever the order
+        assert_eq!(strongest_challenge(&challenges).unwrap()["nonce"], "n2");
+        let reversed: Vec<_> = challenges.iter().rev().cloned().collect();
+        assert_eq!(strongest_challenge(&reversed).unwrap()["nonce"], "n2");
+
+        let digest = calculate_digest_response("alice", "secret", "REGISTER", "sip:example.com", &challenges[1]).unwrap();
+        assert!(digest.contains("algorithm=SHA-256"));
+        let md5_digest = calculate_digest_response("alice", "secret", "REGISTER", "sip:example.com", &challenges[0]).unwrap();
+        assert!(md5_digest.contains("qop=auth,"));
+
+        assert_eq!(
+            digest_hex("SHA-256", "abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let unsupported = "SIP/2.0 401 Unauthorized\r\n\
+                           WWW-Authenticate: Digest realm=\"x\", nonce=\"n\", algorithm=SHA-512-256\r\n\r\n";
+        assert!(strongest_challenge(&parse_auth_header(unsupported).unwrap()).is_err());
+        assert!(parse_auth_header("SIP/2.0 401 Unauthorized\r\n\r\n").is_err());
+
+        let stale = "SIP/2.0 401 Unauthorized\r\n\
+                     WWW-Authenticate: Digest realm=\"x\", nonce=\"n3\", stale=TRUE, algorithm=MD5\r\n\r\n";
+        assert!(challenge_is_stale(&parse_auth_header(stale).unwrap()[0]));
+        assert!(!challenge_is_stale(&challenges[0]));
+    }
+
+    #[test]
+    fn test_preferred_identity_header() {
+        assert_eq!(preferred_identity_header(""), "");
+        assert_eq!(
+            preferred_identity_header("sip:+15551234567@trunk.example.com"),
+            "P-Preferred-Identity: <sip:+15551234567@trunk.example.com>\r\n"
+        );
+        assert_eq!(
+            preferred_identity_header("\"Sales\" <tel:+15551234567>"),
+            "P-Preferred-Identity: \"Sales\" <tel:+15551234567>\r\n"
+        );
+    }
+
+    fn incoming_invite(server: &MockSipServer, call_id: &str) -> String {
+        let sdp = "v=0\r\no=- 4 4 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+                   m=audio 6000 RTP/AVP 9 8 101\r\na=rtpmap:9 G722/8000\r\na=rtpmap:8 PCMA/8000\r\n";
+        format!(
+            "INVITE sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKincoming\r\n\
+             From: \"Bob\" <sip:bob@127.0.0.1>;tag=caller\r\n\
+             To: <sip:alice@127.0.0.1>\r\n\
+             Call-ID: {}\r\n\
+             CSeq: 1 INVITE\r\n\
+             Contact: <sip:bob@127.0.0.1:5090>\r\n\
+             P-Asserted-Identity: <sip:+15551234567@127.0.0.1>\r\n\
+             Content-Type: application/sdp\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            server.addr(),
+            call_id,
+            sdp.len(),
+            sdp
+        )
+    }
+
+    #[tokio::test]
+    async fn test_dnd_rejects_incoming_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_dnd(true).await;
+
+        server.send_request(engine_addr().await, &incoming_invite(&server, "dnd-call")).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert!(responses[0].starts_with("SIP/2.0 486 Busy Here"));
+        assert!(!server.received().iter().any(|message| message.starts_with("SIP/2.0 180")));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+
+        // During a call the caller doesn't wait behind it, and is logged as
+        // do not disturb rather than busy
+        let emitted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = emitted.clone();
+        crate::events::set_sink(move |payload| {
+            if payload["type"] == "missed_call" {
+                sink.lock().unwrap().push(payload["reason"].to_string());
+            }
+        });
+        set_confirmed_call(&server, "dnd-active").await;
+        server.send_request(engine_addr().await, &incoming_invite(&server, "dnd-second")).await;
+
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert!(responses[1].starts_with("SIP/2.0 486 Busy Here"));
+        assert!(SIP_ENGINE.lock().await.waiting_dialog.is_none());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(*emitted.lock().unwrap(), vec!["\"dnd\"".to_string()]);
+        crate::events::set_sink(|_| {});
+    }
+
+    #[tokio::test]
+    async fn test_auto_answer_matching_caller() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        // A caller that doesn't match rings normally
+        set_auto_answer(AutoAnswer { patterns: vec!["door@example.com".to_string()], delay_secs: 0 }).await;
+        server.send_request(engine_addr().await, &incoming_invite(&server, "plain-call")).await;
+        assert_eq!(server.wait_for("SIP/2.0", 1).await.len(), 1);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(SIP_ENGINE.lock().await.auto_answer_task.is_none());
+        assert_eq!(server.received().iter().filter(|message| message.starts_with("SIP/2.0")).count(), 1);
+        hangup_call().await.unwrap();
+
+        // Override: cancelling the countdown leaves the call ringing
+        set_auto_answer(AutoAnswer { patterns: vec!["*@127.0.0.1".to_string()], delay_secs: 1 }).await;
+        server.send_request(engine_addr().await, &incoming_invite(&server, "override-call")).await;
+        assert_eq!(server.wait_for("SIP/2.0", 3).await.len(), 3);
+        assert!(cancel_auto_answer().await);
+        tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().state, CallState::Ringing);
+        hangup_call().await.unwrap();
+
+        // A matching caller is answered without the user
+        set_auto_answer(AutoAnswer { patterns: vec!["*@127.0.0.1".to_string()], delay_secs: 0 }).await;
+        server.send_request(engine_addr().await, &incoming_invite(&server, "door-call")).await;
+        let door_responses = || -> Vec<String> {
+            server.received_method("SIP/2.0").into_iter().filter(|message| message.contains("Call-ID: door-call")).collect()
+        };
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(2);
+        while door_responses().len() < 2 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let responses = door_responses();
+        assert!(responses[0].starts_with("SIP/2.0 180 Ringing"));
+        assert!(responses[1].starts_with("SIP/2.0 200 OK"));
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().state, CallState::Confirmed);
+        assert!(!cancel_auto_answer().await);
+
+        hangup_call().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_call_rings_and_answers() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        server.send_request(engine_addr().await, &incoming_invite(&server, "incoming-call")).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 180 Ringing"));
+        assert!(responses[0].contains("To: <sip:alice@127.0.0.1>;tag="));
+        {
+            let engine = SIP_ENGINE.lock().await;
+            let dialog = engine.active_dialog.as_ref().unwrap();
+            assert_eq!(dialog.state, CallState::Ringing);
+            assert_eq!(dialog.to_tag.as_deref(), Some("caller"));
+        }
+        assert!(wait_for_ringing().await);
+
+        answer_call(None).await.unwrap();
+        assert!(SIP_ENGINE.lock().await.ringing.is_none());
+
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert_eq!(responses.len(), 2);
+        assert!(responses[1].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[1].contains("Call-ID: incoming-call"));
+        assert!(responses[1].contains("RTP/AVP 8 101"));
+
+        let rtp_session = {
+            let engine = SIP_ENGINE.lock().await;
+            let dialog = engine.active_dialog.as_ref().unwrap();
+            assert_eq!(dialog.state, CallState::Confirmed);
+            assert!(dialog.incoming_invite.is_none());
+            dialog.rtp_session.clone().unwrap()
+        };
+        assert_eq!(rtp_session.payload_type().await, 8);
+
+        // A second call while this one is up waits behind it, a third is busy
+        server.send_request(engine_addr().await, &incoming_invite(&server, "second-call")).await;
+        server.send_request(engine_addr().await, &incoming_invite(&server, "third-call")).await;
+        let responses = server.wait_for("SIP/2.0", 4).await;
+        assert_eq!(responses.len(), 4);
+        assert!(responses[2].starts_with("SIP/2.0 180 Ringing"));
+        assert!(responses[2].contains("Call-ID: second-call"));
+        assert!(responses[3].starts_with("SIP/2.0 486 Busy Here"));
+        assert!(responses[3].contains("Call-ID: third-call"));
+        {
+            let engine = SIP_ENGINE.lock().await;
+            assert_eq!(engine.active_dialog.as_ref().unwrap().call_id, "incoming-call");
+            assert_eq!(engine.waiting_dialog.as_ref().unwrap().state, CallState::Ringing);
+        }
+
+        // The waiting caller gives up; our call is untouched
+        let cancel = incoming_invite(&server, "second-call")
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .replace("INVITE sip:", "CANCEL sip:")
+            .replace("CSeq: 1 INVITE", "CSeq: 1 CANCEL");
+        server.send_request(engine_addr().await, &format!("{}\r\n\r\n", cancel)).await;
+        let responses = server.wait_for("SIP/2.0", 6).await;
+        assert!(responses[5].starts_with("SIP/2.0 487 Request Terminated"));
+        assert!(SIP_ENGINE.lock().await.waiting_dialog.is_none());
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().state, CallState::Confirmed);
+
+        hangup_call().await.unwrap();
+        assert_eq!(server.wait_for("BYE", 1).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_call_waiting_swaps_calls() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        assert_eq!(swap_calls().await, Err(SipError::NoActiveCall));
+        make_call("carol", MediaDirection::SendRecv).await.unwrap();
+        server.send_request(engine_addr().await, &incoming_invite(&server, "waiting-call")).await;
+        assert!(server.wait_for("SIP/2.0", 1).await[0].starts_with("SIP/2.0 180 Ringing"));
+
+        // Carol goes on hold and the waiting caller is answered
+        swap_calls().await.unwrap();
+        let invites = server.wait_for("INVITE", 2).await;
+        assert!(invites[1].contains("a=sendonly"));
+        assert!(invites[1].contains("sip:carol@"));
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert!(responses[1].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[1].contains("Call-ID: waiting-call"));
+        {
+            let engine = SIP_ENGINE.lock().await;
+            let active = engine.active_dialog.as_ref().unwrap();
+            assert_eq!((active.call_id.as_str(), &active.state), ("waiting-call", &CallState::Confirmed));
+            let held = engine.waiting_dialog.as_ref().unwrap();
+            assert!(held.remote_uri.starts_with("sip:carol@"));
+        }
+
+        // And back: the caller is held, carol resumed
+        swap_calls().await.unwrap();
+        let invites = server.wait_for("INVITE", 4).await;
+        assert!(invites[2].contains("Call-ID: waiting-call"));
+        assert!(invites[2].contains("a=sendonly"));
+        assert!(invites[3].contains("sip:carol@"));
+        assert!(invites[3].contains("a=sendrecv"));
+        let rtp_session = SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().rtp_session.clone().unwrap();
+        assert_eq!(rtp_session.direction().await, MediaDirection::SendRecv);
+
+        // Hanging up carol leaves the held caller, who comes back on a swap
+        hangup_call().await.unwrap();
+        swap_calls().await.unwrap();
+        let invites = server.wait_for("INVITE", 5).await;
+        assert!(invites[4].contains("Call-ID: waiting-call"));
+        assert!(invites[4].contains("a=sendrecv"));
+        assert!(SIP_ENGINE.lock().await.waiting_dialog.is_none());
+        hangup_call().await.unwrap();
+    }
+
+    // Audio hardware that is never there
+    struct FailingBackend;
 
-    // Check if first response needs further handling
-    if first_response.contains("SIP/2.0 200") {
-        // Call answered immediately
-        println!("[SIP] 200 OK - call answered!");
-        
-        let to_tag = extract_to_tag(&first_response);
-        println!("[SIP] To tag: {:?}", to_tag);
-        
-        let mut engine = SIP_ENGINE.lock().await;
-        if let Some(ref mut dialog) = engine.active_dialog {
-            dialog.to_tag = to_tag.clone();
-            dialog.state = CallState::Confirmed;
-            dialog.cseq = 2; // Auth used CSeq 2
+    impl AudioBackend for FailingBackend {
+        fn name(&self) -> &'static str {
+            "failing"
         }
-        drop(engine);
-        
-        send_ack(&socket, &dest_uri, &call_id, &from_tag, to_tag.as_deref(), &from_uri, &local_addr, server_addr).await?;
-        
-        println!("[SIP] ✓✓✓ Call established! ✓✓✓");
-        
-        // Start RTP media session
-        match start_rtp_media(&first_response, rtp_port).await {
-            Ok((rtp_session, tx_task, rx_task)) => {
-                // Store RTP components in dialog
-                let mut engine = SIP_ENGINE.lock().await;
-                if let Some(ref mut dialog) = engine.active_dialog {
-                    dialog.rtp_session = Some(rtp_session);
-                    dialog.audio_tx_task = Some(Arc::new(tx_task));
-                    dialog.audio_rx_task = Some(Arc::new(rx_task));
-                }
-                println!("[SIP] ✓ RTP media active - call has audio!");
-            }
-            Err(e) => {
-                tracing::error!("[RTP] Failed to start media: {}", e);
-                eprintln!("[RTP] Failed to start media: {}", e);
-                println!("[SIP] Call established but no audio (RTP failed)");
-            }
+
+        fn start(&self, _: Option<String>, _: Option<String>) -> Result<(CallAudio, crate::audio::AudioChannels), SipError> {
+            Err(SipError::Audio("No audio device".to_string()))
         }
-        
-        return Ok(());
-    } else if first_response.contains("SIP/2.0 180") || first_response.contains("SIP/2.0 183") {
-        println!("[SIP] 180/183 Ringing - waiting for answer...");
-        let mut engine = SIP_ENGINE.lock().await;
-        if let Some(ref mut dialog) = engine.active_dialog {
-            dialog.state = CallState::Ringing;
-            dialog.cseq = 2; // Auth used CSeq 2
+
+        fn ring(&self, _: &crate::settings::Ringer) -> Result<Ringing, SipError> {
+            Ok(Ringing::silent())
         }
-        drop(engine);
     }
 
-    // Continue listening for more responses
-    let mut buf = vec![0u8; 4096];
-    loop {
-        let response_result = tokio::time::timeout(
-            std::time::Duration::from_secs(30),
-            socket.recv_from(&mut buf)
-        ).await;
+    #[tokio::test]
+    async fn test_answer_without_media_hangs_up() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        crate::settings::save_require_media(true).unwrap();
+        set_audio_backend(Arc::new(FailingBackend)).await;
 
-        match response_result {
-            Ok(Ok((size, from_addr))) => {
-                buf.truncate(size);
-                let response_str = String::from_utf8_lossy(&buf);
-                println!("[SIP] Received response from {} ({} bytes):", from_addr, size);
-                println!("{}", response_str);
+        server.send_request(engine_addr().await, &incoming_invite(&server, "no-media-call")).await;
+        assert_eq!(server.wait_for("SIP/2.0", 1).await.len(), 1);
 
-                if response_str.contains("SIP/2.0 100") {
-                    println!("[SIP] 100 Trying - call is being processed");
-                    buf = vec![0u8; 4096]; // Reset buffer
-                    continue;
-                } else if response_str.contains("SIP/2.0 180") || response_str.contains("SIP/2.0 183") {
-                    println!("[SIP] 180/183 Ringing - remote party is being alerted");
-                    let mut engine = SIP_ENGINE.lock().await;
-                    if let Some(ref mut dialog) = engine.active_dialog {
-                        dialog.state = CallState::Ringing;
-                    }
-                    drop(engine);
-                    buf = vec![0u8; 4096]; // Reset buffer
-                    continue;
-                } else if response_str.contains("SIP/2.0 200") {
-                    println!("[SIP] 200 OK - call answered!");
-                    
-                    // Extract To tag from response
-                    let to_tag = extract_to_tag(&response_str);
-                    println!("[SIP] To tag: {:?}", to_tag);
-                    
-                    // Update dialog
-                    let mut engine = SIP_ENGINE.lock().await;
-                    if let Some(ref mut dialog) = engine.active_dialog {
-                        dialog.to_tag = to_tag.clone();
-                        dialog.state = CallState::Confirmed;
-                    }
-                    drop(engine);
-                    
-                    // Send ACK
-                    send_ack(&socket, &dest_uri, &call_id, &from_tag, to_tag.as_deref(), &from_uri, &local_addr, server_addr).await?;
-                    
-                    println!("[SIP] ✓✓��� Call established! ✓✓✓");
-                    // Start RTP media session
-                    match start_rtp_media(&response_str, rtp_port).await {
-                        Ok((rtp_session, tx_task, rx_task)) => {
-                            // Store RTP components in dialog
-                            let mut engine = SIP_ENGINE.lock().await;
-                            if let Some(ref mut dialog) = engine.active_dialog {
-                                dialog.rtp_session = Some(rtp_session);
-                                dialog.audio_tx_task = Some(Arc::new(tx_task));
-                                dialog.audio_rx_task = Some(Arc::new(rx_task));
-                            }
-                            println!("[SIP] ✓ RTP media active - call has audio!");
-                        }
-                        Err(e) => {
-                            tracing::error!("[RTP] Failed to start media: {}", e);
-                            eprintln!("[RTP] Failed to start media: {}", e);
-                            println!("[SIP] Call established but no audio (RTP failed)");
-                        }
-                    }
-                    
-                    return Ok(());
-                } else if response_str.contains("SIP/2.0 4") || response_str.contains("SIP/2.0 5") || response_str.contains("SIP/2.0 6") {
-                    let status_line = response_str.lines().next().unwrap_or("Unknown error");
-                    println!("[SIP] Call failed: {}", status_line);
-                    
-                    // Clean up dialog
-                    let mut engine = SIP_ENGINE.lock().await;
-                    engine.active_dialog = None;
-                    
-                    return Err(format!("Call failed: {}", status_line));
-                }
-            }
-            Ok(Err(e)) => {
-                println!("[SIP] Socket error: {}", e);
-                return Err(format!("Socket error: {}", e));
-            }
-            Err(_) => {
-                println!("[SIP] Timeout waiting for response");
-                return Err("Timeout waiting for call response".to_string());
-            }
+        // Answered, then hung up because the call would have no audio
+        let result = answer_call(None).await;
+        assert!(matches!(result, Err(SipError::Audio(_))));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert_eq!(server.wait_for("BYE", 1).await.len(), 1);
+        assert!(server.received_method("SIP/2.0").iter().any(|message| message.starts_with("SIP/2.0 200 OK")));
+    }
+
+    #[tokio::test]
+    async fn test_answer_waits_for_matching_ack() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.set_auto_ack(false);
+        reset_engine(&server).await;
+        set_registered().await;
+
+        server.send_request(engine_addr().await, &incoming_invite(&server, "ack-call")).await;
+        assert_eq!(server.wait_for("SIP/2.0", 1).await.len(), 1);
+        let answer = tokio::spawn(answer_call(None));
+
+        let ok = server.wait_for("SIP/2.0", 2).await.pop().unwrap();
+        assert!(ok.starts_with("SIP/2.0 200 OK"));
+        let ack = crate::mock_sip::build_ack(&ok).unwrap();
+
+        // The caller resending its INVITE gets the 200 OK again at once,
+        // well before the first timer retransmission (T1)
+        let resent_at = tokio::time::Instant::now();
+        server.send_request(engine_addr().await, &incoming_invite(&server, "ack-call")).await;
+        let oks = server.wait_for("SIP/2.0", 3).await;
+        assert_eq!(oks[2], ok);
+        assert!(resent_at.elapsed() < SIP_T1);
+
+        // Wrong CSeq or to-tag: not ours, so the 200 OK keeps coming
+        server.send_request(engine_addr().await, &ack.replace("CSeq: 1 ACK", "CSeq: 2 ACK")).await;
+        let our_tag = header_value(&ok, "To").as_deref().and_then(header_tag).unwrap();
+        server.send_request(engine_addr().await, &ack.replace(&our_tag, "someone-else")).await;
+        let oks = server.wait_for("SIP/2.0", 4).await;
+        assert!(oks[3].starts_with("SIP/2.0 200 OK"));
+        assert!(!answer.is_finished());
+        assert!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().awaiting_ack.is_some());
+
+        // The matching ACK completes the answer
+        server.send_request(engine_addr().await, &ack).await;
+        answer.await.unwrap().unwrap();
+        {
+            let engine = SIP_ENGINE.lock().await;
+            let dialog = engine.active_dialog.as_ref().unwrap();
+            assert!(dialog.awaiting_ack.is_none());
+            assert!(dialog.rtp_session.is_some());
         }
+        hangup_call().await.unwrap();
     }
-}
 
-// Send ACK to confirm call establishment
-async fn send_ack(
-    socket: &UdpSocket,
-    dest_uri: &str,
-    call_id: &str,
-    from_tag: &str,
-    to_tag: Option<&str>,
-    from_uri: &str,
-    local_addr: &str,
-    server_addr: std::net::SocketAddr,
-) -> Result<(), String> {
-    let branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-    
-    let to_header = if let Some(tag) = to_tag {
-        format!("<{}>;tag={}", dest_uri, tag)
-    } else {
-        format!("<{}>", dest_uri)
-    };
-    
-    // ACK CSeq must match the INVITE CSeq (which is 2 after auth retry)
-    let ack_msg = format!(
-        "ACK {} SIP/2.0\r\n\
-         Via: SIP/2.0/UDP {};branch={}\r\n\
-         From: <{}>;tag={}\r\n\
-         To: {}\r\n\
-         Call-ID: {}\r\n\
-         CSeq: 2 ACK\r\n\
-         Max-Forwards: 70\r\n\
-         User-Agent: Platypus-Phone/0.1.0\r\n\
-         Content-Length: 0\r\n\
-         \r\n",
-        dest_uri,
-        local_addr,
-        branch,
-        from_uri,
-        from_tag,
-        to_header,
-        call_id
-    );
+    #[tokio::test]
+    async fn test_incoming_call_cancelled() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
 
-    println!("[SIP] Sending ACK...");
-    println!("[SIP] ACK message:\n{}", ack_msg);
-    
-    socket.send_to(ack_msg.as_bytes(), server_addr).await
-        .map_err(|e| format!("Failed to send ACK: {}", e))?;
+        let invite = incoming_invite(&server, "cancelled-call");
+        server.send_request(engine_addr().await, &invite).await;
+        assert_eq!(server.wait_for("SIP/2.0", 1).await.len(), 1);
+        assert!(wait_for_ringing().await);
 
-    println!("[SIP] ✓ ACK sent");
-    Ok(())
-}
+        let cancel = invite
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .replace("INVITE sip:", "CANCEL sip:")
+            .replace("CSeq: 1 INVITE", "CSeq: 1 CANCEL");
+        server.send_request(engine_addr().await, &format!("{}\r\n\r\n", cancel)).await;
 
-// Extract To tag from SIP response
-fn extract_to_tag(response: &str) -> Option<String> {
-    for line in response.lines() {
-        if line.starts_with("To:") || line.starts_with("t:") {
-            if let Some(tag_part) = line.split("tag=").nth(1) {
-                let tag = tag_part.split(';').next()
-                    .unwrap_or(tag_part)
-                    .trim()
-                    .to_string();
-                return Some(tag);
-            }
+        let responses = server.wait_for("SIP/2.0", 3).await;
+        assert_eq!(responses.len(), 3);
+        assert!(responses[1].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[1].contains("CSeq: 1 CANCEL"));
+        assert!(responses[2].starts_with("SIP/2.0 487 Request Terminated"));
+        assert!(responses[2].contains("CSeq: 1 INVITE"));
+
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert!(SIP_ENGINE.lock().await.ringing.is_none());
+        assert!(matches!(answer_call(None).await, Err(SipError::NoActiveCall)));
+    }
+
+    // Wait until the engine rings for the incoming call (it starts after the 180)
+    async fn wait_for_ringing() -> bool {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(2);
+        while SIP_ENGINE.lock().await.ringing.is_none() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
+        SIP_ENGINE.lock().await.ringing.is_some()
     }
-    None
-}
 
-pub async fn answer_call() -> Result<(), String> {
-    let engine = SIP_ENGINE.lock().await;
+    // Inbound dialog `call_id` (alice ← bob) in `state` with nothing set up
+    // yet; tests override the fields they care about
+    fn test_dialog(call_id: &str, state: CallState) -> Dialog {
+        Dialog {
+            call_id: call_id.to_string(),
+            from_tag: "local".to_string(),
+            to_tag: None,
+            cseq: 1,
+            remote_uri: "sip:bob@127.0.0.1".to_string(),
+            remote_target: None,
+            local_uri: "sip:alice@127.0.0.1".to_string(),
+            route_set: Vec::new(),
+            state,
+            srtp_keys: None,
+            server_addr: None,
+            connected_at: None,
+            rtp_session: None,
+            call_audio: None,
+            audio_tx_task: None,
+            audio_rx_task: None,
+            incoming_invite: None,
+            outgoing_invite: None,
+            sent_ack: None,
+            parked: None,
+            awaiting_ack: None,
+            outgoing: false,
+            direction: MediaDirection::SendRecv,
+            attempt: CancelToken::default(),
+        }
+    }
 
-    if !engine.registered {
-        return Err("Not registered".to_string());
+    // Confirmed outgoing call to the mock with a running RTP session
+    async fn set_confirmed_call(server: &MockSipServer, call_id: &str) -> Arc<RtpSession> {
+        let rtp_session = Arc::new(
+            RtpSession::new(LOCALHOST, 0, "127.0.0.1:4000".parse().unwrap(), 0, 8000).await.unwrap()
+        );
+        SIP_ENGINE.lock().await.active_dialog = Some(Dialog {
+            to_tag: Some("remote".to_string()),
+            server_addr: Some(server.addr()),
+            connected_at: Some(std::time::Instant::now()),
+            rtp_session: Some(rtp_session.clone()),
+            ..test_dialog(call_id, CallState::Confirmed)
+        });
+        rtp_session
     }
 
-    println!("[SIP] Answering incoming call");
-    println!("[SIP] Answer functionality not yet implemented");
-    println!("[SIP] In production, this would:");
-    println!("  - Send 200 OK response to INVITE");
-    println!("  - Include SDP in response");
-    println!("  - Establish RTP media stream");
+    // In-dialog request from bob for the call set up by set_confirmed_call
+    fn remote_request(server: &MockSipServer, method: &str, call_id: &str) -> String {
+        format!(
+            "{method} sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKremote{method}\r\n\
+             From: <sip:bob@127.0.0.1>;tag=remote\r\n\
+             To: <sip:alice@127.0.0.1>;tag=local\r\n\
+             Call-ID: {}\r\n\
+             CSeq: 2 {method}\r\n\
+             Content-Length: 0\r\n\r\n",
+            server.addr(),
+            call_id,
+        )
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_remote_hangup() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_confirmed_call(&server, "remote-bye").await;
 
-pub async fn hangup_call() -> Result<(), String> {
-    let engine = SIP_ENGINE.lock().await;
+        server.send_request(engine_addr().await, &remote_request(&server, "BYE", "remote-bye")).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("CSeq: 2 BYE"));
+        assert!(SIP_ENGINE.lock().await.active_dialog.is_none());
+        assert!(server.received_method("BYE").is_empty());
 
-    if !engine.registered {
-        return Err("Not registered".to_string());
+        // A retransmission finds no dialog any more
+        server.send_request(engine_addr().await, &remote_request(&server, "BYE", "remote-bye")).await;
+        let responses = server.wait_for("SIP/2.0", 2).await;
+        assert_eq!(responses.len(), 2);
+        assert!(responses[1].starts_with("SIP/2.0 481"));
     }
 
-    let socket = engine.socket.as_ref().ok_or("SIP not initialized")?.clone();
-    let server = engine.server.clone();
-    
-    let dialog = engine.active_dialog.as_ref()
-        .ok_or("No active call")?
-        .clone();
-    
-    if dialog.state == CallState::Terminated {
-        return Err("Call already terminated".to_string());
+    #[tokio::test]
+    async fn test_remote_hangup_of_waiting_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_confirmed_call(&server, "active-call").await;
+        SIP_ENGINE.lock().await.waiting_dialog = Some(test_dialog("held-call", CallState::Confirmed));
+
+        server.send_request(engine_addr().await, &remote_request(&server, "BYE", "held-call")).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+
+        let engine = SIP_ENGINE.lock().await;
+        assert!(engine.waiting_dialog.is_none());
+        assert_eq!(engine.active_dialog.as_ref().unwrap().call_id, "active-call");
     }
-    
-    drop(engine);
 
-    println!("[SIP] Hanging up call");
-    println!("[SIP] Call-ID: {}", dialog.call_id);
+    #[tokio::test]
+    async fn test_options_answered() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
 
-    // Abort audio tasks if they exist
-    if let Some(tx_task) = dialog.audio_tx_task {
-        tx_task.abort();
-        println!("[Audio] TX task aborted");
+        server.send_request(engine_addr().await, &remote_request(&server, "OPTIONS", "options-ping")).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("\r\nAllow: INVITE, ACK, BYE, CANCEL, OPTIONS, NOTIFY, MESSAGE\r\n"));
+        assert!(responses[0].contains("\r\nSupported: replaces\r\nAccept: application/sdp\r\n"));
     }
-    if let Some(rx_task) = dialog.audio_rx_task {
-        rx_task.abort();
-        println!("[Audio] RX task aborted");
+
+    #[tokio::test]
+    async fn test_unsupported_method_rejected() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+
+        server.send_request(engine_addr().await, &remote_request(&server, "SUBSCRIBE", "subscribe-call")).await;
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 405 Method Not Allowed"));
+        assert!(responses[0].contains(&format!("\r\nAllow: {}\r\n", message::ALLOW.join(", "))));
+    }
+
+    fn replaces_invite(server: &MockSipServer, replaces: &str) -> String {
+        let sdp = "v=0\r\no=- 3 3 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+                   m=audio 6000 RTP/AVP 8 101\r\na=rtpmap:8 PCMA/8000\r\n";
+        format!(
+            "INVITE sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKpickup\r\n\
+             From: <sip:carol@127.0.0.1>;tag=pickup\r\n\
+             To: <sip:alice@127.0.0.1>\r\n\
+             Call-ID: pickup-call\r\n\
+             CSeq: 1 INVITE\r\n\
+             Contact: <sip:carol@127.0.0.1:5090>\r\n\
+             Replaces: {}\r\n\
+             Content-Type: application/sdp\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            server.addr(),
+            replaces,
+            sdp.len(),
+            sdp
+        )
     }
-    // Streams will be dropped automatically when dialog is cleared
 
-    // Build BYE request
-    let branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-    let local_addr = {
+    #[tokio::test]
+    async fn test_replaces_takes_over_call() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        let rtp_session = set_confirmed_call(&server, "original-call").await;
+
+        let invite = replaces_invite(&server, "original-call;to-tag=local;from-tag=remote");
+        server.send_request(engine_addr().await, &invite).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("Call-ID: pickup-call"));
+        assert!(responses[0].contains("RTP/AVP 8 101"));
+
+        // The replaced call is hung up
+        let byes = server.wait_for("BYE", 1).await;
+        assert_eq!(byes.len(), 1);
+        assert!(byes[0].contains("Call-ID: original-call"));
+
+        // ...and its media now belongs to the new dialog
+        assert_eq!(rtp_session.payload_type().await, 8);
         let engine = SIP_ENGINE.lock().await;
-        engine.local_addr.clone()
-    };
-    
-    let to_header = if let Some(ref tag) = dialog.to_tag {
-        format!("<{}>;tag={}", dialog.remote_uri, tag)
-    } else {
-        format!("<{}>", dialog.remote_uri)
-    };
-    
-    let bye_msg = format!(
-        "BYE {} SIP/2.0\r\n\
-         Via: SIP/2.0/UDP {};branch={}\r\n\
-         From: <{}>;tag={}\r\n\
-         To: {}\r\n\
-         Call-ID: {}\r\n\
-         CSeq: {} BYE\r\n\
-         Max-Forwards: 70\r\n\
-         User-Agent: Platypus-Phone/0.1.0\r\n\
-         Content-Length: 0\r\n\
-         \r\n",
-        dialog.remote_uri,
-        local_addr,
-        branch,
-        dialog.local_uri,
-        dialog.from_tag,
-        to_header,
-        dialog.call_id,
-        dialog.cseq + 1
-    );
+        let dialog = engine.active_dialog.as_ref().unwrap();
+        assert_eq!(dialog.call_id, "pickup-call");
+        assert_eq!(dialog.to_tag.as_deref(), Some("pickup"));
+        assert_eq!(dialog.remote_uri, "sip:carol@127.0.0.1");
+        assert_eq!(dialog.remote_target.as_deref(), Some("sip:carol@127.0.0.1:5090"));
+        assert!(responses[0].contains(&format!("To: <sip:alice@127.0.0.1>;tag={}", dialog.from_tag)));
+        assert!(Arc::ptr_eq(dialog.rtp_session.as_ref().unwrap(), &rtp_session));
+    }
 
-    println!("[SIP] Sending BYE...");
-    println!("[SIP] Message:\n{}", bye_msg);
+    #[tokio::test]
+    async fn test_replaces_unknown_dialog_rejected() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
+        set_confirmed_call(&server, "original-call").await;
 
-    // Resolve server address
-    let server_addr: std::net::SocketAddr = if server.contains(':') {
-        match server.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                let parts: Vec<&str> = server.split(':').collect();
-                let host = parts[0];
-                let port: u16 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(5060);
-                let addrs = tokio::net::lookup_host(format!("{}:{}", host, port)).await
-                    .map_err(|e| format!("DNS lookup failed: {}", e))?;
-                addrs.into_iter().next()
-                    .ok_or_else(|| format!("No addresses found for {}", host))?
-            }
-        }
-    } else {
-        let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host(format!("{}:5060", server)).await
-            .map_err(|e| format!("DNS lookup failed: {}", e))?
-            .collect();
-        *addrs.first()
-            .ok_or_else(|| format!("No addresses found for {}", server))?
-    };
+        // Tags swapped: that's not our dialog
+        let invite = replaces_invite(&server, "original-call;to-tag=remote;from-tag=local");
+        server.send_request(engine_addr().await, &invite).await;
 
-    // Send BYE
-    socket.send_to(bye_msg.as_bytes(), server_addr).await
-        .map_err(|e| format!("Failed to send BYE: {}", e))?;
-
-    println!("[SIP] ✓ BYE sent ({} bytes to {})", bye_msg.len(), server_addr);
-    println!("[SIP] Waiting for 200 OK...");
-
-    // Wait for 200 OK response
-    let mut buf = vec![0u8; 4096];
-    match tokio::time::timeout(
-        std::time::Duration::from_secs(5),
-        socket.recv_from(&mut buf)
-    ).await {
-        Ok(Ok((size, _))) => {
-            buf.truncate(size);
-            let response_str = String::from_utf8_lossy(&buf);
-            println!("[SIP] Response: {}", response_str.lines().next().unwrap_or("Unknown"));
-            
-            if response_str.contains("SIP/2.0 200") {
-                println!("[SIP] ✓ Call terminated successfully");
-            }
-        }
-        _ => {
-            println!("[SIP] No response to BYE (call terminated anyway)");
-        }
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("SIP/2.0 481"));
+        assert!(server.received_method("BYE").is_empty());
+        assert_eq!(SIP_ENGINE.lock().await.active_dialog.as_ref().unwrap().call_id, "original-call");
     }
 
-    // Clean up dialog
-    let mut engine = SIP_ENGINE.lock().await;
-    engine.active_dialog = None;
+    #[test]
+    fn test_parse_message_summary() {
+        let body = "Messages-Waiting: yes\r\n\
+                    Message-Account: sip:alice@example.com\r\n\
+                    Voice-Message: 2/8 (0/2)\r\n";
+        assert_eq!(
+            parse_message_summary(body),
+            Some(MessageSummary { messages_waiting: true, new_messages: 2, old_messages: 8 })
+        );
 
-    println!("[SIP] ✓ Call ended");
-    Ok(())
-}
+        assert_eq!(
+            parse_message_summary("Messages-Waiting: no\r\n"),
+            Some(MessageSummary { messages_waiting: false, new_messages: 0, old_messages: 0 })
+        );
+        assert_eq!(parse_message_summary(""), None);
+    }
 
-// Unregister from SIP server (send REGISTER with Expires: 0)
-pub async fn unregister() -> Result<(), String> {
-    let engine = SIP_ENGINE.lock().await;
+    #[tokio::test]
+    async fn test_mwi_subscribe_notify_unsubscribe() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        set_registered().await;
 
-    let socket = match engine.socket.as_ref() {
-        Some(s) => s.clone(),
-        None => return Ok(()), // Not initialized, nothing to do
-    };
+        subscribe_mwi().await.unwrap();
 
-    if !engine.registered {
-        return Ok(()); // Not registered, nothing to do
+        let subscribes = server.wait_for("SUBSCRIBE", 1).await;
+        assert!(subscribes[0].contains("Event: message-summary"));
+        assert!(subscribes[0].contains("Expires: 3600"));
+
+        let subscription = SIP_ENGINE.lock().await.mwi_subscription.clone().unwrap();
+        assert_eq!(subscription.to_tag.as_deref(), Some("mock1"));
+
+        let body = "Messages-Waiting: yes\r\nVoice-Message: 1/0\r\n";
+        let notify = format!(
+            "NOTIFY sip:alice@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKnotify\r\n\
+             From: <sip:alice@127.0.0.1>;tag=mock1\r\n\
+             To: <sip:alice@127.0.0.1>;tag={}\r\n\
+             Call-ID: {}\r\n\
+             CSeq: 1 NOTIFY\r\n\
+             Event: message-summary\r\n\
+             Subscription-State: active;expires=3600\r\n\
+             Content-Type: application/simple-message-summary\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            server.addr(),
+            subscription.from_tag,
+            subscription.call_id,
+            body.len(),
+            body
+        );
+        server.send_request(engine_addr().await, &notify).await;
+
+        let responses = server.wait_for("SIP/2.0", 1).await;
+        assert!(responses[0].starts_with("SIP/2.0 200 OK"));
+        assert!(responses[0].contains("CSeq: 1 NOTIFY"));
+
+        unsubscribe_mwi().await;
+        let subscribes = server.wait_for("SUBSCRIBE", 2).await;
+        assert_eq!(subscribes.len(), 2);
+        assert!(subscribes[1].contains("Expires: 0"));
+        assert!(subscribes[1].contains(&format!("Call-ID: {}", subscription.call_id)));
+        assert!(subscribes[1].contains("CSeq: 2 SUBSCRIBE"));
+        assert!(SIP_ENGINE.lock().await.mwi_subscription.is_none());
     }
 
-    let server = engine.server.clone();
-    let user = engine.user.clone();
-    let password = engine.password.clone();
-    let local_addr = engine.local_addr.clone();
-    
-    drop(engine); // Release lock
+    #[tokio::test]
+    async fn test_register_message_is_reproducible() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+        let _ids = ids::set_generator(ids::SequentialIds::default());
 
-    println!("[SIP] Unregistering from {}", server);
+        server.respond("REGISTER", &[200]);
+        register_account(&server.addr().to_string(), "alice", "secret").await.unwrap();
 
-    // Build REGISTER with Expires: 0 to unregister
-    let from_uri = format!("sip:{}@{}", user, server);
-    let to_uri = from_uri.clone();
-    let contact_uri = format!("sip:{}@{}", user, local_addr);
-    let call_id = uuid::Uuid::new_v4().to_string();
-    let branch = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-    let tag = uuid::Uuid::new_v4().simple().to_string();
-
-    let unregister_msg = format!(
-        "REGISTER sip:{} SIP/2.0\r\n\
-         Via: SIP/2.0/UDP {};branch={}\r\n\
-         From: <{}>;tag={}\r\n\
-         To: <{}>\r\n\
-         Call-ID: {}\r\n\
-         CSeq: 1 REGISTER\r\n\
-         Contact: <{}>\r\n\
-         Max-Forwards: 70\r\n\
-         Expires: 0\r\n\
-         User-Agent: Platypus-Phone/0.1.0\r\n\
-         Content-Length: 0\r\n\
-         \r\n",
-        server,
-        local_addr,
-        branch,
-        from_uri,
-        tag,
-        to_uri,
-        call_id,
-        contact_uri
-    );
+        let local_addr = SIP_ENGINE.lock().await.local_addr.clone();
+        let expected = format!(
+            "REGISTER sip:{server} SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {local};branch=z9hG4bKbranch-3\r\n\
+             From: <sip:alice@{server}>;tag=tag-2\r\n\
+             To: <sip:alice@{server}>\r\n\
+             Call-ID: call-1\r\n\
+             CSeq: 1 REGISTER\r\n\
+             Contact: <sip:alice@{local}>\r\n\
+             Max-Forwards: 70\r\n\
+             Expires: 3600\r\n\
+             Allow: INVITE, ACK, BYE, CANCEL, OPTIONS, NOTIFY, MESSAGE\r\n\
+             Supported: replaces\r\n\
+             User-Agent: Platypus-Phone/0.1.0\r\n\
+             Content-Length: 0\r\n\
+             \r\n",
+            server = server.addr(),
+            local = local_addr,
+        );
+        assert_eq!(server.received_method("REGISTER")[0], expected);
+    }
 
-    // Resolve server address
-    let server_addr: std::net::SocketAddr = if server.contains(':') {
-        match server.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                let parts: Vec<&str> = server.split(':').collect();
-                let host = parts[0];
-                let port: u16 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(5060);
-                
-                let addrs = tokio::net::lookup_host(format!("{}:{}", host, port)).await
-                    .map_err(|e| format!("DNS lookup failed: {}", e))?;
-                
-                addrs.into_iter().next()
-                    .ok_or_else(|| format!("No addresses found for {}", host))?
-            }
-        }
-    } else {
-        let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host(format!("{}:5060", server)).await
-            .map_err(|e| format!("DNS lookup failed: {}", e))?
-            .collect();
-        
-        *addrs.first()
-            .ok_or_else(|| format!("No addresses found for {}", server))?
-    };
+    #[tokio::test]
+    async fn test_crlf_keepalive() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
 
-    // Send initial unregister request
-    socket.send_to(unregister_msg.as_bytes(), server_addr).await
-        .map_err(|e| format!("Failed to send unregister: {}", e))?;
-
-    println!("[SIP] ✓ Unregister sent (Expires: 0)");
-
-    // Wait for response
-    let mut buf = vec![0u8; 4096];
-    match tokio::time::timeout(
-        std::time::Duration::from_secs(3),
-        socket.recv_from(&mut buf)
-    ).await {
-        Ok(Ok((size, _))) => {
-            buf.truncate(size);
-            let response_str = String::from_utf8_lossy(&buf);
-            
-            if response_str.contains("SIP/2.0 200") {
-                println!("[SIP] ✓ Unregistered successfully");
-            } else if response_str.contains("SIP/2.0 401") || response_str.contains("SIP/2.0 407") {
-                println!("[SIP] Authentication required for unregister, sending with auth...");
-                
-                // Parse authentication parameters
-                let auth_params = parse_auth_header(&response_str)?;
-                
-                // Calculate digest response
-                let auth_header = calculate_digest_response(
-                    &user,
-                    &password,
-                    "REGISTER",
-                    &format!("sip:{}", server),
-                    &auth_params,
-                )?;
-                
-                // Build authenticated unregister with same Call-ID and tag
-                let branch2 = format!("z9hG4bK{}", uuid::Uuid::new_v4().simple());
-                let auth_unregister_msg = format!(
-                    "REGISTER sip:{} SIP/2.0\r\n\
-                     Via: SIP/2.0/UDP {};branch={}\r\n\
-                     From: <{}>;tag={}\r\n\
-                     To: <{}>\r\n\
-                     Call-ID: {}\r\n\
-                     CSeq: 2 REGISTER\r\n\
-                     Contact: <{}>\r\n\
-                     Max-Forwards: 70\r\n\
-                     Expires: 0\r\n\
-                     Authorization: {}\r\n\
-                     User-Agent: Platypus-Phone/0.1.0\r\n\
-                     Content-Length: 0\r\n\
-                     \r\n",
-                    server,
-                    local_addr,
-                    branch2,
-                    from_uri,
-                    tag,
-                    to_uri,
-                    call_id,
-                    contact_uri,
-                    auth_header
-                );
-                
-                // Send authenticated unregister
-                socket.send_to(auth_unregister_msg.as_bytes(), server_addr).await
-                    .map_err(|e| format!("Failed to send authenticated unregister: {}", e))?;
-                
-                println!("[SIP] ✓ Authenticated unregister sent");
-                
-                // Wait for final response
-                let mut final_buf = vec![0u8; 4096];
-                match tokio::time::timeout(
-                    std::time::Duration::from_secs(3),
-                    socket.recv_from(&mut final_buf)
-                ).await {
-                    Ok(Ok((final_size, _))) => {
-                        final_buf.truncate(final_size);
-                        let final_str = String::from_utf8_lossy(&final_buf);
-                        if final_str.contains("SIP/2.0 200") {
-                            println!("[SIP] ✓ Unregistered successfully");
-                        } else {
-                            println!("[SIP] Unregister response: {}", final_str.lines().next().unwrap_or("Unknown"));
-                        }
-                    }
-                    _ => {
-                        println!("[SIP] No response to authenticated unregister (continuing anyway)");
-                    }
-                }
-            } else {
-                println!("[SIP] Unregister response: {}", response_str.lines().next().unwrap_or("Unknown"));
-            }
-        }
-        _ => {
-            println!("[SIP] No response to unregister (continuing anyway)");
-        }
+        let task = spawn_keepalive(std::time::Duration::from_millis(20));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        task.abort();
+        let pings = server.received().iter().filter(|message| *message == "\r\n\r\n").count();
+        assert!(pings >= 2, "{} pings", pings);
+
+        // A ping from the server is answered with a pong, a pong is not answered
+        let before = server.received().len();
+        server.send_request(engine_addr().await, "\r\n\r\n").await;
+        server.send_request(engine_addr().await, "\r\n").await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(server.received()[before..], ["\r\n".to_string()]);
     }
 
-    // Update state
-    let mut engine = SIP_ENGINE.lock().await;
-    engine.registered = false;
+    #[test]
+    fn test_outbound_proxy_host() {
+        assert_eq!(outbound_proxy_host("proxy.example.com").as_deref(), Some("proxy.example.com"));
+        assert_eq!(outbound_proxy_host(" proxy.example.com:5080 ").as_deref(), Some("proxy.example.com:5080"));
+        assert_eq!(outbound_proxy_host("sip:10.0.0.1:5060;transport=udp").as_deref(), Some("10.0.0.1:5060"));
+        assert_eq!(outbound_proxy_host("<sip:proxy.example.com;lr>").as_deref(), Some("proxy.example.com"));
+        assert_eq!(outbound_proxy_host(""), None);
+        assert_eq!(outbound_proxy_host("not a host"), None);
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_lookup_matches_socket_family_and_fails_over() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        server.respond("REGISTER", &[200]);
+        reset_engine(&server).await;
 
-pub async fn shutdown() {
-    let mut engine = SIP_ENGINE.lock().await;
+        // The engine's socket is IPv4, so ::1 is never a candidate
+        let candidates = lookup_server("localhost:5070").await.unwrap();
+        assert!(!candidates.is_empty());
+        assert!(candidates.iter().all(|addr| addr.is_ipv4()));
 
-    if engine.socket.is_some() {
-        println!("[SIP] Shutting down SIP stack");
-        engine.socket = None;
-        engine.registered = false;
+        // An address that never answers is skipped for the next one
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let candidates = [silent.local_addr().unwrap(), server.addr()];
+        let socket = SIP_ENGINE.lock().await.socket.clone().unwrap();
+        let local_addr = engine_addr().await;
+        let build = |branch: &str| {
+            format!(
+                "REGISTER sip:127.0.0.1 SIP/2.0\r\n\
+                 Via: SIP/2.0/UDP {};branch={}\r\n\
+                 From: <sip:alice@127.0.0.1>;tag=failover\r\n\
+                 To: <sip:alice@127.0.0.1>\r\n\
+                 Call-ID: failover\r\n\
+                 CSeq: 1 REGISTER\r\n\
+                 Content-Length: 0\r\n\r\n",
+                local_addr, branch
+            )
+        };
+        let _route = ResponseRoute::open("failover");
+        let (response, addr) = send_with_failover(&socket, &candidates, 1, ("alice", "secret"), build)
+            .await
+            .unwrap();
+        assert_eq!(response_status(&response), 200);
+        assert_eq!(addr, server.addr());
+    }
+
+    #[tokio::test]
+    async fn test_server_address_is_cached() {
+        let _guard = ENGINE_TEST_LOCK.lock().await;
+        let server = MockSipServer::start().await;
+        reset_engine(&server).await;
+
+        let addr = resolve_server("localhost:5070").await.unwrap();
+        assert_eq!(addr.port(), 5070);
+        assert_eq!(
+            SIP_ENGINE.lock().await.resolved_server,
+            Some(("localhost:5070".to_string(), addr))
+        );
+
+        // A cached entry is returned without another lookup
+        let cached: SocketAddr = "192.0.2.10:5070".parse().unwrap();
+        SIP_ENGINE.lock().await.resolved_server = Some(("localhost:5070".to_string(), cached));
+        assert_eq!(resolve_server("localhost:5070").await.unwrap(), cached);
+
+        // Registration starts from a fresh lookup
+        server.respond("REGISTER", &[200]);
+        register_account(&server.addr().to_string(), "alice", "secret").await.unwrap();
+        assert_eq!(
+            SIP_ENGINE.lock().await.resolved_server,
+            Some((server.addr().to_string(), server.addr()))
+        );
     }
 }
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use crate::error::SipError;
+
+/// The only crypto suite we offer and accept (RFC 4568)
+pub const CRYPTO_SUITE: &str = "AES_CM_128_HMAC_SHA1_80";
+
+const MASTER_KEY_LEN: usize = 16;
+const MASTER_SALT_LEN: usize = 14;
+const AUTH_KEY_LEN: usize = 20;
+const AUTH_TAG_LEN: usize = 10;
+// Packets this far behind the highest index are rejected as replays (RFC 3711 3.3.2)
+const REPLAY_WINDOW: u64 = 64;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+/// SRTP master key and salt exchanged via SDES (`a=crypto` in the SDP)
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrtpKeys {
+    master_key: [u8; MASTER_KEY_LEN],
+    master_salt: [u8; MASTER_SALT_LEN],
+}
+
+impl SrtpKeys {
+    /// Generate a fresh random master key and salt
+    pub fn generate() -> Result<Self, SipError> {
+        let mut material = [0u8; MASTER_KEY_LEN + MASTER_SALT_LEN];
+        getrandom::getrandom(&mut material)
+            .map_err(|e| SipError::Internal(format!("Failed to generate SRTP key: {}", e)))?;
+        Self::from_material(&material)
+    }
+
+    fn from_material(material: &[u8]) -> Result<Self, SipError> {
+        if material.len() != MASTER_KEY_LEN + MASTER_SALT_LEN {
+            return Err(SipError::Protocol(format!(
+                "SRTP key material must be {} bytes, got {}",
+                MASTER_KEY_LEN + MASTER_SALT_LEN,
+                material.len()
+            )));
+        }
+
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        let mut master_salt = [0u8; MASTER_SALT_LEN];
+        master_key.copy_from_slice(&material[..MASTER_KEY_LEN]);
+        master_salt.copy_from_slice(&material[MASTER_KEY_LEN..]);
+
+        Ok(Self { master_key, master_salt })
+    }
+
+    /// SDP attribute advertising these keys, e.g. `a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:...`
+    pub fn crypto_attribute(&self, tag: u32) -> String {
+        let mut material = Vec::with_capacity(MASTER_KEY_LEN + MASTER_SALT_LEN);
+        material.extend_from_slice(&self.master_key);
+        material.extend_from_slice(&self.master_salt);
+
+        format!("a=crypto:{} {} inline:{}", tag, CRYPTO_SUITE, BASE64.encode(material))
+    }
+}
+
+/// Find the peer's keys for our crypto suite in an SDP body
+pub fn parse_crypto_attribute(sdp: &str) -> Option<SrtpKeys> {
+    sdp.lines()
+        .map(|line| line.trim())
+        .filter_map(|line| line.strip_prefix("a=crypto:"))
+        .find_map(|value| {
+            // <tag> <suite> inline:<key||salt>[|lifetime][|MKI:len]
+            let mut parts = value.split_whitespace();
+            let _tag = parts.next()?;
+            if parts.next()? != CRYPTO_SUITE {
+                return None;
+            }
+            let inline = parts.next()?.strip_prefix("inline:")?;
+            let encoded = inline.split('|').next()?;
+            let material = BASE64.decode(encoded).ok()?;
+            SrtpKeys::from_material(&material).ok()
+        })
+}
+
+/// Whether an SDP body uses the secure RTP profile on its audio line
+pub fn is_secure_profile(sdp: &str) -> bool {
+    sdp.lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("m=audio"))
+        .and_then(|line| line.split_whitespace().nth(2))
+        .map(|profile| profile == "RTP/SAVP")
+        .unwrap_or(false)
+}
+
+/// Crypto state for one direction of an SRTP stream (RFC 3711)
+///
+/// Outgoing packets are protected with our keys; incoming packets are
+/// unprotected with the keys the peer sent in its SDP.
+#[derive(Debug)]
+pub struct SrtpContext {
+    keys: SrtpKeys,
+    session_key: [u8; MASTER_KEY_LEN],
+    session_salt: [u8; MASTER_SALT_LEN],
+    auth_key: [u8; AUTH_KEY_LEN],
+    // Index state per SSRC (RFC 3711 3.2.3): the peer may switch sources
+    // under the same keys, and each counts its own sequence numbers
+    streams: HashMap<u32, StreamIndex>,
+}
+
+// Where one SSRC's packets are up to
+#[derive(Clone, Copy, Debug, Default)]
+struct StreamIndex {
+    // Rollover counter and highest sequence number seen (for the packet index)
+    roc: u32,
+    highest_seq: Option<u16>,
+    // Bit n set: the packet n behind the highest index has been received
+    replay_window: u64,
+}
+
+impl SrtpContext {
+    /// Derive the session keys from the master key and salt (key derivation rate 0)
+    pub fn new(keys: &SrtpKeys) -> Self {
+        let mut session_key = [0u8; MASTER_KEY_LEN];
+        let mut session_salt = [0u8; MASTER_SALT_LEN];
+        let mut auth_key = [0u8; AUTH_KEY_LEN];
+
+        derive_key(keys, 0x00, &mut session_key);
+        derive_key(keys, 0x01, &mut auth_key);
+        derive_key(keys, 0x02, &mut session_salt);
+
+        Self {
+            keys: keys.clone(),
+            session_key,
+            session_salt,
+            auth_key,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Master keys this context was derived from
+    pub fn keys(&self) -> &SrtpKeys {
+        &self.keys
+    }
+
+    /// Encrypt and authenticate an outgoing RTP packet
+    pub fn protect(&mut self, packet: &[u8]) -> Result<Vec<u8>, SipError> {
+        let header_len = rtp_header_len(packet)?;
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let stream = self.streams.entry(ssrc).or_default();
+
+        // The sender bumps the rollover counter when the sequence number wraps
+        if let Some(highest) = stream.highest_seq {
+            if seq < highest && highest - seq > 0x8000 {
+                stream.roc = stream.roc.wrapping_add(1);
+            }
+        }
+        stream.highest_seq = Some(seq);
+        let roc = stream.roc;
+
+        let index = ((roc as u64) << 16) | seq as u64;
+
+        let mut output = packet.to_vec();
+        self.apply_keystream(ssrc, index, &mut output[header_len..]);
+
+        let tag = self.auth_tag(&output, roc);
+        output.extend_from_slice(&tag);
+        Ok(output)
+    }
+
+    /// Verify and decrypt an incoming SRTP packet
+    pub fn unprotect(&mut self, packet: &[u8]) -> Result<Vec<u8>, SipError> {
+        if packet.len() < AUTH_TAG_LEN {
+            return Err(SipError::Protocol("SRTP packet too short".to_string()));
+        }
+
+        let (authenticated, tag) = packet.split_at(packet.len() - AUTH_TAG_LEN);
+        let header_len = rtp_header_len(authenticated)?;
+        let seq = u16::from_be_bytes([authenticated[2], authenticated[3]]);
+        let ssrc = u32::from_be_bytes([authenticated[8], authenticated[9], authenticated[10], authenticated[11]]);
+
+        // A new SSRC starts from scratch, but only gets an entry once one of
+        // its packets is authentic
+        let mut stream = self.streams.get(&ssrc).copied().unwrap_or_default();
+        let roc = stream.estimate_roc(seq);
+        let index = ((roc as u64) << 16) | seq as u64;
+
+        // Reject replays before spending time on the MAC
+        let highest_index = stream.highest_seq.map(|highest| ((stream.roc as u64) << 16) | highest as u64);
+        if let Some(highest_index) = highest_index {
+            if index <= highest_index {
+                let behind = highest_index - index;
+                if behind >= REPLAY_WINDOW {
+                    return Err(SipError::Protocol("SRTP packet too old".to_string()));
+                }
+                if stream.replay_window & (1 << behind) != 0 {
+                    return Err(SipError::Protocol("SRTP packet replayed".to_string()));
+                }
+            }
+        }
+
+        let expected = self.auth_tag(authenticated, roc);
+        if !constant_time_eq(&expected, tag) {
+            return Err(SipError::Protocol("SRTP authentication failed".to_string()));
+        }
+
+        // Only move the replay window forward once the packet is authentic
+        match highest_index {
+            Some(highest_index) if index <= highest_index => {
+                stream.replay_window |= 1 << (highest_index - index);
+            }
+            _ => {
+                let ahead = highest_index.map_or(REPLAY_WINDOW, |highest_index| index - highest_index);
+                stream.replay_window = if ahead >= REPLAY_WINDOW { 0 } else { stream.replay_window << ahead };
+                stream.replay_window |= 1;
+                stream.roc = roc;
+                stream.highest_seq = Some(seq);
+            }
+        }
+        self.streams.insert(ssrc, stream);
+
+        let mut output = authenticated.to_vec();
+        self.apply_keystream(ssrc, index, &mut output[header_len..]);
+        Ok(output)
+    }
+
+    // AES-CM: IV = (salt * 2^16) XOR (SSRC * 2^64) XOR (index * 2^16)
+    fn apply_keystream(&self, ssrc: u32, index: u64, payload: &mut [u8]) {
+        let mut iv = [0u8; 16];
+        iv[..MASTER_SALT_LEN].copy_from_slice(&self.session_salt);
+        for (i, byte) in ssrc.to_be_bytes().iter().enumerate() {
+            iv[4 + i] ^= byte;
+        }
+        for (i, byte) in index.to_be_bytes()[2..].iter().enumerate() {
+            iv[8 + i] ^= byte;
+        }
+
+        let mut cipher = Aes128Ctr::new(&self.session_key.into(), &iv.into());
+        cipher.apply_keystream(payload);
+    }
+
+    // HMAC-SHA1 over the packet and the rollover counter, truncated to 80 bits
+    fn auth_tag(&self, packet: &[u8], roc: u32) -> [u8; AUTH_TAG_LEN] {
+        let mut mac = HmacSha1::new_from_slice(&self.auth_key).expect("HMAC accepts any key length");
+        mac.update(packet);
+        mac.update(&roc.to_be_bytes());
+
+        let mut tag = [0u8; AUTH_TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..AUTH_TAG_LEN]);
+        tag
+    }
+}
+
+impl StreamIndex {
+    // Guess the sender's rollover counter for this sequence number (RFC 3711 3.3.1)
+    fn estimate_roc(&self, seq: u16) -> u32 {
+        let highest = match self.highest_seq {
+            Some(highest) => highest,
+            None => return self.roc,
+        };
+
+        if highest < 0x8000 {
+            if seq > highest && seq - highest > 0x8000 {
+                self.roc.saturating_sub(1)
+            } else {
+                self.roc
+            }
+        } else if highest - 0x8000 > seq {
+            self.roc.wrapping_add(1)
+        } else {
+            self.roc
+        }
+    }
+}
+
+// Session key derivation with the AES-CM PRF (RFC 3711 4.3)
+fn derive_key(keys: &SrtpKeys, label: u8, output: &mut [u8]) {
+    let mut iv = [0u8; 16];
+    iv[..MASTER_SALT_LEN].copy_from_slice(&keys.master_salt);
+    iv[7] ^= label;
+
+    output.fill(0);
+    let mut cipher = Aes128Ctr::new(&keys.master_key.into(), &iv.into());
+    cipher.apply_keystream(output);
+}
+
+// Length of the RTP header including CSRCs and extension
+fn rtp_header_len(packet: &[u8]) -> Result<usize, SipError> {
+    if packet.len() < 12 {
+        return Err(SipError::Protocol("RTP packet too short".to_string()));
+    }
+
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let mut len = 12 + csrc_count * 4;
+
+    if packet[0] & 0x10 != 0 {
+        if packet.len() < len + 4 {
+            return Err(SipError::Protocol("RTP extension header truncated".to_string()));
+        }
+        let words = u16::from_be_bytes([packet[len + 2], packet[len + 3]]) as usize;
+        len += 4 + words * 4;
+    }
+
+    if packet.len() < len {
+        return Err(SipError::Protocol("RTP header truncated".to_string()));
+    }
+
+    Ok(len)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_key_derivation_rfc3711_vectors() {
+        // RFC 3711 Appendix B.3
+        let material = [
+            hex("E1F97A0D3E018BE0D64FA32C06DE4139"),
+            hex("0EC675AD498AFEEBB6960B3AABE6"),
+        ]
+        .concat();
+        let keys = SrtpKeys::from_material(&material).unwrap();
+        let context = SrtpContext::new(&keys);
+
+        assert_eq!(context.session_key.to_vec(), hex("C61E7A93744F39EE10734AFE3FF7A087"));
+        assert_eq!(context.session_salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        assert_eq!(context.auth_key.to_vec(), hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4"));
+    }
+
+    #[test]
+    fn test_protect_unprotect_round_trip() {
+        let keys = SrtpKeys::generate().unwrap();
+        let mut sender = SrtpContext::new(&keys);
+        let mut receiver = SrtpContext::new(&keys);
+
+        let packet = crate::rtp::RtpPacket::new(0, 65535, 160, 0x1234, vec![0xAB; 160]).to_bytes();
+        let protected = sender.protect(&packet).unwrap();
+        assert_eq!(protected.len(), packet.len() + AUTH_TAG_LEN);
+        assert_ne!(&protected[12..packet.len()], &packet[12..]);
+        assert_eq!(receiver.unprotect(&protected).unwrap(), packet);
+
+        // Sequence number wraps: the rollover counter must follow on both sides
+        let packet = crate::rtp::RtpPacket::new(0, 0, 320, 0x1234, vec![0xCD; 160]).to_bytes();
+        let protected = sender.protect(&packet).unwrap();
+        assert_eq!(receiver.unprotect(&protected).unwrap(), packet);
+        assert_eq!(receiver.streams[&0x1234].roc, 1);
+
+        // Tampering is detected
+        let mut tampered = protected.clone();
+        tampered[20] ^= 0x01;
+        assert!(receiver.unprotect(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_replayed_packets_are_rejected() {
+        let keys = SrtpKeys::generate().unwrap();
+        let mut sender = SrtpContext::new(&keys);
+        let mut receiver = SrtpContext::new(&keys);
+
+        let protected: Vec<Vec<u8>> = (0..100u16)
+            .map(|seq| {
+                let packet = crate::rtp::RtpPacket::new(0, seq, seq as u32 * 160, 0x1234, vec![0xAB; 160]);
+                sender.protect(&packet.to_bytes()).unwrap()
+            })
+            .collect();
+
+        // Out of order within the window is fine, but only once
+        assert!(receiver.unprotect(&protected[10]).is_ok());
+        assert!(receiver.unprotect(&protected[8]).is_ok());
+        assert!(receiver.unprotect(&protected[10]).is_err());
+        assert!(receiver.unprotect(&protected[8]).is_err());
+        assert!(receiver.unprotect(&protected[9]).is_ok());
+
+        // Jumping ahead keeps the packets still inside the window
+        assert!(receiver.unprotect(&protected[70]).is_ok());
+        assert!(receiver.unprotect(&protected[10]).is_err());
+        assert!(receiver.unprotect(&protected[6]).is_err()); // Fell out of the window
+        assert!(receiver.unprotect(&protected[7]).is_ok());
+        assert!(receiver.unprotect(&protected[99]).is_ok());
+        assert!(receiver.unprotect(&protected[70]).is_err());
+        assert!(receiver.unprotect(&protected[36]).is_ok());
+        assert!(receiver.unprotect(&protected[35]).is_err());
+    }
+
+    #[test]
+    fn test_index_state_is_per_ssrc() {
+        let keys = SrtpKeys::generate().unwrap();
+        let mut first = SrtpContext::new(&keys);
+        let mut second = SrtpContext::new(&keys);
+        let mut receiver = SrtpContext::new(&keys);
+
+        let packet = crate::rtp::RtpPacket::new(0, 60000, 160, 0xAAAA, vec![0xAB; 160]).to_bytes();
+        assert_eq!(receiver.unprotect(&first.protect(&packet).unwrap()).unwrap(), packet);
+
+        // A second source starting lower is neither a rollover nor a replay
+        let packet = crate::rtp::RtpPacket::new(0, 100, 160, 0xBBBB, vec![0xCD; 160]).to_bytes();
+        assert_eq!(receiver.unprotect(&second.protect(&packet).unwrap()).unwrap(), packet);
+        assert_eq!(receiver.streams[&0xBBBB].roc, 0);
+
+        // and the first source carries on where it was
+        let packet = crate::rtp::RtpPacket::new(0, 60001, 320, 0xAAAA, vec![0xAB; 160]).to_bytes();
+        assert_eq!(receiver.unprotect(&first.protect(&packet).unwrap()).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_crypto_attribute_round_trip() {
+        let keys = SrtpKeys::generate().unwrap();
+        let sdp = format!(
+            "v=0\r\nm=audio 4000 RTP/SAVP 0\r\na=crypto:2 AES_CM_128_HMAC_SHA1_32 inline:AAAA\r\n{}|2^31\r\n",
+            keys.crypto_attribute(1)
+        );
+
+        assert_eq!(parse_crypto_attribute(&sdp), Some(keys));
+        assert!(is_secure_profile(&sdp));
+        assert!(!is_secure_profile("m=audio 4000 RTP/AVP 0\r\n"));
+        assert_eq!(parse_crypto_attribute("m=audio 4000 RTP/AVP 0\r\n"), None);
+    }
+}
@@ -0,0 +1,656 @@
+//! STUN (RFC 5389) and TURN (RFC 5766) client for call media
+//!
+//! STUN tells us the public address our RTP socket is seen from. TURN
+//! allocates a relay address on a server and forwards media through it,
+//! for NATs that won't let the peer reach the mapped address directly.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use crate::error::SipError;
+
+/// Default STUN/TURN server port
+pub const DEFAULT_PORT: u16 = 3478;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+
+// Message types (method plus class bits)
+const BINDING_REQUEST: u16 = 0x0001;
+const ALLOCATE_REQUEST: u16 = 0x0003;
+const REFRESH_REQUEST: u16 = 0x0004;
+const CHANNEL_BIND_REQUEST: u16 = 0x0009;
+const DATA_INDICATION: u16 = 0x0017;
+const CLASS_MASK: u16 = 0x0110;
+const SUCCESS_CLASS: u16 = 0x0100;
+const ERROR_CLASS: u16 = 0x0110;
+
+// Attribute types
+const MAPPED_ADDRESS: u16 = 0x0001;
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const ERROR_CODE: u16 = 0x0009;
+const CHANNEL_NUMBER: u16 = 0x000C;
+const LIFETIME: u16 = 0x000D;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA: u16 = 0x0013;
+const REALM: u16 = 0x0014;
+const NONCE: u16 = 0x0015;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// IANA protocol number for UDP, as REQUESTED-TRANSPORT wants it
+const TRANSPORT_UDP: u8 = 17;
+
+// Channel numbers 0x4000-0x7FFF; a channel can't be moved to another peer,
+// so each new peer address gets the next one
+const FIRST_CHANNEL: u16 = 0x4000;
+const LAST_CHANNEL: u16 = 0x7FFF;
+
+/// Allocation lifetime we ask for (the server may grant less)
+pub const ALLOCATION_LIFETIME_SECS: u32 = 600;
+
+// Permissions last 5 minutes and channels 10; refreshing both well inside
+// the shorter one keeps the relay open for the whole call
+const REFRESH_INTERVAL: Duration = Duration::from_secs(240);
+
+// Requests are retried a few times; a call is waiting on the answer
+const REQUEST_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A STUN message: header fields plus attributes in wire order
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunMessage {
+    pub msg_type: u16,
+    pub transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl StunMessage {
+    /// New message with a random transaction ID
+    pub fn new(msg_type: u16) -> Result<Self, SipError> {
+        let mut transaction_id = [0u8; 12];
+        getrandom::getrandom(&mut transaction_id)
+            .map_err(|e| SipError::Internal(format!("Failed to generate STUN transaction ID: {}", e)))?;
+        Ok(Self { msg_type, transaction_id, attributes: Vec::new() })
+    }
+
+    pub fn add(&mut self, attribute: u16, value: Vec<u8>) {
+        self.attributes.push((attribute, value));
+    }
+
+    /// Add an XOR-obfuscated address attribute (XOR-PEER-ADDRESS etc.)
+    pub fn add_xor_address(&mut self, attribute: u16, addr: SocketAddr) {
+        let value = xor_address(addr, &self.transaction_id);
+        self.add(attribute, value);
+    }
+
+    /// Serialize without MESSAGE-INTEGRITY
+    pub fn encode(&self) -> Vec<u8> {
+        let attributes = self.encode_attributes();
+        let mut message = self.encode_header(attributes.len());
+        message.extend_from_slice(&attributes);
+        message
+    }
+
+    /// Serialize with MESSAGE-INTEGRITY keyed by `key`. The HMAC covers the
+    /// message up to the attribute, with the length already counting it.
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let attributes = self.encode_attributes();
+        let mut message = self.encode_header(attributes.len() + 24);
+        message.extend_from_slice(&attributes);
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(&message);
+        let integrity = mac.finalize().into_bytes();
+
+        message.extend_from_slice(&MESSAGE_INTEGRITY.to_be_bytes());
+        message.extend_from_slice(&20u16.to_be_bytes());
+        message.extend_from_slice(&integrity);
+        message
+    }
+
+    fn encode_header(&self, length: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN + length);
+        header.extend_from_slice(&self.msg_type.to_be_bytes());
+        header.extend_from_slice(&(length as u16).to_be_bytes());
+        header.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        header.extend_from_slice(&self.transaction_id);
+        header
+    }
+
+    fn encode_attributes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (attribute, value) in &self.attributes {
+            out.extend_from_slice(&attribute.to_be_bytes());
+            out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            out.extend_from_slice(value);
+            // Attribute values are padded to a multiple of 4 bytes
+            out.resize(out.len().next_multiple_of(4), 0);
+        }
+        out
+    }
+
+    /// Parse a datagram; None if it isn't a well-formed STUN message
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if !is_stun(data) {
+            return None;
+        }
+        let msg_type = u16::from_be_bytes([data[0], data[1]]);
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let body = data.get(HEADER_LEN..HEADER_LEN + length)?;
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&data[8..HEADER_LEN]);
+
+        let mut attributes = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= body.len() {
+            let attribute = u16::from_be_bytes([body[pos], body[pos + 1]]);
+            let len = u16::from_be_bytes([body[pos + 2], body[pos + 3]]) as usize;
+            let value = body.get(pos + 4..pos + 4 + len)?;
+            attributes.push((attribute, value.to_vec()));
+            pos += (4 + len).next_multiple_of(4);
+        }
+
+        Some(Self { msg_type, transaction_id, attributes })
+    }
+
+    pub fn attribute(&self, attribute: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(found, _)| *found == attribute)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn text_attribute(&self, attribute: u16) -> Option<String> {
+        self.attribute(attribute).map(|value| String::from_utf8_lossy(value).to_string())
+    }
+
+    /// Value of an XOR-obfuscated address attribute
+    pub fn xor_address(&self, attribute: u16) -> Option<SocketAddr> {
+        let value = self.attribute(attribute)?;
+        let plain = xor_address_bytes(value, &self.transaction_id)?;
+        plain_address(&plain)
+    }
+
+    /// Our address as the server saw it (XOR-MAPPED-ADDRESS, or the
+    /// MAPPED-ADDRESS of older RFC 3489 servers)
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.xor_address(XOR_MAPPED_ADDRESS)
+            .or_else(|| plain_address(self.attribute(MAPPED_ADDRESS)?))
+    }
+
+    /// ERROR-CODE as (code, reason phrase)
+    pub fn error_code(&self) -> Option<(u16, String)> {
+        let value = self.attribute(ERROR_CODE)?;
+        if value.len() < 4 {
+            return None;
+        }
+        let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+        Some((code, String::from_utf8_lossy(&value[4..]).to_string()))
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.msg_type & CLASS_MASK == SUCCESS_CLASS
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.msg_type & CLASS_MASK == ERROR_CLASS
+    }
+}
+
+/// Whether a datagram is a STUN message (RFC 5389 6: top bits zero, magic cookie)
+pub fn is_stun(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN
+        && data[0] & 0xC0 == 0
+        && u32::from_be_bytes([data[4], data[5], data[6], data[7]]) == MAGIC_COOKIE
+}
+
+// Address attribute value: reserved, family, port, address
+fn plain_address(value: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let ip = match value.get(1)? {
+        0x01 => {
+            let octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// XOR-ed address value for `addr`
+fn xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut value = vec![0, 0];
+    value.extend_from_slice(&addr.port().to_be_bytes());
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value[1] = 0x01;
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value[1] = 0x02;
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    xor_address_bytes(&value, transaction_id).unwrap_or(value)
+}
+
+// XOR is its own inverse: the port with the top of the cookie, the address
+// with the cookie (and the transaction ID for IPv6)
+fn xor_address_bytes(value: &[u8], transaction_id: &[u8; 12]) -> Option<Vec<u8>> {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(transaction_id);
+
+    let mut out = value.to_vec();
+    if out.len() < 8 {
+        return None;
+    }
+    out[2] ^= mask[0];
+    out[3] ^= mask[1];
+    for (byte, mask) in out[4..].iter_mut().zip(mask.iter()) {
+        *byte ^= mask;
+    }
+    Some(out)
+}
+
+// Long-term credential key (RFC 5389 15.4): MD5(username:realm:password)
+fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    md5::compute(format!("{}:{}:{}", username, realm, password)).0.to_vec()
+}
+
+/// Resolve a `host[:port]` STUN/TURN server (port 3478 by default)
+pub async fn resolve(server: &str) -> Result<SocketAddr, SipError> {
+    let server = server.trim();
+    let target = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+        _ => format!("{}:{}", server, DEFAULT_PORT),
+    };
+    let mut addrs = tokio::net::lookup_host(&target)
+        .await
+        .map_err(|e| SipError::Network(format!("Failed to resolve {}: {}", server, e)))?;
+    addrs.next().ok_or_else(|| SipError::Network(format!("No address for {}", server)))
+}
+
+// Send a request and wait for the response with the same transaction ID,
+// retrying a few times. Anything else arriving meanwhile is dropped.
+async fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &StunMessage,
+    key: Option<&[u8]>,
+) -> Result<StunMessage, SipError> {
+    let bytes = match key {
+        Some(key) => request.encode_with_integrity(key),
+        None => request.encode(),
+    };
+    let mut buf = [0u8; 1500];
+
+    for _ in 0..REQUEST_ATTEMPTS {
+        socket.send_to(&bytes, server).await
+            .map_err(|e| SipError::Network(format!("Failed to send STUN request: {}", e)))?;
+
+        let deadline = tokio::time::Instant::now() + REQUEST_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (size, from) = received
+                .map_err(|e| SipError::Network(format!("Failed to receive STUN response: {}", e)))?;
+            match StunMessage::decode(&buf[..size]) {
+                Some(response) if from == server && response.transaction_id == request.transaction_id => {
+                    return Ok(response);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    Err(SipError::Timeout(format!("No STUN response from {}", server)))
+}
+
+/// Ask a STUN server which public address `socket` is seen from
+pub async fn binding(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, SipError> {
+    let response = transact(socket, server, &StunMessage::new(BINDING_REQUEST)?, None).await?;
+    if !response.is_success() {
+        return Err(SipError::Network(format!("STUN binding failed: {}", describe_error(&response))));
+    }
+    response
+        .mapped_address()
+        .ok_or_else(|| SipError::Protocol("STUN response without a mapped address".to_string()))
+}
+
+fn describe_error(response: &StunMessage) -> String {
+    match response.error_code() {
+        Some((code, reason)) => format!("{} {}", code, reason),
+        None => format!("unexpected message type {:#06x}", response.msg_type),
+    }
+}
+
+/// A TURN allocation: the relay address media is advertised on, and the
+/// channel that carries it to the peer
+#[derive(Debug)]
+pub struct TurnRelay {
+    server: SocketAddr,
+    username: String,
+    realm: String,
+    key: Vec<u8>,
+    relayed_addr: SocketAddr,
+    // The server may send a new nonce (438 Stale Nonce) at any time
+    nonce: Mutex<String>,
+    // Channel bound to the peer, if any
+    channel: Mutex<Option<(u16, SocketAddr)>>,
+    // When the refreshes last went out (None: send them right away)
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl TurnRelay {
+    /// Allocate a UDP relay on `server` for `socket`, answering the
+    /// server's long-term credential challenge
+    pub async fn allocate(socket: &UdpSocket, server: SocketAddr, username: &str, password: &str) -> Result<Self, SipError> {
+        let response = transact(socket, server, &allocate_request()?, None).await?;
+        if !response.is_error() || response.error_code().map(|(code, _)| code) != Some(401) {
+            return Err(SipError::Network(format!("TURN allocation failed: {}", describe_error(&response))));
+        }
+
+        let realm = response.text_attribute(REALM).unwrap_or_default();
+        let mut relay = Self {
+            server,
+            username: username.to_string(),
+            realm: realm.clone(),
+            key: long_term_key(username, &realm, password),
+            relayed_addr: server,
+            nonce: Mutex::new(response.text_attribute(NONCE).unwrap_or_default()),
+            channel: Mutex::new(None),
+            last_refresh: Mutex::new(Some(Instant::now())),
+        };
+
+        // One more try if the nonce went stale in between
+        for _ in 0..2 {
+            let mut request = allocate_request()?;
+            relay.add_credentials(&mut request);
+            let response = transact(socket, server, &request, Some(&relay.key)).await?;
+
+            if response.is_success() {
+                relay.relayed_addr = response
+                    .xor_address(XOR_RELAYED_ADDRESS)
+                    .ok_or_else(|| SipError::Protocol("TURN allocation without a relayed address".to_string()))?;
+                println!("[TURN] Allocated relay {} on {}", relay.relayed_addr, server);
+                return Ok(relay);
+            }
+            if !relay.update_nonce(&response) {
+                return Err(SipError::Network(format!("TURN allocation failed: {}", describe_error(&response))));
+            }
+        }
+
+        Err(SipError::Network("TURN allocation failed: nonce kept going stale".to_string()))
+    }
+
+    /// Address the server relays for us (what goes in the SDP)
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    /// TURN server media is exchanged with
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    fn add_credentials(&self, request: &mut StunMessage) {
+        request.add(USERNAME, self.username.as_bytes().to_vec());
+        request.add(REALM, self.realm.as_bytes().to_vec());
+        request.add(NONCE, self.nonce.lock().unwrap().as_bytes().to_vec());
+    }
+
+    // Take the new nonce from a 438 Stale Nonce; false for any other error
+    fn update_nonce(&self, response: &StunMessage) -> bool {
+        match (response.error_code(), response.text_attribute(NONCE)) {
+            (Some((438, _)), Some(nonce)) => {
+                *self.nonce.lock().unwrap() = nonce;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// ChannelBind request pointing a channel at `peer`. The same peer keeps
+    /// its channel (re-sending refreshes it); a new peer gets the next one.
+    pub fn channel_bind_request(&self, peer: SocketAddr) -> Result<Vec<u8>, SipError> {
+        let number = {
+            let mut channel = self.channel.lock().unwrap();
+            let number = match *channel {
+                Some((number, bound)) if bound == peer => number,
+                Some((number, _)) if number < LAST_CHANNEL => number + 1,
+                _ => FIRST_CHANNEL,
+            };
+            *channel = Some((number, peer));
+            number
+        };
+
+        let mut request = StunMessage::new(CHANNEL_BIND_REQUEST)?;
+        request.add(CHANNEL_NUMBER, [number.to_be_bytes().as_slice(), &[0, 0]].concat());
+        request.add_xor_address(XOR_PEER_ADDRESS, peer);
+        self.add_credentials(&mut request);
+        Ok(request.encode_with_integrity(&self.key))
+    }
+
+    /// Refresh request keeping the allocation for `lifetime` seconds (0 releases it)
+    pub fn refresh_request(&self, lifetime: u32) -> Result<Vec<u8>, SipError> {
+        let mut request = StunMessage::new(REFRESH_REQUEST)?;
+        request.add(LIFETIME, lifetime.to_be_bytes().to_vec());
+        self.add_credentials(&mut request);
+        Ok(request.encode_with_integrity(&self.key))
+    }
+
+    /// Requests to send now to keep the allocation and channel alive
+    /// (empty until the refresh interval has passed)
+    pub fn due_refreshes(&self) -> Result<Vec<Vec<u8>>, SipError> {
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if last_refresh.is_some_and(|at| at.elapsed() < REFRESH_INTERVAL) {
+                return Ok(Vec::new());
+            }
+            *last_refresh = Some(Instant::now());
+        }
+
+        let mut requests = vec![self.refresh_request(ALLOCATION_LIFETIME_SECS)?];
+        let peer = self.channel.lock().unwrap().map(|(_, peer)| peer);
+        if let Some(peer) = peer {
+            requests.push(self.channel_bind_request(peer)?);
+        }
+        Ok(requests)
+    }
+
+    /// Frame media for the peer as ChannelData (RFC 5766 11.4)
+    pub fn wrap(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let (number, _) = (*self.channel.lock().unwrap())?;
+        let mut data = Vec::with_capacity(4 + payload.len());
+        data.extend_from_slice(&number.to_be_bytes());
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(payload);
+        Some(data)
+    }
+
+    /// Media relayed from the peer (ChannelData or a Data indication), with
+    /// the peer address. Responses to our refreshes are consumed here.
+    pub fn unwrap(&self, datagram: &[u8]) -> Option<(Vec<u8>, SocketAddr)> {
+        if datagram.len() >= 4 && (0x40..=0x7F).contains(&datagram[0]) {
+            let number = u16::from_be_bytes([datagram[0], datagram[1]]);
+            let length = u16::from_be_bytes([datagram[2], datagram[3]]) as usize;
+            let (bound, peer) = (*self.channel.lock().unwrap())?;
+            if number != bound {
+                return None;
+            }
+            return Some((datagram.get(4..4 + length)?.to_vec(), peer));
+        }
+
+        let message = StunMessage::decode(datagram)?;
+        if message.msg_type == DATA_INDICATION {
+            let peer = message.xor_address(XOR_PEER_ADDRESS)?;
+            return Some((message.attribute(DATA)?.to_vec(), peer));
+        }
+
+        if message.is_error() && !self.update_nonce(&message) {
+            tracing::warn!("[TURN] Relay request failed: {}", describe_error(&message));
+            println!("[TURN] Relay request failed: {}", describe_error(&message));
+        } else if message.is_error() {
+            // Stale nonce: send the refreshes again with the new one
+            *self.last_refresh.lock().unwrap() = None;
+        }
+        None
+    }
+}
+
+fn allocate_request() -> Result<StunMessage, SipError> {
+    let mut request = StunMessage::new(ALLOCATE_REQUEST)?;
+    request.add(REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]);
+    request.add(LIFETIME, ALLOCATION_LIFETIME_SECS.to_be_bytes().to_vec());
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers Binding and (after one 401 challenge) Allocate like a TURN server
+    async fn mock_turn_server() -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                let request = StunMessage::decode(&buf[..size]).unwrap();
+                let mut response = StunMessage { attributes: Vec::new(), ..request.clone() };
+                match request.msg_type {
+                    BINDING_REQUEST => {
+                        response.msg_type = BINDING_REQUEST | SUCCESS_CLASS;
+                        response.add_xor_address(XOR_MAPPED_ADDRESS, "203.0.113.5:40000".parse().unwrap());
+                    }
+                    ALLOCATE_REQUEST if request.attribute(MESSAGE_INTEGRITY).is_none() => {
+                        response.msg_type = ALLOCATE_REQUEST | ERROR_CLASS;
+                        response.add(ERROR_CODE, [&[0, 0, 4, 1][..], b"Unauthorized"].concat());
+                        response.add(REALM, b"example.org".to_vec());
+                        response.add(NONCE, b"nonce-1".to_vec());
+                    }
+                    ALLOCATE_REQUEST => {
+                        assert_eq!(request.attribute(USERNAME), Some(b"alice".as_slice()));
+                        assert_eq!(request.attribute(NONCE), Some(b"nonce-1".as_slice()));
+                        // Check the integrity with the key the password gives
+                        let key = long_term_key("alice", "example.org", "secret");
+                        let signed = StunMessage {
+                            attributes: request.attributes[..request.attributes.len() - 1].to_vec(),
+                            ..request.clone()
+                        }
+                        .encode_with_integrity(&key);
+                        assert_eq!(&signed[..], &buf[..size]);
+
+                        response.msg_type = ALLOCATE_REQUEST | SUCCESS_CLASS;
+                        response.add_xor_address(XOR_RELAYED_ADDRESS, "198.51.100.1:50000".parse().unwrap());
+                        response.add_xor_address(XOR_MAPPED_ADDRESS, from);
+                    }
+                    _ => continue,
+                }
+                socket.send_to(&response.encode(), from).await.unwrap();
+            }
+        });
+        (addr, task)
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut message = StunMessage::new(BINDING_REQUEST).unwrap();
+        message.add(USERNAME, b"bob".to_vec());
+        message.add_xor_address(XOR_PEER_ADDRESS, "192.0.2.1:32853".parse().unwrap());
+        message.add_xor_address(XOR_MAPPED_ADDRESS, "[2001:db8::1]:5000".parse().unwrap());
+
+        let bytes = message.encode();
+        assert!(is_stun(&bytes));
+        assert_eq!(bytes.len() % 4, 0);
+        let decoded = StunMessage::decode(&bytes).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.attribute(USERNAME), Some(b"bob".as_slice()));
+        assert_eq!(decoded.xor_address(XOR_PEER_ADDRESS), Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(decoded.mapped_address(), Some("[2001:db8::1]:5000".parse().unwrap()));
+
+        // RTP is never mistaken for STUN
+        assert!(!is_stun(&[0x80, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_xor_address_matches_rfc_5769() {
+        // XOR-MAPPED-ADDRESS from the RFC 5769 2.2 sample response
+        let transaction_id = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+        let value = xor_address("192.0.2.1:32853".parse().unwrap(), &transaction_id);
+        assert_eq!(value, [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+    }
+
+    #[test]
+    fn test_error_code() {
+        let mut message = StunMessage::new(ALLOCATE_REQUEST | ERROR_CLASS).unwrap();
+        message.add(ERROR_CODE, [&[0, 0, 4, 38][..], b"Stale Nonce"].concat());
+        assert_eq!(message.error_code(), Some((438, "Stale Nonce".to_string())));
+        assert!(message.is_error() && !message.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_binding_and_allocation() {
+        let (server, task) = mock_turn_server().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(binding(&socket, server).await.unwrap(), "203.0.113.5:40000".parse().unwrap());
+
+        let relay = TurnRelay::allocate(&socket, server, "alice", "secret").await.unwrap();
+        assert_eq!(relay.relayed_addr(), "198.51.100.1:50000".parse().unwrap());
+        task.abort();
+    }
+
+    #[test]
+    fn test_channel_data() {
+        let relay = TurnRelay {
+            server: "127.0.0.1:3478".parse().unwrap(),
+            username: "alice".to_string(),
+            realm: "example.org".to_string(),
+            key: long_term_key("alice", "example.org", "secret"),
+            relayed_addr: "198.51.100.1:50000".parse().unwrap(),
+            nonce: Mutex::new("nonce-1".to_string()),
+            channel: Mutex::new(None),
+            last_refresh: Mutex::new(Some(Instant::now())),
+        };
+        let peer: SocketAddr = "192.0.2.7:4000".parse().unwrap();
+
+        // Nothing goes out before a channel is bound
+        assert!(relay.wrap(b"rtp").is_none());
+        let bind = StunMessage::decode(&relay.channel_bind_request(peer).unwrap()).unwrap();
+        assert_eq!(bind.attribute(CHANNEL_NUMBER), Some([0x40, 0x00, 0, 0].as_slice()));
+        assert_eq!(bind.xor_address(XOR_PEER_ADDRESS), Some(peer));
+
+        let framed = relay.wrap(b"rtp").unwrap();
+        assert_eq!(framed, [0x40, 0x00, 0x00, 0x03, b'r', b't', b'p']);
+        assert_eq!(relay.unwrap(&framed), Some((b"rtp".to_vec(), peer)));
+
+        // A new peer moves to the next channel; the old one is no longer accepted
+        relay.channel_bind_request("192.0.2.8:4000".parse().unwrap()).unwrap();
+        assert_eq!(relay.wrap(b"x").unwrap()[..2], [0x40, 0x01]);
+        assert_eq!(relay.unwrap(&framed), None);
+
+        // Data indications carry the peer address themselves
+        let mut indication = StunMessage::new(DATA_INDICATION).unwrap();
+        indication.add_xor_address(XOR_PEER_ADDRESS, peer);
+        indication.add(DATA, b"media".to_vec());
+        assert_eq!(relay.unwrap(&indication.encode()), Some((b"media".to_vec(), peer)));
+
+        // A stale nonce is picked up for the next refresh
+        let mut stale = StunMessage::new(REFRESH_REQUEST | ERROR_CLASS).unwrap();
+        stale.add(ERROR_CODE, [&[0, 0, 4, 38][..], b"Stale Nonce"].concat());
+        stale.add(NONCE, b"nonce-2".to_vec());
+        assert_eq!(relay.unwrap(&stale.encode()), None);
+        assert_eq!(*relay.nonce.lock().unwrap(), "nonce-2");
+        assert_eq!(relay.due_refreshes().unwrap().len(), 2);
+        assert!(relay.due_refreshes().unwrap().is_empty());
+    }
+}
@@ -1,7 +1,34 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/tauri";
 import { listen } from "@tauri-apps/api/event";
-import { usePhoneStore } from "./store";
+import { usePhoneStore, CallState } from "./store";
+
+// Commands reject with a SipError ({ kind, message }) or a plain string
+function errorMessage(error: unknown): string {
+  if (error && typeof error === "object" && "message" in error) {
+    return String((error as { message: unknown }).message);
+  }
+  return String(error);
+}
+
+// The engine reports the dialog state (CALLING, RINGING, CONFIRMED,
+// TERMINATED); the controls are keyed on the UI's own states. RINGING is
+// an incoming call unless we just placed one (outgoing calls go through
+// CALLING first).
+function uiCallState(state: string, current: CallState, registered: boolean): CallState {
+  switch (state) {
+    case "CALLING":
+      return "OUTGOING";
+    case "RINGING":
+      return current === "OUTGOING" ? "OUTGOING" : "INCOMING";
+    case "CONFIRMED":
+      return "ACTIVE";
+    case "TERMINATED":
+      return registered ? "REGISTERED" : "INITIALIZED";
+    default:
+      return state as CallState;
+  }
+}
 
 function App() {
   const { 
@@ -32,7 +59,8 @@ function App() {
         setIsRegistered(event.payload.registered);
         setCallState(event.payload.registered ? "REGISTERED" : "INITIALIZED");
       } else if (event.payload.type === "call_state") {
-        setCallState(event.payload.state);
+        const { callState, isRegistered } = usePhoneStore.getState();
+        setCallState(uiCallState(event.payload.state, callState, isRegistered));
       }
     });
 
@@ -85,8 +113,8 @@ function App() {
     if (!phoneNumber) return;
     
     try {
+      // call_state events drive the controls from here
       await invoke("make_call", { number: phoneNumber });
-      setCallState("OUTGOING");
     } catch (error) {
       console.error("Call failed:", error);
     }
@@ -149,7 +177,7 @@ function App() {
       }
     } catch (error) {
       console.error("Failed to load audio devices:", error);
-      setTestResult(`Error: ${error}`);
+      setTestResult(`Error: ${errorMessage(error)}`);
     }
   };
 
@@ -161,7 +189,7 @@ function App() {
       });
       setTestResult(result);
     } catch (error) {
-      setTestResult(`Microphone test failed: ${error}`);
+      setTestResult(`Microphone test failed: ${errorMessage(error)}`);
     }
   };
 
@@ -173,7 +201,7 @@ function App() {
       });
       setTestResult(result);
     } catch (error) {
-      setTestResult(`Speaker test failed: ${error}`);
+      setTestResult(`Speaker test failed: ${errorMessage(error)}`);
     }
   };
 
@@ -192,7 +220,7 @@ function App() {
       setShowSettings(false);
     } catch (error) {
       console.error("Failed to save audio device preferences:", error);
-      setTestResult(`Error saving preferences: ${error}`);
+      setTestResult(`Error saving preferences: ${errorMessage(error)}`);
     }
   };
 